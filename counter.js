@@ -3,9 +3,6 @@ addEventListener("fetch", async (event) => {
 });
 
 async function handleRequest(request) {
-    let counter = await kv.test.get("counter");
-    counter = (counter === null ? 0 : parseInt(counter)) + 1;
-
-    await kv.test.put("counter", "" + counter);
+    let counter = await kv.test.increment("counter");
     return new Response("New counter: " + counter);
 }
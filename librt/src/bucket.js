@@ -0,0 +1,369 @@
+const { readSource } = require("./kv.js");
+
+// Keep in sync with OBJECT_PART_SIZE in the runtime.
+const OBJECT_PART_SIZE = 1024 * 1024;
+
+// Fields of `httpMetadata` and the headers they are written to.
+const HTTP_METADATA_HEADERS = {
+    contentType: "content-type",
+    contentLanguage: "content-language",
+    contentDisposition: "content-disposition",
+    contentEncoding: "content-encoding",
+    cacheControl: "cache-control",
+};
+
+/**
+ * @param {any} call
+ * @param {Array<ArrayBuffer | ArrayBufferView>} buffers
+ * @returns {Promise<{value: any, buffers: Array<ArrayBuffer>}>}
+ */
+function callBucketService(call, buffers) {
+    return new Promise((resolve, reject) => {
+        _callServiceWrapper({
+            Async: call,
+        }, buffers, (result, buffers) => {
+            if(result.Err) {
+                reject(new Error(result.Err));
+            } else if(result.Ok.Err) {
+                reject(new Error(result.Ok.Err));
+            } else {
+                resolve({ value: result.Ok.Ok, buffers: buffers });
+            }
+        })
+    });
+}
+
+/**
+ * Converts `httpMetadata` given as an object or a `Headers` into an object of strings.
+ *
+ * @param {any} input
+ * @returns {Object<string, string>}
+ */
+function normalizeHttpMetadata(input) {
+    let out = {};
+    if(!input) {
+        return out;
+    }
+    if(typeof(input.get) === "function") {
+        for(const [field, header] of Object.entries(HTTP_METADATA_HEADERS)) {
+            let value = input.get(header);
+            if(value !== null && value !== undefined) {
+                out[field] = String(value);
+            }
+        }
+    } else {
+        for(const [field, value] of Object.entries(input)) {
+            if(value !== null && value !== undefined) {
+                out[field] = String(value);
+            }
+        }
+    }
+    return out;
+}
+
+/**
+ * Metadata of an object in a bucket.
+ */
+class BucketObject {
+    constructor(info) {
+        this.key = info.key;
+        this.size = info.size;
+        this.etag = info.etag;
+        this.httpEtag = "\"" + info.etag + "\"";
+        this.uploaded = new Date(info.uploaded);
+        this.httpMetadata = info.http_metadata;
+        this.customMetadata = info.custom_metadata;
+    }
+
+    /**
+     * Sets the headers stored in `httpMetadata` on `headers`.
+     *
+     * @param {Headers} headers
+     */
+    writeHttpMetadata(headers) {
+        for(const [field, header] of Object.entries(HTTP_METADATA_HEADERS)) {
+            if(this.httpMetadata[field] !== undefined) {
+                headers.set(header, this.httpMetadata[field]);
+            }
+        }
+    }
+}
+
+/**
+ * A stream of the data of an object, read on demand.
+ *
+ * Supports the reader interface of `ReadableStream` and async iteration. Reading fails if the
+ * object is modified or deleted after the stream was opened.
+ */
+class ObjectStream {
+    /**
+     * @param {Bucket} bucket
+     * @param {Object} info
+     * @param {number} start
+     * @param {number} end
+     * @param {ArrayBuffer} first Data already read from `start`.
+     */
+    constructor(bucket, info, start, end, first) {
+        this._bucket = bucket;
+        this._info = info;
+        this._pos = start;
+        this._end = end;
+        this._first = first;
+    }
+
+    getReader() {
+        return {
+            read: async () => {
+                if(this._first !== null) {
+                    let data = this._first;
+                    this._first = null;
+                    this._pos += data.byteLength;
+                    return { done: false, value: new Uint8Array(data) };
+                }
+                if(this._pos >= this._end) {
+                    return { done: true, value: undefined };
+                }
+                let res = await this._bucket._read(this._info.key, {
+                    Offset: { offset: this._pos, length: this._end - this._pos },
+                }, this._info.etag);
+                if(res === null || res.data.byteLength === 0) {
+                    throw new Error("unexpected end of object");
+                }
+                this._pos += res.data.byteLength;
+                return { done: false, value: new Uint8Array(res.data) };
+            },
+            cancel: async () => {
+                this._first = null;
+                this._pos = this._end;
+            },
+            releaseLock: () => {},
+        };
+    }
+
+    async *[Symbol.asyncIterator]() {
+        let reader = this.getReader();
+        while(true) {
+            let { done, value } = await reader.read();
+            if(done) return;
+            yield value;
+        }
+    }
+}
+
+/**
+ * An object in a bucket, with its data.
+ */
+class BucketObjectBody extends BucketObject {
+    constructor(bucket, info, start, end, first) {
+        super(info);
+        this.range = { offset: start, length: end - start };
+        this.body = new ObjectStream(bucket, info, start, end, first);
+        this.bodyUsed = false;
+    }
+
+    /**
+     * @returns {Promise<ArrayBuffer>}
+     */
+    async arrayBuffer() {
+        if(this.bodyUsed) {
+            throw new TypeError("body already used");
+        }
+        this.bodyUsed = true;
+        let out = new Uint8Array(this.range.length);
+        let offset = 0;
+        for await (const chunk of this.body) {
+            out.set(chunk, offset);
+            offset += chunk.byteLength;
+        }
+        return out.buffer;
+    }
+
+    /**
+     * @returns {Promise<string>}
+     */
+    async text() {
+        return new TextDecoder().decode(await this.arrayBuffer());
+    }
+
+    /**
+     * @returns {Promise<any>}
+     */
+    async json() {
+        return JSON.parse(await this.text());
+    }
+}
+
+class Bucket {
+    /**
+     *
+     * @param {string} name
+     */
+    constructor(name) {
+        this.name = name;
+    }
+
+    /**
+     * @param {string} key
+     * @returns {Promise<BucketObject | null>}
+     */
+    async head(key) {
+        let res = await callBucketService({
+            BucketHead: {
+                bucket: this.name,
+                key: key,
+            }
+        }, []);
+        return res.value ? new BucketObject(res.value) : null;
+    }
+
+    async _read(key, range, etag) {
+        let res = await callBucketService({
+            BucketGet: {
+                bucket: this.name,
+                key: key,
+                range: range,
+                etag: etag,
+            }
+        }, []);
+        return res.value ? { info: res.value.object, offset: res.value.offset, data: res.buffers[0] } : null;
+    }
+
+    /**
+     * Returns an object and a stream of its data, or null if it doesn't exist.
+     *
+     * @param {string} key
+     * @param {Object} opts
+     * @param {{offset: number | undefined, length: number | undefined} | {suffix: number} | undefined} opts.range
+     * @returns {Promise<BucketObjectBody | null>}
+     */
+    async get(key, opts) {
+        let range = { Offset: { offset: 0 } };
+        if(opts?.range?.suffix !== undefined) {
+            range = { Suffix: opts.range.suffix };
+        } else if(opts?.range) {
+            range = { Offset: { offset: opts.range.offset || 0, length: opts.range.length } };
+        }
+
+        let res = await this._read(key, range, null);
+        if(res === null) {
+            return null;
+        }
+        let start = res.offset;
+        let end = range.Offset?.length !== undefined
+            ? Math.min(start + range.Offset.length, res.info.size)
+            : res.info.size;
+        return new BucketObjectBody(this, res.info, start, end, res.data);
+    }
+
+    /**
+     * Writes an object, replacing the previous one.
+     *
+     * @param {string} key
+     * @param {any} value A `ReadableStream`, an async iterable of buffers, a buffer, a string or null.
+     * @param {Object} opts
+     * @param {Object | Headers | undefined} opts.httpMetadata
+     * @param {Object<string, string> | undefined} opts.customMetadata
+     * @returns {Promise<BucketObject>}
+     */
+    async put(key, value, opts) {
+        let uploadId = null;
+        let parts = 0;
+        let pending = new Uint8Array(OBJECT_PART_SIZE);
+        let pendingLen = 0;
+
+        if(value !== null && value !== undefined) {
+            for await (let data of readSource(value)) {
+                while(data.byteLength !== 0) {
+                    let n = Math.min(data.byteLength, OBJECT_PART_SIZE - pendingLen);
+                    pending.set(data.subarray(0, n), pendingLen);
+                    pendingLen += n;
+                    data = data.subarray(n);
+                    if(pendingLen === OBJECT_PART_SIZE) {
+                        let res = await callBucketService({
+                            BucketPutPart: {
+                                bucket: this.name,
+                                upload_id: uploadId,
+                                index: parts,
+                            }
+                        }, [pending]);
+                        uploadId = res.value;
+                        parts++;
+                        pendingLen = 0;
+                    }
+                }
+            }
+        }
+
+        let customMetadata = {};
+        for(const [k, v] of Object.entries(opts?.customMetadata || {})) {
+            customMetadata[k] = String(v);
+        }
+        let res = await callBucketService({
+            BucketPut: {
+                bucket: this.name,
+                key: key,
+                upload_id: uploadId,
+                parts: parts,
+                metadata: {
+                    http_metadata: normalizeHttpMetadata(opts?.httpMetadata),
+                    custom_metadata: customMetadata,
+                },
+            }
+        }, [pending.slice(0, pendingLen)]);
+        return new BucketObject(res.value);
+    }
+
+    /**
+     * Lists objects in key order.
+     *
+     * @param {Object} opts
+     * @param {string | undefined} opts.prefix
+     * @param {string | undefined} opts.delimiter Keys containing it after the prefix are rolled up into `delimitedPrefixes`.
+     * @param {string | undefined} opts.cursor
+     * @param {number | undefined} opts.limit Defaults to 1000.
+     * @returns {Promise<{objects: BucketObject[], delimitedPrefixes: string[], truncated: boolean, cursor: string | undefined}>}
+     */
+    async list(opts) {
+        let res = await callBucketService({
+            BucketList: {
+                bucket: this.name,
+                prefix: opts?.prefix || "",
+                delimiter: opts?.delimiter || null,
+                cursor: opts?.cursor || null,
+                limit: opts?.limit || 1000,
+            }
+        }, []);
+        let list = res.value;
+        return {
+            objects: list.objects.map(x => new BucketObject(x)),
+            delimitedPrefixes: list.delimited_prefixes,
+            truncated: list.cursor !== null,
+            cursor: list.cursor !== null ? list.cursor : undefined,
+        };
+    }
+
+    /**
+     * @param {string} key
+     * @returns {Promise<void>}
+     */
+    async delete(key) {
+        await callBucketService({
+            BucketDelete: {
+                bucket: this.name,
+                key: key,
+            }
+        }, []);
+    }
+}
+
+const bucketHandler = {
+    get: function(target, prop, receiver) {
+        if(prop in target) {
+            return target[prop];
+        } else {
+            return new Bucket(prop);
+        }
+    }
+}
+
+export const buckets = new Proxy({}, bucketHandler);
@@ -0,0 +1,198 @@
+/**
+ * @param {any} call
+ * @param {Array<ArrayBuffer | ArrayBufferView>} buffers
+ * @returns {Promise<any>}
+ */
+function callDbService(call, buffers) {
+    return new Promise((resolve, reject) => {
+        _callServiceWrapper({
+            Async: call,
+        }, buffers, (result) => {
+            if(result.Err) {
+                reject(new Error(result.Err));
+            } else if(result.Ok.Err) {
+                reject(new Error(result.Ok.Err));
+            } else {
+                resolve(result.Ok.Ok);
+            }
+        })
+    });
+}
+
+/**
+ * Converts a bound value into a `SqlParam`. Blobs are appended to `buffers`.
+ *
+ * @param {any} value
+ * @param {Array<ArrayBuffer | ArrayBufferView>} buffers
+ */
+function encodeParam(value, buffers) {
+    if(value === null || value === undefined) {
+        return "Null";
+    } else if(typeof(value) === "boolean") {
+        return { Integer: value ? 1 : 0 };
+    } else if(typeof(value) === "number") {
+        return Number.isSafeInteger(value) ? { Integer: value } : { Real: value };
+    } else if(typeof(value) === "bigint") {
+        return { Integer: Number(value) };
+    } else if(typeof(value) === "string") {
+        return { Text: value };
+    } else if(value instanceof ArrayBuffer || ArrayBuffer.isView(value)) {
+        buffers.push(value);
+        return { Blob: buffers.length - 1 };
+    } else {
+        throw new TypeError("unsupported parameter type");
+    }
+}
+
+/**
+ * Converts a `SqlValue` returned by the database into a JavaScript value.
+ */
+function decodeValue(value) {
+    if(value === "Null") {
+        return null;
+    } else if(value.Integer !== undefined) {
+        return value.Integer;
+    } else if(value.Real !== undefined) {
+        return value.Real;
+    } else if(value.Text !== undefined) {
+        return value.Text;
+    } else {
+        return new Uint8Array(value.Blob);
+    }
+}
+
+function decodeRow(columns, row) {
+    let out = {};
+    for(let i = 0; i < columns.length; i++) {
+        out[columns[i]] = decodeValue(row[i]);
+    }
+    return out;
+}
+
+function decodeMeta(result) {
+    return {
+        changes: result.changes,
+        last_row_id: result.last_insert_rowid,
+    };
+}
+
+/**
+ * Runs statements in one transaction.
+ *
+ * @param {PreparedStatement[]} statements
+ * @returns {Promise<any[]>} The raw result of each statement.
+ */
+async function execute(statements) {
+    let buffers = [];
+    let encoded = statements.map(stmt => ({
+        sql: stmt._sql,
+        params: stmt._params.map(x => encodeParam(x, buffers)),
+    }));
+    return await callDbService({
+        SqlExecute: {
+            statements: encoded,
+        }
+    }, buffers);
+}
+
+class PreparedStatement {
+    /**
+     * @param {string} sql
+     * @param {any[]} params
+     */
+    constructor(sql, params) {
+        this._sql = sql;
+        this._params = params;
+    }
+
+    /**
+     * Returns a statement with the given values bound to its parameters.
+     *
+     * @param  {...any} values
+     * @returns {PreparedStatement}
+     */
+    bind(...values) {
+        return new PreparedStatement(this._sql, values);
+    }
+
+    /**
+     * Runs the statement and returns all rows as objects.
+     *
+     * @returns {Promise<{results: Object[], meta: {changes: number, last_row_id: number}}>}
+     */
+    async all() {
+        let [result] = await execute([this]);
+        return {
+            results: result.rows.map(row => decodeRow(result.columns, row)),
+            meta: decodeMeta(result),
+        };
+    }
+
+    /**
+     * Runs the statement and returns the first row, or the value of `column` in the first row.
+     * Returns null if there are no rows.
+     *
+     * @param {string | undefined} column
+     * @returns {Promise<any>}
+     */
+    async first(column) {
+        let [result] = await execute([this]);
+        if(result.rows.length === 0) {
+            return null;
+        }
+        let row = decodeRow(result.columns, result.rows[0]);
+        if(column === undefined) {
+            return row;
+        }
+        if(!(column in row)) {
+            throw new Error("no such column: " + column);
+        }
+        return row[column];
+    }
+
+    /**
+     * Runs the statement and returns the number of changed rows and the last inserted row id.
+     *
+     * @returns {Promise<{meta: {changes: number, last_row_id: number}}>}
+     */
+    async run() {
+        let [result] = await execute([this]);
+        return { meta: decodeMeta(result) };
+    }
+
+    /**
+     * Runs the statement and returns all rows as arrays.
+     *
+     * @returns {Promise<any[][]>}
+     */
+    async raw() {
+        let [result] = await execute([this]);
+        return result.rows.map(row => row.map(decodeValue));
+    }
+}
+
+class Database {
+    /**
+     * @param {string} sql
+     * @returns {PreparedStatement}
+     */
+    prepare(sql) {
+        return new PreparedStatement(sql, []);
+    }
+
+    /**
+     * Runs statements in one transaction. If a statement fails, none of them take effect.
+     *
+     * @param {PreparedStatement[]} statements
+     * @returns {Promise<Array<{results: Object[], meta: {changes: number, last_row_id: number}}>>}
+     */
+    async batch(statements) {
+        let results = await execute(statements);
+        return results.map(result => ({
+            results: result.rows.map(row => decodeRow(result.columns, row)),
+            meta: decodeMeta(result),
+        }));
+    }
+}
+
+export const db = new Database();
@@ -1,5 +1,102 @@
 
 
+// Keep in sync with MAX_KV_TX_KEYS in the runtime.
+const MAX_TX_KEYS = 100;
+
+// Keep in sync with BLOB_CHUNK_SIZE in the runtime.
+const BLOB_CHUNK_SIZE = 1024 * 1024;
+
+/**
+ * A stream of the chunks of a blob, read on demand.
+ *
+ * Supports the reader interface of `ReadableStream` and async iteration.
+ */
+class BlobStream {
+    /**
+     * @param {KvNamespace} ns 
+     * @param {Object} info 
+     */
+    constructor(ns, info) {
+        this._ns = ns;
+        this._info = info;
+        this._next = 0;
+        this.size = info.size;
+    }
+
+    getReader() {
+        return {
+            read: async () => {
+                if(this._next >= this._info.chunks) {
+                    return { done: true, value: undefined };
+                }
+                let chunk = await this._ns._getBlobChunk(this._info.blob_id, this._next);
+                this._next++;
+                return { done: false, value: new Uint8Array(chunk) };
+            },
+            cancel: async () => {
+                this._next = this._info.chunks;
+            },
+            releaseLock: () => {},
+        };
+    }
+
+    async *[Symbol.asyncIterator]() {
+        let reader = this.getReader();
+        while(true) {
+            let { done, value } = await reader.read();
+            if(done) return;
+            yield value;
+        }
+    }
+
+    /**
+     * Reads the remaining chunks into one buffer.
+     * 
+     * @returns {Promise<ArrayBuffer>}
+     */
+    async arrayBuffer() {
+        let out = new Uint8Array(this.size);
+        let offset = 0;
+        for await (const chunk of this) {
+            out.set(chunk, offset);
+            offset += chunk.byteLength;
+        }
+        return out.buffer.slice(0, offset);
+    }
+}
+
+/**
+ * Yields the data of a stream, an async iterable or a buffer as `Uint8Array`s.
+ * 
+ * @param {any} source 
+ */
+export async function* readSource(source) {
+    if(typeof(source) === "string") {
+        yield new TextEncoder().encode(source);
+    } else if(source instanceof ArrayBuffer) {
+        yield new Uint8Array(source);
+    } else if(ArrayBuffer.isView(source)) {
+        yield new Uint8Array(source.buffer, source.byteOffset, source.byteLength);
+    } else if(source && typeof(source.getReader) === "function") {
+        let reader = source.getReader();
+        try {
+            while(true) {
+                let { done, value } = await reader.read();
+                if(done) break;
+                yield* readSource(value);
+            }
+        } finally {
+            reader.releaseLock();
+        }
+    } else if(source && source[Symbol.asyncIterator]) {
+        for await (const x of source) {
+            yield* readSource(x);
+        }
+    } else {
+        throw new TypeError("expecting a stream, an async iterable or a buffer");
+    }
+}
+
 class KvNamespace {
     /**
      * 
@@ -51,16 +148,62 @@ class KvNamespace {
         }
     }
 
+    /**
+     * @param {ArrayBuffer | ArrayBufferView} key
+     * @returns {Promise<{value: ArrayBuffer, metadata: ArrayBuffer} | null>}
+     */
+    getWithMetadataRaw(key) {
+        return new Promise((resolve, reject) => {
+            _callServiceWrapper({
+                Async: {
+                    KvGetWithMetadata: {
+                        namespace: this.name,
+                    }
+                }
+            }, [key], (result, buffers) => {
+                if(result.Err) {
+                    reject(new Error(result.Err));
+                } else if(result.Ok.Err) {
+                    reject(new Error(result.Ok.Err));
+                } else {
+                    if(result.Ok.Ok) {
+                        resolve({ value: buffers[0], metadata: buffers[1] });
+                    } else {
+                        resolve(null);
+                    }
+                }
+            })
+        });
+    }
+
+    /**
+     * @param {string} key
+     * @returns {Promise<{value: string | null, metadata: any}>}
+     */
+    async getWithMetadata(key) {
+        let keyRaw = new TextEncoder().encode(key);
+        let res = await this.getWithMetadataRaw(keyRaw.buffer);
+        if(res !== null) {
+            return {
+                value: new TextDecoder().decode(res.value),
+                metadata: res.metadata.byteLength !== 0 ? JSON.parse(new TextDecoder().decode(res.metadata)) : null,
+            };
+        } else {
+            return { value: null, metadata: null };
+        }
+    }
+
     /**
      * @param {ArrayBuffer | ArrayBufferView} key
      * @param {ArrayBuffer | ArrayBufferView} value
-     * @param {boolean} ifNotExists
      * @param {Object} opts 
      * @param {boolean | undefined} opts.ifNotExists
      * @param {number | undefined} opts.ttlMs
+     * @param {ArrayBuffer | ArrayBufferView | undefined} opts.metadata
      * @returns {Promise<void>}
      */
     putRaw(key, value, opts) {
+        let args = opts?.metadata ? [key, value, opts.metadata] : [key, value];
         return new Promise((resolve, reject) => {
             _callServiceWrapper({
                 Async: {
@@ -70,7 +213,7 @@ class KvNamespace {
                         ttl_ms: opts?.ttlMs || 0,
                     }
                 }
-            }, [key, value], (result) => {
+            }, args, (result) => {
                 if(result.Err) {
                     reject(new Error(result.Err));
                 } else if(result.Ok.Err) {
@@ -88,12 +231,17 @@ class KvNamespace {
      * @param {Object} opts 
      * @param {boolean | undefined} opts.ifNotExists
      * @param {number | undefined} opts.ttlMs
+     * @param {any} opts.metadata JSON-serializable metadata to store with the value
      * @returns {Promise<void>}
      */
     async put(key, value, opts) {
         let keyRaw = new TextEncoder().encode(key);
         let valueRaw = new TextEncoder().encode(value);
-        await this.putRaw(keyRaw.buffer, valueRaw.buffer, opts);
+        let rawOpts = { ...opts };
+        if(opts?.metadata !== undefined) {
+            rawOpts.metadata = new TextEncoder().encode(JSON.stringify(opts.metadata)).buffer;
+        }
+        await this.putRaw(keyRaw.buffer, valueRaw.buffer, rawOpts);
     }
 
     /**
@@ -154,6 +302,248 @@ class KvNamespace {
         );
     }
 
+    /**
+     * Commits a transaction if every read key still holds the value it was read with.
+     * 
+     * @param {[ArrayBuffer | ArrayBufferView, ArrayBuffer | ArrayBufferView | null][]} reads keys and the values read, null for absent keys
+     * @param {[ArrayBuffer | ArrayBufferView, {value: ArrayBuffer | ArrayBufferView, metadata: ArrayBuffer | ArrayBufferView | undefined, ttlMs: number | undefined} | null][]} writes keys and what to write, null for deletes
+     * @returns {Promise<boolean>} whether the transaction was committed
+     */
+    commitRaw(reads, writes) {
+        const bufferList = [];
+        for(const [k, v] of reads) {
+            bufferList.push(k);
+            if(v !== null) bufferList.push(v);
+        }
+        for(const [k, w] of writes) {
+            bufferList.push(k);
+            if(w !== null) {
+                bufferList.push(w.value);
+                bufferList.push(w.metadata || new ArrayBuffer(0));
+            }
+        }
+        return new Promise((resolve, reject) => {
+            _callServiceWrapper({
+                Async: {
+                    KvCommit: {
+                        namespace: this.name,
+                        reads: reads.map(([_k, v]) => v !== null),
+                        writes: writes.map(([_k, w]) => w !== null ? { Put: { ttl_ms: w.ttlMs || 0 } } : "Delete"),
+                    }
+                }
+            }, bufferList, (result) => {
+                if(result.Err) {
+                    reject(new Error(result.Err));
+                } else if(result.Ok.Err) {
+                    reject(new Error(result.Ok.Err));
+                } else {
+                    resolve(result.Ok.Ok);
+                }
+            })
+        });
+    }
+
+    /**
+     * Runs `fn` in a transaction and commits its writes atomically.
+     * 
+     * Reads and writes are buffered, and reads see earlier writes of the same transaction. If a
+     * key read by the transaction is changed by someone else before the commit, `fn` is run again
+     * with a fresh transaction, so it should not have side effects other than through `tx`.
+     * 
+     * @template T
+     * @param {(tx: KvTransaction) => Promise<T>} fn
+     * @param {Object} opts
+     * @param {number | undefined} opts.maxRetries
+     * @returns {Promise<T>} the result of the attempt that committed
+     */
+    async transaction(fn, { maxRetries = 5 } = {}) {
+        for(let attempt = 0; ; attempt++) {
+            const tx = new KvTransaction(this);
+            const result = await fn(tx);
+            if(await tx._commit()) {
+                return result;
+            }
+            if(attempt >= maxRetries) {
+                throw new Error("transaction conflict");
+            }
+            // Randomized backoff, so that conflicting transactions do not retry in lockstep.
+            const delay = Math.random() * 10 * Math.pow(2, attempt);
+            await new Promise(resolve => setTimeout(resolve, delay));
+        }
+    }
+
+    /**
+     * @param {(ArrayBuffer | ArrayBufferView)[]} keys
+     * @returns {Promise<(ArrayBuffer | null)[]>}
+     */
+    getManyRaw(keys) {
+        return new Promise((resolve, reject) => {
+            _callServiceWrapper({
+                Async: {
+                    KvGetMany: {
+                        namespace: this.name,
+                    }
+                }
+            }, keys, (result, buffers) => {
+                if(result.Err) {
+                    reject(new Error(result.Err));
+                } else if(result.Ok.Err) {
+                    reject(new Error(result.Ok.Err));
+                } else {
+                    let index = 0;
+                    resolve(result.Ok.Ok.map(found => found ? buffers[index++] : null));
+                }
+            })
+        });
+    }
+
+    /**
+     * @param {string[]} keys
+     * @returns {Promise<(string | null)[]>}
+     */
+    async getMany(keys) {
+        const encoder = new TextEncoder();
+        const decoder = new TextDecoder();
+        const values = await this.getManyRaw(keys.map(x => encoder.encode(x).buffer));
+        return values.map(x => x !== null ? decoder.decode(x) : null);
+    }
+
+    /**
+     * @param {[ArrayBuffer | ArrayBufferView, ArrayBuffer | ArrayBufferView][]} entries
+     * @param {Object} opts
+     * @param {number | undefined} opts.ttlMs
+     * @returns {Promise<void>}
+     */
+    putManyRaw(entries, opts) {
+        const bufferList = [];
+        for(const [k, v] of entries) {
+            bufferList.push(k);
+            bufferList.push(v);
+        }
+        return new Promise((resolve, reject) => {
+            _callServiceWrapper({
+                Async: {
+                    KvPutMany: {
+                        namespace: this.name,
+                        ttl_ms: opts?.ttlMs || 0,
+                    }
+                }
+            }, bufferList, (result) => {
+                if(result.Err) {
+                    reject(new Error(result.Err));
+                } else if(result.Ok.Err) {
+                    reject(new Error(result.Ok.Err));
+                } else {
+                    resolve();
+                }
+            })
+        });
+    }
+
+    /**
+     * @param {[string, string][]} entries
+     * @param {Object} opts
+     * @param {number | undefined} opts.ttlMs
+     * @returns {Promise<void>}
+     */
+    async putMany(entries, opts) {
+        const encoder = new TextEncoder();
+        await this.putManyRaw(
+            entries.map(x => x.map(x => encoder.encode(x).buffer)),
+            opts,
+        );
+    }
+
+    /**
+     * @param {(ArrayBuffer | ArrayBufferView)[]} keys
+     * @returns {Promise<void>}
+     */
+    deleteManyRaw(keys) {
+        return new Promise((resolve, reject) => {
+            _callServiceWrapper({
+                Async: {
+                    KvDeleteMany: {
+                        namespace: this.name,
+                    }
+                }
+            }, keys, (result) => {
+                if(result.Err) {
+                    reject(new Error(result.Err));
+                } else if(result.Ok.Err) {
+                    reject(new Error(result.Ok.Err));
+                } else {
+                    resolve();
+                }
+            })
+        });
+    }
+
+    /**
+     * @param {string[]} keys
+     * @returns {Promise<void>}
+     */
+    async deleteMany(keys) {
+        const encoder = new TextEncoder();
+        await this.deleteManyRaw(keys.map(x => encoder.encode(x).buffer));
+    }
+
+    /**
+     * @param {ArrayBuffer | ArrayBufferView} key
+     * @param {number} delta
+     * @param {Object} opts
+     * @param {number | undefined} opts.ttlMs
+     * @returns {Promise<number>}
+     */
+    incrementRaw(key, delta, opts) {
+        return new Promise((resolve, reject) => {
+            _callServiceWrapper({
+                Async: {
+                    KvIncrement: {
+                        namespace: this.name,
+                        delta: delta,
+                        ttl_ms: opts?.ttlMs || 0,
+                    }
+                }
+            }, [key], (result) => {
+                if(result.Err) {
+                    reject(new Error(result.Err));
+                } else if(result.Ok.Err) {
+                    reject(new Error(result.Ok.Err));
+                } else {
+                    resolve(result.Ok.Ok);
+                }
+            })
+        });
+    }
+
+    /**
+     * Atomically adds `delta` to the integer stored at `key`. A missing key counts as 0.
+     * 
+     * @param {string} key
+     * @param {number} delta
+     * @param {Object} opts
+     * @param {number | undefined} opts.ttlMs
+     * @returns {Promise<number>} the new value
+     */
+    async increment(key, delta = 1, opts) {
+        if(!Number.isSafeInteger(delta)) {
+            throw new TypeError("delta must be an integer");
+        }
+        let keyRaw = new TextEncoder().encode(key);
+        return await this.incrementRaw(keyRaw.buffer, delta, opts);
+    }
+
+    /**
+     * @param {string} key
+     * @param {number} delta
+     * @param {Object} opts
+     * @param {number | undefined} opts.ttlMs
+     * @returns {Promise<number>} the new value
+     */
+    async decrement(key, delta = 1, opts) {
+        return await this.increment(key, -delta, opts);
+    }
+
     /**
      * @param {ArrayBuffer | ArrayBufferView} key
      * @returns {Promise<void>}
@@ -242,6 +632,404 @@ class KvNamespace {
         });
         return results.map(x => new TextDecoder().decode(x));
     }
+
+    /**
+     * 
+     * @param {Object} args 
+     * @param {ArrayBuffer | ArrayBufferView} args.prefix
+     * @param {string | null} args.cursor
+     * @param {number} args.limit
+     * @param {boolean} args.includeValues
+     * @param {boolean} args.includeMetadata
+     * @returns {Promise<{keys: {name: ArrayBuffer, expiration: number | undefined, value: ArrayBuffer | undefined, metadata: ArrayBuffer | undefined}[], cursor: string | null, list_complete: boolean}>}
+     */
+    listRaw({prefix = new ArrayBuffer(0), cursor = null, limit = 1000, includeValues = false, includeMetadata = false}) {
+        return new Promise((resolve, reject) => {
+            _callServiceWrapper({
+                Async: {
+                    KvList: {
+                        namespace: this.name,
+                        cursor: cursor,
+                        limit: limit,
+                        include_values: includeValues,
+                        include_metadata: includeMetadata,
+                    }
+                }
+            }, [prefix], (result, buffers) => {
+                if(result.Err) {
+                    reject(new Error(result.Err));
+                } else if(result.Ok.Err) {
+                    reject(new Error(result.Ok.Err));
+                } else {
+                    const res = result.Ok.Ok;
+                    const keys = [];
+                    let index = 0;
+                    for(const expiration of res.expirations) {
+                        const entry = { name: buffers[index++] };
+                        if(expiration !== 0) entry.expiration = expiration;
+                        if(includeValues) entry.value = buffers[index++];
+                        if(includeMetadata) entry.metadata = buffers[index++];
+                        keys.push(entry);
+                    }
+                    resolve({
+                        keys: keys,
+                        cursor: res.cursor,
+                        list_complete: res.list_complete,
+                    });
+                }
+            })
+        });
+    }
+
+    /**
+     * 
+     * @param {Object} args 
+     * @param {string} args.prefix
+     * @param {string | null} args.cursor
+     * @param {number} args.limit
+     * @param {boolean} args.includeValues
+     * @param {boolean} args.includeMetadata
+     * @returns {Promise<{keys: {name: string, expiration: number | undefined, value: string | undefined, metadata: any}[], cursor: string | null, list_complete: boolean}>}
+     */
+    async list({prefix = "", cursor = null, limit = 1000, includeValues = false, includeMetadata = false} = {}) {
+        const decoder = new TextDecoder();
+        const res = await this.listRaw({
+            prefix: new TextEncoder().encode(prefix).buffer,
+            cursor: cursor,
+            limit: limit,
+            includeValues: includeValues,
+            includeMetadata: includeMetadata,
+        });
+        for(const entry of res.keys) {
+            entry.name = decoder.decode(entry.name);
+            if(entry.value !== undefined) {
+                entry.value = decoder.decode(entry.value);
+            }
+            if(entry.metadata !== undefined) {
+                entry.metadata = entry.metadata.byteLength !== 0 ? JSON.parse(decoder.decode(entry.metadata)) : null;
+            }
+        }
+        return res;
+    }
+
+    /**
+     * @param {string | null} blobId
+     * @param {number} index
+     * @param {Uint8Array} data
+     * @returns {Promise<string>} The blob id.
+     */
+    _putBlobChunk(blobId, index, data) {
+        return new Promise((resolve, reject) => {
+            _callServiceWrapper({
+                Async: {
+                    KvPutBlobChunk: {
+                        namespace: this.name,
+                        blob_id: blobId,
+                        index: index,
+                    }
+                }
+            }, [data], (result) => {
+                if(result.Err) {
+                    reject(new Error(result.Err));
+                } else if(result.Ok.Err) {
+                    reject(new Error(result.Ok.Err));
+                } else {
+                    resolve(result.Ok.Ok);
+                }
+            })
+        });
+    }
+
+    /**
+     * @param {string} blobId
+     * @param {number} index
+     * @returns {Promise<ArrayBuffer>}
+     */
+    _getBlobChunk(blobId, index) {
+        return new Promise((resolve, reject) => {
+            _callServiceWrapper({
+                Async: {
+                    KvGetBlobChunk: {
+                        namespace: this.name,
+                        blob_id: blobId,
+                        index: index,
+                    }
+                }
+            }, [], (result, buffers) => {
+                if(result.Err) {
+                    reject(new Error(result.Err));
+                } else if(result.Ok.Err) {
+                    reject(new Error(result.Ok.Err));
+                } else if(result.Ok.Ok) {
+                    resolve(buffers[0]);
+                } else {
+                    reject(new Error("blob was deleted while reading"));
+                }
+            })
+        });
+    }
+
+    /**
+     * Stores a large value in chunks. The value becomes visible to `getStream` atomically once
+     * all of it is written.
+     * 
+     * @param {ArrayBuffer | ArrayBufferView} key
+     * @param {any} source A `ReadableStream`, an async iterable of buffers, or a buffer.
+     * @param {Object} opts 
+     * @param {number | undefined} opts.ttlMs
+     * @param {ArrayBuffer | ArrayBufferView | undefined} opts.metadata
+     * @returns {Promise<void>}
+     */
+    async putStreamRaw(key, source, opts) {
+        let blobId = null;
+        let chunks = 0;
+        let size = 0;
+        let pending = new Uint8Array(BLOB_CHUNK_SIZE);
+        let pendingLen = 0;
+
+        const flush = async () => {
+            blobId = await this._putBlobChunk(blobId, chunks, pending.slice(0, pendingLen));
+            chunks++;
+            size += pendingLen;
+            pendingLen = 0;
+        };
+
+        for await (let data of readSource(source)) {
+            while(data.byteLength !== 0) {
+                let n = Math.min(data.byteLength, BLOB_CHUNK_SIZE - pendingLen);
+                pending.set(data.subarray(0, n), pendingLen);
+                pendingLen += n;
+                data = data.subarray(n);
+                if(pendingLen === BLOB_CHUNK_SIZE) {
+                    await flush();
+                }
+            }
+        }
+        if(pendingLen !== 0 || chunks === 0) {
+            await flush();
+        }
+
+        let args = opts?.metadata ? [key, opts.metadata] : [key];
+        return new Promise((resolve, reject) => {
+            _callServiceWrapper({
+                Async: {
+                    KvCommitBlob: {
+                        namespace: this.name,
+                        blob_id: blobId,
+                        chunks: chunks,
+                        size: size,
+                        ttl_ms: opts?.ttlMs || 0,
+                    }
+                }
+            }, args, (result) => {
+                if(result.Err) {
+                    reject(new Error(result.Err));
+                } else if(result.Ok.Err) {
+                    reject(new Error(result.Ok.Err));
+                } else {
+                    resolve();
+                }
+            })
+        });
+    }
+
+    /**
+     * @param {string} key
+     * @param {any} source A `ReadableStream`, an async iterable of buffers, a buffer or a string.
+     * @param {Object} opts 
+     * @param {number | undefined} opts.ttlMs
+     * @param {any} opts.metadata JSON-serializable metadata to store with the value
+     * @returns {Promise<void>}
+     */
+    async putStream(key, source, opts) {
+        let keyRaw = new TextEncoder().encode(key);
+        let rawOpts = { ...opts };
+        if(opts?.metadata !== undefined) {
+            rawOpts.metadata = new TextEncoder().encode(JSON.stringify(opts.metadata)).buffer;
+        }
+        await this.putStreamRaw(keyRaw.buffer, source, rawOpts);
+    }
+
+    /**
+     * @param {ArrayBuffer | ArrayBufferView} key
+     * @returns {Promise<{stream: BlobStream, metadata: ArrayBuffer} | null>}
+     */
+    getStreamWithMetadataRaw(key) {
+        return new Promise((resolve, reject) => {
+            _callServiceWrapper({
+                Async: {
+                    KvGetBlob: {
+                        namespace: this.name,
+                    }
+                }
+            }, [key], (result, buffers) => {
+                if(result.Err) {
+                    reject(new Error(result.Err));
+                } else if(result.Ok.Err) {
+                    reject(new Error(result.Ok.Err));
+                } else if(result.Ok.Ok) {
+                    resolve({ stream: new BlobStream(this, result.Ok.Ok), metadata: buffers[0] });
+                } else {
+                    resolve(null);
+                }
+            })
+        });
+    }
+
+    /**
+     * Returns a stream of a value stored with `putStream`, or null if there is none.
+     * 
+     * @param {string} key
+     * @returns {Promise<BlobStream | null>}
+     */
+    async getStream(key) {
+        let keyRaw = new TextEncoder().encode(key);
+        let res = await this.getStreamWithMetadataRaw(keyRaw.buffer);
+        return res !== null ? res.stream : null;
+    }
+
+    /**
+     * @param {string} key
+     * @returns {Promise<{stream: BlobStream | null, metadata: any}>}
+     */
+    async getStreamWithMetadata(key) {
+        let keyRaw = new TextEncoder().encode(key);
+        let res = await this.getStreamWithMetadataRaw(keyRaw.buffer);
+        if(res !== null) {
+            return {
+                stream: res.stream,
+                metadata: res.metadata.byteLength !== 0 ? JSON.parse(new TextDecoder().decode(res.metadata)) : null,
+            };
+        } else {
+            return { stream: null, metadata: null };
+        }
+    }
+
+    /**
+     * @param {ArrayBuffer | ArrayBufferView} key
+     * @returns {Promise<void>}
+     */
+    deleteStreamRaw(key) {
+        return new Promise((resolve, reject) => {
+            _callServiceWrapper({
+                Async: {
+                    KvDeleteBlob: {
+                        namespace: this.name,
+                    }
+                }
+            }, [key], (result) => {
+                if(result.Err) {
+                    reject(new Error(result.Err));
+                } else if(result.Ok.Err) {
+                    reject(new Error(result.Ok.Err));
+                } else {
+                    resolve();
+                }
+            })
+        });
+    }
+
+    /**
+     * Deletes a value stored with `putStream`.
+     * 
+     * @param {string} key
+     * @returns {Promise<void>}
+     */
+    async deleteStream(key) {
+        let keyRaw = new TextEncoder().encode(key);
+        await this.deleteStreamRaw(keyRaw.buffer);
+    }
+}
+
+class KvTransaction {
+    /**
+     * @param {KvNamespace} ns
+     */
+    constructor(ns) {
+        this.ns = ns;
+
+        /** @type {Map<string, ArrayBuffer | null>} raw values read, null for absent keys */
+        this.reads = new Map();
+
+        /** @type {Map<string, {value: string, metadata: any, ttlMs: number | undefined} | null>} buffered writes, null for deletes */
+        this.writes = new Map();
+
+        this.done = false;
+    }
+
+    _checkKey(key) {
+        if(this.done) {
+            throw new Error("transaction already finished");
+        }
+        if(!this.reads.has(key) && !this.writes.has(key) && this.reads.size + this.writes.size >= MAX_TX_KEYS) {
+            throw new Error("transaction has too many keys");
+        }
+    }
+
+    /**
+     * @param {string} key
+     * @returns {Promise<string | null>}
+     */
+    async get(key) {
+        this._checkKey(key);
+        if(this.writes.has(key)) {
+            const w = this.writes.get(key);
+            return w !== null ? w.value : null;
+        }
+        if(!this.reads.has(key)) {
+            const value = await this.ns.getRaw(new TextEncoder().encode(key).buffer);
+            // Keep the first value read, which is what the commit is checked against.
+            if(!this.reads.has(key)) {
+                this.reads.set(key, value);
+            }
+        }
+        const value = this.reads.get(key);
+        return value !== null ? new TextDecoder().decode(value) : null;
+    }
+
+    /**
+     * @param {string} key
+     * @param {string} value
+     * @param {Object} opts
+     * @param {number | undefined} opts.ttlMs
+     * @param {any} opts.metadata JSON-serializable metadata to store with the value
+     */
+    put(key, value, opts) {
+        this._checkKey(key);
+        this.writes.set(key, { value: String(value), metadata: opts?.metadata, ttlMs: opts?.ttlMs });
+    }
+
+    /**
+     * @param {string} key
+     */
+    delete(key) {
+        this._checkKey(key);
+        this.writes.set(key, null);
+    }
+
+    /**
+     * @returns {Promise<boolean>}
+     */
+    async _commit() {
+        this.done = true;
+        if(this.reads.size === 0 && this.writes.size === 0) {
+            return true;
+        }
+        const encoder = new TextEncoder();
+        const reads = [];
+        for(const [k, v] of this.reads) {
+            reads.push([encoder.encode(k).buffer, v]);
+        }
+        const writes = [];
+        for(const [k, w] of this.writes) {
+            writes.push([encoder.encode(k).buffer, w !== null ? {
+                value: encoder.encode(w.value).buffer,
+                metadata: w.metadata !== undefined ? encoder.encode(JSON.stringify(w.metadata)).buffer : undefined,
+                ttlMs: w.ttlMs,
+            } : null]);
+        }
+        return await this.ns.commitRaw(reads, writes);
+    }
 }
 
 const kvHandler = {
@@ -5,33 +5,57 @@ class Console {
 
     }
 
-    log(text) {
+    _write(level, text) {
         _callServiceWrapper({
             Sync: {
-                Log: "" + text
+                Log: {
+                    level: level,
+                    text: "" + text,
+                }
             }
         }, []);
     }
+
+    debug(text) {
+        this._write("debug", text);
+    }
+
+    log(text) {
+        this._write("info", text);
+    }
+
+    info(text) {
+        this._write("info", text);
+    }
+
+    warn(text) {
+        this._write("warn", text);
+    }
+
+    error(text) {
+        this._write("error", text);
+    }
 }
 
 class FetchEvent {
     /**
      * 
-     * @param {string} type 
      * @param {Object} request 
+     * @param {number} taskId 
      */
-    constructor(request) {
+    constructor(request, taskId) {
         this.type = "fetch";
         this.request = request;
+        this._taskId = taskId;
     }
 
     async respondWith(res) {
         try {
             await this._respondWith(res);
         } catch(e) {
-            console.log("caught exception in respondWith");
-            if(e && e.stack) console.log(e.stack);
-            else console.log(e);
+            console.error("caught exception in respondWith");
+            if(e && e.stack) console.error(e.stack);
+            else console.error(e);
             await this._respondWith(new Response("caught exception when handling request", { status: 500 }));
         }
     }
@@ -60,13 +84,73 @@ class FetchEvent {
         _callServiceWrapper({
             Sync: {
                 SendFetchResponse: {
-                    status: res.status,
-                    headers: headers,
+                    task_id: this._taskId,
+                    response: {
+                        status: res.status,
+                        headers: headers,
+                    },
                 }
             }
         }, [body]);
         _callServiceWrapper({
-            Sync: "Done",
+            Sync: {
+                Done: this._taskId,
+            }
+        }, [])
+    }
+}
+
+class KvChangeEvent {
+    /**
+     * 
+     * @param {string} namespace 
+     * @param {Object[]} changes 
+     * @param {number} taskId 
+     */
+    constructor(namespace, changes, taskId) {
+        this.type = "kvchange";
+        this.namespace = namespace;
+        this.changes = changes;
+        this._taskId = taskId;
+        this._promises = [];
+    }
+
+    /**
+     * Extends the handling of this event until `p` settles. The changes are delivered
+     * again if `p` rejects.
+     * 
+     * @param {Promise} p 
+     */
+    waitUntil(p) {
+        this._promises.push(p);
+    }
+
+    async _complete(ok) {
+        if(ok) {
+            try {
+                await Promise.all(this._promises);
+            } catch(e) {
+                console.error("caught exception in kvchange handler");
+                if(e && e.stack) console.error(e.stack);
+                else console.error(e);
+                ok = false;
+            }
+        }
+        _callServiceWrapper({
+            Sync: {
+                SendFetchResponse: {
+                    task_id: this._taskId,
+                    response: {
+                        status: ok ? 200 : 500,
+                        headers: {},
+                    },
+                }
+            }
+        }, [new ArrayBuffer(0)]);
+        _callServiceWrapper({
+            Sync: {
+                Done: this._taskId,
+            }
         }, [])
     }
 }
@@ -208,22 +292,65 @@ export function _dispatchEvent(ev) {
                 headers: headers,
                 body: body,
             });
+            let cf = buildRequestCf(rawReq.metadata);
+            Object.defineProperty(req, "cf", {
+                value: cf,
+                enumerable: true,
+            });
             //console.log(`[request] ${req.method} ${req.url} x-forwarded-for(${req.headers.get("x-forwarded-for")})`);
-            let targetEvent = new FetchEvent(req);
+            let targetEvent = new FetchEvent(req, ev[ty].task_id);
+            targetEvent.client = cf;
             try {
                 dispatchEvent(targetEvent);
             } catch(e) {
-                console.log("dispatchEvent exception: " + e);
+                console.error("dispatchEvent exception: " + e);
                 targetEvent._respondWith(new Response("caught exception when dispatching request", { status: 500 }));
             }
             break;
         }
+        case "KvChange": {
+            const decoder = new TextDecoder();
+            let changes = ev[ty].changes.map(x => ({
+                seq: x.seq,
+                key: decoder.decode(new Uint8Array(x.key)),
+                value: x.value !== null ? decoder.decode(new Uint8Array(x.value)) : null,
+                time: x.time,
+            }));
+            let targetEvent = new KvChangeEvent(ev[ty].namespace, changes, ev[ty].task_id);
+            let ok = true;
+            try {
+                dispatchEvent(targetEvent);
+            } catch(e) {
+                console.error("dispatchEvent exception: " + e);
+                ok = false;
+            }
+            targetEvent._complete(ok);
+            break;
+        }
         default: {
             throw new TypeError("bad event type: " + ty);
         }
     }
 }
 
+/**
+ * Converts `RequestMetadata` from the runtime into a `request.cf`-like object.
+ * 
+ * @param {Object} metadata 
+ * @returns {Object}
+ */
+function buildRequestCf(metadata) {
+    if(!metadata) metadata = {};
+    return Object.freeze({
+        requestId: metadata.request_id || "",
+        clientIp: metadata.client_ip || null,
+        clientPort: metadata.client_port || null,
+        httpProtocol: metadata.http_protocol || "",
+        tlsCipher: metadata.tls_cipher || null,
+        tlsSni: metadata.tls_sni || null,
+    });
+}
+
 export function getFileFromBundle(name) {
     return _callServiceWrapper({
         Sync: {
@@ -242,6 +369,8 @@ export const crypto = {
 };
 
 export const kv = require("./kv.js").kv;
+export const buckets = require("./bucket.js").buckets;
+export const db = require("./db.js").db;
 
 export const console = new Console();
 export const Request = workerFetch.Request;
@@ -3,15 +3,6 @@ addEventListener("fetch", async (event) => {
 });
 
 async function handleRequest(req) {
-    let counter = await kv.testNs.get("counter");
-    if(counter === null) {
-        counter = 0;
-    } else {
-        counter = parseInt(counter);
-    }
-
-    counter += 1;
-
-    await kv.testNs.put("counter", "" + counter);
+    let counter = await kv.testNs.increment("counter");
     return new Response("New counter: " + counter);
 }
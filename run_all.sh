@@ -1,6 +1,6 @@
 #!/bin/sh
 
-export RUST_LOG=rusty_workers=info,rusty_workers_fetchd=info,rusty_workers_runtime=info,rusty_workers_proxy=info
+export RUST_LOG=rusty_workers=info,rusty_workers_fetchd=info,rusty_workers_sqld=info,rusty_workers_runtime=info,rusty_workers_proxy=info
 
 # https://stackoverflow.com/questions/360201/how-do-i-kill-background-processes-jobs-when-my-shell-script-exits
 trap "exit" INT TERM
@@ -10,7 +10,10 @@ cd playground
 ./build_bundles.sh
 cd ..
 
+./target/release/rusty-workers-cli db migrate --db-url mysql://root@localhost:4000/rusty_workers || exit 1
+
 ./target/release/rusty-workers-fetchd --rpc-listen 127.0.0.1:3200 &
+./target/release/rusty-workers-sqld --rpc-listen 127.0.0.1:3202 --data-dir ./data/sql &
 ./target/release/rusty-workers-runtime --rpc-listen 127.0.0.1:3201 \
     --db-url mysql://root@localhost:4000/rusty_workers \
     --max-num-of-instances 50 \
@@ -19,6 +22,7 @@ cd ..
     --max-concurrent-requests 50 &
 ./target/release/rusty-workers-proxy \
     --fetch-service 127.0.0.1:3200 \
+    --sql-service 127.0.0.1:3202 \
     --http-listen 0.0.0.0:3280 \
     --db-url mysql://root@localhost:4000/rusty_workers \
     --runtimes 127.0.0.1:3201 \
@@ -4,19 +4,74 @@ extern crate log;
 
 use anyhow::Result;
 use rand::Rng;
-use rusty_workers::app::AppConfig;
-use rusty_workers::db::DataClient;
+use rusty_workers::app::{AppConfig, Deployment, KvNamespace, KvWatch};
+use rusty_workers::db::{DataClient, SCHEMA_VERSION};
+use rusty_workers::objstore::{BUCKET_NAMESPACE_PREFIX, MAX_BUCKET_ID_LEN};
 use rusty_workers::tarpc;
 use rusty_workers::types::*;
+use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 use structopt::StructOpt;
 use thiserror::Error;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+/// Number of entries fetched or written per database call during export and import.
+const EXPORT_BATCH_SIZE: u32 = 1000;
+
+/// Number of bundles fetched per database call by `gc-bundles`.
+const GC_BATCH_SIZE: u32 = 100;
 
 #[derive(Debug, Error)]
 enum CliError {
     #[error("bad id128")]
     BadId128,
+
+    #[error("bad cursor")]
+    BadCursor,
+
+    #[error("kv namespace {0} is not registered to this app")]
+    UnownedNamespace(String),
+
+    #[error("bad export record on line {0}: {1}")]
+    BadExportRecord(usize, String),
+
+    #[error("namespace ids must not start with \"{}\"", BUCKET_NAMESPACE_PREFIX)]
+    ReservedNamespaceId,
+
+    #[error("bad bucket id: {0}")]
+    BadBucketId(String),
+
+    #[error("bucket {0} is not registered to this app")]
+    UnownedBucket(String),
+
+    #[error("baseline is newer than the latest schema version {}", SCHEMA_VERSION)]
+    BadBaseline,
+
+    #[error("deployment {0} not found")]
+    DeploymentNotFound(u64),
+
+    #[error("no earlier deployment to roll back to")]
+    NoEarlierDeployment,
+
+    #[error("bundle {0} of the deployment no longer exists")]
+    MissingBundle(String),
+
+    #[error("control plane error: {0}")]
+    ControlPlane(String),
+}
+
+/// One line of a namespace export file. Keys, values and metadata are base64-encoded.
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportRecord {
+    key: String,
+    value: String,
+
+    #[serde(default)]
+    metadata: String,
+
+    /// Expiration time in milliseconds since the Unix epoch, or 0 if the entry never expires.
+    #[serde(default)]
+    expiration: u64,
 }
 
 #[derive(Debug, StructOpt)]
@@ -40,13 +95,50 @@ enum Cmd {
 
     /// App management.
     App {
-        /// MySQL-compatible database URL.
+        /// Database URL: `mysql://...`, `sqlite://path/to/file.db` or `memory://`.
         #[structopt(long, env = "DB_URL")]
         db_url: String,
 
         #[structopt(subcommand)]
         op: AppCmd,
     },
+
+    /// Database schema management.
+    Db {
+        /// Database URL: `mysql://...`, `sqlite://path/to/file.db` or `memory://`.
+        #[structopt(long, env = "DB_URL")]
+        db_url: String,
+
+        #[structopt(subcommand)]
+        op: DbCmd,
+    },
+}
+
+/// Recorded with a deployment.
+#[derive(Debug, StructOpt)]
+struct DeployOpt {
+    #[structopt(long, env = "USER", default_value = "")]
+    author: String,
+
+    /// Describes the deployment.
+    #[structopt(long, short = "m", default_value = "")]
+    message: String,
+}
+
+#[derive(Debug, StructOpt)]
+enum DbCmd {
+    /// Apply pending schema migrations.
+    #[structopt(name = "migrate")]
+    Migrate {
+        /// Record migrations up to this version as applied without running them. For databases
+        /// set up by hand from `sql/` before migrations were recorded.
+        #[structopt(long, default_value = "0")]
+        baseline: u32,
+    },
+
+    /// Print the installed and required schema versions.
+    #[structopt(name = "version")]
+    Version,
 }
 
 #[derive(Debug, StructOpt)]
@@ -85,6 +177,9 @@ enum AppCmd {
 
         #[structopt(long)]
         bundle: String,
+
+        #[structopt(flatten)]
+        deploy: DeployOpt,
     },
     #[structopt(name = "add-single-file-app")]
     AddSingleFileApp {
@@ -92,22 +187,195 @@ enum AppCmd {
 
         #[structopt(long)]
         js: String,
+
+        #[structopt(flatten)]
+        deploy: DeployOpt,
     },
     #[structopt(name = "delete-app")]
     DeleteApp { appid: String },
+    /// Lists the deployments of an app, newest first.
+    #[structopt(name = "deployments")]
+    Deployments { appid: String },
+    /// Redeploys the config and bundle of an earlier deployment.
+    #[structopt(name = "rollback")]
+    Rollback {
+        appid: String,
+
+        /// Version to roll back to. Defaults to the one before the latest.
+        #[structopt(long)]
+        to: Option<u64>,
+
+        #[structopt(flatten)]
+        deploy: DeployOpt,
+    },
+    /// Deletes old deployments of an app, so that `gc-bundles` can delete their bundles.
+    #[structopt(name = "prune-deployments")]
+    PruneDeployments {
+        appid: String,
+
+        /// Number of latest deployments to keep.
+        #[structopt(long, default_value = "10")]
+        keep: u32,
+    },
     #[structopt(name = "get-app")]
     GetApp { appid: String },
     #[structopt(name = "get-bundle")]
     GetBundle { bundle: String },
+    /// Deletes bundles that no app or deployment references.
+    #[structopt(name = "gc-bundles")]
+    GcBundles {
+        /// Keep bundles uploaded or deployed less than this many seconds ago, so that deploys in
+        /// progress don't lose their bundle.
+        #[structopt(long, default_value = "3600")]
+        min_age_secs: u64,
+
+        /// Only print the bundles that would be deleted.
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Prints the stored logs of an app, oldest first.
+    #[structopt(name = "logs")]
+    Logs {
+        appid: String,
+
+        /// Print lines logged within this long before now, e.g. `10m` or `2h`.
+        #[structopt(long, default_value = "10m", parse(try_from_str = parse_duration::parse))]
+        since: std::time::Duration,
+
+        /// Maximum number of lines to print.
+        #[structopt(long, default_value = "100")]
+        limit: u32,
+
+        /// Continue from the cursor printed by a previous run.
+        #[structopt(long)]
+        cursor: Option<String>,
+    },
+    /// Follows what the workers of an app log, as JSON Lines, until interrupted.
+    #[structopt(name = "tail")]
+    Tail {
+        appid: String,
+
+        /// Control plane URL. The control plane must be started with the runtime addresses.
+        #[structopt(long, env = "RW_CP_URL")]
+        cp: String,
+
+        /// Only print lines of this level or higher: `debug`, `info`, `warn` or `error`.
+        #[structopt(long, default_value = "debug")]
+        level: LogLevel,
+
+        /// Only print lines containing this text.
+        #[structopt(long, default_value = "")]
+        contains: String,
+    },
+    #[structopt(name = "create-namespace")]
+    CreateNamespace {
+        /// Namespace id. Defaults to a random one.
+        #[structopt(long)]
+        id: Option<String>,
+
+        /// Id of the app that may bind this namespace.
+        #[structopt(long)]
+        owner: String,
+
+        #[structopt(long)]
+        name: String,
+
+        /// Maximum number of keys. 0 means unlimited.
+        #[structopt(long, default_value = "0")]
+        max_keys: u64,
+
+        /// Maximum total size of keys, values and metadata in bytes. 0 means unlimited.
+        #[structopt(long, default_value = "0")]
+        max_bytes: u64,
+    },
+    #[structopt(name = "list-namespaces")]
+    ListNamespaces {
+        #[structopt(long)]
+        owner: Option<String>,
+    },
+    #[structopt(name = "delete-namespace")]
+    DeleteNamespace { id: String },
+    /// Register the namespaces bound by apps that were added before namespaces had to be
+    /// registered. A namespace bound by several apps is registered to the oldest one.
+    #[structopt(name = "backfill-namespaces")]
+    BackfillNamespaces,
+    /// Registers an object storage bucket to the app that may bind it.
+    #[structopt(name = "create-bucket")]
+    CreateBucket {
+        id: String,
+
+        /// Id of the app that may bind this bucket.
+        #[structopt(long)]
+        owner: String,
+    },
+    #[structopt(name = "list-buckets")]
+    ListBuckets {
+        #[structopt(long)]
+        owner: Option<String>,
+    },
+    /// Unregisters a bucket. With the `db` object store, its objects are deleted too.
+    #[structopt(name = "delete-bucket")]
+    DeleteBucket { id: String },
+    /// Writes all live entries of a namespace as JSON Lines.
+    #[structopt(name = "export-namespace")]
+    ExportNamespace {
+        namespace: String,
+
+        /// Output file. Defaults to stdout.
+        #[structopt(long, short = "o")]
+        output: Option<String>,
+    },
+    /// Reads entries written by `export-namespace` into a namespace, overwriting existing keys.
+    #[structopt(name = "import-namespace")]
+    ImportNamespace {
+        namespace: String,
+
+        /// Input file. Defaults to stdin.
+        #[structopt(long, short = "i")]
+        input: Option<String>,
+    },
+    /// Delivers future changes to a namespace to an app as "kvchange" events.
+    #[structopt(name = "create-watch")]
+    CreateWatch {
+        /// Watch id. Defaults to a random one.
+        #[structopt(long)]
+        id: Option<String>,
+
+        /// Id of the app that receives the changes. Must own the namespace.
+        #[structopt(long)]
+        appid: String,
+
+        #[structopt(long)]
+        namespace: String,
+
+        /// Only watch keys starting with this prefix.
+        #[structopt(long, default_value = "")]
+        prefix: String,
+    },
+    #[structopt(name = "list-watches")]
+    ListWatches {
+        #[structopt(long)]
+        appid: Option<String>,
+    },
+    #[structopt(name = "delete-watch")]
+    DeleteWatch { id: String },
     #[structopt(name = "list-worker-data")]
     ListWorkerData {
         namespace: String,
+        #[structopt(long, default_value = "")]
+        prefix: String,
         #[structopt(long)]
-        from: String,
-        #[structopt(long)]
+        cursor: Option<String>,
+        #[structopt(long, default_value = "100")]
         limit: u32,
         #[structopt(long)]
+        include_values: bool,
+        #[structopt(long)]
+        include_metadata: bool,
+        #[structopt(long)]
         base64_key: bool,
+        #[structopt(long)]
+        base64_value: bool,
     },
     #[structopt(name = "get-worker-data")]
     GetWorkerData {
@@ -154,6 +422,10 @@ enum RuntimeCmd {
         #[structopt(long)]
         fetch_service: SocketAddr,
 
+        /// Enable the V8 inspector for the spawned worker.
+        #[structopt(long)]
+        inspector: bool,
+
         script: String,
     },
 
@@ -165,6 +437,9 @@ enum RuntimeCmd {
 
     #[structopt(name = "fetch")]
     Fetch { handle: String },
+
+    #[structopt(name = "inspector-url")]
+    InspectorUrl { handle: String },
 }
 
 #[tokio::main]
@@ -181,8 +456,9 @@ async fn main() -> Result<()> {
                     config,
                     script,
                     fetch_service,
+                    inspector,
                 } => {
-                    let config = if let Some(config) = config {
+                    let mut config: WorkerConfiguration = if let Some(config) = config {
                         let text = read_file(&config).await?;
                         serde_json::from_str(&text)?
                     } else {
@@ -192,12 +468,20 @@ async fn main() -> Result<()> {
                                 max_time_ms: 50,
                                 max_io_concurrency: 10,
                                 max_io_per_request: 50,
+                                max_concurrent_requests: 1,
+                                max_sql_rows: 1000,
+                                max_sql_time_ms: 1000,
                             },
                             fetch_service,
                             env: Default::default(),
                             kv_namespaces: Default::default(),
+                            buckets: Default::default(),
+                            sql_service: None,
+                            bundle_id: Default::default(),
+                            inspector: false,
                         }
                     };
+                    config.inspector |= inspector;
                     let script = read_file_raw(&script).await?;
                     let result = client
                         .spawn_worker(make_context(), appid, config, script)
@@ -221,10 +505,38 @@ async fn main() -> Result<()> {
                     let result = client.fetch(make_context(), worker_handle, req).await?;
                     println!("{}", serde_json::to_string(&result).unwrap());
                 }
+                RuntimeCmd::InspectorUrl { handle } => {
+                    let worker_handle = WorkerHandle { id: handle };
+                    let result = client.inspector_url(make_context(), worker_handle).await?;
+                    println!("{}", serde_json::to_string(&result).unwrap());
+                }
+            }
+        }
+        Cmd::Db { db_url, op } => {
+            let client = DataClient::new(&db_url).await?;
+            match op {
+                DbCmd::Migrate { baseline } => {
+                    if baseline > SCHEMA_VERSION {
+                        return Err(CliError::BadBaseline.into());
+                    }
+                    let applied = client.schema_migrate(baseline).await?;
+                    println!("{}", serde_json::to_string(&applied)?);
+                }
+                DbCmd::Version => {
+                    let installed = client.schema_version().await?;
+                    println!(
+                        "{}",
+                        serde_json::json!({
+                            "installed": installed,
+                            "required": SCHEMA_VERSION,
+                        })
+                    );
+                }
             }
         }
         Cmd::App { db_url, op } => {
             let client = DataClient::new(&db_url).await?;
+            client.check_schema().await?;
             match op {
                 AppCmd::ListRoutes { domain } => {
                     let routes = client.route_mapping_list_for_domain(&domain).await?;
@@ -250,15 +562,19 @@ async fn main() -> Result<()> {
                     let result = client.route_mapping_lookup(&domain, &path).await?;
                     println!("{}", serde_json::to_string(&result)?);
                 }
-                AppCmd::AddApp { config, bundle } => {
+                AppCmd::AddApp {
+                    config,
+                    bundle,
+                    deploy,
+                } => {
                     let config = read_file(&config).await?;
                     let mut config: AppConfig = toml::from_str(&config)?;
                     let bundle = read_file_raw(&bundle).await?;
 
-                    do_add_app(&client, &mut config, &bundle).await?;
+                    do_add_app(&client, &mut config, &bundle, &deploy).await?;
                     println!("OK");
                 }
-                AppCmd::AddSingleFileApp { config, js } => {
+                AppCmd::AddSingleFileApp { config, js, deploy } => {
                     let config = read_file(&config).await?;
                     let mut config: AppConfig = toml::from_str(&config)?;
 
@@ -270,17 +586,43 @@ async fn main() -> Result<()> {
                         builder.finish()?;
                     }
 
-                    do_add_app(&client, &mut config, &archive).await?;
+                    do_add_app(&client, &mut config, &archive, &deploy).await?;
                     println!("OK");
                 }
                 AppCmd::DeleteApp { appid } => {
                     let appid = rusty_workers::app::AppId(appid);
                     client.app_metadata_delete(&appid.0).await?;
+                    client.app_deployment_prune(&appid.0, 0).await?;
 
-                    // TODO: Delete logs?
+                    // Logs are left to the proxies' log pruner, which keeps them for the default
+                    // retention period.
 
                     println!("OK");
                 }
+                AppCmd::Deployments { appid } => {
+                    let deployments = client.app_deployment_list(&appid).await?;
+                    println!("{}", serde_json::to_string(&deployments)?);
+                }
+                AppCmd::Rollback { appid, to, deploy } => {
+                    let target = match to {
+                        Some(version) => client
+                            .app_deployment_get(&appid, version)
+                            .await?
+                            .ok_or(CliError::DeploymentNotFound(version))?,
+                        None => client
+                            .app_deployment_list(&appid)
+                            .await?
+                            .into_iter()
+                            .nth(1)
+                            .ok_or(CliError::NoEarlierDeployment)?,
+                    };
+                    let version = do_rollback(&client, target, deploy).await?;
+                    println!("OK (version {})", version);
+                }
+                AppCmd::PruneDeployments { appid, keep } => {
+                    let n = client.app_deployment_prune(&appid, keep).await?;
+                    println!("{}", n);
+                }
                 AppCmd::GetApp { appid } => {
                     let result: Option<AppConfig> = client.app_metadata_get(&appid).await?;
                     println!("{}", serde_json::to_string(&result)?);
@@ -292,32 +634,245 @@ async fn main() -> Result<()> {
                         serde_json::to_string(&bundle.map(|x| base64::encode(&x)))?
                     );
                 }
+                AppCmd::GcBundles {
+                    min_age_secs,
+                    dry_run,
+                } => {
+                    let deleted = gc_bundles(&client, min_age_secs, dry_run).await?;
+                    println!("{}", serde_json::to_string(&deleted)?);
+                }
+                AppCmd::Logs {
+                    appid,
+                    since,
+                    limit,
+                    cursor,
+                } => {
+                    let now = rusty_workers::util::current_millis();
+                    let start = now.saturating_sub(since.as_millis() as u64);
+                    let page = client
+                        .log_range(&appid, start..now + 1, cursor.as_deref(), limit)
+                        .await?;
+                    let lines: Vec<serde_json::Value> = page
+                        .entries
+                        .into_iter()
+                        .map(|x| serde_json::json!({ "time": x.time, "text": x.text }))
+                        .collect();
+                    let serialized = serde_json::to_string(&serde_json::json!({
+                        "lines": lines,
+                        "cursor": page.cursor,
+                    }))?;
+                    println!("{}", serialized);
+                }
+                AppCmd::Tail {
+                    appid,
+                    cp,
+                    level,
+                    contains,
+                } => {
+                    tail_logs(&cp, &appid, level, &contains).await?;
+                }
+                AppCmd::CreateNamespace {
+                    id,
+                    owner,
+                    name,
+                    max_keys,
+                    max_bytes,
+                } => {
+                    let id = id.unwrap_or_else(|| {
+                        let mut id = [0u8; 16];
+                        rand::thread_rng().fill(&mut id);
+                        base64::encode(&id)
+                    });
+                    if id.starts_with(BUCKET_NAMESPACE_PREFIX) {
+                        return Err(CliError::ReservedNamespaceId.into());
+                    }
+                    let ns = KvNamespace {
+                        id,
+                        owner,
+                        name,
+                        createtime: rusty_workers::util::current_millis(),
+                        max_keys,
+                        max_bytes,
+                        used_keys: 0,
+                        used_bytes: 0,
+                    };
+                    client.kv_namespace_create(&ns).await?;
+                    println!("{}", serde_json::to_string(&ns.id)?);
+                }
+                AppCmd::ListNamespaces { owner } => {
+                    let result: Vec<KvNamespace> = client
+                        .kv_namespace_list(owner.as_deref())
+                        .await?
+                        .into_iter()
+                        .filter(|x| !x.id.starts_with(BUCKET_NAMESPACE_PREFIX))
+                        .collect();
+                    println!("{}", serde_json::to_string(&result)?);
+                }
+                AppCmd::DeleteNamespace { id } => {
+                    if id.starts_with(BUCKET_NAMESPACE_PREFIX) {
+                        return Err(CliError::ReservedNamespaceId.into());
+                    }
+                    client.kv_namespace_delete(&id).await?;
+                    println!("OK");
+                }
+                AppCmd::BackfillNamespaces => {
+                    let result = client.kv_namespace_backfill().await?;
+                    println!("{}", serde_json::to_string(&result)?);
+                }
+                AppCmd::CreateBucket { id, owner } => {
+                    if id.is_empty() || id.len() > MAX_BUCKET_ID_LEN {
+                        return Err(CliError::BadBucketId(id).into());
+                    }
+                    // Buckets are registered as namespaces, which also keeps usage counters for
+                    // the objects of the `db` object store.
+                    let ns = KvNamespace {
+                        id: format!("{}{}", BUCKET_NAMESPACE_PREFIX, id),
+                        owner,
+                        name: id,
+                        createtime: rusty_workers::util::current_millis(),
+                        max_keys: 0,
+                        max_bytes: 0,
+                        used_keys: 0,
+                        used_bytes: 0,
+                    };
+                    client.kv_namespace_create(&ns).await?;
+                    println!("{}", serde_json::to_string(&ns.name)?);
+                }
+                AppCmd::ListBuckets { owner } => {
+                    let result: Vec<KvNamespace> = client
+                        .kv_namespace_list(owner.as_deref())
+                        .await?
+                        .into_iter()
+                        .filter(|x| x.id.starts_with(BUCKET_NAMESPACE_PREFIX))
+                        .collect();
+                    println!("{}", serde_json::to_string(&result)?);
+                }
+                AppCmd::DeleteBucket { id } => {
+                    client
+                        .kv_namespace_delete(&format!("{}{}", BUCKET_NAMESPACE_PREFIX, id))
+                        .await?;
+                    println!("OK");
+                }
+                AppCmd::ExportNamespace { namespace, output } => {
+                    let output: Box<dyn tokio::io::AsyncWrite + Unpin> = match output {
+                        Some(path) => Box::new(tokio::fs::File::create(path).await?),
+                        None => Box::new(tokio::io::stdout()),
+                    };
+                    let count = export_namespace(&client, &namespace, output).await?;
+                    eprintln!("exported {} entries", count);
+                }
+                AppCmd::ImportNamespace { namespace, input } => {
+                    let input: Box<dyn tokio::io::AsyncRead + Unpin> = match input {
+                        Some(path) => Box::new(tokio::fs::File::open(path).await?),
+                        None => Box::new(tokio::io::stdin()),
+                    };
+                    let (imported, skipped) = import_namespace(&client, &namespace, input).await?;
+                    eprintln!(
+                        "imported {} entries, skipped {} expired entries",
+                        imported, skipped
+                    );
+                }
+                AppCmd::CreateWatch {
+                    id,
+                    appid,
+                    namespace,
+                    prefix,
+                } => {
+                    match client.kv_namespace_get(&namespace).await? {
+                        Some(ns) if ns.owner == appid => {}
+                        _ => return Err(CliError::UnownedNamespace(namespace).into()),
+                    }
+                    let id = id.unwrap_or_else(|| {
+                        let mut id = [0u8; 16];
+                        rand::thread_rng().fill(&mut id);
+                        base64::encode(&id)
+                    });
+                    let watch = KvWatch {
+                        id,
+                        appid,
+                        namespace_id: namespace,
+                        prefix: prefix.into_bytes(),
+                        cursor: client.worker_data_changes_latest_seq().await?,
+                        createtime: rusty_workers::util::current_millis(),
+                    };
+                    client.kv_watch_create(&watch).await?;
+                    println!("{}", serde_json::to_string(&watch.id)?);
+                }
+                AppCmd::ListWatches { appid } => {
+                    let result = client.kv_watch_list(appid.as_deref()).await?;
+                    println!("{}", serde_json::to_string(&result)?);
+                }
+                AppCmd::DeleteWatch { id } => {
+                    client.kv_watch_delete(&id).await?;
+                    println!("OK");
+                }
                 AppCmd::ListWorkerData {
                     namespace,
-                    from,
+                    prefix,
+                    cursor,
                     limit,
+                    include_values,
+                    include_metadata,
                     base64_key,
+                    base64_value,
                 } => {
-                    let from = if !base64_key {
-                        Vec::from(from)
+                    let prefix = if !base64_key {
+                        Vec::from(prefix)
                     } else {
-                        base64::decode(&from)?
+                        base64::decode(&prefix)?
+                    };
+                    let after = match cursor {
+                        Some(x) => Some(
+                            rusty_workers::db::decode_list_cursor(&x).ok_or(CliError::BadCursor)?,
+                        ),
+                        None => None,
                     };
 
-                    let keys = client
-                        .worker_data_scan_keys(&namespace, &from, None, limit)
+                    let (entries, list_complete) = client
+                        .worker_data_list(
+                            &namespace,
+                            &prefix,
+                            after.as_deref(),
+                            limit,
+                            include_values,
+                            include_metadata,
+                        )
                         .await?;
-                    let keys: Vec<Option<String>> = keys
+                    let cursor = if list_complete {
+                        None
+                    } else {
+                        entries
+                            .last()
+                            .map(|x| rusty_workers::db::encode_list_cursor(&x.key))
+                    };
+                    let keys: Vec<serde_json::Value> = entries
                         .into_iter()
-                        .map(|k| {
-                            if !base64_key {
-                                String::from_utf8(k).ok()
-                            } else {
-                                Some(base64::encode(&k))
+                        .map(|x| {
+                            let mut entry = serde_json::Map::new();
+                            entry.insert("name".into(), encode_bytes(x.key, base64_key).into());
+                            if x.expiration != 0 {
+                                entry.insert("expiration".into(), x.expiration.into());
+                            }
+                            if include_values {
+                                entry.insert(
+                                    "value".into(),
+                                    encode_bytes(x.value, base64_value).into(),
+                                );
                             }
+                            if include_metadata {
+                                entry.insert(
+                                    "metadata".into(),
+                                    String::from_utf8(x.metadata).ok().into(),
+                                );
+                            }
+                            entry.into()
                         })
                         .collect();
-                    let serialized = serde_json::to_string(&keys)?;
+                    let serialized = serde_json::to_string(&serde_json::json!({
+                        "keys": keys,
+                        "cursor": cursor,
+                        "list_complete": list_complete,
+                    }))?;
                     println!("{}", serialized);
                 }
                 AppCmd::GetWorkerData {
@@ -363,7 +918,7 @@ async fn main() -> Result<()> {
                         base64::decode(&value)?
                     };
                     client
-                        .worker_data_put(&namespace, &key, &value, false, 0)
+                        .worker_data_put(&namespace, &key, &value, &[], false, 0)
                         .await?;
                     println!("OK");
                 }
@@ -400,17 +955,242 @@ async fn read_file_raw(path: &str) -> Result<Vec<u8>> {
     Ok(buf)
 }
 
-async fn do_add_app(client: &DataClient, config: &mut AppConfig, bundle: &[u8]) -> Result<()> {
-    let mut bundle_id = [0u8; 16];
-    rand::thread_rng().fill(&mut bundle_id);
-    let bundle_id = base64::encode(&bundle_id);
-    client.app_bundle_put(&bundle_id, &bundle).await?;
+/// Checks the bindings of an app config.
+async fn check_app_config(client: &DataClient, config: &AppConfig) -> Result<()> {
+    for binding in &config.kv_namespaces {
+        if binding.id.starts_with(BUCKET_NAMESPACE_PREFIX) {
+            return Err(CliError::ReservedNamespaceId.into());
+        }
+        match client.kv_namespace_get(&binding.id).await? {
+            Some(ns) if ns.owner == config.id.0 => {}
+            _ => return Err(CliError::UnownedNamespace(binding.id.clone()).into()),
+        }
+    }
+    for binding in &config.buckets {
+        if binding.id.is_empty() || binding.id.len() > MAX_BUCKET_ID_LEN {
+            return Err(CliError::BadBucketId(binding.id.clone()).into());
+        }
+        let id = format!("{}{}", BUCKET_NAMESPACE_PREFIX, binding.id);
+        match client.kv_namespace_get(&id).await? {
+            Some(ns) if ns.owner == config.id.0 => {}
+            _ => return Err(CliError::UnownedBucket(binding.id.clone()).into()),
+        }
+    }
+    Ok(())
+}
+
+async fn do_add_app(
+    client: &DataClient,
+    config: &mut AppConfig,
+    bundle: &[u8],
+    deploy: &DeployOpt,
+) -> Result<()> {
+    check_app_config(client, config).await?;
+
+    // Skip the upload if the same code is already stored. Touching it also keeps `gc-bundles`
+    // from deleting it before the app references it.
+    let bundle_id = rusty_workers::app::bundle_id(bundle);
+    if !client.app_bundle_touch(&bundle_id).await? {
+        client.app_bundle_put(&bundle_id, &bundle).await?;
+    }
     config.bundle_id = bundle_id;
 
-    client.app_metadata_put(config).await?;
+    client
+        .app_deploy(config, &deploy.author, &deploy.message)
+        .await?;
     Ok(())
 }
 
+/// Deploys the config and bundle of `target` again, as a new deployment. Returns its version.
+async fn do_rollback(client: &DataClient, target: Deployment, deploy: DeployOpt) -> Result<u64> {
+    // Namespaces may have been deleted or reassigned since.
+    check_app_config(client, &target.config).await?;
+
+    // Touching the bundle also keeps `gc-bundles` from deleting it until the app references it.
+    if !client.app_bundle_touch(&target.config.bundle_id).await? {
+        return Err(CliError::MissingBundle(target.config.bundle_id).into());
+    }
+
+    let message = if deploy.message.is_empty() {
+        format!("rollback to version {}", target.version)
+    } else {
+        deploy.message
+    };
+    Ok(client
+        .app_deploy(&target.config, &deploy.author, &message)
+        .await?)
+}
+
+/// Deletes bundles that are older than `min_age_secs` and not referenced by any app or
+/// deployment. Returns the ids of the deleted bundles, or of the bundles that would be deleted in
+/// a dry run.
+async fn gc_bundles(client: &DataClient, min_age_secs: u64, dry_run: bool) -> Result<Vec<String>> {
+    let created_before =
+        rusty_workers::util::current_millis().saturating_sub(min_age_secs.saturating_mul(1000));
+    let mut deleted = vec![];
+    loop {
+        let ids = client
+            .app_bundle_list_unreferenced(created_before, GC_BATCH_SIZE)
+            .await?;
+        if dry_run {
+            return Ok(ids);
+        }
+        let mut progress = false;
+        for id in ids.iter() {
+            // Checked again at deletion, in case an app started referencing it meanwhile.
+            if client
+                .app_bundle_delete_unreferenced(id, created_before)
+                .await?
+            {
+                progress = true;
+                deleted.push(id.clone());
+            }
+        }
+        if ids.len() < GC_BATCH_SIZE as usize || !progress {
+            return Ok(deleted);
+        }
+    }
+}
+
+/// Streams the live log of an app from the control plane to stdout until the control plane closes
+/// the stream.
+async fn tail_logs(cp: &str, appid: &str, level: LogLevel, contains: &str) -> Result<()> {
+    use hyper::body::HttpBody;
+
+    let body = serde_json::to_vec(&serde_json::json!({
+        "appid": appid,
+        "level": level,
+        "contains": contains,
+    }))?;
+    let req = hyper::Request::post(format!("{}/v1/tail_logs", cp.trim_end_matches('/')))
+        .body(hyper::Body::from(body))?;
+    let res = hyper::Client::new().request(req).await?;
+    if !res.status().is_success() {
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        return Err(CliError::ControlPlane(String::from_utf8_lossy(&body).into_owned()).into());
+    }
+
+    // The control plane sends whole lines in each chunk.
+    let mut body = res.into_body();
+    let mut stdout = tokio::io::stdout();
+    while let Some(chunk) = body.data().await {
+        stdout.write_all(&chunk?).await?;
+        stdout.flush().await?;
+    }
+    Ok(())
+}
+
+/// Writes all live entries of a namespace to `output`, one `ExportRecord` per line. Returns the
+/// number of entries written.
+async fn export_namespace(
+    client: &DataClient,
+    namespace: &str,
+    output: impl tokio::io::AsyncWrite + Unpin,
+) -> Result<u64> {
+    let mut output = tokio::io::BufWriter::new(output);
+    let mut after: Option<Vec<u8>> = None;
+    let mut count = 0u64;
+    loop {
+        let (entries, list_complete) = client
+            .worker_data_list(
+                namespace,
+                &[],
+                after.as_deref(),
+                EXPORT_BATCH_SIZE,
+                true,
+                true,
+            )
+            .await?;
+        for entry in &entries {
+            let record = ExportRecord {
+                key: base64::encode(&entry.key),
+                value: base64::encode(&entry.value),
+                metadata: base64::encode(&entry.metadata),
+                expiration: entry.expiration,
+            };
+            let mut line = serde_json::to_vec(&record)?;
+            line.push(b'\n');
+            output.write_all(&line).await?;
+            count += 1;
+        }
+        if list_complete {
+            break;
+        }
+        after = entries.last().map(|x| x.key.clone());
+    }
+    output.flush().await?;
+    Ok(count)
+}
+
+/// Reads `ExportRecord`s from `input` into a namespace. Entries that have expired since the export
+/// are skipped. Returns the number of entries imported and skipped.
+async fn import_namespace(
+    client: &DataClient,
+    namespace: &str,
+    input: impl tokio::io::AsyncRead + Unpin,
+) -> Result<(u64, u64)> {
+    let mut lines = tokio::io::BufReader::new(input).lines();
+    let mut line_no = 0usize;
+    let mut imported = 0u64;
+    let mut skipped = 0u64;
+
+    // Entries without metadata or expiration are written in batches.
+    let mut batch: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+
+    while let Some(line) = lines.next_line().await? {
+        line_no += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let bad_record =
+            |e: &dyn std::fmt::Display| CliError::BadExportRecord(line_no, e.to_string());
+        let record: ExportRecord = serde_json::from_str(&line).map_err(|e| bad_record(&e))?;
+        let key = base64::decode(&record.key).map_err(|e| bad_record(&e))?;
+        let value = base64::decode(&record.value).map_err(|e| bad_record(&e))?;
+        let metadata = base64::decode(&record.metadata).map_err(|e| bad_record(&e))?;
+
+        if record.expiration == 0 && metadata.is_empty() {
+            batch.push((key, value));
+            if batch.len() >= EXPORT_BATCH_SIZE as usize {
+                client.worker_data_put_many(namespace, &batch, 0).await?;
+                batch.clear();
+            }
+        } else {
+            let ttl_ms = if record.expiration == 0 {
+                0
+            } else {
+                match record
+                    .expiration
+                    .checked_sub(rusty_workers::util::current_millis())
+                {
+                    Some(x) if x > 0 => x,
+                    _ => {
+                        skipped += 1;
+                        continue;
+                    }
+                }
+            };
+            client
+                .worker_data_put(namespace, &key, &value, &metadata, false, ttl_ms)
+                .await?;
+        }
+        imported += 1;
+    }
+    if !batch.is_empty() {
+        client.worker_data_put_many(namespace, &batch, 0).await?;
+    }
+    Ok((imported, skipped))
+}
+
+/// Encodes a key or value for display, as base64 or as a string if it is valid UTF-8.
+fn encode_bytes(data: Vec<u8>, use_base64: bool) -> Option<String> {
+    if use_base64 {
+        Some(base64::encode(&data))
+    } else {
+        String::from_utf8(data).ok()
+    }
+}
+
 fn make_context() -> tarpc::context::Context {
     let mut current = tarpc::context::current();
     current.deadline = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
@@ -10,20 +10,53 @@ use hyper::{
     service::{make_service_fn, service_fn},
     Body, Request, Response, StatusCode,
 };
+use rand::Rng;
+use rusty_workers::app::{KvNamespace, KvWatch};
 use rusty_workers::db::DataClient;
+use rusty_workers::objstore::BUCKET_NAMESPACE_PREFIX;
+use rusty_workers::rpc::RuntimeServiceClient;
+use rusty_workers::tarpc;
+use rusty_workers::types::LogFilter;
 use serde_json::json;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use structopt::StructOpt;
 use thiserror::Error;
+use tokio::net::lookup_host;
+use tokio::sync::mpsc;
 use types::*;
 
 const MAX_REQUEST_BODY_SIZE: usize = 8 * 1024 * 1024;
 
+/// How often `/v1/watch_namespace` polls the change log.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Maximum number of changes read from the change log at once.
+const WATCH_BATCH_SIZE: u32 = 1000;
+
+/// How long one `tail_logs` call to a runtime waits for new lines.
+const TAIL_POLL_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Time to wait before reconnecting to a runtime that cannot be tailed.
+const TAIL_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
 #[derive(Error, Debug)]
 enum CpError {
     #[error("request body too large")]
     RequestBodyTooLarge,
+
+    #[error("namespace not found")]
+    NamespaceNotFound,
+
+    #[error("namespace is not owned by the app")]
+    UnownedNamespace,
+
+    #[error("namespace id is reserved")]
+    ReservedNamespaceId,
+
+    #[error("no runtimes configured")]
+    NoRuntimes,
 }
 
 #[derive(Debug, StructOpt, Clone)]
@@ -33,18 +66,19 @@ struct Opt {
     #[structopt(short = "l", long)]
     http_listen: SocketAddr,
 
-    /// TiKV PD addresses.
-    #[structopt(long, env = "TIKV_PD")]
-    tikv_pd: String,
-
-    /// MySQL-compatible database URL.
+    /// Database URL: `mysql://...`, `sqlite://path/to/file.db` or `memory://`.
     #[structopt(long, env = "RW_DB_URL")]
     db_url: String,
+
+    /// Runtime service backends, comma-separated. Required to tail app logs.
+    #[structopt(long, env = "RUNTIMES")]
+    runtimes: Option<String>,
 }
 
 struct Server {
     config: Opt,
     kv: DataClient,
+    runtimes: Vec<SocketAddr>,
 }
 
 impl Server {
@@ -75,6 +109,123 @@ impl Server {
                 self.kv.route_mapping_delete(&opt.domain, &opt.path).await?;
                 Ok(mk_json_response(&())?)
             }
+            "/v1/create_namespace" => {
+                let opt: CreateNamespaceOpt = serde_json::from_slice(&req_body)?;
+                let id = opt.id.unwrap_or_else(|| {
+                    let mut id = [0u8; 16];
+                    rand::thread_rng().fill(&mut id);
+                    base64::encode(&id)
+                });
+                if id.starts_with(BUCKET_NAMESPACE_PREFIX) {
+                    return Err(CpError::ReservedNamespaceId.into());
+                }
+                let ns = KvNamespace {
+                    id,
+                    owner: opt.owner,
+                    name: opt.name,
+                    createtime: rusty_workers::util::current_millis(),
+                    max_keys: opt.max_keys,
+                    max_bytes: opt.max_bytes,
+                    used_keys: 0,
+                    used_bytes: 0,
+                };
+                self.kv.kv_namespace_create(&ns).await?;
+                Ok(mk_json_response(&ns)?)
+            }
+            "/v1/list_namespaces" => {
+                let opt: ListNamespacesOpt = serde_json::from_slice(&req_body)?;
+                // Buckets are registered as namespaces too, see `rusty-workers-cli app create-bucket`.
+                let namespaces: Vec<KvNamespace> = self
+                    .kv
+                    .kv_namespace_list(opt.owner.as_deref())
+                    .await?
+                    .into_iter()
+                    .filter(|x| !x.id.starts_with(BUCKET_NAMESPACE_PREFIX))
+                    .collect();
+                Ok(mk_json_response(&namespaces)?)
+            }
+            "/v1/delete_namespace" => {
+                let opt: DeleteNamespaceOpt = serde_json::from_slice(&req_body)?;
+                if opt.id.starts_with(BUCKET_NAMESPACE_PREFIX) {
+                    return Err(CpError::ReservedNamespaceId.into());
+                }
+                self.kv.kv_namespace_delete(&opt.id).await?;
+                Ok(mk_json_response(&())?)
+            }
+            "/v1/watch_namespace" => {
+                let opt: WatchNamespaceOpt = serde_json::from_slice(&req_body)?;
+                let prefix = base64::decode(&opt.prefix)?;
+                let after = match opt.after {
+                    Some(x) => x,
+                    None => self.kv.worker_data_changes_latest_seq().await?,
+                };
+                let (tx, body) = Body::channel();
+                tokio::spawn(
+                    self.clone()
+                        .stream_changes(opt.namespace, prefix, after, tx),
+                );
+                let mut res = Response::new(body);
+                res.headers_mut().insert(
+                    "content-type",
+                    HeaderValue::from_static("application/x-ndjson"),
+                );
+                Ok(res)
+            }
+            "/v1/tail_logs" => {
+                let opt: TailLogsOpt = serde_json::from_slice(&req_body)?;
+                if self.runtimes.is_empty() {
+                    return Err(CpError::NoRuntimes.into());
+                }
+                let filter = LogFilter {
+                    min_level: opt.level,
+                    contains: opt.contains,
+                };
+                let (tx, body) = Body::channel();
+                tokio::spawn(self.clone().stream_logs(opt.appid, filter, tx));
+                let mut res = Response::new(body);
+                res.headers_mut().insert(
+                    "content-type",
+                    HeaderValue::from_static("application/x-ndjson"),
+                );
+                Ok(res)
+            }
+            "/v1/create_watch" => {
+                let opt: CreateWatchOpt = serde_json::from_slice(&req_body)?;
+                let ns = self
+                    .kv
+                    .kv_namespace_get(&opt.namespace)
+                    .await?
+                    .ok_or(CpError::NamespaceNotFound)?;
+                if ns.owner != opt.appid {
+                    return Err(CpError::UnownedNamespace.into());
+                }
+                let id = opt.id.unwrap_or_else(|| {
+                    let mut id = [0u8; 16];
+                    rand::thread_rng().fill(&mut id);
+                    base64::encode(&id)
+                });
+                let watch = KvWatch {
+                    id,
+                    appid: opt.appid,
+                    namespace_id: opt.namespace,
+                    prefix: base64::decode(&opt.prefix)?,
+                    // Only changes made after the watch is created are delivered.
+                    cursor: self.kv.worker_data_changes_latest_seq().await?,
+                    createtime: rusty_workers::util::current_millis(),
+                };
+                self.kv.kv_watch_create(&watch).await?;
+                Ok(mk_json_response(&watch)?)
+            }
+            "/v1/list_watches" => {
+                let opt: ListWatchesOpt = serde_json::from_slice(&req_body)?;
+                let watches = self.kv.kv_watch_list(opt.appid.as_deref()).await?;
+                Ok(mk_json_response(&watches)?)
+            }
+            "/v1/delete_watch" => {
+                let opt: DeleteWatchOpt = serde_json::from_slice(&req_body)?;
+                self.kv.kv_watch_delete(&opt.id).await?;
+                Ok(mk_json_response(&())?)
+            }
             _ => {
                 let mut res = Response::new(Body::from("not found"));
                 *res.status_mut() = StatusCode::NOT_FOUND;
@@ -82,6 +233,175 @@ impl Server {
             }
         }
     }
+
+    /// Streams changes to `namespace` as JSON lines until the client disconnects.
+    async fn stream_changes(
+        self: Arc<Self>,
+        namespace: String,
+        prefix: Vec<u8>,
+        mut after: u64,
+        mut tx: hyper::body::Sender,
+    ) {
+        loop {
+            let changes = match self
+                .kv
+                .worker_data_changes(&namespace, &prefix, after, WATCH_BATCH_SIZE)
+                .await
+            {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("watch_namespace: cannot read changes: {:?}", e);
+                    tx.abort();
+                    return;
+                }
+            };
+            if changes.is_empty() {
+                // Detect disconnected clients while idle.
+                if futures::future::poll_fn(|cx| tx.poll_ready(cx))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+                tokio::time::sleep(WATCH_POLL_INTERVAL).await;
+                continue;
+            }
+
+            let mut chunk = String::new();
+            for change in changes {
+                after = change.seq;
+                let record = ChangeRecord {
+                    seq: change.seq,
+                    key: base64::encode(&change.key),
+                    value: change.value.map(|x| base64::encode(&x)),
+                    time: change.time,
+                };
+                // Serializing this struct cannot fail.
+                chunk += &serde_json::to_string(&record).unwrap();
+                chunk.push('\n');
+            }
+            if tx.send_data(chunk.into()).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Streams lines logged by workers of `appid` on all runtimes as JSON lines until the client
+    /// disconnects.
+    async fn stream_logs(
+        self: Arc<Self>,
+        appid: String,
+        filter: LogFilter,
+        mut tx: hyper::body::Sender,
+    ) {
+        let (records_tx, mut records_rx) = mpsc::channel(self.runtimes.len());
+        for addr in self.runtimes.iter() {
+            tokio::spawn(tail_runtime(
+                *addr,
+                appid.clone(),
+                filter.clone(),
+                records_tx.clone(),
+            ));
+        }
+        drop(records_tx);
+
+        // Runtime tasks stop when `records_rx` is dropped.
+        while let Some(records) = records_rx.recv().await {
+            if records.is_empty() {
+                // Detect disconnected clients while idle.
+                if futures::future::poll_fn(|cx| tx.poll_ready(cx))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+                continue;
+            }
+
+            let mut chunk = String::new();
+            for record in records {
+                // Serializing this struct cannot fail.
+                chunk += &serde_json::to_string(&record).unwrap();
+                chunk.push('\n');
+            }
+            if tx.send_data(chunk.into()).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Follows the log of `appid` on the runtime at `addr`, and sends each batch of lines to `tx`,
+/// including empty ones, until `tx` is closed.
+async fn tail_runtime(
+    addr: SocketAddr,
+    appid: String,
+    filter: LogFilter,
+    tx: mpsc::Sender<Vec<LogRecord>>,
+) {
+    let mut after = 0;
+    let mut dropped = 0;
+    while !tx.is_closed() {
+        let mut client = match RuntimeServiceClient::connect_noretry(addr).await {
+            Ok(x) => x,
+            Err(e) => {
+                debug!("tail_logs: cannot connect to runtime {}: {:?}", addr, e);
+                tokio::time::sleep(TAIL_RETRY_INTERVAL).await;
+                continue;
+            }
+        };
+        let runtime = match client.id(tarpc::context::current()).await {
+            Ok(x) => x,
+            Err(e) => {
+                debug!("tail_logs: cannot fetch id from runtime {}: {:?}", addr, e);
+                tokio::time::sleep(TAIL_RETRY_INTERVAL).await;
+                continue;
+            }
+        };
+
+        loop {
+            let mut ctx = tarpc::context::current();
+            ctx.deadline = SystemTime::now() + TAIL_POLL_TIMEOUT + TAIL_RETRY_INTERVAL;
+            let tail = match client
+                .tail_logs(
+                    ctx,
+                    appid.clone(),
+                    filter.clone(),
+                    after,
+                    TAIL_POLL_TIMEOUT.as_millis() as u64,
+                )
+                .await
+            {
+                Ok(Ok(x)) => x,
+                Ok(Err(e)) => {
+                    warn!("tail_logs: runtime {} failed: {:?}", runtime.0, e);
+                    break;
+                }
+                Err(e) => {
+                    debug!("tail_logs: rpc to runtime {} failed: {:?}", runtime.0, e);
+                    break;
+                }
+            };
+            after = tail.cursor;
+            dropped += tail.dropped;
+
+            let records: Vec<LogRecord> = tail
+                .entries
+                .into_iter()
+                .map(|x| LogRecord {
+                    runtime: runtime.0.clone(),
+                    time: x.time,
+                    level: x.level,
+                    text: x.text,
+                    dropped: std::mem::replace(&mut dropped, 0),
+                })
+                .collect();
+            if tx.send(records).await.is_err() {
+                return;
+            }
+        }
+        tokio::time::sleep(TAIL_RETRY_INTERVAL).await;
+    }
 }
 
 #[tokio::main]
@@ -90,9 +410,20 @@ async fn main() -> Result<()> {
     rusty_workers::init();
     let opt = Opt::from_args();
 
+    let kv = DataClient::new(&opt.db_url).await?;
+    kv.check_schema().await?;
+
+    let mut runtimes: Vec<SocketAddr> = Vec::new();
+    if let Some(ref x) = opt.runtimes {
+        for elem in x.split(',') {
+            runtimes.extend(lookup_host(elem).await?);
+        }
+    }
+
     let server = Arc::new(Server {
-        kv: DataClient::new(opt.tikv_pd.split(",").collect(), &opt.db_url).await?,
+        kv,
         config: opt.clone(),
+        runtimes,
     });
 
     let make_svc = make_service_fn(move |_| {
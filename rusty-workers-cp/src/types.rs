@@ -1,3 +1,4 @@
+use rusty_workers::types::LogLevel;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -22,3 +23,113 @@ pub struct DeleteRouteOpt {
     pub domain: String,
     pub path: String,
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CreateNamespaceOpt {
+    /// Defaults to a random id.
+    #[serde(default)]
+    pub id: Option<String>,
+    pub owner: String,
+    pub name: String,
+
+    /// 0 means unlimited.
+    #[serde(default)]
+    pub max_keys: u64,
+
+    /// 0 means unlimited.
+    #[serde(default)]
+    pub max_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListNamespacesOpt {
+    #[serde(default)]
+    pub owner: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteNamespaceOpt {
+    pub id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WatchNamespaceOpt {
+    pub namespace: String,
+
+    /// Base64-encoded key prefix.
+    #[serde(default)]
+    pub prefix: String,
+
+    /// Only stream changes after this sequence number. Defaults to the latest change.
+    #[serde(default)]
+    pub after: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TailLogsOpt {
+    pub appid: String,
+
+    /// Only stream lines of this level or higher.
+    #[serde(default)]
+    pub level: LogLevel,
+
+    /// Only stream lines containing this text.
+    #[serde(default)]
+    pub contains: String,
+}
+
+/// A line streamed by `/v1/tail_logs`, one JSON object per line.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogRecord {
+    /// Id of the runtime that ran the worker.
+    pub runtime: String,
+    pub time: u64,
+    pub level: LogLevel,
+    pub text: String,
+
+    /// Number of lines on the same runtime that were dropped before this one because the stream
+    /// fell behind.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub dropped: u64,
+}
+
+fn is_zero(x: &u64) -> bool {
+    *x == 0
+}
+
+/// A change streamed by `/v1/watch_namespace`, one JSON object per line.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChangeRecord {
+    pub seq: u64,
+
+    /// Base64-encoded key.
+    pub key: String,
+
+    /// Base64-encoded value, or `None` if the key was deleted.
+    pub value: Option<String>,
+    pub time: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CreateWatchOpt {
+    /// Defaults to a random id.
+    #[serde(default)]
+    pub id: Option<String>,
+    pub appid: String,
+    pub namespace: String,
+
+    /// Base64-encoded key prefix.
+    #[serde(default)]
+    pub prefix: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListWatchesOpt {
+    #[serde(default)]
+    pub appid: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteWatchOpt {
+    pub id: String,
+}
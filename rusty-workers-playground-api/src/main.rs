@@ -10,12 +10,12 @@ use hyper::{
     service::{make_service_fn, service_fn},
     Body, Request, Response, StatusCode,
 };
-use rand::Rng;
 use rusty_workers::app::AppConfig;
 use rusty_workers::db::DataClient;
+use rusty_workers::objstore::BUCKET_NAMESPACE_PREFIX;
+use rusty_workers::util::current_millis;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
 use structopt::StructOpt;
 use thiserror::Error;
 use types::*;
@@ -32,6 +32,12 @@ enum CpError {
 
     #[error("bad auth token")]
     BadAuthToken,
+
+    #[error("namespace {0} is not registered to this app")]
+    UnownedNamespace(String),
+
+    #[error("bucket {0} is not registered to this app")]
+    UnownedBucket(String),
 }
 
 #[derive(Debug, StructOpt, Clone)]
@@ -44,9 +50,9 @@ struct Opt {
     #[structopt(short = "l", long)]
     http_listen: SocketAddr,
 
-    /// TiKV PD addresses.
-    #[structopt(long, env = "TIKV_PD")]
-    tikv_pd: String,
+    /// Database URL: `mysql://...`, `sqlite://path/to/file.db` or `memory://`.
+    #[structopt(long, env = "RW_DB_URL")]
+    db_url: String,
 
     /// Authentication token, 128-bit base64.
     #[structopt(long, env = "RW_AUTH_TOKEN")]
@@ -104,64 +110,74 @@ impl Server {
                 let mut config: AppConfig = opt.config;
                 let bundle = base64::decode(&opt.bundle_b64)?;
 
-                cleanup_previous_app(&self.kv, &config.id).await?;
+                for binding in &config.kv_namespaces {
+                    match self.kv.kv_namespace_get(&binding.id).await? {
+                        Some(ns)
+                            if ns.owner == config.id.0
+                                && !ns.id.starts_with(BUCKET_NAMESPACE_PREFIX) => {}
+                        _ => return Err(CpError::UnownedNamespace(binding.id.clone()).into()),
+                    }
+                }
+                for binding in &config.buckets {
+                    let id = format!("{}{}", BUCKET_NAMESPACE_PREFIX, binding.id);
+                    match self.kv.kv_namespace_get(&id).await? {
+                        Some(ns) if ns.owner == config.id.0 => {}
+                        _ => return Err(CpError::UnownedBucket(binding.id.clone()).into()),
+                    }
+                }
 
-                let mut bundle_id = [0u8; 16];
-                rand::thread_rng().fill(&mut bundle_id);
-                self.kv.app_bundle_put(&bundle_id, bundle).await?;
-                config.bundle_id = rusty_workers::app::encode_id128(&bundle_id);
+                // Previous bundles are kept for rollbacks and deleted by `gc-bundles`.
+                let bundle_id = rusty_workers::app::bundle_id(&bundle);
+                if !self.kv.app_bundle_touch(&bundle_id).await? {
+                    self.kv.app_bundle_put(&bundle_id, &bundle).await?;
+                }
+                config.bundle_id = bundle_id;
 
-                self.kv.app_metadata_put(&config).await?;
+                self.kv.app_deploy(&config, "playground", "").await?;
                 Ok(mk_json_response(&())?)
             }
             "/v1/delete_app" => {
                 let opt: DeleteAppOpt = serde_json::from_slice(&req_body)?;
-                let appid = rusty_workers::app::AppId(opt.appid);
-                cleanup_previous_app(&self.kv, &appid).await?;
-                self.kv.app_metadata_delete(&appid.0).await?;
+                self.kv.app_metadata_delete(&opt.appid).await?;
+                self.kv.app_deployment_prune(&opt.appid, 0).await?;
 
-                // TODO: Delete logs?
+                // Logs are left to the proxies' log pruner, which keeps them for the default
+                // retention period.
 
                 Ok(mk_json_response(&())?)
             }
             "/v1/delete_namespace" => {
                 let opt: DeleteNamespaceOpt = serde_json::from_slice(&req_body)?;
-                let namespace =
-                    rusty_workers::app::decode_id128(&opt.nsid).ok_or_else(|| CpError::BadId128)?;
                 let keys = self
                     .kv
-                    .worker_data_scan_keys(&namespace, b"", None, opt.batch_size)
+                    .worker_data_scan_keys(&opt.nsid, b"", None, opt.batch_size)
                     .await?;
-                for k in keys.iter() {
-                    self.kv.worker_data_delete(&namespace, k).await?;
-                }
+                self.kv.worker_data_delete_many(&opt.nsid, &keys).await?;
                 Ok(mk_json_response(&keys.len())?)
             }
             "/v1/logs" => {
                 let opt: LogsOpt = serde_json::from_slice(&req_body)?;
-                let now = SystemTime::now();
-                let since = now - Duration::from_secs(opt.since_secs);
+                let now = current_millis();
+                let since = now.saturating_sub(opt.since_secs.saturating_mul(1000));
 
                 #[derive(serde::Serialize)]
                 struct Item {
                     time: String,
                     text: String,
                 }
-                let mut items = vec![];
 
-                self.kv
-                    .log_range(&format!("app-{}", opt.appid), since..now, |time, text| {
-                        items.push(Item {
-                            time: time.to_string(),
-                            text: text.to_string(),
-                        });
-                        if items.len() >= opt.limit as usize {
-                            false
-                        } else {
-                            true
-                        }
-                    })
+                let page = self
+                    .kv
+                    .log_range(&opt.appid, since..now + 1, None, opt.limit)
                     .await?;
+                let items: Vec<Item> = page
+                    .entries
+                    .into_iter()
+                    .map(|x| Item {
+                        time: x.time.to_string(),
+                        text: x.text,
+                    })
+                    .collect();
                 Ok(mk_json_response(&items)?)
             }
             _ => {
@@ -179,8 +195,11 @@ async fn main() -> Result<()> {
     rusty_workers::init();
     let opt = Opt::from_args();
 
+    let kv = DataClient::new(&opt.db_url).await?;
+    kv.check_schema().await?;
+
     let server = Arc::new(Server {
-        kv: DataClient::new(opt.tikv_pd.split(",").collect()).await?,
+        kv,
         _config: opt.clone(),
         auth_token: rusty_workers::app::decode_id128(&opt.auth_token)
             .ok_or_else(|| CpError::BadId128)?,
@@ -240,23 +259,3 @@ async fn read_request_body(req: Request<Body>) -> Result<Vec<u8>> {
     body_error?;
     Ok(full_body)
 }
-
-async fn cleanup_previous_app(
-    client: &DataClient,
-    appid: &rusty_workers::app::AppId,
-) -> Result<()> {
-    if let Some(prev_md) = client.app_metadata_get(&appid.0).await? {
-        if let Ok(prev_config) = serde_json::from_slice::<AppConfig>(&prev_md) {
-            client
-                .app_bundle_delete(
-                    &rusty_workers::app::decode_id128(&prev_config.bundle_id)
-                        .ok_or_else(|| CpError::BadId128)?,
-                )
-                .await?;
-            warn!("deleted previous bundle");
-        } else {
-            warn!("unable to decode previous metadata");
-        }
-    }
-    Ok(())
-}
@@ -11,4 +11,7 @@ pub struct LocalConfig {
     pub dropout_rate: f32,
     pub route_cache_size: usize,
     pub app_cache_size: usize,
+    pub trust_forwarded_for: bool,
+    pub server_timing: bool,
+    pub per_app_metrics: bool,
 }
@@ -0,0 +1,154 @@
+//! Background garbage collection of expired KV entries and blobs, abandoned blob uploads and old
+//! KV changes.
+
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use rusty_workers::db::DataClient;
+use rusty_workers::util::current_millis;
+use std::time::Duration;
+
+lazy_static! {
+    static ref KV_GC_DELETED: IntCounter = register_int_counter!(
+        "kv_gc_deleted",
+        "Number of expired KV entries deleted by the garbage collector"
+    )
+    .unwrap();
+    static ref KV_GC_DRY_RUN_FOUND: IntCounter = register_int_counter!(
+        "kv_gc_dry_run_found",
+        "Number of expired KV entries found by the garbage collector in dry-run mode"
+    )
+    .unwrap();
+    static ref KV_GC_BLOBS_DELETED: IntCounter = register_int_counter!(
+        "kv_gc_blobs_deleted",
+        "Number of expired blobs and abandoned blob chunks deleted by the garbage collector"
+    )
+    .unwrap();
+    static ref KV_GC_CHANGES_DELETED: IntCounter = register_int_counter!(
+        "kv_gc_changes_deleted",
+        "Number of KV change log entries deleted by the garbage collector"
+    )
+    .unwrap();
+    static ref KV_GC_ERRORS: IntCounter = register_int_counter!(
+        "kv_gc_errors",
+        "Number of failed garbage collection batches"
+    )
+    .unwrap();
+}
+
+pub struct KvGcConfig {
+    pub interval: Duration,
+    pub batch_size: u32,
+    pub dry_run: bool,
+
+    /// How long to keep the KV change log. `None` keeps it forever.
+    pub changes_retention: Option<Duration>,
+}
+
+/// Periodically deletes expired KV entries, one bounded batch at a time.
+///
+/// Other proxy replicas may run this concurrently; overlapping batches just delete fewer rows.
+pub async fn run(client: DataClient, config: KvGcConfig) {
+    info!(
+        "kv gc started (interval {:?}, batch size {}, dry run: {})",
+        config.interval, config.batch_size, config.dry_run
+    );
+    loop {
+        tokio::time::sleep(config.interval).await;
+        collect(&client, &config).await;
+        if !config.dry_run {
+            collect_blobs(&client, &config).await;
+        }
+        if let Some(retention) = config.changes_retention {
+            collect_changes(&client, &config, retention).await;
+        }
+    }
+}
+
+async fn collect(client: &DataClient, config: &KvGcConfig) {
+    let mut total = 0u64;
+    loop {
+        let n = match client
+            .worker_data_delete_expired(config.batch_size, config.dry_run)
+            .await
+        {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("kv gc: batch failed: {:?}", e);
+                KV_GC_ERRORS.inc();
+                break;
+            }
+        };
+        total += n;
+
+        if config.dry_run {
+            // Nothing is deleted, so the next batch would see the same rows.
+            KV_GC_DRY_RUN_FOUND.inc_by(n);
+            break;
+        }
+        KV_GC_DELETED.inc_by(n);
+        if n == 0 || n < config.batch_size as u64 {
+            break;
+        }
+    }
+
+    if total != 0 {
+        if config.dry_run {
+            info!("kv gc: found at least {} expired entries (dry run)", total);
+        } else {
+            info!("kv gc: deleted {} expired entries", total);
+        }
+    }
+}
+
+async fn collect_blobs(client: &DataClient, config: &KvGcConfig) {
+    let mut total = 0u64;
+    loop {
+        let n = match client.worker_blob_delete_expired(config.batch_size).await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("kv gc: blob batch failed: {:?}", e);
+                KV_GC_ERRORS.inc();
+                break;
+            }
+        };
+        total += n;
+        KV_GC_BLOBS_DELETED.inc_by(n);
+        if n == 0 || n < config.batch_size as u64 {
+            break;
+        }
+    }
+
+    if total != 0 {
+        info!(
+            "kv gc: deleted {} expired blobs and abandoned chunks",
+            total
+        );
+    }
+}
+
+async fn collect_changes(client: &DataClient, config: &KvGcConfig, retention: Duration) {
+    let before = current_millis().saturating_sub(retention.as_millis() as u64);
+    let mut total = 0u64;
+    loop {
+        let n = match client
+            .worker_data_delete_changes(before, config.batch_size)
+            .await
+        {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("kv gc: change log batch failed: {:?}", e);
+                KV_GC_ERRORS.inc();
+                break;
+            }
+        };
+        total += n;
+        KV_GC_CHANGES_DELETED.inc_by(n);
+        if n == 0 || n < config.batch_size as u64 {
+            break;
+        }
+    }
+
+    if total != 0 {
+        info!("kv gc: deleted {} old changes", total);
+    }
+}
@@ -0,0 +1,90 @@
+//! Background pruning of app logs that are older than their retention period.
+
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use rusty_workers::db::DataClient;
+use rusty_workers::types::*;
+use rusty_workers::util::current_millis;
+use std::time::Duration;
+
+lazy_static! {
+    static ref LOG_GC_DELETED: IntCounter = register_int_counter!(
+        "log_gc_deleted",
+        "Number of app log lines deleted by the log retention pruner"
+    )
+    .unwrap();
+    static ref LOG_GC_ERRORS: IntCounter =
+        register_int_counter!("log_gc_errors", "Number of failed log pruning batches").unwrap();
+}
+
+pub struct LogGcConfig {
+    pub interval: Duration,
+    pub batch_size: u32,
+
+    /// How long to keep logs of apps that don't set `log_retention_secs`. `None` keeps them
+    /// forever.
+    pub default_retention: Option<Duration>,
+}
+
+/// Periodically deletes log lines older than the retention period of their app, one bounded
+/// batch at a time.
+///
+/// Logs of deleted apps are kept for the default retention period. Other proxy replicas may run
+/// this concurrently; overlapping batches just delete fewer rows.
+pub async fn run(client: DataClient, config: LogGcConfig) {
+    info!(
+        "log gc started (interval {:?}, batch size {}, default retention {:?})",
+        config.interval, config.batch_size, config.default_retention
+    );
+    loop {
+        tokio::time::sleep(config.interval).await;
+        let apps = match client.applog_apps().await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("log gc: cannot list apps: {:?}", e);
+                LOG_GC_ERRORS.inc();
+                continue;
+            }
+        };
+        for appid in apps {
+            if let Err(e) = collect(&client, &config, &appid).await {
+                warn!("log gc: batch failed for app {}: {:?}", appid, e);
+                LOG_GC_ERRORS.inc();
+            }
+        }
+    }
+}
+
+async fn collect(client: &DataClient, config: &LogGcConfig, appid: &str) -> GenericResult<()> {
+    let retention = match client
+        .app_metadata_get(appid)
+        .await?
+        .and_then(|x| x.log_retention_secs)
+    {
+        Some(0) => None,
+        Some(x) => Some(Duration::from_secs(x)),
+        None => config.default_retention,
+    };
+    let retention = match retention {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+
+    let before = current_millis().saturating_sub(retention.as_millis() as u64);
+    let mut total = 0u64;
+    loop {
+        let n = client
+            .applog_delete_before(appid, before, config.batch_size)
+            .await?;
+        total += n;
+        LOG_GC_DELETED.inc_by(n);
+        if n == 0 || n < config.batch_size as u64 {
+            break;
+        }
+    }
+
+    if total != 0 {
+        info!("log gc: deleted {} old log lines of app {}", total, appid);
+    }
+    Ok(())
+}
@@ -2,7 +2,10 @@
 extern crate log;
 
 mod config;
+mod gc;
+mod loggc;
 mod sched;
+mod watch;
 
 use anyhow::Result;
 use once_cell::sync::OnceCell;
@@ -15,6 +18,7 @@ use tokio::net::lookup_host;
 use std::time::Instant;
 use crate::config::*;
 use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Response, Server,Request};
 use sched::SchedError;
@@ -38,6 +42,10 @@ struct Opt {
     #[structopt(long, env = "RW_FETCH_SERVICE")]
     fetch_service: String,
 
+    /// SQL service that holds app databases. Apps have no database if unset.
+    #[structopt(long, env = "RW_SQL_SERVICE")]
+    sql_service: Option<String>,
+
     /// Runtime service backends, comma-separated.
     #[structopt(long, env = "RUNTIMES")]
     runtimes: String,
@@ -58,6 +66,23 @@ struct Opt {
     #[structopt(long, env = "RW_MAX_IO_PER_REQUEST", default_value = "50")]
     max_io_per_request: u32,
 
+    /// Max number of requests handled concurrently by one instance. Values above 1 let I/O-bound
+    /// apps interleave requests in the same isolate.
+    #[structopt(
+        long,
+        env = "RW_MAX_CONCURRENT_REQUESTS_PER_INSTANCE",
+        default_value = "1"
+    )]
+    max_concurrent_requests_per_instance: u32,
+
+    /// Max number of rows returned by a SQL statement.
+    #[structopt(long, env = "RW_MAX_SQL_ROWS", default_value = "1000")]
+    max_sql_rows: u32,
+
+    /// Max time of a batch of SQL statements, in milliseconds.
+    #[structopt(long, env = "RW_MAX_SQL_TIME_MS", default_value = "1000")]
+    max_sql_time_ms: u32,
+
     /// Max ready instances per app
     #[structopt(long, env = "RW_MAX_READY_INSTANCES_PER_APP", default_value = "50")]
     max_ready_instances_per_app: usize,
@@ -90,13 +115,66 @@ struct Opt {
     #[structopt(long, env = "RW_ROUTE_CACHE_SIZE", default_value = "1000")]
     pub route_cache_size: usize,
 
-    /// MySQL-compatible database URL.
+    /// Database URL: `mysql://...`, `sqlite://path/to/file.db` or `memory://`.
     #[structopt(long, env = "RW_DB_URL")]
     pub db_url: String,
 
     /// Size of app cache.
     #[structopt(long, env = "RW_APP_CACHE_SIZE", default_value = "100")]
     pub app_cache_size: usize,
+
+    /// Take the client address from the last entry of the `X-Forwarded-For` header. Only enable
+    /// this when the proxy is directly behind a trusted load balancer.
+    #[structopt(long, env = "RW_TRUST_FORWARDED_FOR")]
+    pub trust_forwarded_for: bool,
+
+    /// Report per-request resource usage in a `Server-Timing` response header.
+    #[structopt(long, env = "RW_SERVER_TIMING")]
+    pub server_timing: bool,
+
+    /// Break usage metrics down by app id. Each app adds its own time series, so only enable this
+    /// with a bounded number of apps.
+    #[structopt(long, env = "RW_PER_APP_METRICS")]
+    pub per_app_metrics: bool,
+
+    /// Interval between garbage collection runs for expired KV entries, in seconds. 0 disables
+    /// garbage collection.
+    #[structopt(long, env = "RW_KV_GC_INTERVAL_SECS", default_value = "60")]
+    pub kv_gc_interval_secs: u64,
+
+    /// Maximum number of expired KV entries to delete in one batch.
+    #[structopt(long, env = "RW_KV_GC_BATCH_SIZE", default_value = "1000")]
+    pub kv_gc_batch_size: u32,
+
+    /// Only count expired KV entries instead of deleting them.
+    #[structopt(long, env = "RW_KV_GC_DRY_RUN")]
+    pub kv_gc_dry_run: bool,
+
+    /// How long to keep the KV change log, in seconds. 0 keeps it forever.
+    #[structopt(long, env = "RW_KV_CHANGES_RETENTION_SECS", default_value = "604800")]
+    pub kv_changes_retention_secs: u64,
+
+    /// Interval between deliveries of KV changes to watching apps, in milliseconds. 0 disables
+    /// delivery. Should be enabled on exactly one proxy.
+    #[structopt(long, env = "RW_KV_WATCH_INTERVAL_MS", default_value = "0")]
+    pub kv_watch_interval_ms: u64,
+
+    /// Maximum number of changes delivered to an app in one event.
+    #[structopt(long, env = "RW_KV_WATCH_BATCH_SIZE", default_value = "100")]
+    pub kv_watch_batch_size: u32,
+
+    /// Interval between runs of the app log pruner, in seconds. 0 disables pruning.
+    #[structopt(long, env = "RW_LOG_GC_INTERVAL_SECS", default_value = "600")]
+    pub log_gc_interval_secs: u64,
+
+    /// Maximum number of log lines to delete in one batch.
+    #[structopt(long, env = "RW_LOG_GC_BATCH_SIZE", default_value = "1000")]
+    pub log_gc_batch_size: u32,
+
+    /// How long to keep logs of apps that don't set their own retention, in seconds. 0 keeps them
+    /// forever.
+    #[structopt(long, env = "RW_LOG_RETENTION_SECS", default_value = "604800")]
+    pub log_retention_secs: u64,
 }
 
 #[tokio::main]
@@ -119,12 +197,23 @@ async fn main() -> Result<()> {
     }
 
     let kv_client = rusty_workers::db::DataClient::new(&opt.db_url).await?;
+    kv_client.check_schema().await?;
 
     let fetch_service = lookup_host(&opt.fetch_service)
         .await?
         .next()
         .expect("fetch service unresolved");
 
+    let sql_service = match opt.sql_service {
+        Some(ref x) => Some(
+            lookup_host(x)
+                .await?
+                .next()
+                .expect("sql service unresolved"),
+        ),
+        None => None,
+    };
+
     SCHEDULER
         .set(sched::Scheduler::new(
             WorkerConfiguration {
@@ -133,10 +222,17 @@ async fn main() -> Result<()> {
                     max_time_ms: opt.max_time_ms,
                     max_io_concurrency: opt.max_io_concurrency,
                     max_io_per_request: opt.max_io_per_request,
+                    max_concurrent_requests: opt.max_concurrent_requests_per_instance,
+                    max_sql_rows: opt.max_sql_rows,
+                    max_sql_time_ms: opt.max_sql_time_ms,
                 },
                 fetch_service,
                 env: Default::default(),
                 kv_namespaces: Default::default(),
+                buckets: Default::default(),
+                sql_service,
+                bundle_id: Default::default(),
+                inspector: false,
             },
             LocalConfig {
                 max_ready_instances_per_app: opt.max_ready_instances_per_app,
@@ -146,12 +242,58 @@ async fn main() -> Result<()> {
                 dropout_rate: opt.dropout_rate,
                 route_cache_size: opt.route_cache_size,
                 app_cache_size: opt.app_cache_size,
+                trust_forwarded_for: opt.trust_forwarded_for,
+                server_timing: opt.server_timing,
+                per_app_metrics: opt.per_app_metrics,
                 runtime_cluster,
             },
             kv_client,
         ))
         .unwrap_or_else(|_| panic!("cannot set scheduler"));
 
+    if opt.kv_gc_interval_secs != 0 {
+        let gc_client = rusty_workers::db::DataClient::new(&opt.db_url).await?;
+        tokio::spawn(gc::run(
+            gc_client,
+            gc::KvGcConfig {
+                interval: std::time::Duration::from_secs(opt.kv_gc_interval_secs),
+                batch_size: opt.kv_gc_batch_size,
+                dry_run: opt.kv_gc_dry_run,
+                changes_retention: match opt.kv_changes_retention_secs {
+                    0 => None,
+                    x => Some(std::time::Duration::from_secs(x)),
+                },
+            },
+        ));
+    }
+
+    if opt.log_gc_interval_secs != 0 {
+        let gc_client = rusty_workers::db::DataClient::new(&opt.db_url).await?;
+        tokio::spawn(loggc::run(
+            gc_client,
+            loggc::LogGcConfig {
+                interval: std::time::Duration::from_secs(opt.log_gc_interval_secs),
+                batch_size: opt.log_gc_batch_size,
+                default_retention: match opt.log_retention_secs {
+                    0 => None,
+                    x => Some(std::time::Duration::from_secs(x)),
+                },
+            },
+        ));
+    }
+
+    if opt.kv_watch_interval_ms != 0 {
+        let watch_client = rusty_workers::db::DataClient::new(&opt.db_url).await?;
+        tokio::spawn(watch::run(
+            SCHEDULER.get().unwrap().clone(),
+            watch_client,
+            watch::KvWatchConfig {
+                interval: std::time::Duration::from_millis(opt.kv_watch_interval_ms),
+                batch_size: opt.kv_watch_batch_size,
+            },
+        ));
+    }
+
     tokio::spawn(async move {
         loop {
             let scheduler = SCHEDULER.get().unwrap();
@@ -220,25 +362,28 @@ async fn main() -> Result<()> {
     
 
 
-    let make_svc = make_service_fn(|_| async move {
-        Ok::<_, hyper::Error>(service_fn(|req| async move {
-            let scheduler = SCHEDULER.get().unwrap();
-            match scheduler.handle_request(req).await {
-                Ok(x) => Ok::<_, hyper::Error>(x),
-                Err(e) => {
-                    debug!("handle_request failed: {:?}", e);
-                    let res = match e.downcast::<SchedError>() {
-                        Ok(e) => e.build_response(),
-                        Err(_) => {
-                            let mut res = Response::new(Body::from("internal server error"));
-                            *res.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR;
-                            res
-                        }
-                    };
-                    Ok::<_, hyper::Error>(res)
+    let make_svc = make_service_fn(|conn: &AddrStream| {
+        let remote_addr = conn.remote_addr();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req| async move {
+                let scheduler = SCHEDULER.get().unwrap();
+                match scheduler.handle_request(req, remote_addr).await {
+                    Ok(x) => Ok::<_, hyper::Error>(x),
+                    Err(e) => {
+                        debug!("handle_request failed: {:?}", e);
+                        let res = match e.downcast::<SchedError>() {
+                            Ok(e) => e.build_response(),
+                            Err(_) => {
+                                let mut res = Response::new(Body::from("internal server error"));
+                                *res.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR;
+                                res
+                            }
+                        };
+                        Ok::<_, hyper::Error>(res)
+                    }
                 }
-            }
-        }))
+            }))
+        }
     });
     info!("starting http server");
 
@@ -1,17 +1,21 @@
 use crate::config::*;
 use anyhow::Result;
 use futures::StreamExt;
+use lazy_static::lazy_static;
 use lru_time_cache::LruCache;
+use prometheus::{register_int_counter, register_int_counter_vec, IntCounter, IntCounterVec};
 use rand::distributions::{Distribution, Open01, WeightedIndex};
 use rand::Rng;
 use rusty_workers::app::*;
 use rusty_workers::db::DataClient;
+use rusty_workers::objstore::BUCKET_NAMESPACE_PREFIX;
 use rusty_workers::rpc::RuntimeServiceClient;
 use rusty_workers::tarpc;
 use rusty_workers::types::*;
-use std::collections::BTreeMap;
 use std::collections::VecDeque;
-use std::sync::atomic::{AtomicU16, Ordering};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use thiserror::Error;
@@ -21,6 +25,36 @@ use tokio::sync::{
 };
 use tokio::sync::{Mutex as AsyncMutex, RwLock as AsyncRwLock};
 
+lazy_static! {
+    static ref CPU_TIME_US: UsageCounter =
+        UsageCounter::new("cpu_time_us", "CPU time used by requests, in microseconds");
+    static ref REQUESTS: UsageCounter =
+        UsageCounter::new("requests", "Number of completed requests");
+    static ref KV_SUBREQUESTS: UsageCounter = UsageCounter::new(
+        "kv_subrequests",
+        "Number of KV operations issued by requests"
+    );
+    static ref FETCH_SUBREQUESTS: UsageCounter = UsageCounter::new(
+        "fetch_subrequests",
+        "Number of outgoing fetches issued by requests"
+    );
+    static ref BUCKET_SUBREQUESTS: UsageCounter = UsageCounter::new(
+        "bucket_subrequests",
+        "Number of bucket operations issued by requests"
+    );
+    static ref SQL_SUBREQUESTS: UsageCounter = UsageCounter::new(
+        "sql_subrequests",
+        "Number of SQL batches issued by requests"
+    );
+}
+
+/// A usage counter over all apps, and a counter labeled by app id that is only updated if
+/// `per_app_metrics` is enabled.
+struct UsageCounter {
+    total: IntCounter,
+    per_app: IntCounterVec,
+}
+
 #[derive(Debug, Error)]
 pub enum SchedError {
     #[error("no available instance")]
@@ -34,6 +68,9 @@ pub enum SchedError {
 
     #[error("request failed after retries")]
     RequestFailedAfterRetries,
+
+    #[error("kv namespace is not bound to the app")]
+    UnboundKvNamespace,
 }
 
 pub struct Scheduler {
@@ -93,6 +130,9 @@ pub struct ReadyInstance {
 
     /// The tarpc client.
     client: RuntimeServiceClient,
+
+    /// Number of requests being handled by this instance, shared between copies of it.
+    in_flight: Arc<AtomicU32>,
 }
 
 impl ReadyInstance {
@@ -144,7 +184,16 @@ impl AppState {
     }
 
     async fn pool_instance(&self, scheduler: &Scheduler, inst: ReadyInstance) {
-        if rand::thread_rng().sample::<f32, _>(Open01) > scheduler.local_config.dropout_rate {
+        let prev_in_flight = inst.in_flight.fetch_sub(1, Ordering::SeqCst);
+        if prev_in_flight < self.max_concurrent_requests() {
+            // A copy of this instance is still in the pool.
+            return;
+        }
+
+        // Only drop out idle instances, so that other requests on it aren't affected.
+        if prev_in_flight > 1
+            || rand::thread_rng().sample::<f32, _>(Open01) > scheduler.local_config.dropout_rate
+        {
             self.ready_instances.lock().await.push_back(inst);
         } else {
             // Dropped out. Let's terminate it.
@@ -156,9 +205,18 @@ impl AppState {
 
     async fn get_instance(&self, scheduler: &Scheduler) -> Result<ReadyInstance> {
         self.gc_ready_instances(scheduler).await;
-        if let Some(mut inst) = self.ready_instances.lock().await.pop_front() {
-            inst.update_last_active();
-            return Ok(inst);
+        {
+            let mut ready_instances = self.ready_instances.lock().await;
+            if let Some(mut inst) = ready_instances.pop_front() {
+                inst.update_last_active();
+
+                // Keep sharing the instance until it is saturated.
+                let in_flight = inst.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                if in_flight < self.max_concurrent_requests() {
+                    ready_instances.push_front(inst.clone());
+                }
+                return Ok(inst);
+            }
         }
 
         let clients = scheduler.clients.read().await;
@@ -196,17 +254,44 @@ impl AppState {
                 self.bundle.clone(),
             )
             .await??;
-        Ok(ReadyInstance {
+        let inst = ReadyInstance {
             rtid,
             last_active: Instant::now(),
             handle,
             client,
-        })
+            in_flight: Arc::new(AtomicU32::new(1)),
+        };
+
+        // A copy of the instance stays in the pool while it can take more requests.
+        if self.max_concurrent_requests() > 1 {
+            self.ready_instances.lock().await.push_back(inst.clone());
+        }
+        Ok(inst)
+    }
+
+    fn max_concurrent_requests(&self) -> u32 {
+        self.config.executor.max_concurrent_requests.max(1)
     }
 
    
 }
 
+impl UsageCounter {
+    fn new(name: &str, help: &str) -> Self {
+        Self {
+            total: register_int_counter!(name, help).unwrap(),
+            per_app: register_int_counter_vec!(format!("app_{}", name), help, &["appid"]).unwrap(),
+        }
+    }
+
+    fn inc_by(&self, appid: Option<&str>, v: u64) {
+        self.total.inc_by(v);
+        if let Some(appid) = appid {
+            self.per_app.with_label_values(&[appid]).inc_by(v);
+        }
+    }
+}
+
 impl Scheduler {
     pub fn new(
         worker_config: WorkerConfiguration,
@@ -275,6 +360,7 @@ impl Scheduler {
     pub async fn handle_request(
         &self,
         mut req: hyper::Request<hyper::Body>,
+        remote_addr: SocketAddr,
     ) -> Result<hyper::Response<hyper::Body>> {
         println!("handle_request is running");
         // Rewrite host to remove port.
@@ -328,6 +414,7 @@ impl Scheduler {
         println!("************appid is = {:?}",appid);
 
 
+        let metadata = self.build_request_metadata(&req, remote_addr);
         let method = req.method().as_str().to_string();
         let mut headers = BTreeMap::new();
         let url = format!("https://{}{}", host.split(":").nth(0).unwrap(), uri); // TODO: detect https
@@ -368,6 +455,7 @@ impl Scheduler {
             method,
             url,
             body: HttpBody::Binary(full_body),
+            metadata,
         };
        
         
@@ -447,6 +535,10 @@ impl Scheduler {
             // Pool it back.
             app.pool_instance(self, instance).await;
 
+            self.record_usage(&appid, &fetch_res.usage);
+            let usage = fetch_res.usage;
+            let fetch_res = fetch_res.response;
+
             // Build response.
             let mut res = hyper::Response::new(match fetch_res.body {
                 HttpBody::Binary(bytes) => hyper::Body::from(bytes),
@@ -467,12 +559,147 @@ impl Scheduler {
                 hyper::header::HeaderValue::from_bytes(b"rworkers").unwrap(),
             );
 
+            if self.local_config.server_timing {
+                res.headers_mut().append(
+                    "server-timing",
+                    hyper::header::HeaderValue::from_str(&format_server_timing(&usage))?,
+                );
+            }
+
             return Ok(res);
         }
 
         Err(SchedError::RequestFailedAfterRetries.into())
     }
 
+    /// Delivers changes to a KV namespace to an app that watches it.
+    ///
+    /// Returns an error if the app's handler fails, in which case the changes should be delivered
+    /// again later.
+    pub async fn dispatch_kv_change(
+        &self,
+        appid: &AppId,
+        namespace_id: &str,
+        changes: Vec<KvChange>,
+    ) -> Result<()> {
+        let mut app = self.apps.lock().await.get(appid).cloned();
+        if app.is_none() {
+            let (back_tx, back_rx) = oneshot::channel();
+            if self
+                .lookup_app_tx
+                .try_send((appid.clone(), back_tx))
+                .is_ok()
+            {
+                let _ = back_rx.await;
+                app = self.apps.lock().await.get(appid).cloned();
+            }
+        }
+        let app = app.ok_or(SchedError::NoRouteMapping)?;
+
+        let namespace = app
+            .config
+            .kv_namespaces
+            .iter()
+            .find(|(_, id)| id.as_str() == namespace_id)
+            .map(|(name, _)| name.clone())
+            .ok_or(SchedError::UnboundKvNamespace)?;
+        let batch = KvChangeBatch { namespace, changes };
+
+        // Backend retries.
+        for _ in 0..3usize {
+            let mut instance = app.get_instance(self).await?;
+            debug!(
+                "delivering {} kv changes to app {}, instance {}",
+                batch.changes.len(),
+                appid.0,
+                instance.rtid.0
+            );
+
+            let mut ctx = tarpc::context::current();
+            ctx.deadline = std::time::SystemTime::now()
+                + Duration::from_millis(self.local_config.request_timeout_ms);
+
+            let res = match instance
+                .client
+                .kv_change(ctx, instance.handle.clone(), batch.clone())
+                .await
+            {
+                Ok(x) => x,
+                Err(e) => {
+                    // Network error. Drop this and select another instance.
+                    self.clients.write().await.remove(&instance.rtid);
+                    info!("network error for instance {}: {:?}", instance.rtid.0, e);
+                    continue;
+                }
+            };
+
+            match res {
+                Ok(usage) => {
+                    app.pool_instance(self, instance).await;
+                    record_usage(&appid.0, &usage);
+                    return Ok(());
+                }
+                Err(ExecutionError::NoSuchWorker) => continue,
+                Err(e) => {
+                    if !e.terminates_worker() {
+                        app.pool_instance(self, instance).await;
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+
+        Err(SchedError::RequestFailedAfterRetries.into())
+    }
+
+    /// Collects information about the client connection.
+    fn build_request_metadata(
+        &self,
+        req: &hyper::Request<hyper::Body>,
+        remote_addr: SocketAddr,
+    ) -> RequestMetadata {
+        // Clients can send their own `X-Forwarded-For`, so only the last entry, which is appended
+        // by the load balancer in front of the proxy, can be trusted.
+        let forwarded_ip = if self.local_config.trust_forwarded_for {
+            req.headers()
+                .get("x-forwarded-for")
+                .and_then(|x| x.to_str().ok())
+                .and_then(|x| x.rsplit(',').next())
+                .and_then(|x| x.trim().parse::<IpAddr>().ok())
+        } else {
+            None
+        };
+
+        let (client_ip, client_port) = match forwarded_ip {
+            Some(ip) => (ip, None),
+            None => (remote_addr.ip(), Some(remote_addr.port())),
+        };
+
+        // TLS is not terminated by the proxy, so `tls_cipher` and `tls_sni` are left empty.
+        RequestMetadata {
+            request_id: rusty_workers::util::rand_hex(16),
+            client_ip: Some(client_ip),
+            client_port,
+            http_protocol: format!("{:?}", req.version()),
+            tls_cipher: None,
+            tls_sni: None,
+        }
+    }
+
+    fn record_usage(&self, appid: &AppId, usage: &ResourceUsage) {
+        let appid = if self.local_config.per_app_metrics {
+            Some(appid.0.as_str())
+        } else {
+            None
+        };
+        REQUESTS.inc_by(appid, 1);
+        CPU_TIME_US.inc_by(appid, usage.cpu_time_us);
+        KV_SUBREQUESTS.inc_by(appid, usage.kv_subrequests as u64);
+        FETCH_SUBREQUESTS.inc_by(appid, usage.fetch_subrequests as u64);
+        BUCKET_SUBREQUESTS.inc_by(appid, usage.bucket_subrequests as u64);
+        SQL_SUBREQUESTS.inc_by(appid, usage.sql_subrequests as u64);
+    }
+
     /// Query each runtime for its health/load status, etc.
     pub async fn query_runtimes(&self) {
         let mut to_drop = vec![];
@@ -623,10 +850,18 @@ impl Scheduler {
             for (id, (bundle_id, worker_config)) in apps {
                 match self.kv_client.app_metadata_get(&id.0).await {
                     Ok(Some(config)) => {
+                        let owned_namespaces = match self.owned_kv_namespaces(&id).await {
+                            Ok(x) => x,
+                            Err(_) => continue,
+                        };
+                        // Apps that no longer own their namespaces are dropped and fail to load.
                         if config.bundle_id != bundle_id
                             || config.env != worker_config.env
-                            || decode_kv_namespaces(&config.kv_namespaces)
-                                != worker_config.kv_namespaces
+                            || decode_kv_namespaces(&config.kv_namespaces, &owned_namespaces)
+                                .as_ref()
+                                != Ok(&worker_config.kv_namespaces)
+                            || decode_buckets(&config.buckets, &owned_namespaces).as_ref()
+                                != Ok(&worker_config.buckets)
                         {
                             info!("app changed. removing app {} from cache", id.0);
                             self.apps.lock().await.remove(&id);
@@ -644,6 +879,17 @@ impl Scheduler {
         }
     }
 
+    /// Returns the ids of the namespaces registered to an app, including those of its buckets.
+    async fn owned_kv_namespaces(&self, id: &AppId) -> GenericResult<BTreeSet<String>> {
+        Ok(self
+            .kv_client
+            .kv_namespace_list(Some(&id.0))
+            .await?
+            .into_iter()
+            .map(|x| x.id)
+            .collect())
+    }
+
     async fn lookup_app_background(&self, mut rx: Receiver<(AppId, oneshot::Sender<()>)>) {
         loop {
             let (appid, back_ch) = match rx.recv().await {
@@ -691,9 +937,40 @@ impl Scheduler {
             }
         };
 
+        let owned_namespaces = match self.owned_kv_namespaces(&id).await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("do_lookup_app_background: db error: {:?}", e);
+                return;
+            }
+        };
+        let kv_namespaces = match decode_kv_namespaces(&config.kv_namespaces, &owned_namespaces) {
+            Ok(x) => x,
+            Err(ns) => {
+                error!(
+                    "app {} binds kv namespace {} that is not registered to it. not loading it",
+                    id.0, ns
+                );
+                return;
+            }
+        };
+
+        let buckets = match decode_buckets(&config.buckets, &owned_namespaces) {
+            Ok(x) => x,
+            Err(bucket) => {
+                error!(
+                    "app {} binds bucket {} that is not registered to it. not loading it",
+                    id.0, bucket
+                );
+                return;
+            }
+        };
+
         let mut target_config = self.worker_config.clone();
         target_config.env = config.env.clone();
-        target_config.kv_namespaces = decode_kv_namespaces(&config.kv_namespaces);
+        target_config.kv_namespaces = kv_namespaces;
+        target_config.buckets = buckets;
+        target_config.bundle_id = config.bundle_id.clone();
 
         let state = AppState {
             id: id.clone(),
@@ -717,6 +994,7 @@ impl SchedError {
             SchedError::NoRouteMapping => hyper::StatusCode::BAD_GATEWAY,
             SchedError::RequestBodyTooLarge => hyper::StatusCode::PAYLOAD_TOO_LARGE,
             SchedError::RequestFailedAfterRetries => hyper::StatusCode::SERVICE_UNAVAILABLE,
+            SchedError::UnboundKvNamespace => hyper::StatusCode::BAD_GATEWAY,
         };
         let mut res = hyper::Response::new(hyper::Body::from(
             status.canonical_reason().unwrap_or("unknown error"),
@@ -730,10 +1008,39 @@ impl SchedError {
     }
 }
 
-fn decode_kv_namespaces(namespaces: &[KvNamespaceConfig]) -> BTreeMap<String, String> {
+/// Maps namespace names to ids. Fails with the id of the first namespace not in `owned`, or
+/// that is the namespace of a bucket.
+fn decode_kv_namespaces(
+    namespaces: &[KvNamespaceConfig],
+    owned: &BTreeSet<String>,
+) -> Result<BTreeMap<String, String>, String> {
     namespaces
         .iter()
-        .map(|x| (x.name.clone(), x.id.clone()))
+        .map(|x| {
+            if owned.contains(&x.id) && !x.id.starts_with(BUCKET_NAMESPACE_PREFIX) {
+                Ok((x.name.clone(), x.id.clone()))
+            } else {
+                Err(x.id.clone())
+            }
+        })
+        .collect()
+}
+
+/// Maps bucket names to ids. Fails with the id of the first bucket whose namespace is not in
+/// `owned`.
+fn decode_buckets(
+    buckets: &[BucketConfig],
+    owned: &BTreeSet<String>,
+) -> Result<BTreeMap<String, String>, String> {
+    buckets
+        .iter()
+        .map(|x| {
+            if owned.contains(&format!("{}{}", BUCKET_NAMESPACE_PREFIX, x.id)) {
+                Ok((x.name.clone(), x.id.clone()))
+            } else {
+                Err(x.id.clone())
+            }
+        })
         .collect()
 }
 
@@ -750,3 +1057,17 @@ fn lookup_submappings<'a>(
     None
 }
 
+/// Formats resource usage as a `Server-Timing` header value.
+fn format_server_timing(usage: &ResourceUsage) -> String {
+    format!(
+        "cpu;dur={:.3}, io;desc=\"calls={} kv={} fetch={} bucket={} sql={}\", mem;desc=\"heap={} ab={}\"",
+        usage.cpu_time_us as f64 / 1000.0,
+        usage.io_calls,
+        usage.kv_subrequests,
+        usage.fetch_subrequests,
+        usage.bucket_subrequests,
+        usage.sql_subrequests,
+        usage.peak_heap_bytes,
+        usage.peak_array_buffer_bytes,
+    )
+}
@@ -0,0 +1,105 @@
+//! Delivery of KV change feeds to watching apps.
+
+use crate::sched::Scheduler;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use rusty_workers::app::{AppId, KvWatch};
+use rusty_workers::db::DataClient;
+use std::sync::Arc;
+use std::time::Duration;
+
+lazy_static! {
+    static ref KV_WATCH_DELIVERED: IntCounter = register_int_counter!(
+        "kv_watch_delivered",
+        "Number of KV changes delivered to watching apps"
+    )
+    .unwrap();
+    static ref KV_WATCH_ERRORS: IntCounter =
+        register_int_counter!("kv_watch_errors", "Number of failed KV change deliveries").unwrap();
+}
+
+pub struct KvWatchConfig {
+    pub interval: Duration,
+    pub batch_size: u32,
+}
+
+/// Periodically delivers new changes to every registered watch.
+///
+/// Delivery is at-least-once: a watch's cursor only advances after the app has handled a batch,
+/// so a failed or interrupted delivery is retried on the next run. Only one proxy replica
+/// should run this.
+pub async fn run(scheduler: Arc<Scheduler>, client: DataClient, config: KvWatchConfig) {
+    info!(
+        "kv watch started (interval {:?}, batch size {})",
+        config.interval, config.batch_size
+    );
+    loop {
+        tokio::time::sleep(config.interval).await;
+        let watches = match client.kv_watch_list(None).await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("kv watch: cannot list watches: {:?}", e);
+                continue;
+            }
+        };
+        for watch in watches {
+            deliver(&scheduler, &client, &config, watch).await;
+        }
+    }
+}
+
+async fn deliver(
+    scheduler: &Scheduler,
+    client: &DataClient,
+    config: &KvWatchConfig,
+    watch: KvWatch,
+) {
+    let mut cursor = watch.cursor;
+    loop {
+        let changes = match client
+            .worker_data_changes(
+                &watch.namespace_id,
+                &watch.prefix,
+                cursor,
+                config.batch_size,
+            )
+            .await
+        {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("kv watch {}: cannot read changes: {:?}", watch.id, e);
+                KV_WATCH_ERRORS.inc();
+                return;
+            }
+        };
+        let last_seq = match changes.last() {
+            Some(x) => x.seq,
+            None => return,
+        };
+        let n = changes.len();
+
+        if let Err(e) = scheduler
+            .dispatch_kv_change(&AppId(watch.appid.clone()), &watch.namespace_id, changes)
+            .await
+        {
+            warn!(
+                "kv watch {}: delivery to app {} failed: {:?}",
+                watch.id, watch.appid, e
+            );
+            KV_WATCH_ERRORS.inc();
+            return;
+        }
+        KV_WATCH_DELIVERED.inc_by(n as u64);
+
+        if let Err(e) = client.kv_watch_advance(&watch.id, last_seq).await {
+            warn!("kv watch {}: cannot advance cursor: {:?}", watch.id, e);
+            KV_WATCH_ERRORS.inc();
+            return;
+        }
+        cursor = last_seq;
+
+        if n < config.batch_size as usize {
+            return;
+        }
+    }
+}
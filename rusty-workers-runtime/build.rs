@@ -0,0 +1,50 @@
+//! With the `embedded-librt-snapshot` feature, creates the librt startup snapshot at build time
+//! and writes it to `$OUT_DIR/librt_snapshot.bin`, to be embedded in the runtime. An empty file
+//! means that the runtime must create the snapshot when it starts.
+
+#[cfg(feature = "embedded-librt-snapshot")]
+extern crate rusty_v8_build as rusty_v8;
+
+#[cfg(feature = "embedded-librt-snapshot")]
+#[path = "src/snapshot.rs"]
+mod snapshot;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    #[cfg(feature = "embedded-librt-snapshot")]
+    embed_librt_snapshot();
+}
+
+#[cfg(feature = "embedded-librt-snapshot")]
+fn embed_librt_snapshot() {
+    use rusty_v8 as v8;
+    use std::path::PathBuf;
+
+    const LIBRT_PATH: &str = "../librt/dist/main.js";
+
+    println!("cargo:rerun-if-changed={}", LIBRT_PATH);
+    println!("cargo:rerun-if-changed=src/snapshot.rs");
+
+    let out = PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("librt_snapshot.bin");
+
+    // A snapshot only works with the CPU architecture that created it, so cross builds leave it
+    // to the runtime.
+    let blob = if std::env::var("TARGET").unwrap() == std::env::var("HOST").unwrap() {
+        let platform = v8::new_default_platform().unwrap();
+        v8::V8::initialize_platform(platform);
+        v8::V8::initialize();
+
+        let librt = std::fs::read_to_string(LIBRT_PATH).unwrap();
+        match snapshot::create_librt_snapshot(&librt) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("cargo:warning={}. the runtime will create it on start", e);
+                vec![]
+            }
+        }
+    } else {
+        vec![]
+    };
+    std::fs::write(out, blob).unwrap();
+}
@@ -34,11 +34,41 @@ pub struct Config {
     #[structopt(long, env = "RW_EXECUTION_CONCURRENCY", default_value = "4")]
     pub execution_concurrency: usize,
 
+    /// Wall-clock ceiling for script execution, as a multiple of the CPU time budget.
+    #[structopt(long, env = "RW_MAX_WALL_TIME_FACTOR", default_value = "10")]
+    pub max_wall_time_factor: u32,
+
     /// CPU wait timeout in milliseconds.
     #[structopt(long, env = "RW_CPU_WAIT_TIMEOUT_MS", default_value = "1000")]
     pub cpu_wait_timeout_ms: u64,
 
-    /// MySQL-compatible database URL.
+    /// Max number of V8 code caches kept in memory.
+    #[structopt(long, env = "RW_CODE_CACHE_SIZE", default_value = "100")]
+    pub code_cache_size: usize,
+
+    /// Store code caches in the database so that other runtimes can use them.
+    #[structopt(long, env = "RW_SHARE_CODE_CACHE")]
+    pub share_code_cache: bool,
+
+    /// Evaluate librt in each new context instead of starting isolates from a snapshot.
+    #[structopt(long, env = "RW_DISABLE_LIBRT_SNAPSHOT")]
+    pub disable_librt_snapshot: bool,
+
+    /// Allow workers to be spawned with the V8 inspector enabled. Inspector sessions listen on
+    /// 127.0.0.1 only.
+    #[structopt(long, env = "RW_ENABLE_INSPECTOR")]
+    pub enable_inspector: bool,
+
+    /// Time in milliseconds a worker may stay paused in the debugger before it is detached.
+    /// Paused workers hold an execution token.
+    #[structopt(long, env = "RW_INSPECTOR_MAX_PAUSE_MS", default_value = "60000")]
+    pub inspector_max_pause_ms: u64,
+
+    /// Database URL: `mysql://...`, `sqlite://path/to/file.db` or `memory://`.
     #[structopt(long, env = "RW_DB_URL")]
     pub db_url: String,
+
+    /// Object store for buckets: `db` to store objects in the database, or `file:///path/to/dir`.
+    #[structopt(long, env = "RW_OBJECT_STORE", default_value = "db")]
+    pub object_store: String,
 }
@@ -0,0 +1,43 @@
+//! Per-thread CPU time measurement.
+
+use std::time::Duration;
+
+/// The CPU-time clock of a thread. Can be read from any thread.
+#[derive(Copy, Clone, Debug)]
+pub struct ThreadCpuClock(libc::clockid_t);
+
+impl ThreadCpuClock {
+    /// Returns the CPU-time clock of the calling thread.
+    pub fn current() -> Option<Self> {
+        let mut clock: libc::clockid_t = 0;
+        let ret = unsafe { libc::pthread_getcpuclockid(libc::pthread_self(), &mut clock) };
+        if ret == 0 {
+            Some(Self(clock))
+        } else {
+            None
+        }
+    }
+
+    /// Reads the CPU time consumed by the thread so far.
+    pub fn now(&self) -> Option<Duration> {
+        read_clock(self.0)
+    }
+}
+
+/// Reads the CPU time consumed by the calling thread so far, with `CLOCK_THREAD_CPUTIME_ID`.
+pub fn current_thread_cpu_time() -> Option<Duration> {
+    read_clock(libc::CLOCK_THREAD_CPUTIME_ID)
+}
+
+fn read_clock(clock: libc::clockid_t) -> Option<Duration> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    let ret = unsafe { libc::clock_gettime(clock, &mut ts) };
+    if ret == 0 {
+        Some(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+    } else {
+        None
+    }
+}
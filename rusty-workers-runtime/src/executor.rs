@@ -1,6 +1,8 @@
 use crate::buffer::*;
+use crate::cputime::{current_thread_cpu_time, ThreadCpuClock};
 use crate::engine::*;
 use crate::error::*;
+use crate::inspector::{self, Inspector, InspectorEndpoint};
 use crate::interface::*;
 use crate::io::*;
 use crate::isolate::{IsolateGeneration, IsolateGenerationBox, MemoryPoolBox, Poison};
@@ -14,74 +16,170 @@ use std::cell::{Cell, UnsafeCell};
 use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::io::Read;
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
 
 const MAX_RESPONSE_BODY_SIZE: usize = 8 * 1024 * 1024;
 
+/// Task id used for the top-level evaluation of the script.
+const INIT_TASK_ID: u64 = 0;
+
 pub struct Instance {
     state: Option<InstanceState>,
 }
 
+/// Messages from an instance to its monitor. CPU time is budgeted per task.
 #[derive(Copy, Clone, Debug)]
 pub enum TimerControl {
-    Start,
+    /// Script code of the given task starts running.
+    Start(u64),
+
+    /// Script code stops running.
     Stop,
-    Reset,
+
+    /// Execution is stopped at a breakpoint.
+    Pause,
+
+    /// Execution continues after a `Pause`.
+    Resume,
+
+    /// The given task has finished.
+    Reset(u64),
 }
 
 struct InstanceState {
     rt: tokio::runtime::Handle,
     worker_runtime: Arc<Runtime>,
-    task_rx: mpsc::Receiver<Task>,
+
+    /// New tasks, forwarded from `InstanceHandle`.
+    task_rx: crossbeam::channel::Receiver<InstanceEvent>,
+
+    /// Results of I/O operations of all tasks.
+    io_tx: crossbeam::channel::Sender<IoEvent>,
+    io_rx: crossbeam::channel::Receiver<IoEvent>,
 
     /// Unpacked files in the worker bundle.
     files: BTreeMap<String, Arc<[u8]>>,
 
     script: Arc<[u8]>,
 
+    /// V8 code cache for `script`, if available.
+    code_cache: Option<Arc<[u8]>>,
+
     timer_tx: tokio::sync::mpsc::UnboundedSender<TimerControl>,
     conf: Arc<WorkerConfiguration>,
     handle: WorkerHandle,
-    io_waiter: Option<IoWaiter>,
 
-    done: bool,
+    /// Tasks in progress, keyed by task id.
+    tasks: BTreeMap<u64, TaskState>,
 
-    fetch_response_channel: Option<tokio::sync::oneshot::Sender<ExecutionResult<ResponseObject>>>,
+    /// The task whose code is running. Microtasks run before the running slice ends, so their CPU
+    /// time, I/O and memory are charged to the task that queued them.
+    current_task: Option<u64>,
+
+    next_task_id: u64,
+
+    /// Thread CPU time when the timer was last started.
+    cpu_slice_start: Option<Duration>,
+
+    /// Inspector endpoint, until the inspector is attached to the context.
+    inspector_endpoint: Option<InspectorEndpoint>,
+
+    inspector: Option<Rc<Inspector>>,
 
     appid: String,
 }
 
+/// State of a task in progress. An instance runs up to `max_concurrent_requests` tasks at a time,
+/// interleaved at I/O boundaries.
+struct TaskState {
+    io_waiter: IoWaiter,
+
+    done: bool,
+
+    fetch_response_channel: Option<tokio::sync::oneshot::Sender<ExecutionResult<FetchResult>>>,
+
+    /// Resources used by this task.
+    usage: ResourceUsage,
+
+    /// Held until the task finishes.
+    _concurrency_permit: OwnedSemaphorePermit,
+}
+
+enum InstanceEvent {
+    Task(Task, OwnedSemaphorePermit),
+    Io(IoEvent),
+
+    /// The `InstanceHandle` is dropped.
+    Closed,
+}
+
 pub struct InstanceHandle {
     isolate_handle: v8::IsolateHandle,
     task_tx: mpsc::Sender<Task>,
     termination_reason: TerminationReasonBox,
     creation_generation: IsolateGeneration,
     current_generation: IsolateGenerationBox,
+    inspector_url: Option<String>,
 }
 
 pub struct InstanceTimeControl {
+    /// CPU time budget.
     pub budget: Duration,
+
+    /// CPU-time clock of the thread that runs the instance.
+    pub cpu_clock: Option<ThreadCpuClock>,
+
     pub timer_rx: mpsc::UnboundedReceiver<TimerControl>,
 }
 
 enum Task {
     Fetch(
         RequestObject,
-        tokio::sync::oneshot::Sender<ExecutionResult<ResponseObject>>,
+        tokio::sync::oneshot::Sender<ExecutionResult<FetchResult>>,
+        IoScopeConsumer,
+    ),
+    KvChange(
+        KvChangeBatch,
+        tokio::sync::oneshot::Sender<ExecutionResult<FetchResult>>,
         IoScopeConsumer,
     ),
 }
 
 impl Task {
-    fn make_event(&self) -> ServiceEvent {
+    fn make_event(&self, task_id: u64) -> ServiceEvent {
         match self {
             Task::Fetch(ref req, _, _) => ServiceEvent::Fetch(FetchEvent {
+                task_id,
                 request: req.clone(),
             }),
+            Task::KvChange(ref batch, _, _) => ServiceEvent::KvChange(KvChangeEvent {
+                task_id,
+                namespace: batch.namespace.clone(),
+                changes: batch.changes.clone(),
+            }),
+        }
+    }
+}
+
+/// Moves tasks from the async side into the instance thread, admitting at most as many
+/// concurrent tasks as `concurrency` has permits.
+async fn forward_tasks(
+    mut task_rx: mpsc::Receiver<Task>,
+    event_tx: crossbeam::channel::Sender<InstanceEvent>,
+    concurrency: Arc<Semaphore>,
+) {
+    while let Some(task) = task_rx.recv().await {
+        // The semaphore is never closed.
+        let permit = concurrency.clone().acquire_owned().await.unwrap();
+        if event_tx.send(InstanceEvent::Task(task, permit)).is_err() {
+            // Instance exited.
+            return;
         }
     }
+    drop(event_tx.send(InstanceEvent::Closed));
 }
 
 impl InstanceHandle {
@@ -105,19 +203,50 @@ impl InstanceHandle {
         }
     }
 
+    pub fn inspector_url(&self) -> Option<&str> {
+        self.inspector_url.as_deref()
+    }
+
     pub async fn terminate_for_time_limit(&self) {
         tokio::task::block_in_place(|| {
             self.do_remote_termination(Some(TerminationReason::TimeLimit));
         });
     }
 
-    pub async fn fetch(&self, req: RequestObject) -> ExecutionResult<ResponseObject> {
+    pub async fn fetch(&self, req: RequestObject) -> ExecutionResult<FetchResult> {
+        self.run_task(|result_tx, io_scope_consumer| Task::Fetch(req, result_tx, io_scope_consumer))
+            .await
+    }
+
+    /// Delivers changes to a watched KV namespace. Fails if the script's handler fails.
+    pub async fn kv_change(&self, batch: KvChangeBatch) -> ExecutionResult<ResourceUsage> {
+        let res = self
+            .run_task(|result_tx, io_scope_consumer| {
+                Task::KvChange(batch, result_tx, io_scope_consumer)
+            })
+            .await?;
+        if res.response.status == 200 {
+            Ok(res.usage)
+        } else {
+            Err(ExecutionError::ScriptThrowsException(
+                "kv change handler failed".into(),
+            ))
+        }
+    }
+
+    async fn run_task(
+        &self,
+        make_task: impl FnOnce(
+            tokio::sync::oneshot::Sender<ExecutionResult<FetchResult>>,
+            IoScopeConsumer,
+        ) -> Task,
+    ) -> ExecutionResult<FetchResult> {
         let (result_tx, result_rx) = tokio::sync::oneshot::channel();
         let (_io_scope, io_scope_consumer) = IoScope::new();
 
         // Send fails if the instance has terminated
         self.task_tx
-            .send(Task::Fetch(req, result_tx, io_scope_consumer))
+            .send(make_task(result_tx, io_scope_consumer))
             .await
             .map_err(|_| ExecutionError::NoSuchWorker)?;
 
@@ -157,6 +286,7 @@ impl Instance {
         worker_handle: WorkerHandle,
         appid: String,
         bundle: Vec<u8>,
+        code_cache: Option<Arc<[u8]>>,
         conf: &WorkerConfiguration,
     ) -> GenericResult<(Self, InstanceHandle, InstanceTimeControl)> {
         // Unpack the bundle.
@@ -211,6 +341,12 @@ impl Instance {
         // Allocate a channel of size 1. We don't want to put back pressure here.
         // The (async) sending side would block.
         let (task_tx, task_rx) = mpsc::channel(1);
+        let (event_tx, event_rx) = crossbeam::channel::unbounded();
+        let concurrency = Arc::new(Semaphore::new(
+            conf.executor.max_concurrent_requests.max(1) as usize
+        ));
+        rt.spawn(forward_tasks(task_rx, event_tx, concurrency));
+        let (io_tx, io_rx) = crossbeam::channel::unbounded();
 
         // TODO: unbounded ok here?
         let (timer_tx, timer_rx) = mpsc::unbounded_channel();
@@ -218,6 +354,19 @@ impl Instance {
         let time_control = InstanceTimeControl {
             timer_rx,
             budget: Duration::from_millis(conf.executor.max_time_ms as u64),
+            cpu_clock: ThreadCpuClock::current(),
+        };
+
+        let (inspector_endpoint, inspector_url) = if conf.inspector {
+            let isolate_handle = isolate.thread_safe_handle();
+            let (endpoint, url) = inspector::listen(&rt, &worker_handle, move || {
+                // Interrupt the instance in case it is running script code. An idle instance picks up
+                // the message itself.
+                isolate_handle.request_interrupt(dispatch_inspector_messages, std::ptr::null_mut());
+            })?;
+            (Some(endpoint), Some(url))
+        } else {
+            (None, None)
         };
 
         let isolate_handle = isolate.thread_safe_handle();
@@ -229,20 +378,27 @@ impl Instance {
             termination_reason,
             creation_generation: *generation.0.lock().unwrap(),
             current_generation: generation.clone(),
+            inspector_url,
         };
         let instance = Instance {
             state: Some(InstanceState {
                 rt,
                 worker_runtime,
-                task_rx,
+                task_rx: event_rx,
+                io_tx,
+                io_rx,
                 files,
                 script,
+                code_cache,
                 timer_tx,
                 conf: Arc::new(conf.clone()),
                 handle: worker_handle,
-                io_waiter: None,
-                done: false,
-                fetch_response_channel: None,
+                tasks: BTreeMap::new(),
+                current_task: None,
+                next_task_id: INIT_TASK_ID + 1,
+                cpu_slice_start: None,
+                inspector_endpoint,
+                inspector: None,
                 appid,
             }),
         };
@@ -259,8 +415,8 @@ impl Instance {
         }
 
         if let Some(state) = InstanceState::try_get(isolate) {
-            // Drop `io_waiter` and any `Global` references it holds.
-            state.io_waiter = None;
+            // Drop I/O waiters and any `Global` references they hold.
+            state.tasks.clear();
 
             // `protected_js` expects `InstanceState` to be present
             // FIXME: If compilation failed there may be some references left on the heap.
@@ -275,15 +431,59 @@ impl Instance {
         Ok(())
     }
 
+    /// Compiles the script, consuming `code_cache` if provided.
+    ///
+    /// Returns a new code cache if `code_cache` is absent or rejected by V8.
     fn compile<'s>(
         scope: &mut v8::HandleScope<'s>,
         script: &str,
-    ) -> GenericResult<v8::Local<'s, v8::Script>> {
+        code_cache: Option<&[u8]>,
+    ) -> GenericResult<(v8::Local<'s, v8::Script>, Option<Vec<u8>>)> {
+        use v8::script_compiler::{CompileOptions, NoCacheReason, Source};
+
         let script = v8::String::new(scope, script)
             .ok_or_else(|| GenericError::ScriptInitException("script compilation failed".into()))?;
-        let script = v8::Script::compile(scope, script, None)
+        let (unbound_script, cache_rejected) = match code_cache {
+            Some(code_cache) => {
+                let mut source =
+                    Source::new_with_cached_data(script, None, v8::CachedData::new(code_cache));
+                let unbound_script = v8::script_compiler::compile_unbound_script(
+                    scope,
+                    &mut source,
+                    CompileOptions::ConsumeCodeCache,
+                    NoCacheReason::NoReason,
+                );
+                let rejected = source
+                    .get_cached_data()
+                    .map(|x| x.rejected())
+                    .unwrap_or(true);
+                if rejected {
+                    debug!("code cache rejected");
+                }
+                (unbound_script, rejected)
+            }
+            None => {
+                let mut source = Source::new(script, None);
+                let unbound_script = v8::script_compiler::compile_unbound_script(
+                    scope,
+                    &mut source,
+                    CompileOptions::NoCompileOptions,
+                    NoCacheReason::NoReason,
+                );
+                (unbound_script, true)
+            }
+        };
+        let unbound_script = unbound_script
             .ok_or_else(|| GenericError::ScriptInitException("script compilation failed".into()))?;
-        Ok(script)
+        let new_code_cache = if cache_rejected {
+            unbound_script.create_code_cache().map(|x| x.to_vec())
+        } else {
+            None
+        };
+        Ok((
+            unbound_script.bind_to_current_context(scope),
+            new_code_cache,
+        ))
     }
 
     pub fn run(
@@ -291,7 +491,7 @@ impl Instance {
         context_scope: &mut v8::ContextScope<'_, v8::HandleScope<'_>>,
         ready_callback: impl FnOnce(),
     ) -> GenericResult<()> {
-        let state = self.state.take().unwrap();
+        let mut state = self.state.take().unwrap();
         let worker_runtime = state.worker_runtime.clone();
 
         let worker_handle = state.handle.clone();
@@ -306,11 +506,24 @@ impl Instance {
             let scope: &mut v8::HandleScope<'_> = try_catch.as_mut();
             state.init_global_env(scope)?;
 
+            if let Some(endpoint) = state.inspector_endpoint.take() {
+                state.inspector = Some(Rc::new(Inspector::new(
+                    scope,
+                    endpoint,
+                    state.timer_tx.clone(),
+                    worker_runtime.inspector_max_pause(),
+                )));
+            }
+
             // TODO: Compiler bombs?
             let script = std::str::from_utf8(&state.script).map_err(|_| {
                 GenericError::ScriptInitException("cannot decode script as utf-8 text".into())
             })?;
-            let script = Self::compile(scope, script)?;
+            let (script, new_code_cache) =
+                Self::compile(scope, script, state.code_cache.as_deref())?;
+            if let Some(new_code_cache) = new_code_cache {
+                worker_runtime.store_code_cache(&state.rt, &state.conf.bundle_id, new_code_cache);
+            }
 
             // Notify that we are ready so that timing etc. can start
             ready_callback();
@@ -328,146 +541,154 @@ impl Instance {
         }
         info!("worker instance {} ready", worker_handle.id);
 
-        // Wait for tasks.
+        {
+            let state = InstanceState::get(context_scope);
+            state.stop_timer();
+            state.reset_timer(INIT_TASK_ID);
+        }
+
+        // Wait for tasks and I/O results.
         loop {
             update_stats(&worker_runtime, &worker_handle, context_scope);
 
+            // We are not using CPU now so drop CPU permit
+            drop(permit);
+            let event = InstanceState::get(context_scope).wait_for_event();
+            permit = worker_runtime.acquire_execution_token()?;
+
             let scope = &mut v8::HandleScope::new(context_scope);
             let try_catch = &mut v8::TryCatch::new(scope);
             let scope: &mut v8::HandleScope<'_> = try_catch.as_mut();
-            let state = InstanceState::get(scope);
-            state.stop_timer();
-            state.reset_timer();
-
-            // Cleanup state
-            state.io_waiter = None; // drop it
-            state.done = false;
 
-            drop(permit);
-
-            let task = match state.task_rx.blocking_recv() {
-                Some(x) => x,
-                None => {
-                    // channel closed
-                    break;
+            let task_id = match event {
+                InstanceEvent::Closed => break,
+                InstanceEvent::Task(task, concurrency_permit) => {
+                    let (task_id, event) =
+                        InstanceState::get(scope).begin_task(task, concurrency_permit);
+                    InstanceState::enter_task(scope, task_id);
+
+                    let global = scope.get_current_context().global(scope);
+                    let callback_key = make_string(scope, "_dispatchEvent")?;
+                    let callback = global.get(scope, callback_key.into()).check()?;
+                    let callback = v8::Local::<'_, v8::Function>::try_from(callback)
+                        .map_err(|_| GenericError::Other("bad _dispatchEvent".into()))?;
+                    let recv = v8::undefined(scope);
+                    let event_js = native_to_js(scope, &event)?;
+
+                    protected_js(scope, |scope| {
+                        callback.call(scope, recv.into(), &[event_js]);
+                    })?;
+                    task_id
                 }
-            };
-            permit = worker_runtime.acquire_execution_token()?;
-            let event = task.make_event();
-            let io_scope = state.populate_with_task(task)?;
-            state.start_timer();
-
-            // Start I/O processor (per-request).
-            //
-            // An `IoProcessor` receives the task's `IoScopeConsumer` as its argument, and stops when the
-            // corresponding `IoScope` is dropped.
-            let (io_waiter, io_processor) =
-                IoWaiter::new(state.conf.clone(), state.worker_runtime.clone());
-            state.rt.spawn(io_processor.run(io_scope));
-            state.io_waiter = Some(io_waiter);
-
-            let global = scope.get_current_context().global(scope);
-            let callback_key = make_string(scope, "_dispatchEvent")?;
-            let callback = global.get(scope, callback_key.into()).check()?;
-            let callback = v8::Local::<'_, v8::Function>::try_from(callback)
-                .map_err(|_| GenericError::Other("bad _dispatchEvent".into()))?;
-            let recv = v8::undefined(scope);
-            let event_js = native_to_js(scope, &event)?;
-
-            protected_js(scope, |scope| {
-                callback.call(scope, recv.into(), &[event_js]);
-            })?;
-
-            // Drive to completion.
-            loop {
-                match try_catch.check_on_task() {
-                    Ok(()) => {}
-                    Err(e) => {
-                        if e.terminates_worker() {
-                            InstanceState::try_send_fetch_response(try_catch, Err(e.clone()));
-                            return Err(GenericError::Execution(e));
-                        } else {
-                            debug!("non-critical exception: {:?}", e);
-                            try_catch.reset();
-                            InstanceState::try_send_fetch_response(try_catch, Err(e));
-                            break;
+                InstanceEvent::Io(event) => {
+                    let task_id = event.task_id();
+
+                    // Take the task out (lifetime conflict with `scope`)
+                    let mut task = match InstanceState::get(scope).tasks.remove(&task_id) {
+                        Some(x) => x,
+                        None => {
+                            // Late result of a finished task.
+                            continue;
                         }
-                    }
-                }
+                    };
+                    let completion = task.io_waiter.handle_event(scope, event);
+                    InstanceState::get(scope).tasks.insert(task_id, task);
 
-                let scope = &mut v8::HandleScope::new(try_catch);
-                let state = InstanceState::get(scope);
-
-                if state.done {
-                    break;
+                    let (callback, data, buffers) = match completion {
+                        Some(IoCompletion::Ready(callback, data, buffers)) => {
+                            (callback, data, buffers)
+                        }
+                        Some(IoCompletion::ScopeKilled) => {
+                            // The caller went away. Drop the task; its pending callbacks are never
+                            // called.
+                            debug!("task {} abandoned", task_id);
+                            InstanceState::finish_task(
+                                scope,
+                                task_id,
+                                Err(ExecutionError::IoTimeout),
+                            );
+                            continue;
+                        }
+                        None => continue,
+                    };
+
+                    InstanceState::enter_task(scope, task_id);
+
+                    let callback = v8::Local::<'_, v8::Function>::new(scope, callback);
+                    let recv = v8::undefined(scope);
+
+                    // Don't deserialize onto V8 heap here to ensure OOM safety
+                    let data = data.as_bytes();
+                    acquire_arraybuffer_precheck(scope, data.len())?;
+                    let json_data = v8::ArrayBuffer::new(scope, data.len());
+                    let json_data_backing = json_data.get_backing_store();
+                    json_data_backing
+                        .iter()
+                        .enumerate()
+                        .for_each(|(i, x)| x.set(data[i]));
+
+                    let local_buffers: Vec<v8::Local::<'_, v8::Value>> = buffers.into_iter().map(|x| x.unwrap_on_v8_thread())
+                        .map(|x| v8::Local::new(scope, x.expect("we are on v8 thread but unwrap_on_v8_thread failed to upgrade reference")).into())
+                        .collect();
+                    let target_buffers = v8::Array::new_with_elements(scope, &local_buffers);
+
+                    protected_js(scope, |scope| {
+                        callback.call(
+                            scope,
+                            recv.into(),
+                            &[json_data.into(), target_buffers.into()],
+                        );
+                    })?;
+                    task_id
                 }
+            };
 
-                // Waiting for I/O now. Stop the timer.
-                state.stop_timer();
-
-                // A nice point to update statistics!
-                update_stats(&worker_runtime, &worker_handle, scope);
-
-                // We are not using CPU now so drop CPU permit
-                drop(permit);
-
-                // Take the IO waiter (lifetime conflict with `scope`)
-                let mut io_waiter = InstanceState::get(scope).io_waiter.take().unwrap();
-                let wait_result = io_waiter.wait(scope);
-                InstanceState::get(scope).io_waiter = Some(io_waiter);
+            let result = try_catch.check_on_task();
+            InstanceState::sample_memory_usage(try_catch, task_id);
+            InstanceState::get(try_catch).leave_task();
 
-                let (callback, data, buffers) = match wait_result {
-                    Some(x) => x,
-                    None => {
-                        // Doesn't necessarily need to terminate the instance but would need a lot of graceful
-                        // handling on both the proxy side and the script side.
-                        //
-                        // So just terminate it now.
-                        InstanceState::try_send_fetch_response(
-                            scope,
-                            Err(ExecutionError::IoTimeout),
-                        );
-                        return Err(GenericError::Execution(ExecutionError::IoTimeout));
+            match result {
+                Ok(()) => {}
+                Err(e) => {
+                    let text = match &e {
+                        ExecutionError::ScriptThrowsException(message) => {
+                            format!("uncaught exception: {}", message)
+                        }
+                        _ => format!("worker terminated: {}", e),
+                    };
+                    let state = InstanceState::get(try_catch);
+                    state
+                        .worker_runtime
+                        .write_log(state.appid.clone(), LogLevel::Error, text);
+
+                    if e.terminates_worker() {
+                        InstanceState::finish_task(try_catch, task_id, Err(e.clone()));
+                        return Err(GenericError::Execution(e));
+                    } else {
+                        debug!("non-critical exception: {:?}", e);
+                        try_catch.reset();
+                        InstanceState::finish_task(try_catch, task_id, Err(e));
                     }
-                };
-
-                permit = worker_runtime.acquire_execution_token()?;
-                InstanceState::get(scope).start_timer();
-
-                let callback = v8::Local::<'_, v8::Function>::new(scope, callback);
-
-                // Don't deserialize onto V8 heap here to ensure OOM safety
-                let data = data.as_bytes();
-                acquire_arraybuffer_precheck(scope, data.len())?;
-                let json_data = v8::ArrayBuffer::new(scope, data.len());
-                let json_data_backing = json_data.get_backing_store();
-                json_data_backing
-                    .iter()
-                    .enumerate()
-                    .for_each(|(i, x)| x.set(data[i]));
-
-                let local_buffers: Vec<v8::Local::<'_, v8::Value>> = buffers.into_iter().map(|x| x.unwrap_on_v8_thread())
-                    .map(|x| v8::Local::new(scope, x.expect("we are on v8 thread but unwrap_on_v8_thread failed to upgrade reference")).into())
-                    .collect();
-                let target_buffers = v8::Array::new_with_elements(scope, &local_buffers);
-
-                protected_js(scope, |scope| {
-                    callback.call(
-                        scope,
-                        recv.into(),
-                        &[json_data.into(), target_buffers.into()],
-                    );
-                })?;
+                }
             }
 
-            // Script marked itself as done but we haven't got any response.
-            InstanceState::try_send_fetch_response(
-                try_catch,
-                Ok(ResponseObject {
-                    status: 500,
-                    ..Default::default()
-                }),
-            );
+            // Script marked these tasks as done but we haven't got any response.
+            let done_tasks: Vec<u64> = InstanceState::get(try_catch)
+                .tasks
+                .iter()
+                .filter(|(_, x)| x.done)
+                .map(|(id, _)| *id)
+                .collect();
+            for id in done_tasks {
+                InstanceState::finish_task(
+                    try_catch,
+                    id,
+                    Ok(ResponseObject {
+                        status: 500,
+                        ..Default::default()
+                    }),
+                );
+            }
         }
         Ok(())
     }
@@ -484,22 +705,171 @@ impl InstanceState {
             .and_then(|x| x.as_mut())
     }
 
+    /// Blocks until a new task or an I/O result arrives, serving the inspector in the meantime.
+    fn wait_for_event(&mut self) -> InstanceEvent {
+        let inspector = self.inspector.clone();
+        let mut inspector_rx = inspector
+            .as_ref()
+            .map(|x| x.inbound().clone())
+            .unwrap_or_else(crossbeam::channel::never);
+        loop {
+            let event = crossbeam::channel::select! {
+                recv(self.task_rx) -> event => Ok(event.unwrap_or(InstanceEvent::Closed)),
+                recv(self.io_rx) -> event => Ok(event.map(InstanceEvent::Io).unwrap_or(InstanceEvent::Closed)),
+                recv(inspector_rx) -> message => Err(message),
+            };
+            match event {
+                Ok(event) => return event,
+                Err(message) => match (message, &inspector) {
+                    (Ok(message), Some(inspector)) => inspector.dispatch(&message),
+                    // Debugger endpoint closed.
+                    _ => inspector_rx = crossbeam::channel::never(),
+                },
+            }
+        }
+    }
+
+    /// Registers a new task and starts its I/O processor. Returns the task id and the event to
+    /// dispatch to the script.
+    fn begin_task(
+        &mut self,
+        task: Task,
+        concurrency_permit: OwnedSemaphorePermit,
+    ) -> (u64, ServiceEvent) {
+        let task_id = self.next_task_id;
+        self.next_task_id += 1;
+        let event = task.make_event(task_id);
+
+        let (fetch_response_channel, io_scope) = match task {
+            Task::Fetch(_, res, io_scope) => (res, io_scope),
+            Task::KvChange(_, res, io_scope) => (res, io_scope),
+        };
+
+        // Start I/O processor (per-task).
+        //
+        // An `IoProcessor` receives the task's `IoScopeConsumer` as its argument, and stops when the
+        // corresponding `IoScope` is dropped.
+        let (io_waiter, io_processor) = IoWaiter::new(
+            self.conf.clone(),
+            self.worker_runtime.clone(),
+            self.appid.clone(),
+            task_id,
+            self.io_tx.clone(),
+        );
+        self.rt.spawn(io_processor.run(io_scope));
+
+        self.tasks.insert(
+            task_id,
+            TaskState {
+                io_waiter,
+                done: false,
+                fetch_response_channel: Some(fetch_response_channel),
+                usage: ResourceUsage::default(),
+                _concurrency_permit: concurrency_permit,
+            },
+        );
+        (task_id, event)
+    }
+
+    /// Sends the result of a task if it hasn't responded yet, and drops the task.
+    fn finish_task(isolate: &mut v8::Isolate, task_id: u64, res: ExecutionResult<ResponseObject>) {
+        InstanceState::try_send_fetch_response(isolate, task_id, res);
+        let state = InstanceState::get(isolate);
+        state.tasks.remove(&task_id);
+        state.reset_timer(task_id);
+    }
+
+    /// Starts running code on behalf of a task.
+    fn enter_task(isolate: &mut v8::Isolate, task_id: u64) {
+        // ArrayBuffer peaks are tracked per running slice and charged to the task of the slice.
+        reset_arraybuffer_peak(isolate);
+        let state = InstanceState::get(isolate);
+        state.current_task = Some(task_id);
+        state.start_timer();
+    }
+
+    fn leave_task(&mut self) {
+        self.stop_timer();
+        self.current_task = None;
+    }
+
     fn io_waiter(&mut self) -> JsResult<&mut IoWaiter> {
-        self.io_waiter.as_mut().ok_or_else(|| {
-            JsError::new(JsErrorKind::Error, Some("io service not available".into()))
-        })
+        let tasks = &mut self.tasks;
+        self.current_task
+            .and_then(|id| tasks.get_mut(&id))
+            .map(|x| &mut x.io_waiter)
+            .ok_or_else(|| {
+                JsError::new(JsErrorKind::Error, Some("io service not available".into()))
+            })
     }
 
-    fn start_timer(&self) {
-        drop(self.timer_tx.send(TimerControl::Start));
+    fn start_timer(&mut self) {
+        self.cpu_slice_start = current_thread_cpu_time();
+        let task_id = self.current_task.unwrap_or(INIT_TASK_ID);
+        drop(self.timer_tx.send(TimerControl::Start(task_id)));
     }
 
-    fn stop_timer(&self) {
+    fn stop_timer(&mut self) {
+        let cpu_time = self.running_cpu_time();
+        self.cpu_slice_start = None;
+        let tasks = &mut self.tasks;
+        if let Some(task) = self.current_task.and_then(|id| tasks.get_mut(&id)) {
+            task.usage.cpu_time_us += cpu_time.as_micros() as u64;
+        }
         drop(self.timer_tx.send(TimerControl::Stop));
     }
 
-    fn reset_timer(&self) {
-        drop(self.timer_tx.send(TimerControl::Reset));
+    /// CPU time since the timer was last started.
+    fn running_cpu_time(&self) -> Duration {
+        match (self.cpu_slice_start, current_thread_cpu_time()) {
+            (Some(start), Some(now)) => now.checked_sub(start).unwrap_or_default(),
+            _ => Duration::from_millis(0),
+        }
+    }
+
+    /// Builds the resource usage report of a task so far.
+    fn task_usage(&self, task_id: u64) -> ResourceUsage {
+        let task = match self.tasks.get(&task_id) {
+            Some(x) => x,
+            None => return ResourceUsage::default(),
+        };
+        let mut usage = task.usage.clone();
+        if self.current_task == Some(task_id) {
+            usage.cpu_time_us += self.running_cpu_time().as_micros() as u64;
+        }
+        let counters = task.io_waiter.counters();
+        usage.io_calls = counters.io_calls;
+        usage.kv_subrequests = counters.kv_calls;
+        usage.fetch_subrequests = counters.fetch_calls;
+        usage.bucket_subrequests = counters.bucket_calls;
+        usage.sql_subrequests = counters.sql_calls;
+        usage
+    }
+
+    /// Records peak heap usage of a task, and its peak ArrayBuffer usage if it is running.
+    fn sample_memory_usage(isolate: &mut v8::Isolate, task_id: u64) {
+        let mut stats = v8::HeapStatistics::default();
+        isolate.get_heap_statistics(&mut stats);
+        let array_buffer_bytes = arraybuffer_peak_used_bytes(isolate) as u64;
+        let state = match InstanceState::try_get(isolate) {
+            Some(x) => x,
+            None => return,
+        };
+        let running = state.current_task == Some(task_id);
+        if let Some(task) = state.tasks.get_mut(&task_id) {
+            task.usage.peak_heap_bytes = task
+                .usage
+                .peak_heap_bytes
+                .max(stats.used_heap_size() as u64);
+            if running {
+                task.usage.peak_array_buffer_bytes =
+                    task.usage.peak_array_buffer_bytes.max(array_buffer_bytes);
+            }
+        }
+    }
+
+    fn reset_timer(&self, task_id: u64) {
+        drop(self.timer_tx.send(TimerControl::Reset(task_id)));
     }
 
     /// Builds the global object.
@@ -523,21 +893,22 @@ impl InstanceState {
         Ok(())
     }
 
-    fn populate_with_task(&mut self, task: Task) -> GenericResult<IoScopeConsumer> {
-        match task {
-            Task::Fetch(_, res, io_scope) => {
-                self.fetch_response_channel = Some(res);
-                Ok(io_scope)
-            }
-        }
-    }
-
     fn try_send_fetch_response(
         isolate: &mut v8::Isolate,
+        task_id: u64,
         res: ExecutionResult<ResponseObject>,
     ) -> bool {
-        if let Some(ch) = InstanceState::get(isolate).fetch_response_channel.take() {
-            ch.send(res).is_ok()
+        InstanceState::sample_memory_usage(isolate, task_id);
+        let state = InstanceState::get(isolate);
+        let ch = state
+            .tasks
+            .get_mut(&task_id)
+            .and_then(|x| x.fetch_response_channel.take());
+        if let Some(ch) = ch {
+            let usage = state.task_usage(task_id);
+            debug!("request finished with resource usage {:?}", usage);
+            ch.send(res.map(|response| FetchResult { response, usage }))
+                .is_ok()
         } else {
             false
         }
@@ -683,16 +1054,23 @@ fn call_service_callback(
         match call {
             ServiceCall::Sync(call) => {
                 match call {
-                    SyncCall::Log(s) => {
-                        debug!("log: {}", s);
+                    SyncCall::Log { level, text } => {
+                        debug!("log ({:?}): {}", level, text);
                         let state = InstanceState::get(scope);
-                        state.worker_runtime.write_log(state.appid.clone(), s);
+                        state
+                            .worker_runtime
+                            .write_log(state.appid.clone(), level, text);
                     }
-                    SyncCall::Done => {
+                    SyncCall::Done(task_id) => {
                         let state = InstanceState::get(scope);
-                        state.done = true;
+                        if let Some(task) = state.tasks.get_mut(&task_id) {
+                            task.done = true;
+                        }
                     }
-                    SyncCall::SendFetchResponse(mut res) => {
+                    SyncCall::SendFetchResponse {
+                        task_id,
+                        response: mut res,
+                    } => {
                         let body = local_buffers
                             .get(0)
                             .ok_or_else(|| {
@@ -709,7 +1087,7 @@ fn call_service_callback(
                                 )
                             })?;
                         res.body = HttpBody::Binary(body);
-                        InstanceState::try_send_fetch_response(scope, Ok(res));
+                        InstanceState::try_send_fetch_response(scope, task_id, Ok(res));
                     }
                     SyncCall::GetRandomValues => {
                         let output: &[Cell<u8>] = local_buffers.get(0).ok_or_else(|| {
@@ -773,6 +1151,13 @@ fn queue_microtask_callback(
     })
 }
 
+/// Interrupt callback requested by the inspector endpoint when the debugger sends a message.
+extern "C" fn dispatch_inspector_messages(isolate: &mut v8::Isolate, _data: *mut std::ffi::c_void) {
+    if let Some(inspector) = InstanceState::try_get(isolate).and_then(|x| x.inspector.clone()) {
+        inspector.dispatch_pending();
+    }
+}
+
 fn write_utf8_to_arraybuffer(
     scope: &mut v8::HandleScope<'_>,
     src: v8::Local<'_, v8::String>,
@@ -0,0 +1,350 @@
+//! V8 inspector support, exposed to Chrome DevTools over a local WebSocket.
+
+use crate::executor::TimerControl;
+use futures::{SinkExt, StreamExt};
+use rusty_v8 as v8;
+use rusty_workers::types::*;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{ErrorResponse, Request, Response},
+    http::StatusCode,
+    Message,
+};
+use v8::inspector::{
+    ChannelBase, ChannelImpl, StringBuffer, StringView, V8Inspector, V8InspectorClientBase,
+    V8InspectorClientImpl, V8InspectorSession,
+};
+
+const CONTEXT_GROUP_ID: i32 = 1;
+
+/// Sent on behalf of a debugger that disconnected, so that a paused worker continues running.
+const DISCONNECT_MESSAGE: &str = r#"{"id":-1,"method":"Debugger.disable"}"#;
+
+/// The thread-safe side of an inspector session, created before the isolate starts running.
+pub struct InspectorEndpoint {
+    inbound_rx: crossbeam::channel::Receiver<String>,
+    outbound_tx: mpsc::UnboundedSender<String>,
+}
+
+/// An inspector attached to the context of a worker instance.
+///
+/// Lives on the isolate thread.
+pub struct Inspector {
+    // Field order matters: the session must be dropped before the inspector, and both before
+    // the objects they point to.
+    _v8_session: v8::UniqueRef<V8InspectorSession>,
+    _v8_inspector: v8::UniqueRef<V8Inspector>,
+    _channel: Box<InspectorChannel>,
+    client: Box<InspectorClient>,
+
+    /// Set while a message is being dispatched from an interrupt.
+    dispatching: Cell<bool>,
+}
+
+struct InspectorClient {
+    base: V8InspectorClientBase,
+
+    /// The session to dispatch messages to while paused.
+    session: *mut V8InspectorSession,
+
+    inbound_rx: crossbeam::channel::Receiver<String>,
+    timer_tx: mpsc::UnboundedSender<TimerControl>,
+
+    /// How long the worker may stay paused before the debugger is detached. The instance holds
+    /// an execution token while paused.
+    max_pause: Duration,
+
+    /// Kept outside `self` since V8 calls `quit_message_loop_on_pause` from within
+    /// `run_message_loop_on_pause`.
+    paused: Rc<Cell<bool>>,
+}
+
+struct InspectorChannel {
+    base: ChannelBase,
+    outbound_tx: mpsc::UnboundedSender<String>,
+}
+
+/// Starts listening for a debugger on a random local port.
+///
+/// `on_message` is called from the I/O thread each time the debugger sends a message. Returns the
+/// endpoint and the WebSocket URL to connect to. The URL holds a random token, without which
+/// connections are refused.
+pub fn listen(
+    rt: &tokio::runtime::Handle,
+    worker_handle: &WorkerHandle,
+    on_message: impl Fn() + Send + Sync + 'static,
+) -> GenericResult<(InspectorEndpoint, String)> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|x| x.set_nonblocking(true).map(|_| x))
+        .map_err(|e| GenericError::Other(format!("cannot bind inspector: {:?}", e)))?;
+    let addr = listener
+        .local_addr()
+        .map_err(|e| GenericError::Other(format!("cannot bind inspector: {:?}", e)))?;
+    let path = format!(
+        "/{}/{}",
+        worker_handle.id,
+        rusty_workers::util::rand_hex(16)
+    );
+    let url = format!("ws://{}{}", addr, path);
+
+    let (inbound_tx, inbound_rx) = crossbeam::channel::unbounded();
+    let (outbound_tx, outbound_rx) = mpsc::unbounded_channel();
+    rt.spawn(serve(
+        listener,
+        path,
+        inbound_tx,
+        outbound_rx,
+        Arc::new(on_message),
+    ));
+
+    info!(
+        "inspector for worker {} listening on {}",
+        worker_handle.id, addr
+    );
+    Ok((
+        InspectorEndpoint {
+            inbound_rx,
+            outbound_tx,
+        },
+        url,
+    ))
+}
+
+/// Accepts debugger connections until the inspector is dropped.
+async fn serve(
+    listener: std::net::TcpListener,
+    path: String,
+    inbound_tx: crossbeam::channel::Sender<String>,
+    mut outbound_rx: mpsc::UnboundedReceiver<String>,
+    on_message: Arc<dyn Fn() + Send + Sync>,
+) {
+    let listener = match tokio::net::TcpListener::from_std(listener) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("inspector: cannot listen: {:?}", e);
+            return;
+        }
+    };
+
+    loop {
+        let stream = loop {
+            tokio::select! {
+                conn = listener.accept() => match conn {
+                    Ok((stream, addr)) => {
+                        info!("inspector: debugger connected from {}", addr);
+                        break stream;
+                    }
+                    Err(e) => {
+                        debug!("inspector: accept error: {:?}", e);
+                    }
+                },
+                msg = outbound_rx.recv() => match msg {
+                    // No debugger to deliver to.
+                    Some(_) => {}
+                    // Inspector dropped.
+                    None => return,
+                },
+            }
+        };
+
+        let check_path = |req: &Request, res: Response| -> Result<Response, ErrorResponse> {
+            if ring::constant_time::verify_slices_are_equal(
+                req.uri().path().as_bytes(),
+                path.as_bytes(),
+            )
+            .is_ok()
+            {
+                Ok(res)
+            } else {
+                let mut res = ErrorResponse::new(Some("bad inspector token".into()));
+                *res.status_mut() = StatusCode::FORBIDDEN;
+                Err(res)
+            }
+        };
+        let ws = match tokio_tungstenite::accept_hdr_async(stream, check_path).await {
+            Ok(x) => x,
+            Err(e) => {
+                debug!("inspector: websocket handshake failed: {:?}", e);
+                continue;
+            }
+        };
+        let (mut ws_tx, mut ws_rx) = ws.split();
+
+        loop {
+            tokio::select! {
+                msg = ws_rx.next() => match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        if inbound_tx.send(text).is_err() {
+                            return;
+                        }
+                        on_message();
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                },
+                msg = outbound_rx.recv() => match msg {
+                    Some(text) => {
+                        if ws_tx.send(Message::Text(text)).await.is_err() {
+                            break;
+                        }
+                    }
+                    None => return,
+                },
+            }
+        }
+
+        info!("inspector: debugger disconnected");
+        if inbound_tx.send(DISCONNECT_MESSAGE.to_string()).is_err() {
+            return;
+        }
+        on_message();
+    }
+}
+
+impl Inspector {
+    /// Attaches an inspector to the current context of `scope`.
+    pub fn new(
+        scope: &mut v8::HandleScope<'_>,
+        endpoint: InspectorEndpoint,
+        timer_tx: mpsc::UnboundedSender<TimerControl>,
+        max_pause: Duration,
+    ) -> Self {
+        let mut client = Box::new(InspectorClient {
+            base: V8InspectorClientBase::new::<InspectorClient>(),
+            session: std::ptr::null_mut(),
+            inbound_rx: endpoint.inbound_rx,
+            timer_tx,
+            max_pause,
+            paused: Rc::new(Cell::new(false)),
+        });
+        let mut v8_inspector = V8Inspector::create(scope, &mut *client);
+
+        let context = scope.get_current_context();
+        v8_inspector.context_created(context, CONTEXT_GROUP_ID, StringView::from(&b"worker"[..]));
+
+        let mut channel = Box::new(InspectorChannel {
+            base: ChannelBase::new::<InspectorChannel>(),
+            outbound_tx: endpoint.outbound_tx,
+        });
+        let mut v8_session =
+            v8_inspector.connect(CONTEXT_GROUP_ID, &mut *channel, StringView::empty());
+        client.session = &mut *v8_session;
+
+        Self {
+            _v8_session: v8_session,
+            _v8_inspector: v8_inspector,
+            _channel: channel,
+            client,
+            dispatching: Cell::new(false),
+        }
+    }
+
+    /// Messages received from the debugger.
+    pub fn inbound(&self) -> &crossbeam::channel::Receiver<String> {
+        &self.client.inbound_rx
+    }
+
+    /// Dispatches a message from the debugger.
+    ///
+    /// Must be called on the isolate thread with the context entered. May run script code and may
+    /// block if the message causes a pause.
+    pub fn dispatch(&self, message: &str) {
+        // V8 may re-enter the session through `run_message_loop_on_pause`, so go through the
+        // raw pointer instead of borrowing `self.v8_session`.
+        unsafe {
+            (*self.client.session).dispatch_protocol_message(StringView::from(message.as_bytes()));
+        }
+    }
+
+    /// Dispatches all queued messages. Does nothing if called from within another dispatch.
+    pub fn dispatch_pending(&self) {
+        if self.dispatching.replace(true) {
+            return;
+        }
+        while let Ok(message) = self.client.inbound_rx.try_recv() {
+            self.dispatch(&message);
+        }
+        self.dispatching.set(false);
+    }
+}
+
+impl V8InspectorClientImpl for InspectorClient {
+    fn base(&self) -> &V8InspectorClientBase {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut V8InspectorClientBase {
+        &mut self.base
+    }
+
+    fn run_message_loop_on_pause(&mut self, _context_group_id: i32) {
+        // Time spent at a breakpoint is not charged to the worker.
+        drop(self.timer_tx.send(TimerControl::Pause));
+
+        let paused = self.paused.clone();
+        paused.set(true);
+        let deadline = Instant::now() + self.max_pause;
+        while paused.get() {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.inbound_rx.recv_timeout(timeout) {
+                Ok(message) => unsafe {
+                    (*self.session).dispatch_protocol_message(StringView::from(message.as_bytes()));
+                },
+                Err(crossbeam::channel::RecvTimeoutError::Timeout) => {
+                    // Don't let a forgotten breakpoint hold the execution token forever.
+                    warn!(
+                        "inspector: paused for more than {:?}. detaching the debugger",
+                        self.max_pause
+                    );
+                    unsafe {
+                        (*self.session).dispatch_protocol_message(StringView::from(
+                            DISCONNECT_MESSAGE.as_bytes(),
+                        ));
+                    }
+                    break;
+                }
+                // Endpoint closed.
+                Err(crossbeam::channel::RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        paused.set(false);
+
+        drop(self.timer_tx.send(TimerControl::Resume));
+    }
+
+    fn quit_message_loop_on_pause(&mut self) {
+        self.paused.set(false);
+    }
+
+    fn run_if_waiting_for_debugger(&mut self, _context_group_id: i32) {}
+}
+
+impl InspectorChannel {
+    fn send(&mut self, message: v8::UniquePtr<StringBuffer>) {
+        drop(self.outbound_tx.send(message.unwrap().string().to_string()));
+    }
+}
+
+impl ChannelImpl for InspectorChannel {
+    fn base(&self) -> &ChannelBase {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut ChannelBase {
+        &mut self.base
+    }
+
+    fn send_response(&mut self, _call_id: i32, message: v8::UniquePtr<StringBuffer>) {
+        self.send(message);
+    }
+
+    fn send_notification(&mut self, message: v8::UniquePtr<StringBuffer>) {
+        self.send(message);
+    }
+
+    fn flush_protocol_notifications(&mut self) {}
+}
@@ -1,4 +1,5 @@
 use crate::buffer::*;
+use rusty_workers::objstore::{ObjectInfo, ObjectMetadata, ObjectRange};
 use rusty_workers::types::*;
 use serde::{Deserialize, Serialize};
 use std::cell::Cell;
@@ -12,9 +13,15 @@ pub enum ServiceCall {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum SyncCall {
-    Log(String),
-    Done,
-    SendFetchResponse(ResponseObject),
+    Log {
+        level: LogLevel,
+        text: String,
+    },
+    Done(u64),
+    SendFetchResponse {
+        task_id: u64,
+        response: ResponseObject,
+    },
     GetRandomValues,
     GetFile(String),
     Crypto(crate::crypto::CryptoCall),
@@ -32,6 +39,10 @@ pub enum AsyncCallV {
     KvGet {
         namespace: String,
     },
+    KvGetWithMetadata {
+        namespace: String,
+    },
+    /// Buffers: key, value, and optionally metadata.
     KvPut {
         namespace: String,
         if_not_exists: bool,
@@ -50,18 +61,195 @@ pub enum AsyncCallV {
         num_writes: u32,
         ttl_ms: u64,
     },
+    /// Buffers: for each read, the key, followed by the value read if the key was present; then
+    /// for each write, the key, followed by the value and metadata if it is a put.
+    KvCommit {
+        namespace: String,
+
+        /// Whether each read key was present.
+        reads: Vec<bool>,
+        writes: Vec<KvTxWrite>,
+    },
+    /// Buffers: keys.
+    KvGetMany {
+        namespace: String,
+    },
+    /// Buffers: key and value of each entry.
+    KvPutMany {
+        namespace: String,
+        ttl_ms: u64,
+    },
+    /// Buffers: keys.
+    KvDeleteMany {
+        namespace: String,
+    },
+    /// Buffers: key.
+    KvIncrement {
+        namespace: String,
+        delta: i64,
+        ttl_ms: u64,
+    },
+    /// Buffers: key prefix.
+    KvList {
+        namespace: String,
+        cursor: Option<String>,
+        limit: u32,
+        include_values: bool,
+        include_metadata: bool,
+    },
+    /// Buffers: chunk data. Starts a new upload if `blob_id` is `None`. Returns the blob id.
+    KvPutBlobChunk {
+        namespace: String,
+        blob_id: Option<String>,
+        index: u32,
+    },
+    /// Buffers: key, and optionally metadata.
+    KvCommitBlob {
+        namespace: String,
+        blob_id: String,
+        chunks: u32,
+        size: u64,
+        ttl_ms: u64,
+    },
+    /// Buffers: key. Returns a `KvBlobInfo` and the metadata, if the blob exists.
+    KvGetBlob {
+        namespace: String,
+    },
+    KvGetBlobChunk {
+        namespace: String,
+        blob_id: String,
+        index: u32,
+    },
+    /// Buffers: key.
+    KvDeleteBlob {
+        namespace: String,
+    },
+    /// Buffers: part data of exactly `OBJECT_PART_SIZE` bytes. Starts a new upload if `upload_id`
+    /// is `None`. Returns the upload id.
+    BucketPutPart {
+        bucket: String,
+        upload_id: Option<String>,
+        index: u32,
+    },
+    /// Buffers: the data after the uploaded parts. Writes the object with a single call if there
+    /// are no parts and `upload_id` is `None`. Returns an `ObjectInfo`.
+    BucketPut {
+        bucket: String,
+        key: String,
+        upload_id: Option<String>,
+        parts: u32,
+        metadata: ObjectMetadata,
+    },
+    /// Returns an `ObjectInfo`, if the object exists.
+    BucketHead {
+        bucket: String,
+        key: String,
+    },
+    /// Reads a range of an object, failing if `etag` is set and the object has changed. Returns a
+    /// `BucketReadResult` and the data, if the object exists.
+    BucketGet {
+        bucket: String,
+        key: String,
+        range: ObjectRange,
+        etag: Option<String>,
+    },
+    /// Returns an `ObjectList`.
+    BucketList {
+        bucket: String,
+        prefix: String,
+        delimiter: Option<String>,
+        cursor: Option<String>,
+        limit: u32,
+    },
+    BucketDelete {
+        bucket: String,
+        key: String,
+    },
+    /// Buffers: blob parameters, referenced by `SqlParam::Blob`. Runs the statements in one
+    /// transaction and returns a `SqlResult` for each.
+    SqlExecute {
+        statements: Vec<SqlStatementV>,
+    },
+}
+
+/// A statement in `AsyncCallV::SqlExecute`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SqlStatementV {
+    pub sql: String,
+    pub params: Vec<SqlParam>,
+}
+
+/// A parameter of a `SqlStatementV`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum SqlParam {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+
+    /// Index of the buffer holding the value.
+    Blob(usize),
+}
+
+/// A buffered write in `AsyncCallV::KvCommit`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum KvTxWrite {
+    Put { ttl_ms: u64 },
+    Delete,
+}
+
+/// Result of `AsyncCallV::KvList`.
+///
+/// Buffers hold the key of each entry, followed by its value and metadata if requested.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KvListResult {
+    pub expirations: Vec<u64>,
+    pub cursor: Option<String>,
+    pub list_complete: bool,
+}
+
+/// Result of `AsyncCallV::KvGetBlob`. The metadata is returned as a buffer.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KvBlobInfo {
+    pub blob_id: String,
+    pub size: u64,
+    pub chunks: u32,
+    pub expiration: u64,
+}
+
+/// Result of `AsyncCallV::BucketGet`. The data is returned as a buffer.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BucketReadResult {
+    pub object: ObjectInfo,
+
+    /// Offset of the data in the object.
+    pub offset: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ServiceEvent {
     Fetch(FetchEvent),
+    KvChange(KvChangeEvent),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FetchEvent {
+    /// Identifies the task in `SyncCall::Done` and `SyncCall::SendFetchResponse`.
+    pub task_id: u64,
     pub request: RequestObject,
 }
 
+/// Changes to a watched KV namespace.
+///
+/// The script completes the task with `SyncCall::SendFetchResponse` (status 200 on success)
+/// followed by `SyncCall::Done`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KvChangeEvent {
+    pub task_id: u64,
+    pub namespace: String,
+    pub changes: Vec<KvChange>,
+}
+
 pub struct ReadableByteCellSlice<'a>(&'a [Cell<u8>]);
 
 impl<'a> ReadableByteCellSlice<'a> {
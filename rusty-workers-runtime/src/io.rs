@@ -1,9 +1,15 @@
-use crate::interface::{AsyncCall, AsyncCallV};
+use crate::buffer::JsArrayBufferViewRef;
+use crate::interface::{
+    AsyncCall, AsyncCallV, BucketReadResult, KvBlobInfo, KvListResult, KvTxWrite, SqlParam,
+    SqlStatementV,
+};
 use crate::remote_buffer::*;
 use crate::runtime::Runtime;
 use anyhow::Result;
 use rusty_v8 as v8;
-use rusty_workers::rpc::FetchServiceClient;
+use rusty_workers::db::{KvBlob, KvMutation, BLOB_CHUNK_SIZE};
+use rusty_workers::objstore::OBJECT_PART_SIZE;
+use rusty_workers::rpc::{FetchServiceClient, SqlServiceClient};
 use rusty_workers::tarpc;
 use rusty_workers::types::*;
 use serde::{Deserialize, Serialize};
@@ -16,38 +22,85 @@ use tokio::sync::{oneshot, Semaphore};
 
 const MAX_KV_KEY_SIZE: usize = 2048;
 const MAX_KV_VALUE_SIZE: usize = 4 * 1024 * 1024;
+const MAX_KV_METADATA_SIZE: usize = 1024;
 const MAX_FETCH_REQUEST_BODY_SIZE: usize = 2 * 1024 * 1024;
 const MAX_KV_SCAN_LIMIT: u32 = 100; // 100 * 2K = 200K max
+const MAX_KV_LIST_LIMIT: u32 = 1000;
+const MAX_KV_BATCH_SIZE: usize = 100;
+const MAX_KV_BATCH_BYTES: usize = 16 * 1024 * 1024;
+const MAX_KV_LIST_BYTES: usize = 16 * 1024 * 1024;
+const MAX_KV_TX_KEYS: usize = 100;
+const MAX_KV_TX_BYTES: usize = 16 * 1024 * 1024;
+const MAX_KV_BLOB_SIZE: usize = 64 * 1024 * 1024;
+const MAX_KV_BLOB_CHUNKS: u32 = (MAX_KV_BLOB_SIZE / BLOB_CHUNK_SIZE) as u32;
+const MAX_OBJECT_KEY_SIZE: usize = 1024;
+const MAX_OBJECT_METADATA_SIZE: usize = 8192;
+const MAX_OBJECT_SIZE: usize = 1024 * 1024 * 1024;
+const MAX_OBJECT_PARTS: u32 = (MAX_OBJECT_SIZE / OBJECT_PART_SIZE) as u32;
+const MAX_BUCKET_READ_SIZE: usize = OBJECT_PART_SIZE;
+const MAX_BUCKET_LIST_LIMIT: u32 = 1000;
+const MAX_SQL_BATCH_SIZE: usize = 100;
+const MAX_SQL_LENGTH: usize = 100 * 1024;
+const MAX_SQL_BATCH_BYTES: usize = 16 * 1024 * 1024;
 
 pub struct IoWaiter {
     remaining_budget: u32,
+    counters: IoCounters,
     inflight: Slab<v8::Global<v8::Function>>,
     task: tokio::sync::mpsc::Sender<(usize, AsyncCall)>,
-    result: crossbeam::channel::Receiver<BackToExecutorItem>,
     _conf: Arc<WorkerConfiguration>,
     remote_buffer_set: RemoteBufferSet,
 }
 
+/// Counters of async calls issued through an `IoWaiter`.
+#[derive(Default, Copy, Clone, Debug)]
+pub struct IoCounters {
+    pub io_calls: u32,
+    pub kv_calls: u32,
+    pub fetch_calls: u32,
+    pub bucket_calls: u32,
+    pub sql_calls: u32,
+}
+
 pub struct IoProcessor {
     task: tokio::sync::mpsc::Receiver<(usize, AsyncCall)>,
     inflight_sem: Arc<Semaphore>,
     shared: Arc<IoProcessorSharedState>,
 }
 
+/// A message from an `IoProcessor` to the executor, tagged with the id of the task that owns it.
+pub struct IoEvent {
+    task_id: u64,
+    item: BackToExecutorItem,
+}
+
+/// What the executor should do after handling an `IoEvent`.
+pub enum IoCompletion {
+    /// Call the callback with the result.
+    Ready(v8::Global<v8::Function>, String, Vec<RemoteBuffer>),
+
+    /// The task's `IoScope` was dropped. No more results will arrive.
+    ScopeKilled,
+}
+
 enum BackToExecutorItem {
     TaskResult((usize, String, Vec<RemoteBuffer>)),
     BufferCreation {
         size: usize,
         return_channel: oneshot::Sender<Option<RemoteBuffer>>,
     },
+    ScopeKilled,
 }
 
 struct IoProcessorSharedState {
     conf: Arc<WorkerConfiguration>,
     worker_runtime: Arc<Runtime>,
     fetch_client: AsyncMutex<Option<FetchServiceClient>>,
+    sql_client: AsyncMutex<Option<SqlServiceClient>>,
+    appid: String,
 
-    result: crossbeam::channel::Sender<BackToExecutorItem>,
+    task_id: u64,
+    result: crossbeam::channel::Sender<IoEvent>,
 }
 
 /// An `IoScope` is a handle that a task sender holds to signal that I/O operations should
@@ -68,7 +121,8 @@ enum IoTask {
 }
 
 struct IoResponseHandle {
-    result: crossbeam::channel::Sender<BackToExecutorItem>,
+    task_id: u64,
+    result: crossbeam::channel::Sender<IoEvent>,
     index: usize,
 }
 
@@ -80,12 +134,16 @@ impl IoScope {
 }
 
 impl IoWaiter {
+    /// Creates the I/O state of a task of app `appid`. Results are sent to `result`, tagged with
+    /// `task_id`.
     pub fn new(
         conf: Arc<WorkerConfiguration>,
         worker_runtime: Arc<Runtime>,
+        appid: String,
+        task_id: u64,
+        result: crossbeam::channel::Sender<IoEvent>,
     ) -> (Self, IoProcessor) {
         let init_budget = conf.executor.max_io_per_request;
-        let (result_tx, result_rx) = crossbeam::channel::unbounded();
 
         // Async task backlog
         let (task_tx, task_rx) =
@@ -93,9 +151,9 @@ impl IoWaiter {
 
         let waiter = IoWaiter {
             remaining_budget: init_budget,
+            counters: IoCounters::default(),
             inflight: Slab::new(),
             task: task_tx,
-            result: result_rx,
             _conf: conf.clone(),
             remote_buffer_set: RemoteBufferSet::new(),
         };
@@ -106,7 +164,10 @@ impl IoWaiter {
                 conf,
                 worker_runtime,
                 fetch_client: AsyncMutex::new(None),
-                result: result_tx,
+                sql_client: AsyncMutex::new(None),
+                appid,
+                task_id,
+                result,
             }),
         };
         (waiter, processor)
@@ -125,6 +186,7 @@ impl IoWaiter {
             self.remaining_budget -= 1;
         }
 
+        self.counters.record(&task.v);
         let index = self.inflight.insert(cb);
 
         // We've got a large enough backlog (max_io_per_request + x). And if here we still
@@ -138,30 +200,79 @@ impl IoWaiter {
         }
     }
 
-    pub fn wait(
+    pub fn counters(&self) -> IoCounters {
+        self.counters
+    }
+
+    /// Handles an event from this waiter's `IoProcessor`. Returns `None` if there is nothing
+    /// more to do.
+    pub fn handle_event(
         &mut self,
         scope: &mut v8::HandleScope<'_>,
-    ) -> Option<(v8::Global<v8::Function>, String, Vec<RemoteBuffer>)> {
-        let (index, result, buffers) = loop {
-            // [Blocking in JS hostcall] Receive result. recv() fails once IoScope is dropped.
-            match self.result.recv().ok()? {
-                BackToExecutorItem::TaskResult(x) => break x,
-                BackToExecutorItem::BufferCreation {
-                    size,
-                    return_channel,
-                } => {
-                    let buffer = self.remote_buffer_set.allocate(scope, size);
-                    drop(return_channel.send(buffer));
-                    continue;
+        event: IoEvent,
+    ) -> Option<IoCompletion> {
+        match event.item {
+            BackToExecutorItem::TaskResult((index, result, buffers)) => {
+                if !self.inflight.contains(index) {
+                    return None;
                 }
+                let req = self.inflight.remove(index);
+
+                // A nice point to garbage collect buffer set.
+                self.remote_buffer_set.gc();
+
+                Some(IoCompletion::Ready(req, result, buffers))
             }
-        };
-        let req = self.inflight.remove(index);
+            BackToExecutorItem::BufferCreation {
+                size,
+                return_channel,
+            } => {
+                let buffer = self.remote_buffer_set.allocate(scope, size);
+                drop(return_channel.send(buffer));
+                None
+            }
+            BackToExecutorItem::ScopeKilled => Some(IoCompletion::ScopeKilled),
+        }
+    }
+}
 
-        // A nice point to garbage collect buffer set.
-        self.remote_buffer_set.gc();
+impl IoEvent {
+    pub fn task_id(&self) -> u64 {
+        self.task_id
+    }
+}
 
-        Some((req, result, buffers))
+impl IoCounters {
+    fn record(&mut self, call: &AsyncCallV) {
+        self.io_calls += 1;
+        match call {
+            AsyncCallV::SetTimeout(_) => {}
+            AsyncCallV::Fetch(_) => self.fetch_calls += 1,
+            AsyncCallV::KvGet { .. }
+            | AsyncCallV::KvGetWithMetadata { .. }
+            | AsyncCallV::KvPut { .. }
+            | AsyncCallV::KvDelete { .. }
+            | AsyncCallV::KvScan { .. }
+            | AsyncCallV::KvGetMany { .. }
+            | AsyncCallV::KvPutMany { .. }
+            | AsyncCallV::KvDeleteMany { .. }
+            | AsyncCallV::KvIncrement { .. }
+            | AsyncCallV::KvList { .. }
+            | AsyncCallV::KvCmpUpdate { .. }
+            | AsyncCallV::KvCommit { .. }
+            | AsyncCallV::KvPutBlobChunk { .. }
+            | AsyncCallV::KvCommitBlob { .. }
+            | AsyncCallV::KvGetBlob { .. }
+            | AsyncCallV::KvGetBlobChunk { .. }
+            | AsyncCallV::KvDeleteBlob { .. } => self.kv_calls += 1,
+            AsyncCallV::BucketPutPart { .. }
+            | AsyncCallV::BucketPut { .. }
+            | AsyncCallV::BucketHead { .. }
+            | AsyncCallV::BucketGet { .. }
+            | AsyncCallV::BucketList { .. }
+            | AsyncCallV::BucketDelete { .. } => self.bucket_calls += 1,
+            AsyncCallV::SqlExecute { .. } => self.sql_calls += 1,
+        }
     }
 }
 
@@ -171,6 +282,7 @@ impl IoProcessor {
         Some((
             task,
             IoResponseHandle {
+                task_id: self.shared.task_id,
                 result: self.shared.result.clone(),
                 index,
             },
@@ -185,6 +297,10 @@ impl IoProcessor {
             let next = tokio::select! {
                 _ = &mut scope.kill => {
                     debug!("IoScope killed");
+                    drop(self.shared.result.send(IoEvent {
+                        task_id: self.shared.task_id,
+                        item: BackToExecutorItem::ScopeKilled,
+                    }));
                     break;
                 }
                 x = self.next() => x
@@ -232,9 +348,12 @@ impl IoProcessorSharedState {
         let start_time = Instant::now();
 
         let (tx, rx) = oneshot::channel();
-        drop(self.result.send(BackToExecutorItem::BufferCreation {
-            size: len,
-            return_channel: tx,
+        drop(self.result.send(IoEvent {
+            task_id: self.task_id,
+            item: BackToExecutorItem::BufferCreation {
+                size: len,
+                return_channel: tx,
+            },
         }));
 
         match rx.await {
@@ -262,6 +381,33 @@ impl IoProcessorSharedState {
         })
     }
 
+    /// Reports the result of a KV write to the worker. The quota is enforced by the database, so
+    /// the namespace is only read to explain a rejected write.
+    async fn kv_write_result<T: Serialize>(
+        &self,
+        namespace_id: &str,
+        result: GenericResult<T>,
+    ) -> Result<(String, Vec<RemoteBuffer>)> {
+        match result {
+            Ok(x) => mk_user_ok(x),
+            Err(GenericError::QuotaExceeded) => {
+                let ns = self
+                    .worker_runtime
+                    .data_client()
+                    .kv_namespace_get(namespace_id)
+                    .await?;
+                match ns {
+                    Some(ns) => mk_user_error(format!(
+                        "namespace quota exceeded ({} keys and {} bytes used, max_keys {}, max_bytes {})",
+                        ns.used_keys, ns.used_bytes, ns.max_keys, ns.max_bytes
+                    )),
+                    None => mk_user_error("namespace quota exceeded"),
+                }
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
     async fn handle_task(self: Arc<Self>, task: AsyncCall) -> Result<(String, Vec<RemoteBuffer>)> {
         match task.v {
             AsyncCallV::SetTimeout(n) => {
@@ -335,6 +481,38 @@ impl IoProcessorSharedState {
                     Ok(mk_user_ok(false)?)
                 }
             }
+            AsyncCallV::KvGetWithMetadata { namespace } => {
+                let key = match task
+                    .buffers
+                    .get(0)
+                    .ok_or_else(|| GenericError::Other("missing key".into()))?
+                    .read_to_vec(MAX_KV_KEY_SIZE)
+                {
+                    Some(x) => x,
+                    None => return Ok(mk_user_error("key too large")?),
+                };
+                let namespace_id = match self.conf.kv_namespaces.get(&namespace) {
+                    Some(id) => id,
+                    None => return Ok(mk_user_error("namespace does not exist")?),
+                };
+
+                let result = self
+                    .worker_runtime
+                    .data_client()
+                    .worker_data_get_with_metadata(namespace_id, &key)
+                    .await?;
+                if let Some((value, metadata)) = result {
+                    Ok(mk_user_ok_with_buffers(
+                        true,
+                        vec![
+                            self.allocate_arraybuffer_with_data(&value).await?,
+                            self.allocate_arraybuffer_with_data(&metadata).await?,
+                        ],
+                    )?)
+                } else {
+                    Ok(mk_user_ok(false)?)
+                }
+            }
             AsyncCallV::KvPut {
                 namespace,
                 if_not_exists,
@@ -358,15 +536,25 @@ impl IoProcessorSharedState {
                     Some(x) => x,
                     None => return Ok(mk_user_error("value too large")?),
                 };
+                let metadata = match task
+                    .buffers
+                    .get(2)
+                    .map(|x| x.read_to_vec(MAX_KV_METADATA_SIZE))
+                {
+                    Some(Some(x)) => x,
+                    Some(None) => return Ok(mk_user_error("metadata too large")?),
+                    None => vec![],
+                };
                 let namespace_id = match self.conf.kv_namespaces.get(&namespace) {
                     Some(id) => id,
                     None => return Ok(mk_user_error("namespace does not exist")?),
                 };
-                self.worker_runtime
+                let result = self
+                    .worker_runtime
                     .data_client()
-                    .worker_data_put(namespace_id, &key, &value, if_not_exists, ttl_ms)
-                    .await?;
-                Ok(mk_user_ok(())?)
+                    .worker_data_put(namespace_id, &key, &value, &metadata, if_not_exists, ttl_ms)
+                    .await;
+                self.kv_write_result(namespace_id, result).await
             }
             AsyncCallV::KvDelete { namespace } => {
                 let key = match task
@@ -423,6 +611,195 @@ impl IoProcessorSharedState {
 
                 Ok(mk_user_ok_with_buffers(&(), keys?)?)
             }
+            AsyncCallV::KvGetMany { namespace } => {
+                let keys = match read_kv_batch_keys(&task.buffers) {
+                    Ok(x) => x,
+                    Err(e) => return Ok(mk_user_error(e)?),
+                };
+                let namespace_id = match self.conf.kv_namespaces.get(&namespace) {
+                    Some(id) => id,
+                    None => return Ok(mk_user_error("namespace does not exist")?),
+                };
+
+                let values = self
+                    .worker_runtime
+                    .data_client()
+                    .worker_data_get_many(namespace_id, &keys)
+                    .await?;
+                let total_bytes: usize = values.iter().flatten().map(|x| x.len()).sum();
+                if total_bytes > MAX_KV_BATCH_BYTES {
+                    return Ok(mk_user_error("batch too large")?);
+                }
+
+                // Buffers are only returned for keys that exist.
+                let found: Vec<bool> = values.iter().map(|x| x.is_some()).collect();
+                let buffers: GenericResult<_> = futures::future::try_join_all(
+                    values
+                        .iter()
+                        .flatten()
+                        .map(|x| self.allocate_arraybuffer_with_data(x)),
+                )
+                .await;
+                Ok(mk_user_ok_with_buffers(found, buffers?)?)
+            }
+            AsyncCallV::KvPutMany { namespace, ttl_ms } => {
+                if task.buffers.len() % 2 != 0 {
+                    return Ok(mk_user_error("missing value")?);
+                }
+                if task.buffers.len() / 2 > MAX_KV_BATCH_SIZE {
+                    return Ok(mk_user_error(
+                        "batch size is greater than MAX_KV_BATCH_SIZE",
+                    )?);
+                }
+                let mut entries = Vec::with_capacity(task.buffers.len() / 2);
+                let mut total_bytes = 0usize;
+                for pair in task.buffers.chunks(2) {
+                    let key = match pair[0].read_to_vec(MAX_KV_KEY_SIZE) {
+                        Some(x) => x,
+                        None => return Ok(mk_user_error("key too large")?),
+                    };
+                    let value = match pair[1].read_to_vec(MAX_KV_VALUE_SIZE) {
+                        Some(x) => x,
+                        None => return Ok(mk_user_error("value too large")?),
+                    };
+                    total_bytes += key.len() + value.len();
+                    if total_bytes > MAX_KV_BATCH_BYTES {
+                        return Ok(mk_user_error("batch too large")?);
+                    }
+                    entries.push((key, value));
+                }
+                let namespace_id = match self.conf.kv_namespaces.get(&namespace) {
+                    Some(id) => id,
+                    None => return Ok(mk_user_error("namespace does not exist")?),
+                };
+
+                let result = self
+                    .worker_runtime
+                    .data_client()
+                    .worker_data_put_many(namespace_id, &entries, ttl_ms)
+                    .await;
+                self.kv_write_result(namespace_id, result).await
+            }
+            AsyncCallV::KvDeleteMany { namespace } => {
+                let keys = match read_kv_batch_keys(&task.buffers) {
+                    Ok(x) => x,
+                    Err(e) => return Ok(mk_user_error(e)?),
+                };
+                let namespace_id = match self.conf.kv_namespaces.get(&namespace) {
+                    Some(id) => id,
+                    None => return Ok(mk_user_error("namespace does not exist")?),
+                };
+
+                self.worker_runtime
+                    .data_client()
+                    .worker_data_delete_many(namespace_id, &keys)
+                    .await?;
+                Ok(mk_user_ok(())?)
+            }
+            AsyncCallV::KvIncrement {
+                namespace,
+                delta,
+                ttl_ms,
+            } => {
+                let key = match task
+                    .buffers
+                    .get(0)
+                    .ok_or_else(|| GenericError::Other("missing key".into()))?
+                    .read_to_vec(MAX_KV_KEY_SIZE)
+                {
+                    Some(x) => x,
+                    None => return Ok(mk_user_error("key too large")?),
+                };
+                let namespace_id = match self.conf.kv_namespaces.get(&namespace) {
+                    Some(id) => id,
+                    None => return Ok(mk_user_error("namespace does not exist")?),
+                };
+                let result = self
+                    .worker_runtime
+                    .data_client()
+                    .worker_data_increment(namespace_id, &key, delta, ttl_ms)
+                    .await;
+                self.kv_write_result(namespace_id, result).await
+            }
+            AsyncCallV::KvList {
+                namespace,
+                cursor,
+                limit,
+                include_values,
+                include_metadata,
+            } => {
+                let prefix = match task
+                    .buffers
+                    .get(0)
+                    .ok_or_else(|| GenericError::Other("missing prefix".into()))?
+                    .read_to_vec(MAX_KV_KEY_SIZE)
+                {
+                    Some(x) => x,
+                    None => return Ok(mk_user_error("prefix too large")?),
+                };
+                let after = match cursor.map(|x| rusty_workers::db::decode_list_cursor(&x)) {
+                    Some(Some(x)) => Some(x),
+                    Some(None) => return Ok(mk_user_error("invalid cursor")?),
+                    None => None,
+                };
+                let namespace_id = match self.conf.kv_namespaces.get(&namespace) {
+                    Some(id) => id,
+                    None => return Ok(mk_user_error("namespace does not exist")?),
+                };
+                if limit > MAX_KV_LIST_LIMIT {
+                    return Ok(mk_user_error("limit is greater than MAX_KV_LIST_LIMIT")?);
+                }
+
+                let (mut entries, mut list_complete) = self
+                    .worker_runtime
+                    .data_client()
+                    .worker_data_list(
+                        namespace_id,
+                        &prefix,
+                        after.as_deref(),
+                        limit,
+                        include_values,
+                        include_metadata,
+                    )
+                    .await?;
+
+                // Cut the page short if it gets too large. Always return at least one entry so
+                // that the listing makes progress.
+                let mut total_bytes = 0usize;
+                if let Some(n) = entries.iter().position(|x| {
+                    total_bytes += x.key.len() + x.value.len() + x.metadata.len();
+                    total_bytes > MAX_KV_LIST_BYTES
+                }) {
+                    entries.truncate(n.max(1));
+                    list_complete = false;
+                }
+
+                let cursor = if list_complete {
+                    None
+                } else {
+                    entries
+                        .last()
+                        .map(|x| rusty_workers::db::encode_list_cursor(&x.key))
+                };
+                let mut buffers = vec![];
+                for entry in &entries {
+                    buffers.push(self.allocate_arraybuffer_with_data(&entry.key).await?);
+                    if include_values {
+                        buffers.push(self.allocate_arraybuffer_with_data(&entry.value).await?);
+                    }
+                    if include_metadata {
+                        buffers.push(self.allocate_arraybuffer_with_data(&entry.metadata).await?);
+                    }
+                }
+                Ok(mk_user_ok_with_buffers(
+                    KvListResult {
+                        expirations: entries.iter().map(|x| x.expiration).collect(),
+                        cursor,
+                        list_complete,
+                    },
+                    buffers,
+                )?)
+            }
             AsyncCallV::KvCmpUpdate {
                 namespace,
                 num_assertions,
@@ -466,13 +843,417 @@ impl IoProcessorSharedState {
                     }
                 }
 
-                let success = self
+                let result = self
                     .worker_runtime
                     .data_client()
                     .worker_data_cmpupdate(namespace_id, &assertions, &writes, ttl_ms)
+                    .await;
+                self.kv_write_result(namespace_id, result).await
+            }
+            AsyncCallV::KvCommit {
+                namespace,
+                reads,
+                writes,
+            } => {
+                let (reads, writes) = match read_kv_commit(&reads, &writes, &task.buffers) {
+                    Ok(x) => x,
+                    Err(e) => return Ok(mk_user_error(e)?),
+                };
+                let namespace_id = match self.conf.kv_namespaces.get(&namespace) {
+                    Some(id) => id,
+                    None => return Ok(mk_user_error("namespace does not exist")?),
+                };
+
+                let result = self
+                    .worker_runtime
+                    .data_client()
+                    .worker_data_commit(namespace_id, &reads, &writes)
+                    .await;
+                self.kv_write_result(namespace_id, result).await
+            }
+            AsyncCallV::KvPutBlobChunk {
+                namespace,
+                blob_id,
+                index,
+            } => {
+                let data = match task
+                    .buffers
+                    .get(0)
+                    .ok_or_else(|| GenericError::Other("missing data".into()))?
+                    .read_to_vec(BLOB_CHUNK_SIZE)
+                {
+                    Some(x) => x,
+                    None => return Ok(mk_user_error("chunk too large")?),
+                };
+                let namespace_id = match self.conf.kv_namespaces.get(&namespace) {
+                    Some(id) => id,
+                    None => return Ok(mk_user_error("namespace does not exist")?),
+                };
+                if index >= MAX_KV_BLOB_CHUNKS {
+                    return Ok(mk_user_error("blob is larger than MAX_KV_BLOB_SIZE")?);
+                }
+                // Chunks are only counted once the blob is committed, but a chunk that alone
+                // would take the namespace over its size quota can never be committed.
+                if let Some(ns) = self
+                    .worker_runtime
+                    .data_client()
+                    .kv_namespace_get(namespace_id)
+                    .await?
+                {
+                    let bytes = ns.used_bytes + data.len() as u64;
+                    if ns.max_bytes != 0 && bytes > ns.max_bytes {
+                        return Ok(mk_user_error(format!(
+                            "namespace size quota exceeded ({} > {} bytes)",
+                            bytes, ns.max_bytes
+                        ))?);
+                    }
+                }
+
+                let blob_id = blob_id.unwrap_or_else(|| rusty_workers::util::rand_hex(16));
+                self.worker_runtime
+                    .data_client()
+                    .worker_blob_put_chunk(namespace_id, &blob_id, index, &data)
                     .await?;
+                Ok(mk_user_ok(blob_id)?)
+            }
+            AsyncCallV::KvCommitBlob {
+                namespace,
+                blob_id,
+                chunks,
+                size,
+                ttl_ms,
+            } => {
+                let key = match task
+                    .buffers
+                    .get(0)
+                    .ok_or_else(|| GenericError::Other("missing key".into()))?
+                    .read_to_vec(MAX_KV_KEY_SIZE)
+                {
+                    Some(x) => x,
+                    None => return Ok(mk_user_error("key too large")?),
+                };
+                let metadata = match task
+                    .buffers
+                    .get(1)
+                    .map(|x| x.read_to_vec(MAX_KV_METADATA_SIZE))
+                {
+                    Some(Some(x)) => x,
+                    Some(None) => return Ok(mk_user_error("metadata too large")?),
+                    None => vec![],
+                };
+                let namespace_id = match self.conf.kv_namespaces.get(&namespace) {
+                    Some(id) => id,
+                    None => return Ok(mk_user_error("namespace does not exist")?),
+                };
+                let blob = KvBlob {
+                    blob_id,
+                    size,
+                    chunks,
+                    metadata,
+                    expiration: 0,
+                };
+                let result = self
+                    .worker_runtime
+                    .data_client()
+                    .worker_blob_commit(namespace_id, &key, &blob, ttl_ms)
+                    .await;
+                match result {
+                    Ok(false) => Ok(mk_user_error("incomplete blob upload")?),
+                    result => self.kv_write_result(namespace_id, result.map(|_| ())).await,
+                }
+            }
+            AsyncCallV::KvGetBlob { namespace } => {
+                let key = match task
+                    .buffers
+                    .get(0)
+                    .ok_or_else(|| GenericError::Other("missing key".into()))?
+                    .read_to_vec(MAX_KV_KEY_SIZE)
+                {
+                    Some(x) => x,
+                    None => return Ok(mk_user_error("key too large")?),
+                };
+                let namespace_id = match self.conf.kv_namespaces.get(&namespace) {
+                    Some(id) => id,
+                    None => return Ok(mk_user_error("namespace does not exist")?),
+                };
+
+                let blob = self
+                    .worker_runtime
+                    .data_client()
+                    .worker_blob_get(namespace_id, &key)
+                    .await?;
+                if let Some(blob) = blob {
+                    Ok(mk_user_ok_with_buffers(
+                        Some(KvBlobInfo {
+                            blob_id: blob.blob_id,
+                            size: blob.size,
+                            chunks: blob.chunks,
+                            expiration: blob.expiration,
+                        }),
+                        vec![self.allocate_arraybuffer_with_data(&blob.metadata).await?],
+                    )?)
+                } else {
+                    Ok(mk_user_ok(None::<KvBlobInfo>)?)
+                }
+            }
+            AsyncCallV::KvGetBlobChunk {
+                namespace,
+                blob_id,
+                index,
+            } => {
+                let namespace_id = match self.conf.kv_namespaces.get(&namespace) {
+                    Some(id) => id,
+                    None => return Ok(mk_user_error("namespace does not exist")?),
+                };
 
-                Ok(mk_user_ok(success)?)
+                let data = self
+                    .worker_runtime
+                    .data_client()
+                    .worker_blob_get_chunk(namespace_id, &blob_id, index)
+                    .await?;
+                if let Some(data) = data {
+                    Ok(mk_user_ok_with_buffers(
+                        true,
+                        vec![self.allocate_arraybuffer_with_data(&data).await?],
+                    )?)
+                } else {
+                    Ok(mk_user_ok(false)?)
+                }
+            }
+            AsyncCallV::KvDeleteBlob { namespace } => {
+                let key = match task
+                    .buffers
+                    .get(0)
+                    .ok_or_else(|| GenericError::Other("missing key".into()))?
+                    .read_to_vec(MAX_KV_KEY_SIZE)
+                {
+                    Some(x) => x,
+                    None => return Ok(mk_user_error("key too large")?),
+                };
+                let namespace_id = match self.conf.kv_namespaces.get(&namespace) {
+                    Some(id) => id,
+                    None => return Ok(mk_user_error("namespace does not exist")?),
+                };
+
+                self.worker_runtime
+                    .data_client()
+                    .worker_blob_delete(namespace_id, &key)
+                    .await?;
+                Ok(mk_user_ok(())?)
+            }
+            AsyncCallV::BucketPutPart {
+                bucket,
+                upload_id,
+                index,
+            } => {
+                let data = match task
+                    .buffers
+                    .get(0)
+                    .ok_or_else(|| GenericError::Other("missing data".into()))?
+                    .read_to_vec(OBJECT_PART_SIZE)
+                {
+                    Some(x) if x.len() == OBJECT_PART_SIZE => x,
+                    _ => return Ok(mk_user_error("part size is not OBJECT_PART_SIZE")?),
+                };
+                let bucket_id = match self.conf.buckets.get(&bucket) {
+                    Some(id) => id,
+                    None => return Ok(mk_user_error("bucket does not exist")?),
+                };
+                if index >= MAX_OBJECT_PARTS {
+                    return Ok(mk_user_error("object is larger than MAX_OBJECT_SIZE")?);
+                }
+
+                let upload_id = upload_id.unwrap_or_else(|| rusty_workers::util::rand_hex(16));
+                self.worker_runtime
+                    .object_store()
+                    .put_part(bucket_id, &upload_id, index, &data)
+                    .await?;
+                Ok(mk_user_ok(upload_id)?)
+            }
+            AsyncCallV::BucketPut {
+                bucket,
+                key,
+                upload_id,
+                parts,
+                metadata,
+            } => {
+                let tail = match task
+                    .buffers
+                    .get(0)
+                    .ok_or_else(|| GenericError::Other("missing data".into()))?
+                    .read_to_vec(OBJECT_PART_SIZE)
+                {
+                    Some(x) => x,
+                    None => return Ok(mk_user_error("data after the last part too large")?),
+                };
+                if let Err(e) = check_object_key(&key) {
+                    return Ok(mk_user_error(e)?);
+                }
+                if serde_json::to_vec(&metadata)?.len() > MAX_OBJECT_METADATA_SIZE {
+                    return Ok(mk_user_error("metadata too large")?);
+                }
+                let bucket_id = match self.conf.buckets.get(&bucket) {
+                    Some(id) => id,
+                    None => return Ok(mk_user_error("bucket does not exist")?),
+                };
+                if parts > MAX_OBJECT_PARTS {
+                    return Ok(mk_user_error("object is larger than MAX_OBJECT_SIZE")?);
+                }
+                let upload_id = match upload_id {
+                    Some(x) => x,
+                    None if parts == 0 => rusty_workers::util::rand_hex(16),
+                    None => return Ok(mk_user_error("missing upload id")?),
+                };
+
+                let info = self
+                    .worker_runtime
+                    .object_store()
+                    .complete_upload(bucket_id, &key, &upload_id, parts, &tail, &metadata)
+                    .await?;
+                match info {
+                    Some(info) => Ok(mk_user_ok(info)?),
+                    None => Ok(mk_user_error("incomplete upload")?),
+                }
+            }
+            AsyncCallV::BucketHead { bucket, key } => {
+                if let Err(e) = check_object_key(&key) {
+                    return Ok(mk_user_error(e)?);
+                }
+                let bucket_id = match self.conf.buckets.get(&bucket) {
+                    Some(id) => id,
+                    None => return Ok(mk_user_error("bucket does not exist")?),
+                };
+
+                let info = self
+                    .worker_runtime
+                    .object_store()
+                    .head(bucket_id, &key)
+                    .await?;
+                Ok(mk_user_ok(info)?)
+            }
+            AsyncCallV::BucketGet {
+                bucket,
+                key,
+                range,
+                etag,
+            } => {
+                if let Err(e) = check_object_key(&key) {
+                    return Ok(mk_user_error(e)?);
+                }
+                let bucket_id = match self.conf.buckets.get(&bucket) {
+                    Some(id) => id,
+                    None => return Ok(mk_user_error("bucket does not exist")?),
+                };
+
+                let res = self
+                    .worker_runtime
+                    .object_store()
+                    .read(bucket_id, &key, range, MAX_BUCKET_READ_SIZE as u64)
+                    .await?;
+                let (object, offset, data) = match res {
+                    Some(x) => x,
+                    None if etag.is_some() => {
+                        return Ok(mk_user_error("object was deleted while reading")?)
+                    }
+                    None => return Ok(mk_user_ok(None::<BucketReadResult>)?),
+                };
+                if etag.map(|x| x != object.etag).unwrap_or(false) {
+                    return Ok(mk_user_error("object was modified while reading")?);
+                }
+                Ok(mk_user_ok_with_buffers(
+                    Some(BucketReadResult { object, offset }),
+                    vec![self.allocate_arraybuffer_with_data(&data).await?],
+                )?)
+            }
+            AsyncCallV::BucketList {
+                bucket,
+                prefix,
+                delimiter,
+                cursor,
+                limit,
+            } => {
+                if prefix.len() > MAX_OBJECT_KEY_SIZE {
+                    return Ok(mk_user_error("prefix too large")?);
+                }
+                if let Some(ref cursor) = cursor {
+                    if rusty_workers::db::decode_list_cursor(cursor).is_none() {
+                        return Ok(mk_user_error("invalid cursor")?);
+                    }
+                }
+                let bucket_id = match self.conf.buckets.get(&bucket) {
+                    Some(id) => id,
+                    None => return Ok(mk_user_error("bucket does not exist")?),
+                };
+                if limit == 0 || limit > MAX_BUCKET_LIST_LIMIT {
+                    return Ok(mk_user_error(
+                        "limit must be between 1 and MAX_BUCKET_LIST_LIMIT",
+                    )?);
+                }
+
+                let list = self
+                    .worker_runtime
+                    .object_store()
+                    .list(
+                        bucket_id,
+                        &prefix,
+                        delimiter.as_deref(),
+                        cursor.as_deref(),
+                        limit,
+                    )
+                    .await?;
+                Ok(mk_user_ok(list)?)
+            }
+            AsyncCallV::BucketDelete { bucket, key } => {
+                if let Err(e) = check_object_key(&key) {
+                    return Ok(mk_user_error(e)?);
+                }
+                let bucket_id = match self.conf.buckets.get(&bucket) {
+                    Some(id) => id,
+                    None => return Ok(mk_user_error("bucket does not exist")?),
+                };
+
+                self.worker_runtime
+                    .object_store()
+                    .delete(bucket_id, &key)
+                    .await?;
+                Ok(mk_user_ok(())?)
+            }
+            AsyncCallV::SqlExecute { statements } => {
+                let sql_service = match self.conf.sql_service {
+                    Some(x) => x,
+                    None => return Ok(mk_user_error("database is not available")?),
+                };
+                let statements = match read_sql_statements(statements, &task.buffers) {
+                    Ok(x) => x,
+                    Err(e) => return Ok(mk_user_error(e)?),
+                };
+
+                let mut sql_client_locked = self.sql_client.lock().await;
+                let mut sql_client = if let Some(ref inner) = *sql_client_locked {
+                    inner.clone()
+                } else {
+                    let client = SqlServiceClient::connect(sql_service).await?;
+                    *sql_client_locked = Some(client.clone());
+                    client
+                };
+                drop(sql_client_locked);
+
+                let limits = SqlLimits {
+                    max_rows: self.conf.executor.max_sql_rows,
+                    time_budget_ms: self.conf.executor.max_sql_time_ms as u64,
+                };
+                match sql_client
+                    .execute(
+                        tarpc::context::current(),
+                        self.appid.clone(),
+                        statements,
+                        limits,
+                    )
+                    .await??
+                {
+                    Ok(results) => Ok(mk_user_ok(results)?),
+                    Err(e) => Ok(mk_user_error(e)?),
+                }
             }
         }
     }
@@ -480,11 +1261,136 @@ impl IoProcessorSharedState {
 
 impl IoResponseHandle {
     fn respond(self, data: String, buffers: Vec<RemoteBuffer>) {
-        drop(
-            self.result
-                .send(BackToExecutorItem::TaskResult((self.index, data, buffers))),
-        );
+        drop(self.result.send(IoEvent {
+            task_id: self.task_id,
+            item: BackToExecutorItem::TaskResult((self.index, data, buffers)),
+        }));
+    }
+}
+
+/// Checks that an object key is valid. Errors are reported to the worker.
+fn check_object_key(key: &str) -> Result<(), &'static str> {
+    if key.is_empty() {
+        Err("empty object key")
+    } else if key.len() > MAX_OBJECT_KEY_SIZE {
+        Err("object key too large")
+    } else {
+        Ok(())
+    }
+}
+
+/// Resolves the blob parameters of a SQL batch. Errors are reported to the worker.
+fn read_sql_statements(
+    statements: Vec<SqlStatementV>,
+    buffers: &[JsArrayBufferViewRef],
+) -> Result<Vec<SqlStatement>, &'static str> {
+    if statements.is_empty() {
+        return Err("empty batch");
+    }
+    if statements.len() > MAX_SQL_BATCH_SIZE {
+        return Err("batch size is greater than MAX_SQL_BATCH_SIZE");
+    }
+
+    let mut total_bytes = 0usize;
+    let mut out = Vec::with_capacity(statements.len());
+    for stmt in statements {
+        if stmt.sql.len() > MAX_SQL_LENGTH {
+            return Err("statement too large");
+        }
+        total_bytes += stmt.sql.len();
+        let mut params = Vec::with_capacity(stmt.params.len());
+        for param in stmt.params {
+            params.push(match param {
+                SqlParam::Null => SqlValue::Null,
+                SqlParam::Integer(x) => SqlValue::Integer(x),
+                SqlParam::Real(x) => SqlValue::Real(x),
+                SqlParam::Text(x) => {
+                    total_bytes += x.len();
+                    SqlValue::Text(x)
+                }
+                SqlParam::Blob(index) => {
+                    let data = buffers
+                        .get(index)
+                        .ok_or("missing blob parameter")?
+                        .read_to_vec(MAX_SQL_BATCH_BYTES)
+                        .ok_or("blob parameter too large")?;
+                    total_bytes += data.len();
+                    SqlValue::Blob(data)
+                }
+            });
+        }
+        if total_bytes > MAX_SQL_BATCH_BYTES {
+            return Err("batch is larger than MAX_SQL_BATCH_BYTES");
+        }
+        out.push(SqlStatement {
+            sql: stmt.sql,
+            params,
+        });
+    }
+    Ok(out)
+}
+
+/// Reads the keys of a batch operation. Errors are reported to the worker.
+fn read_kv_batch_keys(buffers: &[JsArrayBufferViewRef]) -> Result<Vec<Vec<u8>>, &'static str> {
+    if buffers.len() > MAX_KV_BATCH_SIZE {
+        return Err("batch size is greater than MAX_KV_BATCH_SIZE");
+    }
+    buffers
+        .iter()
+        .map(|x| x.read_to_vec(MAX_KV_KEY_SIZE).ok_or("key too large"))
+        .collect()
+}
+
+/// Reads the read set and write set of a transaction commit. Errors are reported to the worker.
+fn read_kv_commit(
+    reads: &[bool],
+    writes: &[KvTxWrite],
+    buffers: &[JsArrayBufferViewRef],
+) -> Result<(Vec<(Vec<u8>, Option<Vec<u8>>)>, Vec<(Vec<u8>, KvMutation)>), &'static str> {
+    if reads.len() > MAX_KV_TX_KEYS || writes.len() > MAX_KV_TX_KEYS {
+        return Err("transaction has more than MAX_KV_TX_KEYS reads or writes");
+    }
+
+    let mut buffers = buffers.iter();
+    let mut total_bytes = 0usize;
+    let mut next = |max_size: usize| -> Result<Vec<u8>, &'static str> {
+        let data = buffers
+            .next()
+            .ok_or("missing buffer")?
+            .read_to_vec(max_size)
+            .ok_or("key, value or metadata too large")?;
+        total_bytes += data.len();
+        if total_bytes > MAX_KV_TX_BYTES {
+            return Err("transaction too large");
+        }
+        Ok(data)
+    };
+
+    let mut read_set = Vec::with_capacity(reads.len());
+    for &present in reads {
+        let key = next(MAX_KV_KEY_SIZE)?;
+        let value = if present {
+            Some(next(MAX_KV_VALUE_SIZE)?)
+        } else {
+            None
+        };
+        read_set.push((key, value));
+    }
+
+    let mut write_set = Vec::with_capacity(writes.len());
+    for write in writes {
+        let key = next(MAX_KV_KEY_SIZE)?;
+        let mutation = match *write {
+            KvTxWrite::Put { ttl_ms } => KvMutation::Put {
+                value: next(MAX_KV_VALUE_SIZE)?,
+                metadata: next(MAX_KV_METADATA_SIZE)?,
+                ttl_ms,
+            },
+            KvTxWrite::Delete => KvMutation::Delete,
+        };
+        write_set.push((key, mutation));
     }
+    Ok((read_set, write_set))
 }
 
 fn mk_user_ok<T: serde::Serialize>(value: T) -> Result<(String, Vec<RemoteBuffer>)> {
@@ -1,6 +1,7 @@
 //! V8 isolate owner threads and pools.
 
 use crate::mm::MemoryPool;
+use crate::snapshot::{create_librt_snapshot, init_librt_global};
 use rusty_v8 as v8;
 use std::sync::Arc;
 use tokio::sync::{mpsc, oneshot, Semaphore};
@@ -8,6 +9,14 @@ use tokio::sync::{mpsc, oneshot, Semaphore};
 /// JavaScript-side runtime.
 static LIBRT: &'static str = include_str!("../../librt/dist/main.js");
 
+/// librt snapshot created by the build script. Empty if it could not be created at build time.
+#[cfg(feature = "embedded-librt-snapshot")]
+static EMBEDDED_LIBRT_SNAPSHOT: &[u8] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/librt_snapshot.bin"));
+
+#[cfg(not(feature = "embedded-librt-snapshot"))]
+static EMBEDDED_LIBRT_SNAPSHOT: &[u8] = &[];
+
 pub struct Poison;
 
 pub struct IsolateThreadPool {
@@ -28,6 +37,19 @@ pub struct IsolateConfig {
     pub max_memory_bytes: usize,
 
     pub host_entry_threshold_memory_bytes: usize,
+
+    /// Startup snapshot to create isolates from. If `None`, librt is evaluated in each new context.
+    pub librt_snapshot: Option<LibrtSnapshot>,
+}
+
+/// A V8 startup snapshot whose default context has librt already evaluated.
+#[derive(Clone)]
+pub struct LibrtSnapshot(pub Arc<[u8]>);
+
+impl std::fmt::Debug for LibrtSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LibrtSnapshot({} bytes)", self.0.len())
+    }
 }
 
 struct ThreadGuard<'a> {
@@ -63,6 +85,46 @@ pub struct IsolateGenerationBox(pub Arc<std::sync::Mutex<IsolateGeneration>>);
 #[derive(Clone)]
 pub struct MemoryPoolBox(pub Arc<MemoryPool>);
 
+impl LibrtSnapshot {
+    /// Returns the snapshot embedded at build time, or builds one if there is none. Only builds
+    /// with the `embedded-librt-snapshot` feature embed a snapshot.
+    ///
+    /// Must be called after V8 is initialized.
+    pub fn load() -> Option<Self> {
+        if EMBEDDED_LIBRT_SNAPSHOT.is_empty() {
+            return Self::build();
+        }
+        info!(
+            "using embedded librt snapshot of size {}",
+            EMBEDDED_LIBRT_SNAPSHOT.len()
+        );
+        Some(Self(Arc::from(EMBEDDED_LIBRT_SNAPSHOT)))
+    }
+
+    /// Evaluates librt in a fresh isolate and serializes the resulting heap.
+    ///
+    /// Must be called after V8 is initialized. Returns `None` if librt throws or V8 cannot
+    /// produce a snapshot.
+    pub fn build() -> Option<Self> {
+        let start_time = std::time::Instant::now();
+        let blob = match create_librt_snapshot(LIBRT) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("{}", e);
+                return None;
+            }
+        };
+        let snapshot = Self(Arc::from(blob));
+        let end_time = std::time::Instant::now();
+        info!(
+            "librt snapshot of size {} created in {:?}",
+            snapshot.0.len(),
+            end_time.duration_since(start_time)
+        );
+        Some(snapshot)
+    }
+}
+
 impl IsolateThreadPool {
     pub async fn new(size: usize, config: IsolateConfig) -> Self {
         let start_time = std::time::Instant::now();
@@ -131,18 +193,21 @@ fn isolate_worker(config: &IsolateConfig, job_rx: &mut mpsc::Receiver<IsolateJob
     // Don't allocate any budget for arraybuffers at start.
     let pool = crate::mm::MemoryPool::new(0);
 
-    let params = v8::Isolate::create_params()
+    let mut params = v8::Isolate::create_params()
         .array_buffer_allocator(pool.clone().get_allocator())
         .heap_limits(0, config.max_memory_bytes);
 
+    // Contexts created from the snapshot already have librt evaluated.
+    if let Some(ref snapshot) = config.librt_snapshot {
+        params = params.snapshot_blob(snapshot.0.clone());
+    }
+
     // Must not be moved
     let mut isolate = v8::Isolate::new(params);
 
-    let librt_persistent;
-
-    // Compile librt.
+    // Compile librt, if we are not starting from a snapshot.
     // Many unwraps here! but since we are initializing it should be fine.
-    {
+    let librt_persistent = if config.librt_snapshot.is_none() {
         let mut isolate_scope = v8::HandleScope::new(&mut isolate);
         let context = v8::Context::new(&mut isolate_scope);
         let mut context_scope = v8::ContextScope::new(&mut isolate_scope, context);
@@ -152,8 +217,10 @@ fn isolate_worker(config: &IsolateConfig, job_rx: &mut mpsc::Receiver<IsolateJob
         let librt = v8::Script::compile(scope, librt, None)
             .unwrap()
             .get_unbound_script(scope);
-        librt_persistent = v8::Global::new(scope, librt);
-    }
+        Some(v8::Global::new(scope, librt))
+    } else {
+        None
+    };
 
     let generation = IsolateGenerationBox(Arc::new(std::sync::Mutex::new(IsolateGeneration(0))));
     isolate.set_slot(generation.clone());
@@ -180,12 +247,9 @@ fn isolate_worker(config: &IsolateConfig, job_rx: &mut mpsc::Receiver<IsolateJob
         let mut context_scope = v8::ContextScope::new(&mut isolate_scope, context);
 
         // Run librt initialization.
-        {
+        if let Some(ref librt_persistent) = librt_persistent {
             let scope = &mut v8::HandleScope::new(&mut context_scope);
-
-            let global_key = v8::String::new(scope, "global").unwrap();
-            let global_obj = scope.get_current_context().global(scope);
-            global_obj.set(scope, global_key.into(), global_obj.into());
+            init_librt_global(scope);
 
             let librt = v8::Local::<'_, v8::UnboundScript>::new(scope, librt_persistent.clone())
                 .bind_to_current_context(scope);
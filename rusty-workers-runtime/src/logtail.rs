@@ -0,0 +1,152 @@
+//! In-memory buffers of recent log lines, for apps being followed with
+//! `RuntimeService::tail_logs`.
+
+use rusty_workers::types::*;
+use rusty_workers::util::current_millis;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Maximum number of lines buffered per app.
+const MAX_BUFFERED_LINES: usize = 1000;
+
+/// Maximum number of lines returned by one `tail` call.
+const MAX_LINES_PER_CALL: usize = 500;
+
+/// Maximum time a `tail` call waits for new lines.
+const MAX_TAIL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Buffers of apps that were not tailed for this long are dropped.
+const TAIL_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+pub struct LogTails {
+    apps: Mutex<HashMap<String, Arc<AppTail>>>,
+}
+
+struct AppTail {
+    state: Mutex<AppTailState>,
+
+    /// Carries the sequence number of the latest line, to wake up waiting `tail` calls.
+    latest_tx: watch::Sender<u64>,
+    latest_rx: watch::Receiver<u64>,
+}
+
+struct AppTailState {
+    lines: VecDeque<LiveLogEntry>,
+    last_seq: u64,
+    last_poll: Instant,
+}
+
+impl LogTails {
+    /// Appends a line to the buffer of `appid`, if the app is being tailed.
+    pub fn publish(&self, appid: &str, level: LogLevel, text: &str) {
+        let tail = match self.apps.lock().unwrap().get(appid) {
+            Some(x) => x.clone(),
+            None => return,
+        };
+        let seq = {
+            let mut state = tail.state.lock().unwrap();
+            state.last_seq += 1;
+            let seq = state.last_seq;
+            state.lines.push_back(LiveLogEntry {
+                seq,
+                time: current_millis(),
+                level,
+                text: text.to_string(),
+            });
+            if state.lines.len() > MAX_BUFFERED_LINES {
+                state.lines.pop_front();
+            }
+            seq
+        };
+        drop(tail.latest_tx.send(seq));
+    }
+
+    /// Waits up to `timeout` for lines of `appid` after `after` that match `filter`.
+    ///
+    /// Starts buffering the lines of `appid` if it was not tailed before.
+    pub async fn tail(
+        &self,
+        appid: &str,
+        filter: &LogFilter,
+        mut after: u64,
+        timeout: Duration,
+    ) -> LogTail {
+        let tail = self
+            .apps
+            .lock()
+            .unwrap()
+            .entry(appid.to_string())
+            .or_insert_with(|| Arc::new(AppTail::new()))
+            .clone();
+        let mut latest_rx = tail.latest_rx.clone();
+        let deadline = tokio::time::Instant::now() + timeout.min(MAX_TAIL_TIMEOUT);
+        loop {
+            let result = tail.collect(filter, &mut after);
+            if !result.entries.is_empty() || result.dropped != 0 {
+                return result;
+            }
+            match tokio::time::timeout_at(deadline, latest_rx.changed()).await {
+                Ok(Ok(())) => {}
+                _ => return result,
+            }
+        }
+    }
+
+    /// Drops the buffers of apps that are no longer tailed.
+    pub fn gc(&self) {
+        let mut apps = self.apps.lock().unwrap();
+        apps.retain(|_, tail| tail.state.lock().unwrap().last_poll.elapsed() < TAIL_IDLE_TIMEOUT);
+    }
+}
+
+impl AppTail {
+    fn new() -> Self {
+        let (latest_tx, latest_rx) = watch::channel(0);
+        Self {
+            state: Mutex::new(AppTailState {
+                lines: VecDeque::new(),
+                last_seq: 0,
+                last_poll: Instant::now(),
+            }),
+            latest_tx,
+            latest_rx,
+        }
+    }
+
+    /// Returns the buffered lines after `after` that match `filter`, and advances `after` past
+    /// the lines examined.
+    fn collect(&self, filter: &LogFilter, after: &mut u64) -> LogTail {
+        let mut state = self.state.lock().unwrap();
+        state.last_poll = Instant::now();
+
+        // A cursor from before a restart of this runtime.
+        if *after > state.last_seq {
+            *after = 0;
+        }
+
+        let dropped = match state.lines.front() {
+            Some(x) if x.seq > *after + 1 => x.seq - *after - 1,
+            _ => 0,
+        };
+        let start = *after;
+        let mut entries = vec![];
+        for line in state.lines.iter().skip_while(|x| x.seq <= start) {
+            if entries.len() == MAX_LINES_PER_CALL {
+                break;
+            }
+            *after = line.seq;
+            if filter.matches(line.level, &line.text) {
+                entries.push(line.clone());
+            }
+        }
+
+        LogTail {
+            entries,
+            cursor: *after,
+            dropped,
+        }
+    }
+}
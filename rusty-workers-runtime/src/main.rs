@@ -6,18 +6,22 @@ extern crate log;
 
 mod buffer;
 mod config;
+mod cputime;
 mod crypto;
 mod engine;
 mod error;
 mod executor;
+mod inspector;
 mod interface;
 mod io;
 mod isolate;
+mod logtail;
 mod mm;
 mod remote_buffer;
 mod runtime;
 mod semaphore;
 mod server;
+mod snapshot;
 
 use anyhow::Result;
 use std::net::SocketAddr;
@@ -8,12 +8,20 @@ use std::sync::Arc;
 
 pub struct MemoryPool {
     remaining_bytes: AtomicUsize,
+
+    /// Budget set by the last `reset`.
+    total_bytes: AtomicUsize,
+
+    /// Lowest value of `remaining_bytes` since the last `reset` or `reset_peak`.
+    min_remaining_bytes: AtomicUsize,
 }
 
 impl MemoryPool {
     pub fn new(n: usize) -> Arc<Self> {
         Arc::new(Self {
             remaining_bytes: AtomicUsize::new(n),
+            total_bytes: AtomicUsize::new(n),
+            min_remaining_bytes: AtomicUsize::new(n),
         })
     }
 
@@ -28,6 +36,23 @@ impl MemoryPool {
 
     pub fn reset(&self, n: usize) {
         self.remaining_bytes.store(n, Ordering::Relaxed);
+        self.total_bytes.store(n, Ordering::Relaxed);
+        self.min_remaining_bytes.store(n, Ordering::Relaxed);
+    }
+
+    /// Starts tracking peak usage from the current usage.
+    pub fn reset_peak(&self) {
+        self.min_remaining_bytes.store(
+            self.remaining_bytes.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Peak usage since the last `reset` or `reset_peak`.
+    pub fn peak_used_bytes(&self) -> usize {
+        self.total_bytes
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.min_remaining_bytes.load(Ordering::Relaxed))
     }
 
     pub fn acquire_bytes(&self, n: usize) -> bool {
@@ -43,6 +68,8 @@ impl MemoryPool {
                 .compare_exchange(current, current - n, Ordering::SeqCst, Ordering::SeqCst)
                 .is_ok()
             {
+                self.min_remaining_bytes
+                    .fetch_min(current - n, Ordering::Relaxed);
                 return true;
             }
         }
@@ -124,6 +151,18 @@ pub fn acquire_arraybuffer_precheck(isolate: &mut v8::Isolate, n: usize) -> Gene
     }
 }
 
+pub fn reset_arraybuffer_peak(isolate: &mut v8::Isolate) {
+    isolate.get_slot::<MemoryPoolBox>().unwrap().0.reset_peak();
+}
+
+pub fn arraybuffer_peak_used_bytes(isolate: &mut v8::Isolate) -> usize {
+    isolate
+        .get_slot::<MemoryPoolBox>()
+        .unwrap()
+        .0
+        .peak_used_bytes()
+}
+
 pub fn slice_to_arraybuffer<'s>(
     scope: &mut v8::HandleScope<'s>,
     data: &[u8],
@@ -1,14 +1,19 @@
 use crate::config::Config;
+use crate::cputime::ThreadCpuClock;
 use crate::executor::{Instance, InstanceHandle, InstanceTimeControl, TimerControl};
-use crate::isolate::{IsolateConfig, IsolateThreadPool};
+use crate::isolate::{IsolateConfig, IsolateThreadPool, LibrtSnapshot};
+use crate::logtail::LogTails;
 use crate::semaphore::{Permit, Semaphore};
 use lru_time_cache::LruCache;
 use rusty_v8 as v8;
-use rusty_workers::db::DataClient;
+use rusty_workers::db::{AppLogEntry, DataClient};
+use rusty_workers::objstore::ObjectStoreClient;
 use rusty_workers::types::*;
+use rusty_workers::util::current_millis;
+use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Weak};
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
 use tokio::sync::RwLock as AsyncRwLock;
 use tokio::sync::{
     mpsc::{Receiver, Sender},
@@ -23,8 +28,17 @@ pub struct Runtime {
     pool: IsolateThreadPool,
     execution_token: Semaphore,
     data_client: DataClient,
-    log_tx: tokio::sync::mpsc::Sender<LogEntry>,
+    object_store: ObjectStoreClient,
+    log_tx: tokio::sync::mpsc::Sender<AppLogEntry>,
+    log_tails: LogTails,
     isolate_config: IsolateConfig,
+
+    /// V8 code caches, keyed by bundle id and V8 version.
+    code_cache: std::sync::Mutex<LruCache<String, Arc<[u8]>>>,
+
+    /// Keys recently not found in the shared code cache, so that instances of a bundle that has
+    /// no code cache yet do not query the database on every start.
+    code_cache_misses: std::sync::Mutex<LruCache<String, ()>>,
 }
 
 struct WorkerState {
@@ -32,12 +46,6 @@ struct WorkerState {
     memory_bytes: AtomicUsize,
 }
 
-struct LogEntry {
-    appid: String,
-    time: SystemTime,
-    text: String,
-}
-
 pub struct InstanceStatistics {
     pub used_memory_bytes: usize,
 }
@@ -57,12 +65,30 @@ impl Runtime {
         let max_isolate_memory_bytes = config.max_isolate_memory_bytes;
         let isolate_pool_size = config.isolate_pool_size;
         let execution_concurrency = config.execution_concurrency;
+        let code_cache_size = config.code_cache_size;
 
         let data_client = DataClient::new(&config.db_url).await?;
+        data_client.check_schema().await?;
+        let object_store =
+            ObjectStoreClient::new(&config.object_store, data_client.clone()).await?;
+
+        let librt_snapshot = if config.disable_librt_snapshot {
+            None
+        } else {
+            let snapshot = tokio::task::spawn_blocking(LibrtSnapshot::load)
+                .await
+                .ok()
+                .flatten();
+            if snapshot.is_none() {
+                warn!("falling back to evaluating librt in each context");
+            }
+            snapshot
+        };
 
         let isolate_config = IsolateConfig {
             max_memory_bytes: max_isolate_memory_bytes,
             host_entry_threshold_memory_bytes: 1048576,
+            librt_snapshot,
         };
         let isolate_pool = IsolateThreadPool::new(isolate_pool_size, isolate_config.clone()).await;
 
@@ -78,7 +104,14 @@ impl Runtime {
             isolate_config,
             execution_token: Semaphore::new(execution_concurrency),
             data_client,
+            object_store,
             log_tx,
+            log_tails: LogTails::default(),
+            code_cache: std::sync::Mutex::new(LruCache::with_capacity(code_cache_size)),
+            code_cache_misses: std::sync::Mutex::new(LruCache::with_expiry_duration_and_capacity(
+                CODE_CACHE_MISS_TTL,
+                code_cache_size,
+            )),
         });
         let rt_weak = Arc::downgrade(&rt);
         tokio::spawn(statistics_update_worker(rt_weak, statistics_update_rx));
@@ -96,14 +129,99 @@ impl Runtime {
             .ok_or_else(|| GenericError::Other("timeout waiting for execution token".into()))
     }
 
+    /// How long a worker may stay paused in the debugger.
+    pub fn inspector_max_pause(&self) -> Duration {
+        Duration::from_millis(self.config.inspector_max_pause_ms)
+    }
+
     pub fn data_client(&self) -> &DataClient {
         &self.data_client
     }
 
+    pub fn object_store(&self) -> &ObjectStoreClient {
+        &self.object_store
+    }
+
     pub fn isolate_config(&self) -> &IsolateConfig {
         &self.isolate_config
     }
 
+    /// Looks up the code cache for a bundle, first in memory and then in the database.
+    async fn lookup_code_cache(&self, bundle_id: &str) -> Option<Arc<[u8]>> {
+        if bundle_id.is_empty() {
+            return None;
+        }
+
+        let key = code_cache_key(bundle_id);
+        if let Some(x) = self.code_cache.lock().unwrap().get(&key) {
+            return Some(x.clone());
+        }
+
+        if !self.config.share_code_cache {
+            return None;
+        }
+
+        if self.code_cache_misses.lock().unwrap().get(&key).is_some() {
+            return None;
+        }
+
+        match self
+            .data_client
+            .app_bundle_code_cache_get(bundle_id, v8::V8::get_version())
+            .await
+        {
+            Ok(Some(x)) => {
+                let x: Arc<[u8]> = Arc::from(x);
+                self.code_cache.lock().unwrap().insert(key, x.clone());
+                Some(x)
+            }
+            Ok(None) => {
+                self.code_cache_misses.lock().unwrap().insert(key, ());
+                None
+            }
+            Err(e) => {
+                warn!("cannot fetch code cache for bundle {}: {:?}", bundle_id, e);
+                None
+            }
+        }
+    }
+
+    /// Saves a code cache produced by an instance. Called from the isolate thread.
+    pub fn store_code_cache(
+        self: &Arc<Self>,
+        rt: &tokio::runtime::Handle,
+        bundle_id: &str,
+        cache: Vec<u8>,
+    ) {
+        if bundle_id.is_empty() {
+            return;
+        }
+
+        debug!(
+            "storing code cache of size {} for bundle {}",
+            cache.len(),
+            bundle_id
+        );
+        let cache: Arc<[u8]> = Arc::from(cache);
+        let key = code_cache_key(bundle_id);
+        self.code_cache_misses.lock().unwrap().remove(&key);
+        self.code_cache.lock().unwrap().insert(key, cache.clone());
+
+        if self.config.share_code_cache {
+            let this = self.clone();
+            let bundle_id = bundle_id.to_string();
+            rt.spawn(async move {
+                if let Err(e) = this
+                    .data_client
+                    .app_bundle_code_cache_put(&bundle_id, v8::V8::get_version(), &cache)
+                    .await
+                {
+                    warn!("cannot store code cache for bundle {}: {:?}", bundle_id, e);
+                }
+            });
+        }
+    }
+
     fn instance_thread(
         isolate: &mut v8::ContextScope<'_, v8::HandleScope<'_>>,
         rt: tokio::runtime::Handle,
@@ -111,6 +229,7 @@ impl Runtime {
         worker_handle: WorkerHandle,
         appid: String,
         bundle: Vec<u8>,
+        code_cache: Option<Arc<[u8]>>,
         configuration: &WorkerConfiguration,
         result_tx: oneshot::Sender<Result<(InstanceHandle, InstanceTimeControl), GenericError>>,
     ) {
@@ -121,6 +240,7 @@ impl Runtime {
             worker_handle.clone(),
             appid,
             bundle,
+            code_cache,
             configuration,
         ) {
             Ok((mut instance, handle, timectl)) => {
@@ -143,33 +263,69 @@ impl Runtime {
             }
         }
     }
+    /// Enforces the CPU time budget of each task of an instance.
+    ///
+    /// CPU time is read from the clock of the isolate thread while script code runs, so time
+    /// spent waiting for I/O or an execution token is not charged. A wall-clock ceiling of
+    /// `max_wall_time_factor` times the budget catches cases where the CPU clock doesn't advance.
+    ///
+    /// Exceeding the budget of any task terminates the whole instance.
     async fn monitor_task(
         self: Arc<Self>,
         worker_handle: WorkerHandle,
         mut timectl: InstanceTimeControl,
     ) {
-        let mut deadline = None;
+        // The running task and its current slice.
+        let mut slice: Option<(u64, RunningSlice)> = None;
+        let mut paused_task: Option<u64> = None;
+
+        // Remaining (CPU, wall) budgets of tasks.
+        let mut budgets: BTreeMap<u64, (Duration, Duration)> = BTreeMap::new();
         let initial_budget = timectl.budget;
+        let initial_wall_budget = initial_budget * self.config.max_wall_time_factor;
+        let cpu_clock = timectl.cpu_clock;
 
         loop {
+            let deadline = slice.as_ref().map(|(task_id, slice)| {
+                let (budget, wall_budget) = budgets
+                    .get(task_id)
+                    .copied()
+                    .unwrap_or((initial_budget, initial_wall_budget));
+                slice.next_check(cpu_clock, budget, wall_budget)
+            });
             tokio::select! {
                 op = timectl.timer_rx.recv() => {
                     if let Some(op) = op {
                         match op {
-                            TimerControl::Start => {
-                                deadline = Some(tokio::time::Instant::now() + timectl.budget);
+                            TimerControl::Start(task_id) => {
+                                budgets.entry(task_id).or_insert((initial_budget, initial_wall_budget));
+                                slice = Some((task_id, RunningSlice::start(cpu_clock)));
                             }
                             TimerControl::Stop => {
-                                let now = tokio::time::Instant::now();
-                                if let Some(deadline) = deadline {
-                                    // Restore unused time budget
-                                    timectl.budget = if now > deadline { Duration::from_millis(0) } else { deadline - now };
-                                    debug!("remaining time budget: {:?}", timectl.budget);
+                                if let Some((task_id, slice)) = slice.take() {
+                                    charge_slice(&mut budgets, task_id, &slice, cpu_clock);
+                                }
+                            }
+                            TimerControl::Pause => {
+                                if let Some((task_id, slice)) = slice.take() {
+                                    charge_slice(&mut budgets, task_id, &slice, cpu_clock);
+                                    paused_task = Some(task_id);
+                                }
+                            }
+                            TimerControl::Resume => {
+                                if let Some(task_id) = paused_task.take() {
+                                    slice = Some((task_id, RunningSlice::start(cpu_clock)));
                                 }
-                                deadline = None;
                             }
-                            TimerControl::Reset => {
-                                timectl.budget = initial_budget;
+                            TimerControl::Reset(task_id) => {
+                                if let Some((budget, _)) = budgets.remove(&task_id) {
+                                    debug!(
+                                        "worker {} task {} used {:?} of CPU time",
+                                        worker_handle.id,
+                                        task_id,
+                                        initial_budget.checked_sub(budget).unwrap_or_default(),
+                                    );
+                                }
                             }
                         }
                     } else {
@@ -183,7 +339,20 @@ impl Runtime {
                     }
                 }
                 _ = wait_until(deadline) => {
-                    info!("worker {} timed out", worker_handle.id);
+                    let (task_id, slice) = slice.as_ref().unwrap();
+                    let (budget, wall_budget) = budgets
+                        .get(task_id)
+                        .copied()
+                        .unwrap_or((initial_budget, initial_wall_budget));
+                    let (cpu_time, wall_time) = slice.elapsed(cpu_clock);
+                    if cpu_time < budget && wall_time < wall_budget {
+                        // Not exhausted yet. Check again later.
+                        continue;
+                    }
+                    info!(
+                        "worker {} timed out (task {}, cpu time {:?}, wall time {:?})",
+                        worker_handle.id, task_id, cpu_time, wall_time
+                    );
 
                     if let Some(handle) = self.instances.write().await.remove(&worker_handle) {
                         handle.handle.terminate_for_time_limit().await;
@@ -217,7 +386,7 @@ impl Runtime {
         &self,
         worker_handle: &WorkerHandle,
         req: RequestObject,
-    ) -> ExecutionResult<ResponseObject> {
+    ) -> ExecutionResult<FetchResult> {
         // write() lock for LRU update
         let instance = self
             .instances
@@ -229,17 +398,51 @@ impl Runtime {
         instance.fetch(req).await
     }
 
+    pub async fn kv_change(
+        &self,
+        worker_handle: &WorkerHandle,
+        batch: KvChangeBatch,
+    ) -> ExecutionResult<ResourceUsage> {
+        let instance = self
+            .instances
+            .write()
+            .await
+            .get(&worker_handle)
+            .map(|x| x.handle.clone())
+            .ok_or_else(|| ExecutionError::NoSuchWorker)?;
+        instance.kv_change(batch).await
+    }
+
+    pub async fn inspector_url(
+        &self,
+        worker_handle: &WorkerHandle,
+    ) -> ExecutionResult<Option<String>> {
+        self.instances
+            .read()
+            .await
+            .peek(&worker_handle)
+            .map(|x| x.handle.inspector_url().map(|x| x.to_string()))
+            .ok_or(ExecutionError::NoSuchWorker)
+    }
+
     pub async fn spawn(
         self: &Arc<Self>,
         appid: String,
         bundle: Vec<u8>,
         configuration: &WorkerConfiguration,
     ) -> GenericResult<WorkerHandle> {
+        if configuration.inspector && !self.config.enable_inspector {
+            return Err(GenericError::Other(
+                "inspector is not enabled on this runtime".into(),
+            ));
+        }
+
         let (result_tx, result_rx) = oneshot::channel();
         let worker_handle = WorkerHandle::generate();
         let this = self.clone();
         let worker_handle_2 = worker_handle.clone();
         let configuration = configuration.clone();
+        let code_cache = self.lookup_code_cache(&configuration.bundle_id).await;
         let rt = tokio::runtime::Handle::current();
         tokio::spawn(async move {
             let this2 = this.clone();
@@ -253,6 +456,7 @@ impl Runtime {
                         worker_handle_2,
                         appid,
                         bundle,
+                        code_cache,
                         &configuration,
                         result_tx,
                     )
@@ -325,15 +529,99 @@ impl Runtime {
         if remove_count > 0 {
             info!("gc: removed {} instances", remove_count);
         }
+        self.log_tails.gc();
     }
 
-    pub fn write_log(&self, appid: impl Into<String>, text: impl Into<String>) {
-        drop(self.log_tx.try_send(LogEntry {
-            appid: appid.into(),
-            time: SystemTime::now(),
-            text: text.into(),
+    pub fn write_log(&self, appid: impl Into<String>, level: LogLevel, text: impl Into<String>) {
+        let appid = appid.into();
+        let text = text.into();
+        self.log_tails.publish(&appid, level, &text);
+        drop(self.log_tx.try_send(AppLogEntry {
+            appid,
+            time: current_millis(),
+            subid: rand::random(),
+            text,
         }));
     }
+
+    pub async fn tail_logs(
+        &self,
+        appid: &str,
+        filter: &LogFilter,
+        after: u64,
+        timeout_ms: u64,
+    ) -> LogTail {
+        self.log_tails
+            .tail(appid, filter, after, Duration::from_millis(timeout_ms))
+            .await
+    }
+}
+
+/// An interval during which script code is running.
+struct RunningSlice {
+    cpu_start: Option<Duration>,
+    wall_start: tokio::time::Instant,
+}
+
+impl RunningSlice {
+    fn start(cpu_clock: Option<ThreadCpuClock>) -> Self {
+        Self {
+            cpu_start: cpu_clock.and_then(|x| x.now()),
+            wall_start: tokio::time::Instant::now(),
+        }
+    }
+
+    /// Returns the CPU time and wall time elapsed since the start of this slice.
+    ///
+    /// Falls back to wall time if the CPU clock cannot be read.
+    fn elapsed(&self, cpu_clock: Option<ThreadCpuClock>) -> (Duration, Duration) {
+        let wall_time = tokio::time::Instant::now().duration_since(self.wall_start);
+        let cpu_time = match (self.cpu_start, cpu_clock.and_then(|x| x.now())) {
+            (Some(start), Some(now)) => now.checked_sub(start).unwrap_or_default(),
+            _ => wall_time,
+        };
+        (cpu_time, wall_time)
+    }
+
+    /// Returns the earliest time at which either budget can be exhausted.
+    ///
+    /// A thread cannot consume CPU time faster than wall time, so it is enough to check again
+    /// after the remaining CPU budget has elapsed on the wall clock.
+    fn next_check(
+        &self,
+        cpu_clock: Option<ThreadCpuClock>,
+        cpu_budget: Duration,
+        wall_budget: Duration,
+    ) -> tokio::time::Instant {
+        let (cpu_time, wall_time) = self.elapsed(cpu_clock);
+        let remaining = (cpu_budget.checked_sub(cpu_time).unwrap_or_default())
+            .min(wall_budget.checked_sub(wall_time).unwrap_or_default())
+            .max(Duration::from_millis(1));
+        tokio::time::Instant::now() + remaining
+    }
+}
+
+/// Deducts the time used by a finished slice from the budget of its task.
+fn charge_slice(
+    budgets: &mut BTreeMap<u64, (Duration, Duration)>,
+    task_id: u64,
+    slice: &RunningSlice,
+    cpu_clock: Option<ThreadCpuClock>,
+) {
+    if let Some((budget, wall_budget)) = budgets.get_mut(&task_id) {
+        let (cpu_time, wall_time) = slice.elapsed(cpu_clock);
+        *budget = budget.checked_sub(cpu_time).unwrap_or_default();
+        *wall_budget = wall_budget.checked_sub(wall_time).unwrap_or_default();
+        debug!("remaining time budget of task {}: {:?}", task_id, budget);
+    }
+}
+
+/// How long a miss in the shared code cache is remembered. Another runtime may store a code cache
+/// for the bundle in the meantime.
+const CODE_CACHE_MISS_TTL: Duration = Duration::from_secs(60);
+
+fn code_cache_key(bundle_id: &str) -> String {
+    format!("{}:{}", bundle_id, v8::V8::get_version())
 }
 
 async fn wait_until(deadline: Option<tokio::time::Instant>) {
@@ -344,16 +632,22 @@ async fn wait_until(deadline: Option<tokio::time::Instant>) {
     }
 }
 
-async fn log_worker(rt: Arc<Runtime>, mut rx: tokio::sync::mpsc::Receiver<LogEntry>) {
+/// Maximum number of log lines written to the database in one batch.
+const LOG_BATCH_SIZE: usize = 200;
+
+/// Maximum number of log batches being written at the same time.
+const LOG_WRITE_CONCURRENCY: usize = 4;
+
+async fn log_worker(rt: Arc<Runtime>, mut rx: tokio::sync::mpsc::Receiver<AppLogEntry>) {
     // Mux
-    let channels = (0..16)
+    let channels = (0..LOG_WRITE_CONCURRENCY)
         .map(|_| {
             let rt = Arc::downgrade(&rt);
-            let (tx, mut rx): (Sender<Arc<LogEntry>>, Receiver<Arc<LogEntry>>) =
-                tokio::sync::mpsc::channel(16);
+            let (tx, mut rx): (Sender<Vec<AppLogEntry>>, Receiver<Vec<AppLogEntry>>) =
+                tokio::sync::mpsc::channel(1);
             tokio::spawn(async move {
                 loop {
-                    let entry = if let Some(x) = rx.recv().await {
+                    let batch = if let Some(x) = rx.recv().await {
                         x
                     } else {
                         break;
@@ -363,10 +657,9 @@ async fn log_worker(rt: Arc<Runtime>, mut rx: tokio::sync::mpsc::Receiver<LogEnt
                     } else {
                         break;
                     };
-                    let _ = rt
-                        .data_client
-                        .applog_write(&entry.appid, entry.time, &entry.text)
-                        .await;
+                    if let Err(e) = rt.data_client.applog_write_many(&batch).await {
+                        warn!("log_worker: failed to write {} lines: {:?}", batch.len(), e);
+                    }
                 }
             });
             tx
@@ -375,15 +668,27 @@ async fn log_worker(rt: Arc<Runtime>, mut rx: tokio::sync::mpsc::Receiver<LogEnt
     drop(rt);
     loop {
         let entry = if let Some(x) = rx.recv().await {
-            Arc::new(x)
+            x
         } else {
             break;
         };
-        let futures = channels
+
+        // Lines logged while all writers were busy go into the same batch.
+        let mut batch = vec![entry];
+        while batch.len() < LOG_BATCH_SIZE {
+            match futures::FutureExt::now_or_never(rx.recv()) {
+                Some(Some(x)) => batch.push(x),
+                _ => break,
+            }
+        }
+
+        let permits = channels
             .iter()
-            .map(|x| Box::pin(x.send(entry.clone())))
+            .map(|x| Box::pin(x.reserve()))
             .collect::<Vec<_>>();
-        let _ = futures::future::select_all(futures.into_iter()).await;
+        if let (Ok(permit), _, _) = futures::future::select_all(permits.into_iter()).await {
+            permit.send(batch);
+        }
     }
 }
 
@@ -37,13 +37,44 @@ impl rusty_workers::rpc::RuntimeService for RuntimeServer {
         _: tarpc::context::Context,
         handle: WorkerHandle,
         req: RequestObject,
-    ) -> ExecutionResult<ResponseObject> {
+    ) -> ExecutionResult<FetchResult> {
         self.runtime.fetch(&handle, req).await
     }
 
+    async fn kv_change(
+        self,
+        _: tarpc::context::Context,
+        handle: WorkerHandle,
+        batch: KvChangeBatch,
+    ) -> ExecutionResult<ResourceUsage> {
+        self.runtime.kv_change(&handle, batch).await
+    }
+
     async fn load(self, _: tarpc::context::Context) -> GenericResult<u16> {
         self.runtime.load().await
     }
+
+    async fn inspector_url(
+        self,
+        _: tarpc::context::Context,
+        handle: WorkerHandle,
+    ) -> ExecutionResult<Option<String>> {
+        self.runtime.inspector_url(&handle).await
+    }
+
+    async fn tail_logs(
+        self,
+        _: tarpc::context::Context,
+        appid: String,
+        filter: LogFilter,
+        after: u64,
+        timeout_ms: u64,
+    ) -> GenericResult<LogTail> {
+        Ok(self
+            .runtime
+            .tail_logs(&appid, &filter, after, timeout_ms)
+            .await)
+    }
 }
 
 rusty_workers::impl_listen!(RuntimeServer, rusty_workers::rpc::RuntimeService);
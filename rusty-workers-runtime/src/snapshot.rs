@@ -0,0 +1,54 @@
+//! Creation of the librt startup snapshot.
+//!
+//! Shared with the build script, which embeds a snapshot in the binary, so this module only
+//! depends on `rusty_v8`.
+
+use rusty_v8 as v8;
+
+/// Evaluates `librt` in a fresh isolate and serializes the resulting heap.
+///
+/// Must be called after V8 is initialized.
+pub fn create_librt_snapshot(librt: &str) -> Result<Vec<u8>, &'static str> {
+    let mut creator = v8::SnapshotCreator::new(None);
+
+    // The isolate is owned by `creator` and must not be dropped by us.
+    let mut isolate = unsafe { creator.get_owned_isolate() };
+    let ok = {
+        let scope = &mut v8::HandleScope::new(&mut isolate);
+        let context = v8::Context::new(scope);
+        let ok = {
+            let context_scope = &mut v8::ContextScope::new(scope, context);
+            let scope = &mut v8::HandleScope::new(context_scope);
+            let try_catch = &mut v8::TryCatch::new(scope);
+            let librt = v8::String::new(try_catch, librt).unwrap();
+            let ok = match v8::Script::compile(try_catch, librt, None) {
+                Some(librt) => {
+                    init_librt_global(try_catch);
+                    librt.run(try_catch).is_some()
+                }
+                None => false,
+            };
+            ok && !try_catch.has_caught()
+        };
+        if ok {
+            creator.set_default_context(context);
+        }
+        ok
+    };
+    std::mem::forget(isolate);
+
+    if !ok {
+        return Err("librt threw an exception during snapshot creation");
+    }
+    match creator.create_blob(v8::FunctionCodeHandling::Keep) {
+        Some(blob) => Ok(blob.to_vec()),
+        None => Err("cannot create librt snapshot blob"),
+    }
+}
+
+/// Makes the global object available as `global` before librt runs.
+pub fn init_librt_global(scope: &mut v8::HandleScope<'_>) {
+    let global_key = v8::String::new(scope, "global").unwrap();
+    let global_obj = scope.get_current_context().global(scope);
+    global_obj.set(scope, global_key.into(), global_obj.into());
+}
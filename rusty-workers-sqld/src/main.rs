@@ -0,0 +1,45 @@
+#[macro_use]
+extern crate log;
+
+mod server;
+
+use anyhow::Result;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "rusty-workers-sqld", about = "Rusty Workers (sqld)")]
+struct Opt {
+    /// RPC listen address.
+    #[structopt(short = "l", long)]
+    rpc_listen: SocketAddr,
+
+    /// Directory that holds app databases, one SQLite file per app.
+    #[structopt(long, env = "RW_SQLD_DATA_DIR")]
+    data_dir: PathBuf,
+
+    /// Max number of databases kept open.
+    #[structopt(long, env = "RW_MAX_OPEN_DATABASES", default_value = "1000")]
+    max_open_databases: usize,
+
+    /// Max concurrency.
+    #[structopt(long, env = "RW_MAX_CONCURRENCY", default_value = "1000")]
+    max_concurrency: usize,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    pretty_env_logger::init_timed();
+    rusty_workers::init();
+
+    let opt = Opt::from_args();
+
+    let state = server::SqlState::new(opt.data_dir, opt.max_open_databases)?;
+    server::SqlServer::listen(&opt.rpc_listen, opt.max_concurrency, move || {
+        server::SqlServer::new(state.clone())
+    })
+    .await?;
+
+    Ok(())
+}
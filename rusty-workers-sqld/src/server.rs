@@ -0,0 +1,261 @@
+use anyhow::Result;
+use lru_time_cache::LruCache;
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::{ffi, limits::Limit, Connection, ErrorCode, TransactionBehavior};
+use rusty_workers::tarpc;
+use rusty_workers::types::*;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Max total size of the rows returned by a batch.
+const MAX_RESULT_BYTES: usize = 16 * 1024 * 1024;
+
+/// Time to wait for a database locked by another process.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of virtual machine instructions between time budget checks.
+const PROGRESS_CHECK_INTERVAL: i32 = 1000;
+
+/// Errors caused by the statements of a batch. Reported to the worker.
+#[derive(Error, Debug)]
+enum SqlError {
+    #[error("statement returned more than {0} rows")]
+    RowLimitExceeded(u32),
+
+    #[error("result too large")]
+    ResultTooLarge,
+
+    #[error("time budget exceeded")]
+    TimeBudgetExceeded,
+
+    #[error("transaction control statements are not allowed")]
+    TransactionControl,
+
+    #[error("{0}")]
+    Sqlite(rusqlite::Error),
+}
+
+pub struct SqlState {
+    data_dir: PathBuf,
+    databases: Mutex<LruCache<String, Arc<Database>>>,
+}
+
+/// An open app database. Batches on the same database are serialized.
+struct Database {
+    conn: Mutex<Connection>,
+}
+
+#[derive(Clone)]
+pub struct SqlServer {
+    state: Arc<SqlState>,
+}
+
+impl SqlServer {
+    pub fn new(state: Arc<SqlState>) -> Self {
+        SqlServer { state }
+    }
+}
+
+#[tarpc::server]
+impl rusty_workers::rpc::SqlService for SqlServer {
+    async fn execute(
+        self,
+        _: tarpc::context::Context,
+        appid: String,
+        statements: Vec<SqlStatement>,
+        limits: SqlLimits,
+    ) -> GenericResult<Result<Vec<SqlResult>, String>> {
+        let db = self.state.open(&appid).await?;
+        let res = tokio::task::spawn_blocking(move || {
+            let mut conn = db.conn.lock().unwrap();
+            run_batch(&mut conn, statements, &limits)
+        })
+        .await
+        .map_err(|e| GenericError::Other(format!("sql task failed: {:?}", e)))?;
+        match res {
+            Ok(x) => Ok(Ok(x)),
+            Err(e) => {
+                debug!("sql batch of app {} failed: {}", appid, e);
+                Ok(Err(e.to_string()))
+            }
+        }
+    }
+}
+
+rusty_workers::impl_listen!(SqlServer, rusty_workers::rpc::SqlService);
+
+impl SqlState {
+    pub fn new(data_dir: PathBuf, max_open_databases: usize) -> Result<Arc<Self>> {
+        std::fs::create_dir_all(&data_dir)?;
+        Ok(Arc::new(Self {
+            data_dir,
+            databases: Mutex::new(LruCache::with_capacity(max_open_databases)),
+        }))
+    }
+
+    /// Returns the database of `appid`, opening or creating it if needed.
+    async fn open(&self, appid: &str) -> GenericResult<Arc<Database>> {
+        if let Some(db) = self.databases.lock().unwrap().get(appid) {
+            return Ok(db.clone());
+        }
+
+        let path = self.data_dir.join(format!("{}.db", hex::encode(appid)));
+        let db = tokio::task::spawn_blocking(move || -> GenericResult<Database> {
+            let conn = Connection::open(&path)?;
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            conn.pragma_update_and_check(None, "journal_mode", &"WAL", |_| Ok(()))?;
+
+            // Apps must not reach other files.
+            conn.set_limit(Limit::SQLITE_LIMIT_ATTACHED, 0);
+
+            Ok(Database {
+                conn: Mutex::new(conn),
+            })
+        })
+        .await
+        .map_err(|e| GenericError::Other(format!("sql task failed: {:?}", e)))??;
+
+        // Another request may have opened the database concurrently. Keep the cached one so
+        // that batches on the same database stay serialized.
+        let mut databases = self.databases.lock().unwrap();
+        if let Some(existing) = databases.get(appid) {
+            return Ok(existing.clone());
+        }
+        let db = Arc::new(db);
+        databases.insert(appid.to_string(), db.clone());
+        Ok(db)
+    }
+}
+
+/// Runs a batch of statements in one transaction. The transaction is rolled back if any
+/// statement fails.
+fn run_batch(
+    conn: &mut Connection,
+    statements: Vec<SqlStatement>,
+    limits: &SqlLimits,
+) -> Result<Vec<SqlResult>, SqlError> {
+    let deadline = Instant::now() + Duration::from_millis(limits.time_budget_ms);
+    conn.progress_handler(
+        PROGRESS_CHECK_INTERVAL,
+        Some(move || Instant::now() >= deadline),
+    );
+    let res = run_batch_in_transaction(conn, statements, limits);
+    conn.progress_handler(PROGRESS_CHECK_INTERVAL, None::<fn() -> bool>);
+    res
+}
+
+fn run_batch_in_transaction(
+    conn: &mut Connection,
+    statements: Vec<SqlStatement>,
+    limits: &SqlLimits,
+) -> Result<Vec<SqlResult>, SqlError> {
+    let tx = conn
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .map_err(map_sqlite_error)?;
+    let mut result_bytes = 0usize;
+    let mut results = Vec::with_capacity(statements.len());
+
+    for stmt in statements {
+        if is_transaction_control(&stmt.sql) {
+            return Err(SqlError::TransactionControl);
+        }
+
+        let mut prepared = tx.prepare(&stmt.sql).map_err(map_sqlite_error)?;
+        let columns: Vec<String> = prepared
+            .column_names()
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect();
+        let params = stmt.params.into_iter().map(to_sqlite_value);
+        let mut rows = prepared
+            .query(rusqlite::params_from_iter(params))
+            .map_err(map_sqlite_error)?;
+
+        let mut out = vec![];
+        while let Some(row) = rows.next().map_err(map_sqlite_error)? {
+            if out.len() >= limits.max_rows as usize {
+                return Err(SqlError::RowLimitExceeded(limits.max_rows));
+            }
+            let mut values = Vec::with_capacity(columns.len());
+            for i in 0..columns.len() {
+                let value = from_sqlite_value(row.get_ref(i).map_err(map_sqlite_error)?);
+                result_bytes += value_size(&value);
+                values.push(value);
+            }
+            if result_bytes > MAX_RESULT_BYTES {
+                return Err(SqlError::ResultTooLarge);
+            }
+            out.push(values);
+        }
+        drop(rows);
+        drop(prepared);
+
+        results.push(SqlResult {
+            columns,
+            rows: out,
+            changes: tx.changes(),
+            last_insert_rowid: tx.last_insert_rowid(),
+        });
+    }
+
+    tx.commit().map_err(map_sqlite_error)?;
+    Ok(results)
+}
+
+/// Returns whether a statement begins or ends a transaction. Batches already run in a
+/// transaction managed by the service.
+fn is_transaction_control(sql: &str) -> bool {
+    let keyword = sql
+        .trim_start()
+        .split(|c: char| !c.is_ascii_alphabetic())
+        .next()
+        .unwrap_or("")
+        .to_ascii_uppercase();
+    matches!(
+        keyword.as_str(),
+        "BEGIN" | "COMMIT" | "END" | "ROLLBACK" | "SAVEPOINT" | "RELEASE"
+    )
+}
+
+fn map_sqlite_error(e: rusqlite::Error) -> SqlError {
+    match e {
+        rusqlite::Error::SqliteFailure(
+            ffi::Error {
+                code: ErrorCode::OperationInterrupted,
+                ..
+            },
+            _,
+        ) => SqlError::TimeBudgetExceeded,
+        _ => SqlError::Sqlite(e),
+    }
+}
+
+fn to_sqlite_value(value: SqlValue) -> Value {
+    match value {
+        SqlValue::Null => Value::Null,
+        SqlValue::Integer(x) => Value::Integer(x),
+        SqlValue::Real(x) => Value::Real(x),
+        SqlValue::Text(x) => Value::Text(x),
+        SqlValue::Blob(x) => Value::Blob(x),
+    }
+}
+
+fn from_sqlite_value(value: ValueRef<'_>) -> SqlValue {
+    match value {
+        ValueRef::Null => SqlValue::Null,
+        ValueRef::Integer(x) => SqlValue::Integer(x),
+        ValueRef::Real(x) => SqlValue::Real(x),
+        ValueRef::Text(x) => SqlValue::Text(String::from_utf8_lossy(x).into_owned()),
+        ValueRef::Blob(x) => SqlValue::Blob(x.to_vec()),
+    }
+}
+
+fn value_size(value: &SqlValue) -> usize {
+    match value {
+        SqlValue::Null | SqlValue::Integer(_) | SqlValue::Real(_) => 8,
+        SqlValue::Text(x) => x.len(),
+        SqlValue::Blob(x) => x.len(),
+    }
+}
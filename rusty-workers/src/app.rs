@@ -13,6 +13,19 @@ pub struct AppConfig {
 
     #[serde(default)]
     pub kv_namespaces: Vec<KvNamespaceConfig>,
+
+    #[serde(default)]
+    pub buckets: Vec<BucketConfig>,
+
+    /// How long to keep the app's logs, in seconds. Defaults to the retention configured on the
+    /// proxies.
+    #[serde(default)]
+    pub log_retention_secs: Option<u64>,
+}
+
+/// Returns the id of a bundle: the hex-encoded SHA-256 of its content.
+pub fn bundle_id(bundle: &[u8]) -> String {
+    hex::encode(ring::digest::digest(&ring::digest::SHA256, bundle))
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
@@ -21,6 +34,78 @@ pub struct KvNamespaceConfig {
     pub id: String,
 }
 
+/// Binds the object storage bucket `id` to the name `name` in an app.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct BucketConfig {
+    pub name: String,
+    pub id: String,
+}
+
+/// A registered KV namespace.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct KvNamespace {
+    pub id: String,
+
+    /// Id of the app that may bind this namespace.
+    pub owner: String,
+
+    pub name: String,
+
+    /// Creation time in milliseconds since the Unix epoch.
+    pub createtime: u64,
+
+    /// Maximum number of keys. 0 means unlimited.
+    pub max_keys: u64,
+
+    /// Maximum total size of keys, values and metadata in bytes. 0 means unlimited.
+    pub max_bytes: u64,
+
+    /// Number of keys stored, including expired ones that are not garbage-collected yet.
+    /// Maintained by the database; ignored by `kv_namespace_create`.
+    #[serde(default)]
+    pub used_keys: u64,
+
+    /// Total size of the keys, values and metadata stored, in bytes. Maintained like `used_keys`.
+    #[serde(default)]
+    pub used_bytes: u64,
+}
+
+/// A subscription of an app to changes in a KV namespace.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct KvWatch {
+    pub id: String,
+
+    /// Id of the app that receives the changes.
+    pub appid: String,
+
+    /// Id of the watched namespace. The app must bind it.
+    pub namespace_id: String,
+
+    /// Only changes to keys starting with this prefix are delivered.
+    #[serde(default)]
+    pub prefix: Vec<u8>,
+
+    /// Sequence number of the last change delivered.
+    pub cursor: u64,
+
+    /// Creation time in milliseconds since the Unix epoch.
+    pub createtime: u64,
+}
+
+/// A recorded version of an app's config and bundle.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Deployment {
+    /// Increases with each deployment of the app, starting at 1.
+    pub version: u64,
+
+    pub config: AppConfig,
+    pub author: String,
+    pub message: String,
+
+    /// Deployment time in milliseconds since the Unix epoch.
+    pub createtime: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub struct AppRoute {
     pub domain: String,
@@ -1,324 +1,659 @@
+//! Storage for apps, routes, bundles, logs and worker KV data.
+//!
+//! The storage backend is selected by the scheme of the database URL:
+//!
+//! - `mysql://...`: a MySQL-compatible database (MySQL, TiDB).
+//! - `sqlite://path/to/file.db`: a local SQLite database, for single-node setups and development.
+//! - `memory://`: an in-memory store that is lost on exit, for tests.
+//!
+//! The schema is versioned by numbered migrations, recorded in the `schema_migrations` table.
+//! MySQL databases are migrated with `rusty-workers-cli db migrate`; SQLite databases are migrated
+//! when they are opened. Services call `DataClient::check_schema` at startup and refuse to run
+//! against a schema older than `SCHEMA_VERSION`.
+
+mod memory;
+mod mysql;
+mod sqlite;
+#[cfg(test)]
+mod tests;
+
 use crate::{
-    app::{AppConfig, AppId},
+    app::{AppConfig, Deployment, KvNamespace, KvNamespaceConfig, KvWatch},
     types::*,
     util::current_millis,
 };
-use mysql_async::{params, prelude::Queryable, IsolationLevel, Pool, TxOpts};
-use rand::Rng;
-use std::time::SystemTime;
-use std::{
-    collections::BTreeMap,
-    time::{Duration, UNIX_EPOCH},
-};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+pub use memory::MemoryBackend;
+pub use mysql::MysqlBackend;
+pub use sqlite::SqliteBackend;
 
+/// A handle to the storage backend selected by the database URL.
+///
+/// Dereferences to the backend, so all `DataBackend` operations can be called on it directly.
+/// Clones share the same backend.
+#[derive(Clone)]
 pub struct DataClient {
-    db: Pool,
+    backend: Arc<dyn DataBackend>,
 }
 
-impl DataClient {
-    pub async fn new(db_url: &str) -> GenericResult<Self> {
-        let db = Pool::from_url(db_url)
-            .map_err(|e| GenericError::Other(format!("db connection failed: {:?}", e,)))?;
-        Ok(Self { db })
-    }
+/// Operations provided by a storage backend.
+///
+/// Expired KV entries must be treated as absent by all read operations.
+///
+/// Registered namespaces have counters of the keys and bytes they store, which every write to KV
+/// entries, including garbage collection, must update in the same transaction. Writes that would
+/// take a namespace over its quota fail with `GenericError::QuotaExceeded` and write nothing.
+///
+/// Every write to KV entries is recorded in a change log, in the same transaction as the write.
+/// Entries that expire or are garbage-collected are not recorded.
+///
+/// Blobs are large values stored apart from KV entries, in chunks of at most `BLOB_CHUNK_SIZE`
+/// bytes. They have their own key space within a namespace and are not recorded in the change
+/// log. A committed blob counts as one key towards the namespace quota, with the size of its key,
+/// data and metadata; uploaded chunks are not counted until then.
+#[async_trait]
+pub trait DataBackend: Send + Sync {
+    async fn worker_data_get(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+    ) -> GenericResult<Option<Vec<u8>>>;
 
-    pub async fn worker_data_get(
+    /// Returns the value and metadata of a key.
+    async fn worker_data_get_with_metadata(
         &self,
         namespace_id: &str,
         key: &[u8],
-    ) -> GenericResult<Option<Vec<u8>>> {
-        let mut conn = self.db.get_conn().await?;
-        let value: Option<Vec<u8>> = conn
-            .exec_first(
-                "select appvalue from appkv where nsid = ? and appkey = ? and (appexpiration = 0 or appexpiration > ?)",
-                (namespace_id, key, current_millis()),
-            )
-            .await?;
-        Ok(value)
-    }
+    ) -> GenericResult<Option<(Vec<u8>, Vec<u8>)>>;
+
+    /// Returns the values of multiple keys, in the same order as `keys`.
+    async fn worker_data_get_many(
+        &self,
+        namespace_id: &str,
+        keys: &[Vec<u8>],
+    ) -> GenericResult<Vec<Option<Vec<u8>>>>;
 
-    pub async fn worker_data_put(
+    async fn worker_data_put(
         &self,
         namespace_id: &str,
         key: &[u8],
         value: &[u8],
+        metadata: &[u8],
         if_not_exists: bool,
         ttl_ms: u64,
-    ) -> GenericResult<()> {
-        let mut conn = self.db.get_conn().await?;
-        let empty_md: &[u8] = &[];
-
-        let expiration = if ttl_ms != 0 {
-            current_millis()
-                .checked_add(ttl_ms)
-                .ok_or_else(|| GenericError::Other("expiration time overflow".into()))?
-        } else {
-            0u64
-        };
+    ) -> GenericResult<()>;
 
-        let prms = params! {
-            "nsid" => namespace_id,
-            "appkey" => key,
-            "appvalue" => value,
-            "appmetadata" => empty_md,
-            "appexpiration" => expiration,
-            "currenttime" => current_millis(),
-        };
-
-        if if_not_exists {
-            conn.exec_drop(
-                format!(
-                    "{} where not exists ({})",
-                    "replace into appkv (nsid, appkey, appvalue, appmetadata, appexpiration) select :nsid, :appkey, :appvalue, :appmetadata, :appexpiration",
-                    "select 1 from appkv where nsid = :nsid and appkey = :appkey and (appexpiration = 0 or appexpiration > :currenttime)"
-                ),
-                prms,
-            ).await?;
-        } else {
-            conn.exec_drop(
-                format!(
-                    "{} on duplicate key {}",
-                    "insert into appkv (nsid, appkey, appvalue, appmetadata, appexpiration) values(:nsid, :appkey, :appvalue, :appmetadata, :appexpiration)",
-                    "update appvalue = :appvalue, appexpiration = :appexpiration",
-                ),
-                prms,
-            ).await?;
-        }
-        Ok(())
-    }
+    /// Writes multiple entries in one statement. Later entries win over earlier ones with the same
+    /// key.
+    async fn worker_data_put_many(
+        &self,
+        namespace_id: &str,
+        entries: &[(Vec<u8>, Vec<u8>)],
+        ttl_ms: u64,
+    ) -> GenericResult<()>;
 
-    pub async fn worker_data_scan_keys(
+    async fn worker_data_scan_keys(
         &self,
         namespace_id: &str,
         start: &[u8],
         end: Option<&[u8]>,
         limit: u32,
-    ) -> GenericResult<Vec<Vec<u8>>> {
-        let mut conn = self.db.get_conn().await?;
-        let result: Vec<Vec<u8>> = if let Some(end) = end {
-            conn.exec(
-                "select appkey from appkv where nsid = ? and appkey between ? and ? and (appexpiration = 0 or appexpiration > ?) limit ?",
-                (namespace_id, start, end, current_millis(), limit),
-            )
-            .await?
-        } else {
-            conn.exec(
-                "select appkey from appkv where nsid = ? and appkey >= ? and (appexpiration = 0 or appexpiration > ?) limit ?",
-                (namespace_id, start, current_millis(), limit),
-            )
-            .await?
-        };
-        Ok(result)
-    }
+    ) -> GenericResult<Vec<Vec<u8>>>;
 
-    pub async fn worker_data_delete(&self, namespace_id: &str, key: &[u8]) -> GenericResult<()> {
-        let mut conn = self.db.get_conn().await?;
-        conn.exec_drop(
-            "delete from appkv where nsid = ? and appkey = ?",
-            (namespace_id, key),
-        )
-        .await?;
-        Ok(())
-    }
+    async fn worker_data_delete_many(
+        &self,
+        namespace_id: &str,
+        keys: &[Vec<u8>],
+    ) -> GenericResult<()>;
 
-    pub async fn worker_data_cmpupdate(
+    /// Atomically adds `delta` to the decimal integer stored at `key` and returns the new value.
+    ///
+    /// A missing or expired entry counts as 0. A non-zero `ttl_ms` resets the expiration time;
+    /// otherwise the current expiration time is kept.
+    async fn worker_data_increment(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+        delta: i64,
+        ttl_ms: u64,
+    ) -> GenericResult<i64>;
+
+    /// Lists entries whose keys start with `prefix`, in key order.
+    ///
+    /// Pagination is keyset-based: `after` is the last key of the previous page. Returns the
+    /// entries and whether the listing is complete.
+    async fn worker_data_list(
+        &self,
+        namespace_id: &str,
+        prefix: &[u8],
+        after: Option<&[u8]>,
+        limit: u32,
+        include_values: bool,
+        include_metadata: bool,
+    ) -> GenericResult<(Vec<KvListEntry>, bool)>;
+
+    async fn worker_data_delete(&self, namespace_id: &str, key: &[u8]) -> GenericResult<()>;
+
+    /// Deletes up to `batch_size` expired entries across all namespaces. Returns the number of
+    /// entries deleted.
+    ///
+    /// If `dry_run` is set, only counts the entries that would be deleted.
+    ///
+    /// The expiration condition is re-checked as each row is deleted, so this is safe to run
+    /// concurrently from multiple processes and never removes an entry that was rewritten in the
+    /// meantime.
+    async fn worker_data_delete_expired(
+        &self,
+        batch_size: u32,
+        dry_run: bool,
+    ) -> GenericResult<u64>;
+
+    /// Applies `writes` if every key in `assertions` currently holds the asserted value. Returns
+    /// whether the writes were applied.
+    async fn worker_data_cmpupdate(
         &self,
         namespace_id: &str,
         assertions: &[(Vec<u8>, Vec<u8>)],
         writes: &[(Vec<u8>, Vec<u8>)],
         ttl_ms: u64,
-    ) -> GenericResult<bool> {
-        let current_time = current_millis();
-        let expiration = if ttl_ms != 0 {
-            current_time
-                .checked_add(ttl_ms)
-                .ok_or_else(|| GenericError::Other("expiration time overflow".into()))?
-        } else {
-            0u64
-        };
+    ) -> GenericResult<bool>;
 
-        let mut opts = TxOpts::new();
-        opts.with_isolation_level(IsolationLevel::RepeatableRead);
-        let mut txn = self.db.start_transaction(opts).await?;
-        let stmt = txn
-            .prep("select 1 from appkv where nsid = ? and appkey = ? and appvalue = ? and (appexpiration = 0 or appexpiration > ?) for update")
-            .await?;
-        for (k, v) in assertions {
-            let existence: Option<u32> = txn
-                .exec_first(&stmt, (namespace_id, k, v, current_time))
-                .await?;
-            if existence.is_none() {
-                return Ok(false);
-            }
-        }
-        let empty_md: &[u8] = &[];
-        txn.exec_batch(
-            format!(
-                "{} on duplicate key {}",
-                "insert into appkv (nsid, appkey, appvalue, appmetadata, appexpiration) values(:nsid, :appkey, :appvalue, :appmetadata, :appexpiration)",
-                "update appvalue = :appvalue, appexpiration = :appexpiration",
-            ),
-            writes.iter().map(|(k, v)| params! {
-                "nsid" => namespace_id,
-                "appkey" => k,
-                "appvalue" => v,
-                "appmetadata" => empty_md,
-                "appexpiration" => expiration,
-            }).collect::<Vec<_>>(),
-        ).await?;
-        txn.commit().await?;
-        Ok(true)
-    }
+    /// Applies `writes` atomically if every key in `reads` still holds the value it was read with,
+    /// `None` meaning that the key was absent. Returns whether the writes were applied.
+    ///
+    /// Comparing values rather than versions is enough for serializability: a transaction whose
+    /// reads all still hold at commit time could have run entirely at that point.
+    async fn worker_data_commit(
+        &self,
+        namespace_id: &str,
+        reads: &[(Vec<u8>, Option<Vec<u8>>)],
+        writes: &[(Vec<u8>, KvMutation)],
+    ) -> GenericResult<bool>;
 
-    pub async fn route_mapping_delete_domain(&self, domain: &str) -> GenericResult<()> {
-        let mut conn = self.db.get_conn().await?;
-        conn.exec_drop("delete from routes where `domain` = ?", (domain,))
-            .await?;
-        Ok(())
-    }
+    /// Stores a chunk of a blob that is being uploaded. Fails if the chunk already exists, so that
+    /// committed blobs cannot be modified.
+    async fn worker_blob_put_chunk(
+        &self,
+        namespace_id: &str,
+        blob_id: &str,
+        index: u32,
+        data: &[u8],
+    ) -> GenericResult<()>;
 
-    pub async fn route_mapping_list_for_domain(
+    /// Atomically makes an uploaded blob the value of `key`, replacing and deleting the previous
+    /// blob. Returns false if the uploaded chunks don't add up to `blob.chunks` chunks of
+    /// `blob.size` bytes in total, or if the blob is already committed.
+    ///
+    /// `blob.expiration` is ignored; `ttl_ms` sets the expiration time.
+    async fn worker_blob_commit(
         &self,
-        domain: &str,
-    ) -> GenericResult<BTreeMap<String, String>> {
-        let mut conn = self.db.get_conn().await?;
-        let items: Vec<(String, String)> = conn
-            .exec(
-                "select path, appid from routes where `domain` = ?",
-                (domain,),
-            )
-            .await?;
+        namespace_id: &str,
+        key: &[u8],
+        blob: &KvBlob,
+        ttl_ms: u64,
+    ) -> GenericResult<bool>;
 
-        Ok(items.into_iter().collect())
-    }
+    /// Returns the blob stored at `key`.
+    async fn worker_blob_get(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+    ) -> GenericResult<Option<KvBlob>>;
+
+    /// Returns a chunk of a blob, or `None` if the blob has been deleted.
+    async fn worker_blob_get_chunk(
+        &self,
+        namespace_id: &str,
+        blob_id: &str,
+        index: u32,
+    ) -> GenericResult<Option<Vec<u8>>>;
+
+    async fn worker_blob_delete(&self, namespace_id: &str, key: &[u8]) -> GenericResult<()>;
+
+    /// Lists up to `limit` blobs whose keys start with `prefix` and are not less than `from`, in
+    /// key order.
+    async fn worker_blob_list(
+        &self,
+        namespace_id: &str,
+        prefix: &[u8],
+        from: &[u8],
+        limit: u32,
+    ) -> GenericResult<Vec<(Vec<u8>, KvBlob)>>;
 
-    pub async fn route_mapping_lookup(
+    /// Deletes up to `batch_size` expired blobs and chunks of uploads that were not committed
+    /// within `BLOB_UPLOAD_TIMEOUT_MS`. Returns the number of blobs and chunks deleted.
+    async fn worker_blob_delete_expired(&self, batch_size: u32) -> GenericResult<u64>;
+
+    /// Returns up to `limit` changes to keys starting with `prefix`, with sequence numbers greater
+    /// than `after`, in order.
+    async fn worker_data_changes(
+        &self,
+        namespace_id: &str,
+        prefix: &[u8],
+        after: u64,
+        limit: u32,
+    ) -> GenericResult<Vec<KvChange>>;
+
+    /// Returns the sequence number of the latest change in any namespace, or 0 if there is none.
+    async fn worker_data_changes_latest_seq(&self) -> GenericResult<u64>;
+
+    /// Deletes up to `batch_size` changes made before `before` (milliseconds since the Unix
+    /// epoch). Returns the number of changes deleted.
+    async fn worker_data_delete_changes(&self, before: u64, batch_size: u32) -> GenericResult<u64>;
+
+    async fn kv_watch_create(&self, watch: &KvWatch) -> GenericResult<()>;
+
+    /// Lists watches, optionally only those of `appid`.
+    async fn kv_watch_list(&self, appid: Option<&str>) -> GenericResult<Vec<KvWatch>>;
+
+    async fn kv_watch_delete(&self, id: &str) -> GenericResult<()>;
+
+    /// Records that changes up to `cursor` have been delivered to a watch.
+    async fn kv_watch_advance(&self, id: &str, cursor: u64) -> GenericResult<()>;
+
+    /// Registers a KV namespace. Fails if the id is already taken. The usage counters start from
+    /// the data already stored under the id.
+    async fn kv_namespace_create(&self, ns: &KvNamespace) -> GenericResult<()>;
+
+    async fn kv_namespace_get(&self, id: &str) -> GenericResult<Option<KvNamespace>>;
+
+    /// Lists registered namespaces, optionally only those owned by `owner`.
+    async fn kv_namespace_list(&self, owner: Option<&str>) -> GenericResult<Vec<KvNamespace>>;
+
+    /// Unregisters a namespace and deletes all its data, blobs, change log and watches.
+    async fn kv_namespace_delete(&self, id: &str) -> GenericResult<()>;
+
+    /// Registers the namespaces bound by apps deployed before namespaces had to be registered, and
+    /// returns them. See `bound_kv_namespaces`.
+    async fn kv_namespace_backfill(&self) -> GenericResult<Vec<KvNamespace>>;
+
+    async fn route_mapping_delete_domain(&self, domain: &str) -> GenericResult<()>;
+
+    async fn route_mapping_list_for_domain(
         &self,
         domain: &str,
-        path: &str,
-    ) -> GenericResult<Option<String>> {
-        let mut conn = self.db.get_conn().await?;
-        let appid: Option<String> = conn.exec_first(
-            "select appid from routes where `domain` = ? and ? like concat(`path`, '%') order by length(`path`) desc limit 1",
-            (domain, path)
-        ).await?;
-        // Most specific match
-        Ok(appid)
-    }
+    ) -> GenericResult<BTreeMap<String, String>>;
 
-    pub async fn route_mapping_insert(
+    /// Returns the app mapped to the longest path that is a prefix of `path`.
+    async fn route_mapping_lookup(&self, domain: &str, path: &str)
+        -> GenericResult<Option<String>>;
+
+    async fn route_mapping_insert(
         &self,
         domain: &str,
         path: &str,
         appid: String,
-    ) -> GenericResult<()> {
-        let mut conn = self.db.get_conn().await?;
-        conn.exec_drop(
-            "replace into routes (domain, path, appid, createtime) values(?, ?, ?, ?)",
-            (domain, path, appid, current_millis()),
-        )
-        .await?;
-        Ok(())
-    }
+    ) -> GenericResult<()>;
 
-    pub async fn route_mapping_delete(&self, domain: &str, path: &str) -> GenericResult<()> {
-        let mut conn = self.db.get_conn().await?;
-        conn.exec_drop(
-            "delete from routes where domain = ? and path = ?",
-            (domain, path),
-        )
-        .await?;
-        Ok(())
+    async fn route_mapping_delete(&self, domain: &str, path: &str) -> GenericResult<()>;
+
+    async fn app_metadata_get(&self, appid: &str) -> GenericResult<Option<AppConfig>>;
+
+    async fn app_metadata_put(&self, config: &AppConfig) -> GenericResult<()>;
+
+    async fn app_metadata_delete(&self, appid: &str) -> GenericResult<()>;
+
+    /// Records a new deployment of `config` and makes it the current config of the app, in one
+    /// transaction. Returns the version of the deployment.
+    async fn app_deploy(
+        &self,
+        config: &AppConfig,
+        author: &str,
+        message: &str,
+    ) -> GenericResult<u64>;
+
+    /// Returns the deployments of an app, newest first.
+    async fn app_deployment_list(&self, appid: &str) -> GenericResult<Vec<Deployment>>;
+
+    async fn app_deployment_get(
+        &self,
+        appid: &str,
+        version: u64,
+    ) -> GenericResult<Option<Deployment>>;
+
+    /// Deletes all but the newest `keep` deployments of an app. Returns the number of deployments
+    /// deleted.
+    async fn app_deployment_prune(&self, appid: &str, keep: u32) -> GenericResult<u64>;
+
+    async fn app_bundle_get(&self, id: &str) -> GenericResult<Option<Vec<u8>>>;
+
+    /// Stores a bundle. Bundle ids are derived from the content, so storing an existing bundle
+    /// only refreshes its creation time.
+    async fn app_bundle_put(&self, id: &str, value: &[u8]) -> GenericResult<()>;
+
+    /// Refreshes the creation time of a bundle, so that it is not garbage-collected before an app
+    /// references it. Returns whether the bundle exists.
+    async fn app_bundle_touch(&self, id: &str) -> GenericResult<bool>;
+
+    /// Returns the ids of up to `limit` bundles created before `created_before` that no app or
+    /// recorded deployment references.
+    async fn app_bundle_list_unreferenced(
+        &self,
+        created_before: u64,
+        limit: u32,
+    ) -> GenericResult<Vec<String>>;
+
+    /// Deletes a bundle and its code cache if it was created before `created_before` and no app or
+    /// recorded deployment references it. Returns whether it was deleted.
+    async fn app_bundle_delete_unreferenced(
+        &self,
+        id: &str,
+        created_before: u64,
+    ) -> GenericResult<bool>;
+
+    async fn app_bundle_code_cache_get(
+        &self,
+        bundle_id: &str,
+        v8_version: &str,
+    ) -> GenericResult<Option<Vec<u8>>>;
+
+    async fn app_bundle_code_cache_put(
+        &self,
+        bundle_id: &str,
+        v8_version: &str,
+        cache: &[u8],
+    ) -> GenericResult<()>;
+
+    /// Stores log lines. Lines whose app, time and subid are already stored are dropped.
+    async fn applog_write_many(&self, entries: &[AppLogEntry]) -> GenericResult<()>;
+
+    /// Returns up to `limit` log lines of `appid` logged in `[start, end)` (milliseconds since the
+    /// Unix epoch) and ordered after `after`, in order of time and subid.
+    async fn applog_list(
+        &self,
+        appid: &str,
+        start: u64,
+        end: u64,
+        after: Option<(u64, u32)>,
+        limit: u32,
+    ) -> GenericResult<Vec<AppLogEntry>>;
+
+    /// Returns the ids of the apps that have stored log lines.
+    async fn applog_apps(&self) -> GenericResult<Vec<String>>;
+
+    /// Deletes up to `batch_size` log lines of `appid` logged before `before` (milliseconds since
+    /// the Unix epoch). Returns the number of lines deleted.
+    async fn applog_delete_before(
+        &self,
+        appid: &str,
+        before: u64,
+        batch_size: u32,
+    ) -> GenericResult<u64>;
+
+    /// Returns the versions of the applied schema migrations, in ascending order.
+    async fn schema_migrations(&self) -> GenericResult<Vec<u32>>;
+
+    /// Applies the migrations that are not applied yet, in order, and returns their versions.
+    ///
+    /// Migrations up to `baseline` are recorded as applied without running them, for databases
+    /// whose schema was installed by hand before migrations were recorded.
+    async fn schema_migrate(&self, baseline: u32) -> GenericResult<Vec<u32>>;
+}
+
+/// An entry returned by `DataBackend::worker_data_list`.
+#[derive(Clone, Debug)]
+pub struct KvListEntry {
+    pub key: Vec<u8>,
+
+    /// Empty unless values are requested.
+    pub value: Vec<u8>,
+
+    /// Empty unless metadata is requested.
+    pub metadata: Vec<u8>,
+
+    /// Expiration time in milliseconds since the Unix epoch, or 0 if the entry never expires.
+    pub expiration: u64,
+}
+
+/// A write applied by `DataBackend::worker_data_commit`.
+#[derive(Clone, Debug)]
+pub enum KvMutation {
+    /// Sets the value and metadata of a key. A TTL of 0 means no expiration.
+    Put {
+        value: Vec<u8>,
+        metadata: Vec<u8>,
+        ttl_ms: u64,
+    },
+    Delete,
+}
+
+/// A line of app log.
+#[derive(Clone, Debug)]
+pub struct AppLogEntry {
+    pub appid: String,
+
+    /// Time in milliseconds since the Unix epoch.
+    pub time: u64,
+
+    /// Distinguishes lines of the same app logged in the same millisecond.
+    pub subid: u32,
+
+    pub text: String,
+}
+
+/// A page of log lines returned by `DataClient::log_range`.
+#[derive(Clone, Debug)]
+pub struct LogPage {
+    pub entries: Vec<AppLogEntry>,
+
+    /// Cursor for the next page, or `None` if this is the last page.
+    pub cursor: Option<String>,
+}
+
+/// Schema version required by this build: the version of the latest migration.
+pub const SCHEMA_VERSION: u32 = 8;
+
+/// Maximum size of a blob chunk.
+pub const BLOB_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Time after which chunks of an uncommitted blob upload may be garbage-collected, in milliseconds.
+pub const BLOB_UPLOAD_TIMEOUT_MS: u64 = 3600 * 1000;
+
+/// A blob stored with `DataBackend::worker_blob_commit`.
+#[derive(Clone, Debug)]
+pub struct KvBlob {
+    /// Identifies the chunks of this blob. Unique across a namespace.
+    pub blob_id: String,
+
+    /// Total size in bytes.
+    pub size: u64,
+
+    /// Number of chunks.
+    pub chunks: u32,
+
+    pub metadata: Vec<u8>,
+
+    /// Expiration time in milliseconds since the Unix epoch, or 0 if the blob never expires.
+    pub expiration: u64,
+}
+
+/// Encodes the last key of a page into an opaque list cursor.
+pub fn encode_list_cursor(last_key: &[u8]) -> String {
+    base64::encode(last_key)
+}
+
+/// Decodes a list cursor into the last key of the previous page.
+pub fn decode_list_cursor(cursor: &str) -> Option<Vec<u8>> {
+    base64::decode(cursor).ok()
+}
+
+/// Encodes the time and subid of the last line of a page into an opaque log cursor.
+pub fn encode_log_cursor(time: u64, subid: u32) -> String {
+    format!("{}.{}", time, subid)
+}
+
+/// Decodes a log cursor into the time and subid of the last line of the previous page.
+pub fn decode_log_cursor(cursor: &str) -> Option<(u64, u32)> {
+    let mut parts = cursor.splitn(2, '.');
+    let time = parts.next()?.parse().ok()?;
+    let subid = parts.next()?.parse().ok()?;
+    Some((time, subid))
+}
+
+/// Converts a TTL into an absolute expiration time. A TTL of 0 means no expiration.
+fn expiration_from_ttl(ttl_ms: u64) -> GenericResult<u64> {
+    if ttl_ms != 0 {
+        current_millis()
+            .checked_add(ttl_ms)
+            .ok_or_else(|| GenericError::Other("expiration time overflow".into()))
+    } else {
+        Ok(0)
     }
+}
 
-    pub async fn app_metadata_get(&self, appid: &str) -> GenericResult<Option<AppConfig>> {
-        let mut conn = self.db.get_conn().await?;
-        let (bundle_id, env, kv_namespaces): (String, String, String) = match conn
-            .exec_first(
-                "select bundle_id, env, kv_namespaces from apps where id = ?",
-                (appid,),
-            )
-            .await?
-        {
-            Some(x) => x,
-            None => return Ok(None),
-        };
+/// Adds `delta` to a counter value stored as a decimal string. A missing value counts as 0.
+fn increment_value(prev: Option<&[u8]>, delta: i64) -> GenericResult<i64> {
+    let prev = match prev {
+        Some(x) => std::str::from_utf8(x)
+            .ok()
+            .and_then(|x| x.parse::<i64>().ok())
+            .ok_or_else(|| GenericError::Database("value is not an integer".into()))?,
+        None => 0,
+    };
+    prev.checked_add(delta)
+        .ok_or_else(|| GenericError::Database("integer overflow".into()))
+}
 
-        let config = AppConfig {
-            id: AppId(appid.to_string()),
-            bundle_id,
-            env: serde_json::from_str(&env)?,
-            kv_namespaces: serde_json::from_str(&kv_namespaces)?,
-        };
+/// Returns the namespaces bound by apps that are not registered yet, for
+/// `DataBackend::kv_namespace_backfill`. `apps` holds the id, namespace bindings and creation time
+/// of each app, and `registered` the ids of the registered namespaces.
+///
+/// A namespace bound by several apps is registered to the oldest one. The others fail to load
+/// until their configs are fixed.
+fn bound_kv_namespaces(
+    mut apps: Vec<(String, Vec<KvNamespaceConfig>, u64)>,
+    registered: &[String],
+) -> Vec<KvNamespace> {
+    apps.sort_by_key(|x| x.2);
+    let mut namespaces: BTreeMap<String, KvNamespace> = BTreeMap::new();
+    for (appid, bindings, createtime) in apps {
+        for binding in bindings {
+            if registered.contains(&binding.id) {
+                continue;
+            }
+            if let Some(ns) = namespaces.get(&binding.id) {
+                if ns.owner != appid {
+                    warn!(
+                        "kv namespace {} is bound by apps {} and {}. registering it to {}",
+                        binding.id, ns.owner, appid, ns.owner
+                    );
+                }
+                continue;
+            }
+            namespaces.insert(
+                binding.id.clone(),
+                KvNamespace {
+                    id: binding.id,
+                    owner: appid.clone(),
+                    name: binding.name,
+                    createtime,
+                    max_keys: 0,
+                    max_bytes: 0,
+                    used_keys: 0,
+                    used_bytes: 0,
+                },
+            );
+        }
+    }
+    namespaces.into_iter().map(|(_, x)| x).collect()
+}
 
-        Ok(Some(config))
+/// Returns the smallest key that is greater than all keys starting with `prefix`, or `None` if
+/// there isn't one.
+pub(crate) fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut bound = prefix.to_vec();
+    while let Some(last) = bound.pop() {
+        if last != 0xff {
+            bound.push(last + 1);
+            return Some(bound);
+        }
     }
+    None
+}
 
-    pub async fn app_metadata_put(&self, config: &AppConfig) -> GenericResult<()> {
-        let mut conn = self.db.get_conn().await?;
-        conn.exec_drop(
-            format!(
-                "{} on duplicate key {}",
-                "insert into apps (id, bundle_id, env, kv_namespaces, createtime) values(:id, :bundle_id, :env, :kv_namespaces, :createtime)",
-                "update bundle_id = :bundle_id, env = :env, kv_namespaces = :kv_namespaces",
-            ),
-            params! {
-                "id" => &config.id.0,
-                "bundle_id" => &config.bundle_id,
-                "env" => serde_json::to_string(&config.env)?,
-                "kv_namespaces" => serde_json::to_string(&config.kv_namespaces)?,
-                "createtime" => current_millis(),
-            },
-        ).await?;
-        Ok(())
+impl DataClient {
+    pub async fn new(db_url: &str) -> GenericResult<Self> {
+        let backend: Arc<dyn DataBackend> = if db_url.starts_with("mysql://") {
+            Arc::new(MysqlBackend::new(db_url)?)
+        } else if let Some(path) = db_url.strip_prefix("sqlite://") {
+            Arc::new(SqliteBackend::open(path).await?)
+        } else if db_url.starts_with("memory://") {
+            Arc::new(MemoryBackend::new())
+        } else {
+            return Err(GenericError::Other(
+                "unsupported database url scheme".into(),
+            ));
+        };
+        Ok(Self { backend })
     }
 
-    pub async fn app_metadata_delete(&self, appid: &str) -> GenericResult<()> {
-        let mut conn = self.db.get_conn().await?;
-        conn.exec_drop("delete from apps where id = ?", (appid,))
-            .await?;
-        Ok(())
+    pub fn with_backend(backend: Box<dyn DataBackend>) -> Self {
+        Self {
+            backend: Arc::from(backend),
+        }
     }
 
-    pub async fn app_bundle_get(&self, id: &str) -> GenericResult<Option<Vec<u8>>> {
-        let mut conn = self.db.get_conn().await?;
-        let bundle: Option<Vec<u8>> = conn
-            .exec_first("select bundle from bundles where id = ?", (id,))
-            .await?;
-        Ok(bundle)
+    /// Returns the installed schema version: the highest version such that it and all earlier
+    /// migrations are applied.
+    pub async fn schema_version(&self) -> GenericResult<u32> {
+        let mut version = 0;
+        for v in self.schema_migrations().await? {
+            if v == version + 1 {
+                version = v;
+            } else if v > version {
+                break;
+            }
+        }
+        Ok(version)
     }
 
-    pub async fn app_bundle_put(&self, id: &str, value: &[u8]) -> GenericResult<()> {
-        let mut conn = self.db.get_conn().await?;
-        conn.exec_drop(
-            "insert into bundles (id, bundle, createtime) values(?, ?, ?)",
-            (id, value, current_millis()),
-        )
-        .await?;
+    /// Fails if the installed schema is older than `SCHEMA_VERSION`.
+    pub async fn check_schema(&self) -> GenericResult<()> {
+        let version = self.schema_version().await?;
+        if version < SCHEMA_VERSION {
+            return Err(GenericError::Database(format!(
+                "database schema version {} is older than the required version {}; run `rusty-workers-cli db migrate`",
+                version, SCHEMA_VERSION
+            )));
+        }
         Ok(())
     }
 
-    pub async fn applog_write(
+    /// Returns a page of up to `limit` log lines of `appid` logged within `range` (milliseconds
+    /// since the Unix epoch), oldest first, continuing from `cursor` if given.
+    pub async fn log_range(
         &self,
         appid: &str,
-        logtime: SystemTime,
-        logcontent: &str,
-    ) -> GenericResult<()> {
-        let subid: u32 = rand::thread_rng().gen();
-        let mut conn = self.db.get_conn().await?;
-        conn.exec_drop(
-            "insert into applog (appid, logtime, subid, logcontent) values(?, ?, ?, ?)",
-            (
-                appid,
-                logtime
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap_or_else(|_| Duration::from_millis(0))
-                    .as_millis() as u64,
-                subid,
-                logcontent,
+        range: Range<u64>,
+        cursor: Option<&str>,
+        limit: u32,
+    ) -> GenericResult<LogPage> {
+        let after = match cursor {
+            Some(x) => Some(
+                decode_log_cursor(x).ok_or_else(|| GenericError::Other("bad log cursor".into()))?,
             ),
-        )
-        .await?;
-        Ok(())
+            None => None,
+        };
+        let entries = self
+            .applog_list(appid, range.start, range.end, after, limit)
+            .await?;
+        let cursor = if limit != 0 && entries.len() == limit as usize {
+            entries.last().map(|x| encode_log_cursor(x.time, x.subid))
+        } else {
+            None
+        };
+        Ok(LogPage { entries, cursor })
+    }
+}
+
+impl Deref for DataClient {
+    type Target = dyn DataBackend;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.backend
     }
 }
@@ -0,0 +1,1145 @@
+//! In-memory backend, for tests. Nothing is persisted.
+
+use super::{
+    bound_kv_namespaces, expiration_from_ttl, increment_value, prefix_upper_bound, AppLogEntry,
+    DataBackend, KvBlob, KvListEntry, KvMutation, BLOB_UPLOAD_TIMEOUT_MS, SCHEMA_VERSION,
+};
+use crate::{
+    app::{AppConfig, Deployment, KvNamespace, KvWatch},
+    types::*,
+    util::current_millis,
+};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::Mutex;
+
+pub struct MemoryBackend {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    /// Namespace id -> key -> entry.
+    kv: BTreeMap<String, BTreeMap<Vec<u8>, KvEntry>>,
+
+    kv_namespaces: BTreeMap<String, KvNamespace>,
+
+    /// Sequence number -> (namespace id, change).
+    changes: BTreeMap<u64, (String, KvChange)>,
+
+    /// Sequence number of the latest change, including deleted ones.
+    last_change_seq: u64,
+
+    kv_watches: BTreeMap<String, KvWatch>,
+
+    /// (Namespace id, key) -> blob.
+    blobs: BTreeMap<(String, Vec<u8>), KvBlob>,
+
+    /// (Namespace id, blob id, chunk index) -> (data, creation time).
+    chunks: BTreeMap<(String, String, u32), (Vec<u8>, u64)>,
+
+    /// Domain -> path -> app id.
+    routes: BTreeMap<String, BTreeMap<String, String>>,
+
+    apps: BTreeMap<String, AppConfig>,
+
+    /// App id -> version -> deployment.
+    deployments: BTreeMap<String, BTreeMap<u64, Deployment>>,
+
+    /// Bundles and their creation times.
+    bundles: BTreeMap<String, (Vec<u8>, u64)>,
+
+    /// (Bundle id, V8 version) -> code cache.
+    code_cache: BTreeMap<(String, String), Vec<u8>>,
+
+    /// (App id, log time in milliseconds, subid) -> log line.
+    logs: BTreeMap<(String, u64, u32), String>,
+}
+
+#[derive(Clone)]
+struct KvEntry {
+    value: Vec<u8>,
+    metadata: Vec<u8>,
+    expiration: u64,
+}
+
+impl KvEntry {
+    fn is_live(&self, now: u64) -> bool {
+        self.expiration == 0 || self.expiration > now
+    }
+
+    /// Size counted against namespace quotas, with the key.
+    fn size(&self, key: &[u8]) -> i64 {
+        (key.len() + self.value.len() + self.metadata.len()) as i64
+    }
+}
+
+/// Size of a committed blob counted against namespace quotas, with the key.
+fn blob_size(key: &[u8], blob: &KvBlob) -> i64 {
+    (key.len() + blob.metadata.len()) as i64 + blob.size as i64
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(MemoryState::default()),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MemoryState> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryState {
+    fn bundle_referenced(&self, id: &str) -> bool {
+        self.apps.values().any(|x| x.bundle_id == id)
+            || self
+                .deployments
+                .values()
+                .flat_map(|x| x.values())
+                .any(|x| x.config.bundle_id == id)
+    }
+
+    fn live_entry(&self, namespace_id: &str, key: &[u8]) -> Option<&KvEntry> {
+        let now = current_millis();
+        self.kv
+            .get(namespace_id)
+            .and_then(|ns| ns.get(key))
+            .filter(|x| x.is_live(now))
+    }
+
+    fn namespace_mut(&mut self, namespace_id: &str) -> &mut BTreeMap<Vec<u8>, KvEntry> {
+        self.kv.entry(namespace_id.to_string()).or_default()
+    }
+
+    /// Registers a namespace, counting the entries and blobs already stored under its id.
+    fn register_namespace(&mut self, ns: &KvNamespace) {
+        let (keys, bytes) = self
+            .kv
+            .get(&ns.id)
+            .into_iter()
+            .flatten()
+            .map(|(k, v)| v.size(k))
+            .chain(
+                self.blobs
+                    .iter()
+                    .filter(|((id, _), _)| *id == ns.id)
+                    .map(|((_, k), x)| blob_size(k, x)),
+            )
+            .fold((0, 0), |(keys, bytes), size| (keys + 1, bytes + size));
+        self.kv_namespaces.insert(
+            ns.id.clone(),
+            KvNamespace {
+                used_keys: keys,
+                used_bytes: bytes as u64,
+                ..ns.clone()
+            },
+        );
+    }
+
+    /// Deletes the blob stored at `key` and its chunks, updating the usage counters.
+    fn delete_blob(&mut self, namespace_id: &str, key: &[u8]) -> GenericResult<()> {
+        if let Some(blob) = self.blobs.remove(&(namespace_id.to_string(), key.to_vec())) {
+            self.chunks
+                .retain(|(ns, id, _), _| ns != namespace_id || *id != blob.blob_id);
+            self.add_usage(namespace_id, -1, -blob_size(key, &blob))?;
+        }
+        Ok(())
+    }
+
+    /// Adds to the usage counters of a namespace. Unregistered namespaces have no counters.
+    ///
+    /// Fails with `QuotaExceeded`, changing nothing, if the added keys or bytes go over the quota.
+    fn add_usage(&mut self, namespace_id: &str, keys: i64, bytes: i64) -> GenericResult<()> {
+        let ns = match self.kv_namespaces.get_mut(namespace_id) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let used_keys = (ns.used_keys as i64 + keys).max(0) as u64;
+        let used_bytes = (ns.used_bytes as i64 + bytes).max(0) as u64;
+        if (keys > 0 && ns.max_keys != 0 && used_keys > ns.max_keys)
+            || (bytes > 0 && ns.max_bytes != 0 && used_bytes > ns.max_bytes)
+        {
+            return Err(GenericError::QuotaExceeded);
+        }
+        ns.used_keys = used_keys;
+        ns.used_bytes = used_bytes;
+        Ok(())
+    }
+
+    /// Sets entries, updating the usage counters and recording the changes. Nothing is written if
+    /// that exceeds the quota. Later entries for the same key win.
+    fn put_entries(
+        &mut self,
+        namespace_id: &str,
+        entries: impl IntoIterator<Item = (Vec<u8>, KvEntry)>,
+    ) -> GenericResult<()> {
+        let entries: BTreeMap<Vec<u8>, KvEntry> = entries.into_iter().collect();
+        let (mut keys, mut bytes) = (0, 0);
+        for (k, entry) in &entries {
+            match self.kv.get(namespace_id).and_then(|ns| ns.get(k)) {
+                Some(prev) => bytes += entry.size(k) - prev.size(k),
+                None => {
+                    keys += 1;
+                    bytes += entry.size(k);
+                }
+            }
+        }
+        self.add_usage(namespace_id, keys, bytes)?;
+        for (k, entry) in entries {
+            self.log_change(namespace_id, &k, Some(&entry.value));
+            self.namespace_mut(namespace_id).insert(k, entry);
+        }
+        Ok(())
+    }
+
+    /// Removes the entry at `key`, updating the usage counters and recording the change if it
+    /// existed.
+    fn remove_entry(&mut self, namespace_id: &str, key: &[u8]) -> GenericResult<()> {
+        let removed = self.kv.get_mut(namespace_id).and_then(|ns| ns.remove(key));
+        if let Some(entry) = removed {
+            self.add_usage(namespace_id, -1, -entry.size(key))?;
+            self.log_change(namespace_id, key, None);
+        }
+        Ok(())
+    }
+
+    fn log_change(&mut self, namespace_id: &str, key: &[u8], value: Option<&[u8]>) {
+        self.last_change_seq += 1;
+        let seq = self.last_change_seq;
+        self.changes.insert(
+            seq,
+            (
+                namespace_id.to_string(),
+                KvChange {
+                    seq,
+                    key: key.to_vec(),
+                    value: value.map(|x| x.to_vec()),
+                    time: current_millis(),
+                },
+            ),
+        );
+    }
+}
+
+#[async_trait]
+impl DataBackend for MemoryBackend {
+    async fn worker_data_get(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+    ) -> GenericResult<Option<Vec<u8>>> {
+        Ok(self
+            .state()
+            .live_entry(namespace_id, key)
+            .map(|x| x.value.clone()))
+    }
+
+    async fn worker_data_get_with_metadata(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+    ) -> GenericResult<Option<(Vec<u8>, Vec<u8>)>> {
+        Ok(self
+            .state()
+            .live_entry(namespace_id, key)
+            .map(|x| (x.value.clone(), x.metadata.clone())))
+    }
+
+    async fn worker_data_get_many(
+        &self,
+        namespace_id: &str,
+        keys: &[Vec<u8>],
+    ) -> GenericResult<Vec<Option<Vec<u8>>>> {
+        let state = self.state();
+        Ok(keys
+            .iter()
+            .map(|k| state.live_entry(namespace_id, k).map(|x| x.value.clone()))
+            .collect())
+    }
+
+    async fn worker_data_put(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+        value: &[u8],
+        metadata: &[u8],
+        if_not_exists: bool,
+        ttl_ms: u64,
+    ) -> GenericResult<()> {
+        let expiration = expiration_from_ttl(ttl_ms)?;
+        let mut state = self.state();
+        if if_not_exists && state.live_entry(namespace_id, key).is_some() {
+            return Ok(());
+        }
+        state.put_entries(
+            namespace_id,
+            vec![(
+                key.to_vec(),
+                KvEntry {
+                    value: value.to_vec(),
+                    metadata: metadata.to_vec(),
+                    expiration,
+                },
+            )],
+        )
+    }
+
+    async fn worker_data_put_many(
+        &self,
+        namespace_id: &str,
+        entries: &[(Vec<u8>, Vec<u8>)],
+        ttl_ms: u64,
+    ) -> GenericResult<()> {
+        let expiration = expiration_from_ttl(ttl_ms)?;
+        self.state().put_entries(
+            namespace_id,
+            entries.iter().map(|(k, v)| {
+                (
+                    k.clone(),
+                    KvEntry {
+                        value: v.clone(),
+                        metadata: vec![],
+                        expiration,
+                    },
+                )
+            }),
+        )
+    }
+
+    async fn worker_data_scan_keys(
+        &self,
+        namespace_id: &str,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: u32,
+    ) -> GenericResult<Vec<Vec<u8>>> {
+        let now = current_millis();
+        let state = self.state();
+        let ns = match state.kv.get(namespace_id) {
+            Some(x) => x,
+            None => return Ok(vec![]),
+        };
+        let upper = match end {
+            // `BTreeMap::range` panics on an empty range.
+            Some(end) if end < start => return Ok(vec![]),
+            Some(end) => Bound::Included(end.to_vec()),
+            None => Bound::Unbounded,
+        };
+        Ok(ns
+            .range((Bound::Included(start.to_vec()), upper))
+            .filter(|(_, v)| v.is_live(now))
+            .take(limit as usize)
+            .map(|(k, _)| k.clone())
+            .collect())
+    }
+
+    async fn worker_data_delete_many(
+        &self,
+        namespace_id: &str,
+        keys: &[Vec<u8>],
+    ) -> GenericResult<()> {
+        let mut state = self.state();
+        for k in keys {
+            state.remove_entry(namespace_id, k)?;
+        }
+        Ok(())
+    }
+
+    async fn worker_data_increment(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+        delta: i64,
+        ttl_ms: u64,
+    ) -> GenericResult<i64> {
+        let expiration = expiration_from_ttl(ttl_ms)?;
+        let mut state = self.state();
+        let prev = state.live_entry(namespace_id, key).cloned();
+        let value = increment_value(prev.as_ref().map(|x| x.value.as_slice()), delta)?;
+        let entry = KvEntry {
+            value: value.to_string().into_bytes(),
+            metadata: prev
+                .as_ref()
+                .map(|x| x.metadata.clone())
+                .unwrap_or_default(),
+            expiration: if ttl_ms != 0 {
+                expiration
+            } else {
+                prev.map(|x| x.expiration).unwrap_or(0)
+            },
+        };
+        state.put_entries(namespace_id, vec![(key.to_vec(), entry)])?;
+        Ok(value)
+    }
+
+    async fn worker_data_list(
+        &self,
+        namespace_id: &str,
+        prefix: &[u8],
+        after: Option<&[u8]>,
+        limit: u32,
+        include_values: bool,
+        include_metadata: bool,
+    ) -> GenericResult<(Vec<KvListEntry>, bool)> {
+        let now = current_millis();
+        let state = self.state();
+        let ns = match state.kv.get(namespace_id) {
+            Some(x) => x,
+            None => return Ok((vec![], true)),
+        };
+        let lower = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after.to_vec()),
+            _ => Bound::Included(prefix.to_vec()),
+        };
+        let upper = match prefix_upper_bound(prefix) {
+            // `BTreeMap::range` panics on an empty range.
+            Some(x) if after.map(|after| after >= x.as_slice()).unwrap_or(false) => {
+                return Ok((vec![], true))
+            }
+            Some(x) => Bound::Excluded(x),
+            None => Bound::Unbounded,
+        };
+        let mut entries: Vec<KvListEntry> = ns
+            .range((lower, upper))
+            .filter(|(_, v)| v.is_live(now))
+            .take(limit as usize + 1)
+            .map(|(k, v)| KvListEntry {
+                key: k.clone(),
+                value: if include_values {
+                    v.value.clone()
+                } else {
+                    vec![]
+                },
+                metadata: if include_metadata {
+                    v.metadata.clone()
+                } else {
+                    vec![]
+                },
+                expiration: v.expiration,
+            })
+            .collect();
+        let list_complete = entries.len() <= limit as usize;
+        entries.truncate(limit as usize);
+        Ok((entries, list_complete))
+    }
+
+    async fn worker_data_delete(&self, namespace_id: &str, key: &[u8]) -> GenericResult<()> {
+        self.state().remove_entry(namespace_id, key)
+    }
+
+    async fn worker_data_delete_expired(
+        &self,
+        batch_size: u32,
+        dry_run: bool,
+    ) -> GenericResult<u64> {
+        let now = current_millis();
+        let mut state = self.state();
+        let expired: Vec<(String, Vec<u8>)> = state
+            .kv
+            .iter()
+            .flat_map(|(ns, entries)| {
+                entries
+                    .iter()
+                    .filter(|(_, v)| !v.is_live(now))
+                    .map(move |(k, _)| (ns.clone(), k.clone()))
+            })
+            .take(batch_size as usize)
+            .collect();
+        if !dry_run {
+            // Expiry is not recorded in the change log.
+            for (ns, k) in &expired {
+                if let Some(entry) = state.kv.get_mut(ns).and_then(|x| x.remove(k)) {
+                    state.add_usage(ns, -1, -entry.size(k))?;
+                }
+            }
+        }
+        Ok(expired.len() as u64)
+    }
+
+    async fn worker_data_cmpupdate(
+        &self,
+        namespace_id: &str,
+        assertions: &[(Vec<u8>, Vec<u8>)],
+        writes: &[(Vec<u8>, Vec<u8>)],
+        ttl_ms: u64,
+    ) -> GenericResult<bool> {
+        let expiration = expiration_from_ttl(ttl_ms)?;
+        let mut state = self.state();
+        for (k, v) in assertions {
+            match state.live_entry(namespace_id, k) {
+                Some(x) if x.value == *v => {}
+                _ => return Ok(false),
+            }
+        }
+        state.put_entries(
+            namespace_id,
+            writes.iter().map(|(k, v)| {
+                (
+                    k.clone(),
+                    KvEntry {
+                        value: v.clone(),
+                        metadata: vec![],
+                        expiration,
+                    },
+                )
+            }),
+        )?;
+        Ok(true)
+    }
+
+    async fn worker_data_commit(
+        &self,
+        namespace_id: &str,
+        reads: &[(Vec<u8>, Option<Vec<u8>>)],
+        writes: &[(Vec<u8>, KvMutation)],
+    ) -> GenericResult<bool> {
+        let mut state = self.state();
+        for (k, v) in reads {
+            if state.live_entry(namespace_id, k).map(|x| &x.value) != v.as_ref() {
+                return Ok(false);
+            }
+        }
+
+        // Later writes to the same key win.
+        let mut entries: BTreeMap<Vec<u8>, Option<KvEntry>> = BTreeMap::new();
+        for (k, m) in writes {
+            let entry = match m {
+                KvMutation::Put {
+                    value,
+                    metadata,
+                    ttl_ms,
+                } => Some(KvEntry {
+                    value: value.clone(),
+                    metadata: metadata.clone(),
+                    expiration: expiration_from_ttl(*ttl_ms)?,
+                }),
+                KvMutation::Delete => None,
+            };
+            entries.insert(k.clone(), entry);
+        }
+
+        // Check the quota once for the whole transaction, before anything is written.
+        let (mut keys, mut bytes) = (0, 0);
+        for (k, entry) in &entries {
+            let prev = state.kv.get(namespace_id).and_then(|ns| ns.get(k));
+            keys += entry.is_some() as i64 - prev.is_some() as i64;
+            bytes += entry.as_ref().map(|x| x.size(k)).unwrap_or(0)
+                - prev.map(|x| x.size(k)).unwrap_or(0);
+        }
+        state.add_usage(namespace_id, keys, bytes)?;
+
+        for (k, entry) in entries {
+            match entry {
+                Some(entry) => {
+                    state.log_change(namespace_id, &k, Some(&entry.value));
+                    state.namespace_mut(namespace_id).insert(k, entry);
+                }
+                None => {
+                    let removed = state.kv.get_mut(namespace_id).and_then(|ns| ns.remove(&k));
+                    if removed.is_some() {
+                        state.log_change(namespace_id, &k, None);
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    async fn worker_blob_put_chunk(
+        &self,
+        namespace_id: &str,
+        blob_id: &str,
+        index: u32,
+        data: &[u8],
+    ) -> GenericResult<()> {
+        let mut state = self.state();
+        let k = (namespace_id.to_string(), blob_id.to_string(), index);
+        if state.chunks.contains_key(&k) {
+            return Err(GenericError::Database(format!(
+                "duplicate chunk: {} {}",
+                blob_id, index
+            )));
+        }
+        state.chunks.insert(k, (data.to_vec(), current_millis()));
+        Ok(())
+    }
+
+    async fn worker_blob_commit(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+        blob: &KvBlob,
+        ttl_ms: u64,
+    ) -> GenericResult<bool> {
+        let expiration = expiration_from_ttl(ttl_ms)?;
+        let mut state = self.state();
+        let (chunks, size) = state
+            .chunks
+            .iter()
+            .filter(|((ns, id, _), _)| ns == namespace_id && *id == blob.blob_id)
+            .fold((0u32, 0u64), |(chunks, size), (_, (data, _))| {
+                (chunks + 1, size + data.len() as u64)
+            });
+        if chunks != blob.chunks || size != blob.size {
+            return Ok(false);
+        }
+        if state
+            .blobs
+            .iter()
+            .any(|((ns, _), x)| ns == namespace_id && x.blob_id == blob.blob_id)
+        {
+            return Ok(false);
+        }
+
+        // The previous blob is replaced, so only the difference counts against the quota.
+        let prev_size = state
+            .blobs
+            .get(&(namespace_id.to_string(), key.to_vec()))
+            .map(|x| blob_size(key, x));
+        state.add_usage(
+            namespace_id,
+            prev_size.is_none() as i64,
+            blob_size(key, blob) - prev_size.unwrap_or(0),
+        )?;
+        let prev = state.blobs.insert(
+            (namespace_id.to_string(), key.to_vec()),
+            KvBlob {
+                expiration,
+                ..blob.clone()
+            },
+        );
+        if let Some(prev) = prev {
+            state
+                .chunks
+                .retain(|(ns, id, _), _| ns != namespace_id || *id != prev.blob_id);
+        }
+        Ok(true)
+    }
+
+    async fn worker_blob_get(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+    ) -> GenericResult<Option<KvBlob>> {
+        let now = current_millis();
+        Ok(self
+            .state()
+            .blobs
+            .get(&(namespace_id.to_string(), key.to_vec()))
+            .filter(|x| x.expiration == 0 || x.expiration > now)
+            .cloned())
+    }
+
+    async fn worker_blob_get_chunk(
+        &self,
+        namespace_id: &str,
+        blob_id: &str,
+        index: u32,
+    ) -> GenericResult<Option<Vec<u8>>> {
+        Ok(self
+            .state()
+            .chunks
+            .get(&(namespace_id.to_string(), blob_id.to_string(), index))
+            .map(|(data, _)| data.clone()))
+    }
+
+    async fn worker_blob_delete(&self, namespace_id: &str, key: &[u8]) -> GenericResult<()> {
+        self.state().delete_blob(namespace_id, key)
+    }
+
+    async fn worker_blob_list(
+        &self,
+        namespace_id: &str,
+        prefix: &[u8],
+        from: &[u8],
+        limit: u32,
+    ) -> GenericResult<Vec<(Vec<u8>, KvBlob)>> {
+        let now = current_millis();
+        let start = std::cmp::max(prefix, from);
+        let lower = Bound::Included((namespace_id.to_string(), start.to_vec()));
+        let upper = match prefix_upper_bound(prefix) {
+            // `BTreeMap::range` panics on an empty range.
+            Some(x) if start >= x.as_slice() => return Ok(vec![]),
+            Some(x) => Bound::Excluded((namespace_id.to_string(), x)),
+            None => Bound::Unbounded,
+        };
+        Ok(self
+            .state()
+            .blobs
+            .range((lower, upper))
+            .take_while(|((ns, _), _)| ns == namespace_id)
+            .filter(|(_, x)| x.expiration == 0 || x.expiration > now)
+            .take(limit as usize)
+            .map(|((_, k), v)| (k.clone(), v.clone()))
+            .collect())
+    }
+
+    async fn worker_blob_delete_expired(&self, batch_size: u32) -> GenericResult<u64> {
+        let now = current_millis();
+        let mut state = self.state();
+        let expired: Vec<(String, Vec<u8>)> = state
+            .blobs
+            .iter()
+            .filter(|(_, x)| x.expiration != 0 && x.expiration <= now)
+            .map(|(k, _)| k.clone())
+            .take(batch_size as usize)
+            .collect();
+        for (ns, key) in &expired {
+            state.delete_blob(ns, key)?;
+        }
+
+        let upload_deadline = now.saturating_sub(BLOB_UPLOAD_TIMEOUT_MS);
+        let orphans: Vec<(String, String, u32)> = state
+            .chunks
+            .iter()
+            .filter(|((ns, id, _), (_, createtime))| {
+                *createtime < upload_deadline
+                    && !state
+                        .blobs
+                        .iter()
+                        .any(|((x, _), blob)| x == ns && blob.blob_id == *id)
+            })
+            .map(|(k, _)| k.clone())
+            .take(batch_size as usize)
+            .collect();
+        for k in &orphans {
+            state.chunks.remove(k);
+        }
+        Ok((expired.len() + orphans.len()) as u64)
+    }
+
+    async fn worker_data_changes(
+        &self,
+        namespace_id: &str,
+        prefix: &[u8],
+        after: u64,
+        limit: u32,
+    ) -> GenericResult<Vec<KvChange>> {
+        Ok(self
+            .state()
+            .changes
+            .range((Bound::Excluded(after), Bound::Unbounded))
+            .filter(|(_, (ns, change))| ns == namespace_id && change.key.starts_with(prefix))
+            .take(limit as usize)
+            .map(|(_, (_, change))| change.clone())
+            .collect())
+    }
+
+    async fn worker_data_changes_latest_seq(&self) -> GenericResult<u64> {
+        Ok(self.state().last_change_seq)
+    }
+
+    async fn worker_data_delete_changes(&self, before: u64, batch_size: u32) -> GenericResult<u64> {
+        let mut state = self.state();
+        let old: Vec<u64> = state
+            .changes
+            .iter()
+            .filter(|(_, (_, change))| change.time < before)
+            .take(batch_size as usize)
+            .map(|(seq, _)| *seq)
+            .collect();
+        for seq in &old {
+            state.changes.remove(seq);
+        }
+        Ok(old.len() as u64)
+    }
+
+    async fn kv_watch_create(&self, watch: &KvWatch) -> GenericResult<()> {
+        let mut state = self.state();
+        if state.kv_watches.contains_key(&watch.id) {
+            return Err(GenericError::Database(format!(
+                "duplicate watch id: {}",
+                watch.id
+            )));
+        }
+        state.kv_watches.insert(watch.id.clone(), watch.clone());
+        Ok(())
+    }
+
+    async fn kv_watch_list(&self, appid: Option<&str>) -> GenericResult<Vec<KvWatch>> {
+        Ok(self
+            .state()
+            .kv_watches
+            .values()
+            .filter(|x| appid.map(|appid| x.appid == appid).unwrap_or(true))
+            .cloned()
+            .collect())
+    }
+
+    async fn kv_watch_delete(&self, id: &str) -> GenericResult<()> {
+        self.state().kv_watches.remove(id);
+        Ok(())
+    }
+
+    async fn kv_watch_advance(&self, id: &str, cursor: u64) -> GenericResult<()> {
+        if let Some(watch) = self.state().kv_watches.get_mut(id) {
+            watch.cursor = watch.cursor.max(cursor);
+        }
+        Ok(())
+    }
+
+    async fn kv_namespace_create(&self, ns: &KvNamespace) -> GenericResult<()> {
+        let mut state = self.state();
+        if state.kv_namespaces.contains_key(&ns.id) {
+            return Err(GenericError::Database(format!(
+                "duplicate namespace id: {}",
+                ns.id
+            )));
+        }
+        state.register_namespace(ns);
+        Ok(())
+    }
+
+    async fn kv_namespace_get(&self, id: &str) -> GenericResult<Option<KvNamespace>> {
+        Ok(self.state().kv_namespaces.get(id).cloned())
+    }
+
+    async fn kv_namespace_list(&self, owner: Option<&str>) -> GenericResult<Vec<KvNamespace>> {
+        Ok(self
+            .state()
+            .kv_namespaces
+            .values()
+            .filter(|x| owner.map(|owner| x.owner == owner).unwrap_or(true))
+            .cloned()
+            .collect())
+    }
+
+    async fn kv_namespace_delete(&self, id: &str) -> GenericResult<()> {
+        let mut state = self.state();
+        state.kv_namespaces.remove(id);
+        state.kv.remove(id);
+        state.blobs.retain(|(ns, _), _| ns != id);
+        state.chunks.retain(|(ns, _, _), _| ns != id);
+        state.changes.retain(|_, (ns, _)| ns != id);
+        state.kv_watches.retain(|_, x| x.namespace_id != id);
+        Ok(())
+    }
+
+    async fn kv_namespace_backfill(&self) -> GenericResult<Vec<KvNamespace>> {
+        let mut state = self.state();
+
+        // App creation times are not recorded here, so the app with the smallest id wins.
+        let apps = state
+            .apps
+            .values()
+            .map(|x| (x.id.0.clone(), x.kv_namespaces.clone(), 0))
+            .collect();
+        let registered: Vec<String> = state.kv_namespaces.keys().cloned().collect();
+        let namespaces = bound_kv_namespaces(apps, &registered);
+        for ns in &namespaces {
+            state.register_namespace(ns);
+        }
+        Ok(namespaces)
+    }
+
+    async fn route_mapping_delete_domain(&self, domain: &str) -> GenericResult<()> {
+        self.state().routes.remove(domain);
+        Ok(())
+    }
+
+    async fn route_mapping_list_for_domain(
+        &self,
+        domain: &str,
+    ) -> GenericResult<BTreeMap<String, String>> {
+        Ok(self.state().routes.get(domain).cloned().unwrap_or_default())
+    }
+
+    async fn route_mapping_lookup(
+        &self,
+        domain: &str,
+        path: &str,
+    ) -> GenericResult<Option<String>> {
+        let state = self.state();
+        let routes = match state.routes.get(domain) {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        // Most specific match
+        Ok(routes
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, appid)| appid.clone()))
+    }
+
+    async fn route_mapping_insert(
+        &self,
+        domain: &str,
+        path: &str,
+        appid: String,
+    ) -> GenericResult<()> {
+        self.state()
+            .routes
+            .entry(domain.to_string())
+            .or_default()
+            .insert(path.to_string(), appid);
+        Ok(())
+    }
+
+    async fn route_mapping_delete(&self, domain: &str, path: &str) -> GenericResult<()> {
+        let mut state = self.state();
+        if let Some(routes) = state.routes.get_mut(domain) {
+            routes.remove(path);
+            if routes.is_empty() {
+                state.routes.remove(domain);
+            }
+        }
+        Ok(())
+    }
+
+    async fn app_metadata_get(&self, appid: &str) -> GenericResult<Option<AppConfig>> {
+        Ok(self.state().apps.get(appid).cloned())
+    }
+
+    async fn app_metadata_put(&self, config: &AppConfig) -> GenericResult<()> {
+        self.state()
+            .apps
+            .insert(config.id.0.clone(), config.clone());
+        Ok(())
+    }
+
+    async fn app_metadata_delete(&self, appid: &str) -> GenericResult<()> {
+        self.state().apps.remove(appid);
+        Ok(())
+    }
+
+    async fn app_deploy(
+        &self,
+        config: &AppConfig,
+        author: &str,
+        message: &str,
+    ) -> GenericResult<u64> {
+        let mut state = self.state();
+        let deployments = state.deployments.entry(config.id.0.clone()).or_default();
+        let version = deployments.keys().next_back().copied().unwrap_or(0) + 1;
+        deployments.insert(
+            version,
+            Deployment {
+                version,
+                config: config.clone(),
+                author: author.to_string(),
+                message: message.to_string(),
+                createtime: current_millis(),
+            },
+        );
+        state.apps.insert(config.id.0.clone(), config.clone());
+        Ok(version)
+    }
+
+    async fn app_deployment_list(&self, appid: &str) -> GenericResult<Vec<Deployment>> {
+        Ok(self
+            .state()
+            .deployments
+            .get(appid)
+            .map(|x| x.values().rev().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn app_deployment_get(
+        &self,
+        appid: &str,
+        version: u64,
+    ) -> GenericResult<Option<Deployment>> {
+        Ok(self
+            .state()
+            .deployments
+            .get(appid)
+            .and_then(|x| x.get(&version))
+            .cloned())
+    }
+
+    async fn app_deployment_prune(&self, appid: &str, keep: u32) -> GenericResult<u64> {
+        let mut state = self.state();
+        let deployments = match state.deployments.get_mut(appid) {
+            Some(x) => x,
+            None => return Ok(0),
+        };
+        let n = deployments.len().saturating_sub(keep as usize);
+        let pruned: Vec<u64> = deployments.keys().take(n).copied().collect();
+        for version in pruned.iter() {
+            deployments.remove(version);
+        }
+        if deployments.is_empty() {
+            state.deployments.remove(appid);
+        }
+        Ok(pruned.len() as u64)
+    }
+
+    async fn app_bundle_get(&self, id: &str) -> GenericResult<Option<Vec<u8>>> {
+        Ok(self.state().bundles.get(id).map(|x| x.0.clone()))
+    }
+
+    async fn app_bundle_put(&self, id: &str, value: &[u8]) -> GenericResult<()> {
+        self.state()
+            .bundles
+            .insert(id.to_string(), (value.to_vec(), current_millis()));
+        Ok(())
+    }
+
+    async fn app_bundle_touch(&self, id: &str) -> GenericResult<bool> {
+        match self.state().bundles.get_mut(id) {
+            Some(bundle) => {
+                bundle.1 = current_millis();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn app_bundle_list_unreferenced(
+        &self,
+        created_before: u64,
+        limit: u32,
+    ) -> GenericResult<Vec<String>> {
+        let state = self.state();
+        Ok(state
+            .bundles
+            .iter()
+            .filter(|(id, (_, createtime))| {
+                *createtime < created_before && !state.bundle_referenced(id)
+            })
+            .map(|(id, _)| id.clone())
+            .take(limit as usize)
+            .collect())
+    }
+
+    async fn app_bundle_delete_unreferenced(
+        &self,
+        id: &str,
+        created_before: u64,
+    ) -> GenericResult<bool> {
+        let mut state = self.state();
+        match state.bundles.get(id) {
+            Some((_, createtime)) if *createtime < created_before => {}
+            _ => return Ok(false),
+        }
+        if state.bundle_referenced(id) {
+            return Ok(false);
+        }
+        state.bundles.remove(id);
+        state.code_cache.retain(|(bundle_id, _), _| bundle_id != id);
+        Ok(true)
+    }
+
+    async fn app_bundle_code_cache_get(
+        &self,
+        bundle_id: &str,
+        v8_version: &str,
+    ) -> GenericResult<Option<Vec<u8>>> {
+        Ok(self
+            .state()
+            .code_cache
+            .get(&(bundle_id.to_string(), v8_version.to_string()))
+            .cloned())
+    }
+
+    async fn app_bundle_code_cache_put(
+        &self,
+        bundle_id: &str,
+        v8_version: &str,
+        cache: &[u8],
+    ) -> GenericResult<()> {
+        self.state().code_cache.insert(
+            (bundle_id.to_string(), v8_version.to_string()),
+            cache.to_vec(),
+        );
+        Ok(())
+    }
+
+    async fn applog_write_many(&self, entries: &[AppLogEntry]) -> GenericResult<()> {
+        let mut state = self.state();
+        for entry in entries {
+            state
+                .logs
+                .entry((entry.appid.clone(), entry.time, entry.subid))
+                .or_insert_with(|| entry.text.clone());
+        }
+        Ok(())
+    }
+
+    async fn applog_list(
+        &self,
+        appid: &str,
+        start: u64,
+        end: u64,
+        after: Option<(u64, u32)>,
+        limit: u32,
+    ) -> GenericResult<Vec<AppLogEntry>> {
+        let upper = (appid.to_string(), end, 0);
+        let lower = match after {
+            Some((time, subid)) if time >= start => {
+                let key = (appid.to_string(), time, subid);
+                if key >= upper {
+                    return Ok(vec![]);
+                }
+                Bound::Excluded(key)
+            }
+            _ => {
+                if start >= end {
+                    return Ok(vec![]);
+                }
+                Bound::Included((appid.to_string(), start, 0))
+            }
+        };
+        Ok(self
+            .state()
+            .logs
+            .range((lower, Bound::Excluded(upper)))
+            .take(limit as usize)
+            .map(|((appid, time, subid), text)| AppLogEntry {
+                appid: appid.clone(),
+                time: *time,
+                subid: *subid,
+                text: text.clone(),
+            })
+            .collect())
+    }
+
+    async fn applog_apps(&self) -> GenericResult<Vec<String>> {
+        let mut apps: Vec<String> = self
+            .state()
+            .logs
+            .keys()
+            .map(|(appid, _, _)| appid.clone())
+            .collect();
+        apps.dedup();
+        Ok(apps)
+    }
+
+    async fn applog_delete_before(
+        &self,
+        appid: &str,
+        before: u64,
+        batch_size: u32,
+    ) -> GenericResult<u64> {
+        let mut state = self.state();
+        let keys: Vec<_> = state
+            .logs
+            .range((appid.to_string(), 0, 0)..(appid.to_string(), before, 0))
+            .take(batch_size as usize)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for k in keys.iter() {
+            state.logs.remove(k);
+        }
+        Ok(keys.len() as u64)
+    }
+
+    async fn schema_migrations(&self) -> GenericResult<Vec<u32>> {
+        // Created with the latest schema.
+        Ok((1..=SCHEMA_VERSION).collect())
+    }
+
+    async fn schema_migrate(&self, _baseline: u32) -> GenericResult<Vec<u32>> {
+        Ok(vec![])
+    }
+}
@@ -0,0 +1,1706 @@
+//! MySQL-compatible backend.
+
+use super::{
+    bound_kv_namespaces, expiration_from_ttl, prefix_upper_bound, AppLogEntry, DataBackend, KvBlob,
+    KvListEntry, KvMutation, BLOB_UPLOAD_TIMEOUT_MS,
+};
+use crate::{
+    app::{AppConfig, AppId, Deployment, KvNamespace, KvWatch},
+    types::*,
+    util::current_millis,
+};
+use async_trait::async_trait;
+use mysql_async::{
+    params, prelude::Queryable, Conn, IsolationLevel, Params, Pool, Transaction, TxOpts,
+};
+use std::collections::BTreeMap;
+
+/// `ER_LOCK_DEADLOCK`: the transaction was rolled back to break a deadlock.
+const ER_LOCK_DEADLOCK: u16 = 1213;
+
+/// `ER_LOCK_WAIT_TIMEOUT`: a lock could not be acquired in time.
+const ER_LOCK_WAIT_TIMEOUT: u16 = 1205;
+
+/// Schema migrations: the statements of the files in `sql/migration_NNNNNN`, by version.
+const MIGRATIONS: &[(u32, &[&str])] = &[
+    (
+        1,
+        &[
+            include_str!("../../../sql/migration_000001/appkv.sql"),
+            include_str!("../../../sql/migration_000001/applog.sql"),
+            include_str!("../../../sql/migration_000001/apps.sql"),
+            include_str!("../../../sql/migration_000001/bundles.sql"),
+            include_str!("../../../sql/migration_000001/routes.sql"),
+        ],
+    ),
+    (
+        2,
+        &[include_str!(
+            "../../../sql/migration_000002/bundle_code_cache.sql"
+        )],
+    ),
+    (
+        3,
+        &[include_str!(
+            "../../../sql/migration_000003/kv_namespaces.sql"
+        )],
+    ),
+    (
+        4,
+        &[
+            include_str!("../../../sql/migration_000004/appkv_changes.sql"),
+            include_str!("../../../sql/migration_000004/kv_watches.sql"),
+        ],
+    ),
+    (
+        5,
+        &[
+            include_str!("../../../sql/migration_000005/appkv_blobs.sql"),
+            include_str!("../../../sql/migration_000005/appkv_chunks.sql"),
+        ],
+    ),
+    (
+        6,
+        &[include_str!(
+            "../../../sql/migration_000006/apps_buckets.sql"
+        )],
+    ),
+    (
+        7,
+        &[include_str!(
+            "../../../sql/migration_000007/deployments.sql"
+        )],
+    ),
+    (
+        8,
+        &[include_str!(
+            "../../../sql/migration_000008/apps_log_retention.sql"
+        )],
+    ),
+];
+
+const UPSERT_APP: &str = "insert into apps (id, bundle_id, env, kv_namespaces, buckets, log_retention_secs, createtime) values(:id, :bundle_id, :env, :kv_namespaces, :buckets, :log_retention_secs, :createtime) \
+    on duplicate key update bundle_id = :bundle_id, env = :env, kv_namespaces = :kv_namespaces, buckets = :buckets, log_retention_secs = :log_retention_secs";
+
+/// Maximum number of log lines inserted in one statement.
+const APPLOG_INSERT_BATCH_SIZE: usize = 500;
+
+/// Version, config, author, message and creation time of a deployment.
+type DeploymentRow = (u64, String, String, String, u64);
+
+const SCHEMA_MIGRATIONS_TABLE: &str = "create table if not exists schema_migrations (version int unsigned not null primary key, applied_at bigint unsigned not null)";
+
+/// Version of the migration that adds the KV namespace registry.
+const KV_NAMESPACES_MIGRATION: u32 = 3;
+
+/// Name of the lock held while migrating, so that concurrent `db migrate` runs don't apply the
+/// same migration twice.
+const MIGRATION_LOCK: &str = "rusty_workers_schema_migration";
+
+/// Time to wait for `MIGRATION_LOCK`, in seconds.
+const MIGRATION_LOCK_TIMEOUT_SECS: u32 = 60;
+
+/// Id, owner, name, creation time, quotas and usage counters of a KV namespace.
+type KvNamespaceRow = (String, String, String, u64, u64, u64, i64, i64);
+
+pub struct MysqlBackend {
+    db: Pool,
+}
+
+impl MysqlBackend {
+    pub fn new(db_url: &str) -> GenericResult<Self> {
+        let db = Pool::from_url(db_url)
+            .map_err(|e| GenericError::Other(format!("db connection failed: {:?}", e,)))?;
+        Ok(Self { db })
+    }
+
+    /// Applies pending migrations. Must be called with `MIGRATION_LOCK` held on `conn`.
+    ///
+    /// DDL statements are not transactional in MySQL: if a migration fails halfway, the
+    /// statements already run must be reverted by hand before retrying.
+    ///
+    /// Adding the KV namespace registry also registers the namespaces bound by existing apps, once
+    /// the later migrations have created the tables they are counted from.
+    async fn apply_migrations(conn: &mut Conn, baseline: u32) -> GenericResult<Vec<u32>> {
+        let done: Vec<u32> = conn.query("select version from schema_migrations").await?;
+        let mut applied = vec![];
+        let mut backfill = false;
+        for &(version, files) in MIGRATIONS {
+            if done.contains(&version) {
+                continue;
+            }
+            if version > baseline {
+                for stmt in files
+                    .iter()
+                    .flat_map(|x| x.split(';'))
+                    .map(|x| x.trim())
+                    .filter(|x| !x.is_empty())
+                {
+                    conn.query_drop(stmt).await?;
+                }
+                backfill |= version == KV_NAMESPACES_MIGRATION;
+            }
+            conn.exec_drop(
+                "insert into schema_migrations (version, applied_at) values(?, ?)",
+                (version, current_millis()),
+            )
+            .await?;
+            applied.push(version);
+        }
+        if backfill {
+            backfill_kv_namespaces(conn).await?;
+        }
+        Ok(applied)
+    }
+
+    async fn try_commit(
+        &self,
+        namespace_id: &str,
+        reads: &[(Vec<u8>, Option<Vec<u8>>)],
+        writes: &[(Vec<u8>, KvMutation, u64)],
+    ) -> GenericResult<bool> {
+        let current_time = current_millis();
+
+        let mut opts = TxOpts::new();
+        opts.with_isolation_level(IsolationLevel::RepeatableRead);
+        let mut txn = self.db.start_transaction(opts).await.map_err(txn_error)?;
+
+        // Lock every key the transaction touches, in a consistent order so that concurrent
+        // transactions do not deadlock each other.
+        let mut keys: Vec<&[u8]> = reads
+            .iter()
+            .map(|x| x.0.as_slice())
+            .chain(writes.iter().map(|x| x.0.as_slice()))
+            .collect();
+        keys.sort();
+        keys.dedup();
+        let stmt = txn
+            .prep("select appvalue from appkv where nsid = ? and appkey = ? and (appexpiration = 0 or appexpiration > ?) for update")
+            .await
+            .map_err(txn_error)?;
+        let mut current: BTreeMap<&[u8], Option<Vec<u8>>> = BTreeMap::new();
+        for k in keys {
+            let value: Option<Vec<u8>> = txn
+                .exec_first(&stmt, (namespace_id, k, current_time))
+                .await
+                .map_err(txn_error)?;
+            current.insert(k, value);
+        }
+        for (k, v) in reads {
+            if current[k.as_slice()] != *v {
+                return Ok(false);
+            }
+        }
+
+        let written: Vec<&[u8]> = writes.iter().map(|x| x.0.as_slice()).collect();
+        let before = entry_sizes(&mut txn, namespace_id, &written).await?;
+
+        for (k, m, expiration) in writes {
+            match m {
+                KvMutation::Put {
+                    value, metadata, ..
+                } => {
+                    txn.exec_drop(
+                        format!(
+                            "{} on duplicate key {}",
+                            "insert into appkv (nsid, appkey, appvalue, appmetadata, appexpiration) values(:nsid, :appkey, :appvalue, :appmetadata, :appexpiration)",
+                            "update appvalue = :appvalue, appmetadata = :appmetadata, appexpiration = :appexpiration",
+                        ),
+                        params! {
+                            "nsid" => namespace_id,
+                            "appkey" => k,
+                            "appvalue" => value,
+                            "appmetadata" => metadata,
+                            "appexpiration" => expiration,
+                        },
+                    )
+                    .await
+                    .map_err(txn_error)?;
+                }
+                KvMutation::Delete => {
+                    txn.exec_drop(
+                        "delete from appkv where nsid = ? and appkey = ?",
+                        (namespace_id, k),
+                    )
+                    .await
+                    .map_err(txn_error)?;
+                }
+            }
+        }
+        let changes: Vec<(&[u8], Option<&[u8]>)> = writes
+            .iter()
+            .filter_map(|(k, m, _)| match m {
+                KvMutation::Put { value, .. } => Some((k.as_slice(), Some(value.as_slice()))),
+                // Deleting an absent key changes nothing.
+                KvMutation::Delete if current[k.as_slice()].is_none() => None,
+                KvMutation::Delete => Some((k.as_slice(), None)),
+            })
+            .collect();
+        log_changes(&mut txn, namespace_id, &changes)
+            .await
+            .map_err(txn_error)?;
+        track_entries(&mut txn, namespace_id, &written, &before).await?;
+        txn.commit().await.map_err(txn_error)?;
+        Ok(true)
+    }
+}
+
+/// Converts an error of a write transaction. Lock conflicts become `TryAgain`, which
+/// `worker_data_commit` reports as a failed commit.
+fn txn_error(e: mysql_async::Error) -> GenericError {
+    match e {
+        mysql_async::Error::Server(ref x)
+            if x.code == ER_LOCK_DEADLOCK || x.code == ER_LOCK_WAIT_TIMEOUT =>
+        {
+            GenericError::TryAgain
+        }
+        e => e.into(),
+    }
+}
+
+/// Registers the namespaces bound by existing apps, counting the entries and blobs already stored
+/// under them. See `bound_kv_namespaces`.
+async fn backfill_kv_namespaces(conn: &mut Conn) -> GenericResult<Vec<KvNamespace>> {
+    let apps: Vec<(String, String, u64)> = conn
+        .query("select id, kv_namespaces, createtime from apps")
+        .await?;
+    let apps = apps
+        .into_iter()
+        .map(|(id, bindings, createtime)| Ok((id, serde_json::from_str(&bindings)?, createtime)))
+        .collect::<GenericResult<Vec<_>>>()?;
+    let registered: Vec<String> = conn.query("select id from kv_namespaces").await?;
+    let namespaces = bound_kv_namespaces(apps, &registered);
+    conn.exec_batch(
+        "insert ignore into kv_namespaces (id, owner, name, createtime, max_keys, max_bytes, usedkeys, usedbytes) \
+            select ?, ?, ?, ?, ?, ?, count(*), coalesce(sum(size), 0) from ( \
+                select length(appkey) + length(appvalue) + length(appmetadata) as size from appkv where nsid = ? \
+                union all select length(appkey) + blobsize + length(appmetadata) from appkv_blobs where nsid = ? \
+            ) as stored",
+        namespaces
+            .iter()
+            .map(|x| {
+                (
+                    x.id.as_str(),
+                    x.owner.as_str(),
+                    x.name.as_str(),
+                    x.createtime,
+                    x.max_keys,
+                    x.max_bytes,
+                    x.id.as_str(),
+                    x.id.as_str(),
+                )
+            })
+            .collect::<Vec<_>>(),
+    )
+    .await?;
+    if !namespaces.is_empty() {
+        info!(
+            "registered {} kv namespaces of existing apps",
+            namespaces.len()
+        );
+    }
+    Ok(namespaces)
+}
+
+/// Returns the sizes of the KV entries at `keys` that exist, including expired entries that are not
+/// garbage-collected yet, and locks them.
+async fn entry_sizes(
+    txn: &mut Transaction<'_>,
+    namespace_id: &str,
+    keys: &[&[u8]],
+) -> GenericResult<BTreeMap<Vec<u8>, i64>> {
+    if keys.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    let query = format!(
+        "select appkey, cast(length(appkey) + length(appvalue) + length(appmetadata) as signed) from appkv where nsid = ? and appkey in ({}) for update",
+        vec!["?"; keys.len()].join(", "),
+    );
+    let mut prms: Vec<mysql_async::Value> = vec![namespace_id.into()];
+    prms.extend(keys.iter().map(|x| (*x).into()));
+    let rows: Vec<(Vec<u8>, i64)> = txn.exec(query, prms).await.map_err(txn_error)?;
+    Ok(rows.into_iter().collect())
+}
+
+/// Adds to the usage counters of a namespace. Unregistered namespaces have no counters.
+///
+/// Fails with `QuotaExceeded` if the added keys or bytes go over the quota. The check is part of
+/// the update, which holds the namespace row lock until the transaction ends, so concurrent
+/// writers cannot overshoot the quota.
+async fn add_usage(
+    txn: &mut Transaction<'_>,
+    namespace_id: &str,
+    keys: i64,
+    bytes: i64,
+) -> GenericResult<()> {
+    if keys == 0 && bytes == 0 {
+        return Ok(());
+    }
+    txn.exec_drop(
+        "update kv_namespaces set usedkeys = usedkeys + :keys, usedbytes = usedbytes + :bytes where id = :id \
+            and (:keys <= 0 or max_keys = 0 or usedkeys + :keys <= max_keys) \
+            and (:bytes <= 0 or max_bytes = 0 or usedbytes + :bytes <= max_bytes)",
+        params! {
+            "id" => namespace_id,
+            "keys" => keys,
+            "bytes" => bytes,
+        },
+    )
+    .await
+    .map_err(txn_error)?;
+    // A matched row always changes, since `keys` or `bytes` is not 0.
+    if txn.affected_rows() == 0 {
+        let registered: Option<u32> = txn
+            .exec_first("select 1 from kv_namespaces where id = ?", (namespace_id,))
+            .await
+            .map_err(txn_error)?;
+        if registered.is_some() {
+            return Err(GenericError::QuotaExceeded);
+        }
+    }
+    Ok(())
+}
+
+/// Updates the usage counters of a namespace after writing the KV entries at `keys`, given their
+/// sizes before the write as returned by `entry_sizes`.
+async fn track_entries(
+    txn: &mut Transaction<'_>,
+    namespace_id: &str,
+    keys: &[&[u8]],
+    before: &BTreeMap<Vec<u8>, i64>,
+) -> GenericResult<()> {
+    let after = entry_sizes(txn, namespace_id, keys).await?;
+    add_usage(
+        txn,
+        namespace_id,
+        after.len() as i64 - before.len() as i64,
+        after.values().sum::<i64>() - before.values().sum::<i64>(),
+    )
+}
+
+/// Deletes all chunks of a blob.
+async fn delete_blob_chunks(
+    txn: &mut Transaction<'_>,
+    namespace_id: &str,
+    blob_id: &str,
+) -> Result<(), mysql_async::Error> {
+    txn.exec_drop(
+        "delete from appkv_chunks where nsid = ? and blobid = ?",
+        (namespace_id, blob_id),
+    )
+    .await
+}
+
+/// Records changes to KV entries in the change log. A `None` value records a delete.
+async fn log_changes(
+    txn: &mut Transaction<'_>,
+    namespace_id: &str,
+    changes: &[(&[u8], Option<&[u8]>)],
+) -> Result<(), mysql_async::Error> {
+    let empty: &[u8] = &[];
+    let changetime = current_millis();
+    txn.exec_batch(
+        "insert into appkv_changes (nsid, appkey, appvalue, deleted, changetime) values(?, ?, ?, ?, ?)",
+        changes
+            .iter()
+            .map(|(k, v)| (namespace_id, *k, v.unwrap_or(empty), v.is_none(), changetime))
+            .collect::<Vec<_>>(),
+    )
+    .await
+}
+
+#[async_trait]
+impl DataBackend for MysqlBackend {
+    async fn worker_data_get(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+    ) -> GenericResult<Option<Vec<u8>>> {
+        let mut conn = self.db.get_conn().await?;
+        let value: Option<Vec<u8>> = conn
+            .exec_first(
+                "select appvalue from appkv where nsid = ? and appkey = ? and (appexpiration = 0 or appexpiration > ?)",
+                (namespace_id, key, current_millis()),
+            )
+            .await?;
+        Ok(value)
+    }
+
+    async fn worker_data_get_with_metadata(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+    ) -> GenericResult<Option<(Vec<u8>, Vec<u8>)>> {
+        let mut conn = self.db.get_conn().await?;
+        let value: Option<(Vec<u8>, Vec<u8>)> = conn
+            .exec_first(
+                "select appvalue, appmetadata from appkv where nsid = ? and appkey = ? and (appexpiration = 0 or appexpiration > ?)",
+                (namespace_id, key, current_millis()),
+            )
+            .await?;
+        Ok(value)
+    }
+
+    async fn worker_data_get_many(
+        &self,
+        namespace_id: &str,
+        keys: &[Vec<u8>],
+    ) -> GenericResult<Vec<Option<Vec<u8>>>> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut conn = self.db.get_conn().await?;
+        let query = format!(
+            "select appkey, appvalue from appkv where nsid = ? and appkey in ({}) and (appexpiration = 0 or appexpiration > ?)",
+            vec!["?"; keys.len()].join(", "),
+        );
+        let mut prms: Vec<mysql_async::Value> = vec![namespace_id.into()];
+        prms.extend(keys.iter().map(|x| x.as_slice().into()));
+        prms.push(current_millis().into());
+
+        let rows: Vec<(Vec<u8>, Vec<u8>)> = conn.exec(query, prms).await?;
+        let values: BTreeMap<Vec<u8>, Vec<u8>> = rows.into_iter().collect();
+        Ok(keys.iter().map(|k| values.get(k).cloned()).collect())
+    }
+
+    async fn worker_data_put(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+        value: &[u8],
+        metadata: &[u8],
+        if_not_exists: bool,
+        ttl_ms: u64,
+    ) -> GenericResult<()> {
+        let mut txn = self.db.start_transaction(TxOpts::new()).await?;
+        let expiration = expiration_from_ttl(ttl_ms)?;
+        let before = entry_sizes(&mut txn, namespace_id, &[key]).await?;
+
+        let prms = params! {
+            "nsid" => namespace_id,
+            "appkey" => key,
+            "appvalue" => value,
+            "appmetadata" => metadata,
+            "appexpiration" => expiration,
+            "currenttime" => current_millis(),
+        };
+
+        if if_not_exists {
+            txn.exec_drop(
+                format!(
+                    "{} where not exists ({})",
+                    "replace into appkv (nsid, appkey, appvalue, appmetadata, appexpiration) select :nsid, :appkey, :appvalue, :appmetadata, :appexpiration",
+                    "select 1 from appkv where nsid = :nsid and appkey = :appkey and (appexpiration = 0 or appexpiration > :currenttime)"
+                ),
+                prms,
+            ).await?;
+            if txn.affected_rows() == 0 {
+                // The key exists. Nothing changed.
+                return Ok(());
+            }
+        } else {
+            txn.exec_drop(
+                format!(
+                    "{} on duplicate key {}",
+                    "insert into appkv (nsid, appkey, appvalue, appmetadata, appexpiration) values(:nsid, :appkey, :appvalue, :appmetadata, :appexpiration)",
+                    "update appvalue = :appvalue, appmetadata = :appmetadata, appexpiration = :appexpiration",
+                ),
+                prms,
+            ).await?;
+        }
+        log_changes(&mut txn, namespace_id, &[(key, Some(value))]).await?;
+        track_entries(&mut txn, namespace_id, &[key], &before).await?;
+        txn.commit().await?;
+        Ok(())
+    }
+
+    async fn worker_data_put_many(
+        &self,
+        namespace_id: &str,
+        entries: &[(Vec<u8>, Vec<u8>)],
+        ttl_ms: u64,
+    ) -> GenericResult<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut txn = self.db.start_transaction(TxOpts::new()).await?;
+        let expiration = expiration_from_ttl(ttl_ms)?;
+        let keys: Vec<&[u8]> = entries.iter().map(|(k, _)| k.as_slice()).collect();
+        let before = entry_sizes(&mut txn, namespace_id, &keys).await?;
+        let empty_md: &[u8] = &[];
+        let query = format!(
+            "{} {} on duplicate key {}",
+            "insert into appkv (nsid, appkey, appvalue, appmetadata, appexpiration) values",
+            vec!["(?, ?, ?, ?, ?)"; entries.len()].join(", "),
+            "update appvalue = values(appvalue), appmetadata = values(appmetadata), appexpiration = values(appexpiration)",
+        );
+        let mut prms: Vec<mysql_async::Value> = Vec::with_capacity(entries.len() * 5);
+        for (k, v) in entries {
+            prms.push(namespace_id.into());
+            prms.push(k.as_slice().into());
+            prms.push(v.as_slice().into());
+            prms.push(empty_md.into());
+            prms.push(expiration.into());
+        }
+        txn.exec_drop(query, prms).await?;
+        let changes: Vec<(&[u8], Option<&[u8]>)> = entries
+            .iter()
+            .map(|(k, v)| (k.as_slice(), Some(v.as_slice())))
+            .collect();
+        log_changes(&mut txn, namespace_id, &changes).await?;
+        track_entries(&mut txn, namespace_id, &keys, &before).await?;
+        txn.commit().await?;
+        Ok(())
+    }
+
+    async fn worker_data_scan_keys(
+        &self,
+        namespace_id: &str,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: u32,
+    ) -> GenericResult<Vec<Vec<u8>>> {
+        let mut conn = self.db.get_conn().await?;
+        let result: Vec<Vec<u8>> = if let Some(end) = end {
+            conn.exec(
+                "select appkey from appkv where nsid = ? and appkey between ? and ? and (appexpiration = 0 or appexpiration > ?) limit ?",
+                (namespace_id, start, end, current_millis(), limit),
+            )
+            .await?
+        } else {
+            conn.exec(
+                "select appkey from appkv where nsid = ? and appkey >= ? and (appexpiration = 0 or appexpiration > ?) limit ?",
+                (namespace_id, start, current_millis(), limit),
+            )
+            .await?
+        };
+        Ok(result)
+    }
+
+    async fn worker_data_delete_many(
+        &self,
+        namespace_id: &str,
+        keys: &[Vec<u8>],
+    ) -> GenericResult<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let mut txn = self.db.start_transaction(TxOpts::new()).await?;
+        let query = format!(
+            "delete from appkv where nsid = ? and appkey in ({})",
+            vec!["?"; keys.len()].join(", "),
+        );
+        let mut prms: Vec<mysql_async::Value> = vec![namespace_id.into()];
+        prms.extend(keys.iter().map(|x| x.as_slice().into()));
+        let keys: Vec<&[u8]> = keys.iter().map(|x| x.as_slice()).collect();
+        let existing = entry_sizes(&mut txn, namespace_id, &keys).await?;
+        if existing.is_empty() {
+            return Ok(());
+        }
+        txn.exec_drop(query, prms).await?;
+        let changes: Vec<(&[u8], Option<&[u8]>)> =
+            existing.keys().map(|k| (k.as_slice(), None)).collect();
+        log_changes(&mut txn, namespace_id, &changes).await?;
+        add_usage(
+            &mut txn,
+            namespace_id,
+            -(existing.len() as i64),
+            -existing.values().sum::<i64>(),
+        )
+        .await?;
+        txn.commit().await?;
+        Ok(())
+    }
+
+    async fn worker_data_increment(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+        delta: i64,
+        ttl_ms: u64,
+    ) -> GenericResult<i64> {
+        let mut txn = self.db.start_transaction(TxOpts::new()).await?;
+        let empty_md: &[u8] = &[];
+        let before = entry_sizes(&mut txn, namespace_id, &[key]).await?;
+
+        // The new value is passed back through `LAST_INSERT_ID(expr)`, which MySQL treats as
+        // unsigned. Assignments in `update` are evaluated left to right, so `appexpiration` still
+        // refers to the old value until it is assigned last.
+        txn.exec_drop(
+            format!(
+                "{} on duplicate key {}",
+                "insert into appkv (nsid, appkey, appvalue, appmetadata, appexpiration) values(:nsid, :appkey, cast(cast(last_insert_id(:delta) as signed) as char), :appmetadata, :appexpiration)",
+                "update appvalue = cast(cast(last_insert_id(if(appexpiration = 0 or appexpiration > :currenttime, cast(appvalue as signed), 0) + :delta) as signed) as char), \
+                    appmetadata = if(appexpiration = 0 or appexpiration > :currenttime, appmetadata, :appmetadata), \
+                    appexpiration = if(:ttl_ms = 0, if(appexpiration = 0 or appexpiration > :currenttime, appexpiration, 0), :appexpiration)",
+            ),
+            params! {
+                "nsid" => namespace_id,
+                "appkey" => key,
+                "delta" => delta,
+                "appmetadata" => empty_md,
+                "appexpiration" => expiration_from_ttl(ttl_ms)?,
+                "ttl_ms" => ttl_ms,
+                "currenttime" => current_millis(),
+            },
+        )
+        .await?;
+
+        // Read before logging the change and updating the counters, which run more statements.
+        let value = txn.last_insert_id().unwrap_or(0) as i64;
+        log_changes(
+            &mut txn,
+            namespace_id,
+            &[(key, Some(value.to_string().as_bytes()))],
+        )
+        .await?;
+        track_entries(&mut txn, namespace_id, &[key], &before).await?;
+        txn.commit().await?;
+        Ok(value)
+    }
+
+    async fn worker_data_list(
+        &self,
+        namespace_id: &str,
+        prefix: &[u8],
+        after: Option<&[u8]>,
+        limit: u32,
+        include_values: bool,
+        include_metadata: bool,
+    ) -> GenericResult<(Vec<KvListEntry>, bool)> {
+        let mut conn = self.db.get_conn().await?;
+        let upper_bound = prefix_upper_bound(prefix);
+
+        let mut query = format!(
+            "select appkey, {}, {}, appexpiration from appkv where nsid = ? and appkey >= ?",
+            if include_values { "appvalue" } else { "''" },
+            if include_metadata {
+                "appmetadata"
+            } else {
+                "''"
+            },
+        );
+        let mut prms: Vec<mysql_async::Value> = vec![namespace_id.into(), prefix.into()];
+        if let Some(upper_bound) = upper_bound {
+            query += " and appkey < ?";
+            prms.push(upper_bound.into());
+        }
+        if let Some(after) = after {
+            query += " and appkey > ?";
+            prms.push(after.into());
+        }
+        query += " and (appexpiration = 0 or appexpiration > ?) order by appkey limit ?";
+        prms.push(current_millis().into());
+
+        // Fetch one more row to tell whether there are more entries.
+        prms.push((limit as u64 + 1).into());
+
+        let rows: Vec<(Vec<u8>, Vec<u8>, Vec<u8>, u64)> = conn.exec(query, prms).await?;
+        let list_complete = rows.len() <= limit as usize;
+        let entries = rows
+            .into_iter()
+            .take(limit as usize)
+            .map(|(key, value, metadata, expiration)| KvListEntry {
+                key,
+                value,
+                metadata,
+                expiration,
+            })
+            .collect();
+        Ok((entries, list_complete))
+    }
+
+    async fn worker_data_delete(&self, namespace_id: &str, key: &[u8]) -> GenericResult<()> {
+        let mut txn = self.db.start_transaction(TxOpts::new()).await?;
+        let size = match entry_sizes(&mut txn, namespace_id, &[key]).await?.get(key) {
+            Some(x) => *x,
+            None => return Ok(()),
+        };
+        txn.exec_drop(
+            "delete from appkv where nsid = ? and appkey = ?",
+            (namespace_id, key),
+        )
+        .await?;
+        log_changes(&mut txn, namespace_id, &[(key, None)]).await?;
+        add_usage(&mut txn, namespace_id, -1, -size).await?;
+        txn.commit().await?;
+        Ok(())
+    }
+
+    async fn worker_data_delete_expired(
+        &self,
+        batch_size: u32,
+        dry_run: bool,
+    ) -> GenericResult<u64> {
+        let mut conn = self.db.get_conn().await?;
+        if dry_run {
+            let count: Option<u64> = conn
+                .exec_first(
+                    "select count(*) from (select 1 from appkv where appexpiration > 0 and appexpiration <= ? limit ?) t",
+                    (current_millis(), batch_size),
+                )
+                .await?;
+            Ok(count.unwrap_or(0))
+        } else {
+            drop(conn);
+            let mut txn = self.db.start_transaction(TxOpts::new()).await?;
+            let expired: Vec<(String, Vec<u8>, i64)> = txn
+                .exec(
+                    "select nsid, appkey, cast(length(appkey) + length(appvalue) + length(appmetadata) as signed) from appkv \
+                        where appexpiration > 0 and appexpiration <= ? limit ? for update",
+                    (current_millis(), batch_size),
+                )
+                .await?;
+            if expired.is_empty() {
+                return Ok(0);
+            }
+            txn.exec_batch(
+                "delete from appkv where nsid = ? and appkey = ?",
+                expired
+                    .iter()
+                    .map(|(ns, k, _)| (ns.as_str(), k.as_slice()))
+                    .collect::<Vec<_>>(),
+            )
+            .await?;
+            let mut usage: BTreeMap<&str, (i64, i64)> = BTreeMap::new();
+            for (ns, _, size) in &expired {
+                let x = usage.entry(ns.as_str()).or_default();
+                x.0 -= 1;
+                x.1 -= size;
+            }
+            for (ns, (keys, bytes)) in usage {
+                add_usage(&mut txn, ns, keys, bytes).await?;
+            }
+            txn.commit().await?;
+            Ok(expired.len() as u64)
+        }
+    }
+
+    async fn worker_data_cmpupdate(
+        &self,
+        namespace_id: &str,
+        assertions: &[(Vec<u8>, Vec<u8>)],
+        writes: &[(Vec<u8>, Vec<u8>)],
+        ttl_ms: u64,
+    ) -> GenericResult<bool> {
+        let current_time = current_millis();
+        let expiration = expiration_from_ttl(ttl_ms)?;
+
+        let mut opts = TxOpts::new();
+        opts.with_isolation_level(IsolationLevel::RepeatableRead);
+        let mut txn = self.db.start_transaction(opts).await?;
+        let stmt = txn
+            .prep("select 1 from appkv where nsid = ? and appkey = ? and appvalue = ? and (appexpiration = 0 or appexpiration > ?) for update")
+            .await?;
+        for (k, v) in assertions {
+            let existence: Option<u32> = txn
+                .exec_first(&stmt, (namespace_id, k, v, current_time))
+                .await?;
+            if existence.is_none() {
+                return Ok(false);
+            }
+        }
+        let keys: Vec<&[u8]> = writes.iter().map(|(k, _)| k.as_slice()).collect();
+        let before = entry_sizes(&mut txn, namespace_id, &keys).await?;
+        let empty_md: &[u8] = &[];
+        txn.exec_batch(
+            format!(
+                "{} on duplicate key {}",
+                "insert into appkv (nsid, appkey, appvalue, appmetadata, appexpiration) values(:nsid, :appkey, :appvalue, :appmetadata, :appexpiration)",
+                "update appvalue = :appvalue, appmetadata = :appmetadata, appexpiration = :appexpiration",
+            ),
+            writes.iter().map(|(k, v)| params! {
+                "nsid" => namespace_id,
+                "appkey" => k,
+                "appvalue" => v,
+                "appmetadata" => empty_md,
+                "appexpiration" => expiration,
+            }).collect::<Vec<_>>(),
+        ).await?;
+        let changes: Vec<(&[u8], Option<&[u8]>)> = writes
+            .iter()
+            .map(|(k, v)| (k.as_slice(), Some(v.as_slice())))
+            .collect();
+        log_changes(&mut txn, namespace_id, &changes).await?;
+        track_entries(&mut txn, namespace_id, &keys, &before).await?;
+        txn.commit().await?;
+        Ok(true)
+    }
+
+    async fn worker_data_commit(
+        &self,
+        namespace_id: &str,
+        reads: &[(Vec<u8>, Option<Vec<u8>>)],
+        writes: &[(Vec<u8>, KvMutation)],
+    ) -> GenericResult<bool> {
+        let writes = writes
+            .iter()
+            .map(|(k, m)| {
+                let expiration = match m {
+                    KvMutation::Put { ttl_ms, .. } => expiration_from_ttl(*ttl_ms)?,
+                    KvMutation::Delete => 0,
+                };
+                Ok((k.clone(), m.clone(), expiration))
+            })
+            .collect::<GenericResult<Vec<_>>>()?;
+
+        // Lock conflicts are reported as failed commits, so that the worker retries.
+        match self.try_commit(namespace_id, reads, &writes).await {
+            Err(GenericError::TryAgain) => Ok(false),
+            x => x,
+        }
+    }
+
+    async fn worker_blob_put_chunk(
+        &self,
+        namespace_id: &str,
+        blob_id: &str,
+        index: u32,
+        data: &[u8],
+    ) -> GenericResult<()> {
+        let mut conn = self.db.get_conn().await?;
+        conn.exec_drop(
+            "insert into appkv_chunks (nsid, blobid, chunkidx, data, createtime) values(?, ?, ?, ?, ?)",
+            (namespace_id, blob_id, index, data, current_millis()),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn worker_blob_commit(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+        blob: &KvBlob,
+        ttl_ms: u64,
+    ) -> GenericResult<bool> {
+        let expiration = expiration_from_ttl(ttl_ms)?;
+
+        let mut opts = TxOpts::new();
+        opts.with_isolation_level(IsolationLevel::RepeatableRead);
+        let mut txn = self.db.start_transaction(opts).await?;
+
+        let uploaded: Option<(u32, u64)> = txn
+            .exec_first(
+                "select count(*), cast(coalesce(sum(length(data)), 0) as unsigned) from appkv_chunks where nsid = ? and blobid = ?",
+                (namespace_id, &blob.blob_id),
+            )
+            .await?;
+        if uploaded != Some((blob.chunks, blob.size)) {
+            return Ok(false);
+        }
+        let committed: Option<u32> = txn
+            .exec_first(
+                "select 1 from appkv_blobs where nsid = ? and blobid = ? limit 1 for update",
+                (namespace_id, &blob.blob_id),
+            )
+            .await?;
+        if committed.is_some() {
+            return Ok(false);
+        }
+
+        let prev: Option<(String, i64)> = txn
+            .exec_first(
+                "select blobid, cast(length(appkey) + blobsize + length(appmetadata) as signed) from appkv_blobs where nsid = ? and appkey = ? for update",
+                (namespace_id, key),
+            )
+            .await?;
+        txn.exec_drop(
+            format!(
+                "{} on duplicate key {}",
+                "insert into appkv_blobs (nsid, appkey, blobid, blobsize, chunks, appmetadata, appexpiration) values(:nsid, :appkey, :blobid, :blobsize, :chunks, :appmetadata, :appexpiration)",
+                "update blobid = :blobid, blobsize = :blobsize, chunks = :chunks, appmetadata = :appmetadata, appexpiration = :appexpiration",
+            ),
+            params! {
+                "nsid" => namespace_id,
+                "appkey" => key,
+                "blobid" => &blob.blob_id,
+                "blobsize" => blob.size,
+                "chunks" => blob.chunks,
+                "appmetadata" => &blob.metadata,
+                "appexpiration" => expiration,
+            },
+        )
+        .await?;
+
+        // The previous blob is replaced, so only the difference counts against the quota.
+        let size = (key.len() + blob.metadata.len()) as i64 + blob.size as i64;
+        match prev {
+            Some((prev, prev_size)) => {
+                add_usage(&mut txn, namespace_id, 0, size - prev_size).await?;
+                delete_blob_chunks(&mut txn, namespace_id, &prev).await?;
+            }
+            None => add_usage(&mut txn, namespace_id, 1, size).await?,
+        }
+        txn.commit().await?;
+        Ok(true)
+    }
+
+    async fn worker_blob_get(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+    ) -> GenericResult<Option<KvBlob>> {
+        let mut conn = self.db.get_conn().await?;
+        let row: Option<(String, u64, u32, Vec<u8>, u64)> = conn
+            .exec_first(
+                "select blobid, blobsize, chunks, appmetadata, appexpiration from appkv_blobs where nsid = ? and appkey = ? and (appexpiration = 0 or appexpiration > ?)",
+                (namespace_id, key, current_millis()),
+            )
+            .await?;
+        Ok(
+            row.map(|(blob_id, size, chunks, metadata, expiration)| KvBlob {
+                blob_id,
+                size,
+                chunks,
+                metadata,
+                expiration,
+            }),
+        )
+    }
+
+    async fn worker_blob_get_chunk(
+        &self,
+        namespace_id: &str,
+        blob_id: &str,
+        index: u32,
+    ) -> GenericResult<Option<Vec<u8>>> {
+        let mut conn = self.db.get_conn().await?;
+        Ok(conn
+            .exec_first(
+                "select data from appkv_chunks where nsid = ? and blobid = ? and chunkidx = ?",
+                (namespace_id, blob_id, index),
+            )
+            .await?)
+    }
+
+    async fn worker_blob_delete(&self, namespace_id: &str, key: &[u8]) -> GenericResult<()> {
+        let mut txn = self.db.start_transaction(TxOpts::new()).await?;
+        let prev: Option<(String, i64)> = txn
+            .exec_first(
+                "select blobid, cast(length(appkey) + blobsize + length(appmetadata) as signed) from appkv_blobs where nsid = ? and appkey = ? for update",
+                (namespace_id, key),
+            )
+            .await?;
+        if let Some((prev, size)) = prev {
+            txn.exec_drop(
+                "delete from appkv_blobs where nsid = ? and appkey = ?",
+                (namespace_id, key),
+            )
+            .await?;
+            delete_blob_chunks(&mut txn, namespace_id, &prev).await?;
+            add_usage(&mut txn, namespace_id, -1, -size).await?;
+        }
+        txn.commit().await?;
+        Ok(())
+    }
+
+    async fn worker_blob_list(
+        &self,
+        namespace_id: &str,
+        prefix: &[u8],
+        from: &[u8],
+        limit: u32,
+    ) -> GenericResult<Vec<(Vec<u8>, KvBlob)>> {
+        let mut conn = self.db.get_conn().await?;
+        let start = std::cmp::max(prefix, from);
+
+        let mut query = "select appkey, blobid, blobsize, chunks, appmetadata, appexpiration from appkv_blobs where nsid = ? and appkey >= ?".to_string();
+        let mut prms: Vec<mysql_async::Value> = vec![namespace_id.into(), start.into()];
+        if let Some(upper_bound) = prefix_upper_bound(prefix) {
+            query += " and appkey < ?";
+            prms.push(upper_bound.into());
+        }
+        query += " and (appexpiration = 0 or appexpiration > ?) order by appkey limit ?";
+        prms.push(current_millis().into());
+        prms.push(limit.into());
+
+        let rows: Vec<(Vec<u8>, String, u64, u32, Vec<u8>, u64)> = conn.exec(query, prms).await?;
+        Ok(rows
+            .into_iter()
+            .map(|(key, blob_id, size, chunks, metadata, expiration)| {
+                (
+                    key,
+                    KvBlob {
+                        blob_id,
+                        size,
+                        chunks,
+                        metadata,
+                        expiration,
+                    },
+                )
+            })
+            .collect())
+    }
+
+    async fn worker_blob_delete_expired(&self, batch_size: u32) -> GenericResult<u64> {
+        let current_time = current_millis();
+        let mut deleted = 0u64;
+
+        let mut conn = self.db.get_conn().await?;
+        let expired: Vec<(String, String)> = conn
+            .exec(
+                "select nsid, blobid from appkv_blobs where appexpiration > 0 and appexpiration <= ? limit ?",
+                (current_time, batch_size),
+            )
+            .await?;
+        drop(conn);
+        for (namespace_id, blob_id) in expired {
+            // Re-check the expiration, in case the key was rewritten in the meantime.
+            let mut txn = self.db.start_transaction(TxOpts::new()).await?;
+            let size: Option<i64> = txn
+                .exec_first(
+                    "select cast(length(appkey) + blobsize + length(appmetadata) as signed) from appkv_blobs where nsid = ? and blobid = ? and appexpiration > 0 and appexpiration <= ? for update",
+                    (&namespace_id, &blob_id, current_time),
+                )
+                .await?;
+            if let Some(size) = size {
+                txn.exec_drop(
+                    "delete from appkv_blobs where nsid = ? and blobid = ?",
+                    (&namespace_id, &blob_id),
+                )
+                .await?;
+                delete_blob_chunks(&mut txn, &namespace_id, &blob_id).await?;
+                add_usage(&mut txn, &namespace_id, -1, -size).await?;
+                deleted += 1;
+            }
+            txn.commit().await?;
+        }
+
+        let mut conn = self.db.get_conn().await?;
+        conn.exec_drop(
+            "delete from appkv_chunks where createtime < ? and not exists \
+                (select 1 from appkv_blobs where appkv_blobs.nsid = appkv_chunks.nsid and appkv_blobs.blobid = appkv_chunks.blobid) \
+                limit ?",
+            (current_time.saturating_sub(BLOB_UPLOAD_TIMEOUT_MS), batch_size),
+        )
+        .await?;
+        deleted += conn.affected_rows();
+        Ok(deleted)
+    }
+
+    async fn worker_data_changes(
+        &self,
+        namespace_id: &str,
+        prefix: &[u8],
+        after: u64,
+        limit: u32,
+    ) -> GenericResult<Vec<KvChange>> {
+        let mut conn = self.db.get_conn().await?;
+        let mut query =
+            "select seq, appkey, appvalue, deleted, changetime from appkv_changes where nsid = ? and seq > ? and appkey >= ?"
+                .to_string();
+        let mut prms: Vec<mysql_async::Value> =
+            vec![namespace_id.into(), after.into(), prefix.into()];
+        if let Some(upper_bound) = prefix_upper_bound(prefix) {
+            query += " and appkey < ?";
+            prms.push(upper_bound.into());
+        }
+        query += " order by seq limit ?";
+        prms.push(limit.into());
+
+        let rows: Vec<(u64, Vec<u8>, Vec<u8>, bool, u64)> = conn.exec(query, prms).await?;
+        Ok(rows
+            .into_iter()
+            .map(|(seq, key, value, deleted, time)| KvChange {
+                seq,
+                key,
+                value: if deleted { None } else { Some(value) },
+                time,
+            })
+            .collect())
+    }
+
+    async fn worker_data_changes_latest_seq(&self) -> GenericResult<u64> {
+        let mut conn = self.db.get_conn().await?;
+        let seq: Option<Option<u64>> = conn
+            .query_first("select max(seq) from appkv_changes")
+            .await?;
+        Ok(seq.flatten().unwrap_or(0))
+    }
+
+    async fn worker_data_delete_changes(&self, before: u64, batch_size: u32) -> GenericResult<u64> {
+        let mut conn = self.db.get_conn().await?;
+        conn.exec_drop(
+            "delete from appkv_changes where changetime < ? limit ?",
+            (before, batch_size),
+        )
+        .await?;
+        Ok(conn.affected_rows())
+    }
+
+    async fn kv_watch_create(&self, watch: &KvWatch) -> GenericResult<()> {
+        let mut conn = self.db.get_conn().await?;
+        conn.exec_drop(
+            "insert into kv_watches (id, appid, nsid, prefix, lastseq, createtime) values(?, ?, ?, ?, ?, ?)",
+            (
+                &watch.id,
+                &watch.appid,
+                &watch.namespace_id,
+                &watch.prefix,
+                watch.cursor,
+                watch.createtime,
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn kv_watch_list(&self, appid: Option<&str>) -> GenericResult<Vec<KvWatch>> {
+        let mut conn = self.db.get_conn().await?;
+        let rows: Vec<(String, String, String, Vec<u8>, u64, u64)> = if let Some(appid) = appid {
+            conn.exec(
+                "select id, appid, nsid, prefix, lastseq, createtime from kv_watches where appid = ? order by id",
+                (appid,),
+            )
+            .await?
+        } else {
+            conn.exec(
+                "select id, appid, nsid, prefix, lastseq, createtime from kv_watches order by id",
+                (),
+            )
+            .await?
+        };
+        Ok(rows
+            .into_iter()
+            .map(
+                |(id, appid, namespace_id, prefix, cursor, createtime)| KvWatch {
+                    id,
+                    appid,
+                    namespace_id,
+                    prefix,
+                    cursor,
+                    createtime,
+                },
+            )
+            .collect())
+    }
+
+    async fn kv_watch_delete(&self, id: &str) -> GenericResult<()> {
+        let mut conn = self.db.get_conn().await?;
+        conn.exec_drop("delete from kv_watches where id = ?", (id,))
+            .await?;
+        Ok(())
+    }
+
+    async fn kv_watch_advance(&self, id: &str, cursor: u64) -> GenericResult<()> {
+        let mut conn = self.db.get_conn().await?;
+        conn.exec_drop(
+            "update kv_watches set lastseq = ? where id = ? and lastseq < ?",
+            (cursor, id, cursor),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn kv_namespace_create(&self, ns: &KvNamespace) -> GenericResult<()> {
+        let mut conn = self.db.get_conn().await?;
+        conn.exec_drop(
+            "insert into kv_namespaces (id, owner, name, createtime, max_keys, max_bytes, usedkeys, usedbytes) \
+                select ?, ?, ?, ?, ?, ?, count(*), coalesce(sum(size), 0) from ( \
+                    select length(appkey) + length(appvalue) + length(appmetadata) as size from appkv where nsid = ? \
+                    union all select length(appkey) + blobsize + length(appmetadata) from appkv_blobs where nsid = ? \
+                ) as stored",
+            (
+                &ns.id,
+                &ns.owner,
+                &ns.name,
+                ns.createtime,
+                ns.max_keys,
+                ns.max_bytes,
+                &ns.id,
+                &ns.id,
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn kv_namespace_get(&self, id: &str) -> GenericResult<Option<KvNamespace>> {
+        let mut conn = self.db.get_conn().await?;
+        let row: Option<KvNamespaceRow> = conn
+            .exec_first(
+                "select id, owner, name, createtime, max_keys, max_bytes, usedkeys, usedbytes from kv_namespaces where id = ?",
+                (id,),
+            )
+            .await?;
+        Ok(row.map(kv_namespace_from_row))
+    }
+
+    async fn kv_namespace_list(&self, owner: Option<&str>) -> GenericResult<Vec<KvNamespace>> {
+        let mut conn = self.db.get_conn().await?;
+        let rows: Vec<KvNamespaceRow> = if let Some(owner) = owner {
+            conn.exec(
+                "select id, owner, name, createtime, max_keys, max_bytes, usedkeys, usedbytes from kv_namespaces where owner = ? order by id",
+                (owner,),
+            )
+            .await?
+        } else {
+            conn.exec(
+                "select id, owner, name, createtime, max_keys, max_bytes, usedkeys, usedbytes from kv_namespaces order by id",
+                (),
+            )
+            .await?
+        };
+        Ok(rows.into_iter().map(kv_namespace_from_row).collect())
+    }
+
+    async fn kv_namespace_delete(&self, id: &str) -> GenericResult<()> {
+        let mut txn = self.db.start_transaction(TxOpts::new()).await?;
+        txn.exec_drop("delete from kv_namespaces where id = ?", (id,))
+            .await?;
+        txn.exec_drop("delete from appkv where nsid = ?", (id,))
+            .await?;
+        txn.exec_drop("delete from appkv_blobs where nsid = ?", (id,))
+            .await?;
+        txn.exec_drop("delete from appkv_chunks where nsid = ?", (id,))
+            .await?;
+        txn.exec_drop("delete from appkv_changes where nsid = ?", (id,))
+            .await?;
+        txn.exec_drop("delete from kv_watches where nsid = ?", (id,))
+            .await?;
+        txn.commit().await?;
+        Ok(())
+    }
+
+    async fn kv_namespace_backfill(&self) -> GenericResult<Vec<KvNamespace>> {
+        let mut conn = self.db.get_conn().await?;
+        backfill_kv_namespaces(&mut conn).await
+    }
+
+    async fn route_mapping_delete_domain(&self, domain: &str) -> GenericResult<()> {
+        let mut conn = self.db.get_conn().await?;
+        conn.exec_drop("delete from routes where `domain` = ?", (domain,))
+            .await?;
+        Ok(())
+    }
+
+    async fn route_mapping_list_for_domain(
+        &self,
+        domain: &str,
+    ) -> GenericResult<BTreeMap<String, String>> {
+        let mut conn = self.db.get_conn().await?;
+        let items: Vec<(String, String)> = conn
+            .exec(
+                "select path, appid from routes where `domain` = ?",
+                (domain,),
+            )
+            .await?;
+
+        Ok(items.into_iter().collect())
+    }
+
+    async fn route_mapping_lookup(
+        &self,
+        domain: &str,
+        path: &str,
+    ) -> GenericResult<Option<String>> {
+        let mut conn = self.db.get_conn().await?;
+        let appid: Option<String> = conn.exec_first(
+            "select appid from routes where `domain` = ? and ? like concat(`path`, '%') order by length(`path`) desc limit 1",
+            (domain, path)
+        ).await?;
+        // Most specific match
+        Ok(appid)
+    }
+
+    async fn route_mapping_insert(
+        &self,
+        domain: &str,
+        path: &str,
+        appid: String,
+    ) -> GenericResult<()> {
+        let mut conn = self.db.get_conn().await?;
+        conn.exec_drop(
+            "replace into routes (domain, path, appid, createtime) values(?, ?, ?, ?)",
+            (domain, path, appid, current_millis()),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn route_mapping_delete(&self, domain: &str, path: &str) -> GenericResult<()> {
+        let mut conn = self.db.get_conn().await?;
+        conn.exec_drop(
+            "delete from routes where domain = ? and path = ?",
+            (domain, path),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn app_metadata_get(&self, appid: &str) -> GenericResult<Option<AppConfig>> {
+        let mut conn = self.db.get_conn().await?;
+        let (bundle_id, env, kv_namespaces, buckets, log_retention_secs): (
+            String,
+            String,
+            String,
+            Option<String>,
+            Option<u64>,
+        ) = match conn
+            .exec_first(
+                "select bundle_id, env, kv_namespaces, buckets, log_retention_secs from apps where id = ?",
+                (appid,),
+            )
+            .await?
+        {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let config = AppConfig {
+            id: AppId(appid.to_string()),
+            bundle_id,
+            env: serde_json::from_str(&env)?,
+            kv_namespaces: serde_json::from_str(&kv_namespaces)?,
+            buckets: match buckets {
+                Some(x) => serde_json::from_str(&x)?,
+                None => vec![],
+            },
+            log_retention_secs,
+        };
+
+        Ok(Some(config))
+    }
+
+    async fn app_metadata_put(&self, config: &AppConfig) -> GenericResult<()> {
+        let mut conn = self.db.get_conn().await?;
+        conn.exec_drop(UPSERT_APP, app_params(config)?).await?;
+        Ok(())
+    }
+
+    async fn app_metadata_delete(&self, appid: &str) -> GenericResult<()> {
+        let mut conn = self.db.get_conn().await?;
+        conn.exec_drop("delete from apps where id = ?", (appid,))
+            .await?;
+        Ok(())
+    }
+
+    async fn app_deploy(
+        &self,
+        config: &AppConfig,
+        author: &str,
+        message: &str,
+    ) -> GenericResult<u64> {
+        let mut txn = self.db.start_transaction(TxOpts::new()).await?;
+        let last: Option<Option<u64>> = txn
+            .exec_first(
+                "select max(version) from deployments where appid = ? for update",
+                (&config.id.0,),
+            )
+            .await?;
+        let version = last.flatten().unwrap_or(0) + 1;
+        txn.exec_drop(
+            "insert into deployments (appid, version, bundle_id, config, author, message, createtime) values(?, ?, ?, ?, ?, ?, ?)",
+            (
+                &config.id.0,
+                version,
+                &config.bundle_id,
+                serde_json::to_string(config)?,
+                author,
+                message,
+                current_millis(),
+            ),
+        )
+        .await?;
+        txn.exec_drop(UPSERT_APP, app_params(config)?).await?;
+        txn.commit().await?;
+        Ok(version)
+    }
+
+    async fn app_deployment_list(&self, appid: &str) -> GenericResult<Vec<Deployment>> {
+        let mut conn = self.db.get_conn().await?;
+        let rows: Vec<DeploymentRow> = conn
+            .exec(
+                "select version, config, author, message, createtime from deployments where appid = ? order by version desc",
+                (appid,),
+            )
+            .await?;
+        rows.into_iter().map(deployment_from_row).collect()
+    }
+
+    async fn app_deployment_get(
+        &self,
+        appid: &str,
+        version: u64,
+    ) -> GenericResult<Option<Deployment>> {
+        let mut conn = self.db.get_conn().await?;
+        let row: Option<DeploymentRow> = conn
+            .exec_first(
+                "select version, config, author, message, createtime from deployments where appid = ? and version = ?",
+                (appid, version),
+            )
+            .await?;
+        row.map(deployment_from_row).transpose()
+    }
+
+    async fn app_deployment_prune(&self, appid: &str, keep: u32) -> GenericResult<u64> {
+        let mut conn = self.db.get_conn().await?;
+        let last: Option<Option<u64>> = conn
+            .exec_first(
+                "select max(version) from deployments where appid = ?",
+                (appid,),
+            )
+            .await?;
+        let last = match last.flatten() {
+            Some(x) => x,
+            None => return Ok(0),
+        };
+        conn.exec_drop(
+            "delete from deployments where appid = ? and version <= ?",
+            (appid, last.saturating_sub(keep as u64)),
+        )
+        .await?;
+        Ok(conn.affected_rows())
+    }
+
+    async fn app_bundle_get(&self, id: &str) -> GenericResult<Option<Vec<u8>>> {
+        let mut conn = self.db.get_conn().await?;
+        let bundle: Option<Vec<u8>> = conn
+            .exec_first("select bundle from bundles where id = ?", (id,))
+            .await?;
+        Ok(bundle)
+    }
+
+    async fn app_bundle_put(&self, id: &str, value: &[u8]) -> GenericResult<()> {
+        let mut conn = self.db.get_conn().await?;
+        conn.exec_drop(
+            "insert into bundles (id, bundle, createtime) values(?, ?, ?) on duplicate key update createtime = values(createtime)",
+            (id, value, current_millis()),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn app_bundle_touch(&self, id: &str) -> GenericResult<bool> {
+        let mut conn = self.db.get_conn().await?;
+        conn.exec_drop(
+            "update bundles set createtime = ? where id = ?",
+            (current_millis(), id),
+        )
+        .await?;
+        let exists: Option<bool> = conn
+            .exec_first("select count(*) > 0 from bundles where id = ?", (id,))
+            .await?;
+        Ok(exists.unwrap_or(false))
+    }
+
+    async fn app_bundle_list_unreferenced(
+        &self,
+        created_before: u64,
+        limit: u32,
+    ) -> GenericResult<Vec<String>> {
+        let mut conn = self.db.get_conn().await?;
+        let ids: Vec<String> = conn
+            .exec(
+                "select id from bundles where createtime < ? and not exists (select 1 from apps where apps.bundle_id = bundles.id) and not exists (select 1 from deployments where deployments.bundle_id = bundles.id) limit ?",
+                (created_before, limit),
+            )
+            .await?;
+        Ok(ids)
+    }
+
+    async fn app_bundle_delete_unreferenced(
+        &self,
+        id: &str,
+        created_before: u64,
+    ) -> GenericResult<bool> {
+        let mut txn = self.db.start_transaction(TxOpts::new()).await?;
+        txn.exec_drop(
+            "delete from bundles where id = ? and createtime < ? and not exists (select 1 from apps where bundle_id = ?) and not exists (select 1 from deployments where bundle_id = ?)",
+            (id, created_before, id, id),
+        )
+        .await?;
+        if txn.affected_rows() == 0 {
+            return Ok(false);
+        }
+        txn.exec_drop("delete from bundle_code_cache where bundle_id = ?", (id,))
+            .await?;
+        txn.commit().await?;
+        Ok(true)
+    }
+
+    async fn app_bundle_code_cache_get(
+        &self,
+        bundle_id: &str,
+        v8_version: &str,
+    ) -> GenericResult<Option<Vec<u8>>> {
+        let mut conn = self.db.get_conn().await?;
+        let cache: Option<Vec<u8>> = conn
+            .exec_first(
+                "select cache from bundle_code_cache where bundle_id = ? and v8_version = ?",
+                (bundle_id, v8_version),
+            )
+            .await?;
+        Ok(cache)
+    }
+
+    async fn app_bundle_code_cache_put(
+        &self,
+        bundle_id: &str,
+        v8_version: &str,
+        cache: &[u8],
+    ) -> GenericResult<()> {
+        let mut conn = self.db.get_conn().await?;
+        conn.exec_drop(
+            "replace into bundle_code_cache (bundle_id, v8_version, cache, createtime) values(?, ?, ?, ?)",
+            (bundle_id, v8_version, cache, current_millis()),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn applog_write_many(&self, entries: &[AppLogEntry]) -> GenericResult<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut conn = self.db.get_conn().await?;
+        for batch in entries.chunks(APPLOG_INSERT_BATCH_SIZE) {
+            let query = format!(
+                "insert ignore into applog (appid, logtime, subid, logcontent) values {}",
+                vec!["(?, ?, ?, ?)"; batch.len()].join(", "),
+            );
+            let mut prms: Vec<mysql_async::Value> = Vec::with_capacity(batch.len() * 4);
+            for entry in batch {
+                prms.push(entry.appid.as_str().into());
+                prms.push(entry.time.into());
+                prms.push(entry.subid.into());
+                prms.push(entry.text.as_str().into());
+            }
+            conn.exec_drop(query, prms).await?;
+        }
+        Ok(())
+    }
+
+    async fn applog_list(
+        &self,
+        appid: &str,
+        start: u64,
+        end: u64,
+        after: Option<(u64, u32)>,
+        limit: u32,
+    ) -> GenericResult<Vec<AppLogEntry>> {
+        let mut conn = self.db.get_conn().await?;
+        let rows: Vec<(u64, u32, String)> = if let Some((time, subid)) = after {
+            conn.exec(
+                "select logtime, subid, logcontent from applog where appid = ? and logtime >= ? and logtime < ? and (logtime > ? or (logtime = ? and subid > ?)) order by logtime asc, subid asc limit ?",
+                (appid, start, end, time, time, subid, limit),
+            )
+            .await?
+        } else {
+            conn.exec(
+                "select logtime, subid, logcontent from applog where appid = ? and logtime >= ? and logtime < ? order by logtime asc, subid asc limit ?",
+                (appid, start, end, limit),
+            )
+            .await?
+        };
+        Ok(rows
+            .into_iter()
+            .map(|(time, subid, text)| AppLogEntry {
+                appid: appid.to_string(),
+                time,
+                subid,
+                text,
+            })
+            .collect())
+    }
+
+    async fn applog_apps(&self) -> GenericResult<Vec<String>> {
+        let mut conn = self.db.get_conn().await?;
+        let apps: Vec<String> = conn
+            .query("select distinct appid from applog order by appid")
+            .await?;
+        Ok(apps)
+    }
+
+    async fn applog_delete_before(
+        &self,
+        appid: &str,
+        before: u64,
+        batch_size: u32,
+    ) -> GenericResult<u64> {
+        let mut conn = self.db.get_conn().await?;
+        conn.exec_drop(
+            "delete from applog where appid = ? and logtime < ? limit ?",
+            (appid, before, batch_size),
+        )
+        .await?;
+        Ok(conn.affected_rows())
+    }
+
+    async fn schema_migrations(&self) -> GenericResult<Vec<u32>> {
+        let mut conn = self.db.get_conn().await?;
+        let exists: Option<bool> = conn
+            .query_first("select count(*) > 0 from information_schema.tables where table_schema = database() and table_name = 'schema_migrations'")
+            .await?;
+        if exists != Some(true) {
+            return Ok(vec![]);
+        }
+        let versions: Vec<u32> = conn
+            .query("select version from schema_migrations order by version asc")
+            .await?;
+        Ok(versions)
+    }
+
+    async fn schema_migrate(&self, baseline: u32) -> GenericResult<Vec<u32>> {
+        let mut conn = self.db.get_conn().await?;
+        conn.query_drop(SCHEMA_MIGRATIONS_TABLE).await?;
+
+        let locked: Option<Option<bool>> = conn
+            .exec_first(
+                "select get_lock(?, ?)",
+                (MIGRATION_LOCK, MIGRATION_LOCK_TIMEOUT_SECS),
+            )
+            .await?;
+        if locked != Some(Some(true)) {
+            return Err(GenericError::Database(
+                "cannot acquire the schema migration lock".into(),
+            ));
+        }
+        let res = Self::apply_migrations(&mut conn, baseline).await;
+        conn.exec_drop("select release_lock(?)", (MIGRATION_LOCK,))
+            .await?;
+        res
+    }
+}
+
+fn kv_namespace_from_row(row: KvNamespaceRow) -> KvNamespace {
+    let (id, owner, name, createtime, max_keys, max_bytes, used_keys, used_bytes) = row;
+    KvNamespace {
+        id,
+        owner,
+        name,
+        createtime,
+        max_keys,
+        max_bytes,
+        used_keys: used_keys.max(0) as u64,
+        used_bytes: used_bytes.max(0) as u64,
+    }
+}
+
+fn app_params(config: &AppConfig) -> GenericResult<Params> {
+    Ok(params! {
+        "id" => &config.id.0,
+        "bundle_id" => &config.bundle_id,
+        "env" => serde_json::to_string(&config.env)?,
+        "kv_namespaces" => serde_json::to_string(&config.kv_namespaces)?,
+        "buckets" => serde_json::to_string(&config.buckets)?,
+        "log_retention_secs" => config.log_retention_secs,
+        "createtime" => current_millis(),
+    })
+}
+
+fn deployment_from_row(row: DeploymentRow) -> GenericResult<Deployment> {
+    let (version, config, author, message, createtime) = row;
+    Ok(Deployment {
+        version,
+        config: serde_json::from_str(&config)?,
+        author,
+        message,
+        createtime,
+    })
+}
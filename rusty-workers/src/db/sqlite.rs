@@ -0,0 +1,1842 @@
+//! SQLite backend, for single-node setups and development.
+//!
+//! All operations go through a single connection on the blocking thread pool, so transactions
+//! never conflict with each other.
+
+use super::{
+    bound_kv_namespaces, expiration_from_ttl, increment_value, prefix_upper_bound, AppLogEntry,
+    DataBackend, KvBlob, KvListEntry, KvMutation, BLOB_UPLOAD_TIMEOUT_MS,
+};
+use crate::{
+    app::{AppConfig, AppId, Deployment, KvNamespace, KvWatch},
+    types::*,
+    util::current_millis,
+};
+use async_trait::async_trait;
+use rusqlite::{params, types::Value, Connection, OptionalExtension, TransactionBehavior};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+const SCHEMA_MIGRATIONS_TABLE: &str = r#"
+create table if not exists schema_migrations (
+    version integer not null primary key,
+    applied_at integer not null
+);
+"#;
+
+/// A schema migration. Migrations are written so that they can also run against a database created
+/// before migrations were recorded, which already has some of their tables.
+enum Migration {
+    Sql(&'static str),
+    AddColumn {
+        table: &'static str,
+        column: &'static str,
+        decl: &'static str,
+    },
+}
+
+/// Schema migrations, numbered like the MySQL migrations in `sql/`.
+const MIGRATIONS: &[(u32, Migration)] = &[
+    (
+        1,
+        Migration::Sql(
+            r#"
+create table if not exists appkv (
+    nsid text not null,
+    appkey blob not null,
+    appvalue blob not null,
+    appmetadata blob not null,
+    appexpiration integer not null,
+    primary key (nsid, appkey)
+);
+create index if not exists appkv_appexpiration on appkv (appexpiration);
+
+create table if not exists applog (
+    appid text not null,
+    logtime integer not null,
+    subid integer not null,
+    logcontent text not null,
+    primary key (appid, logtime, subid)
+);
+create index if not exists applog_logtime on applog (logtime);
+
+create table if not exists apps (
+    id text not null primary key,
+    bundle_id text not null,
+    env text not null,
+    kv_namespaces text not null,
+    createtime integer not null
+);
+
+create table if not exists bundles (
+    id text not null primary key,
+    bundle blob not null,
+    createtime integer not null
+);
+
+create table if not exists routes (
+    domain text not null,
+    path text not null,
+    appid text not null,
+    createtime integer not null,
+    primary key (domain, path)
+);
+"#,
+        ),
+    ),
+    (
+        2,
+        Migration::Sql(
+            r#"
+create table if not exists bundle_code_cache (
+    bundle_id text not null,
+    v8_version text not null,
+    cache blob not null,
+    createtime integer not null,
+    primary key (bundle_id, v8_version)
+);
+"#,
+        ),
+    ),
+    (
+        3,
+        Migration::Sql(
+            r#"
+create table if not exists kv_namespaces (
+    id text not null primary key,
+    owner text not null,
+    name text not null,
+    createtime integer not null,
+    max_keys integer not null,
+    max_bytes integer not null,
+    usedkeys integer not null default 0,
+    usedbytes integer not null default 0
+);
+create index if not exists kv_namespaces_owner on kv_namespaces (owner);
+"#,
+        ),
+    ),
+    (
+        4,
+        Migration::Sql(
+            r#"
+create table if not exists appkv_changes (
+    seq integer primary key autoincrement,
+    nsid text not null,
+    appkey blob not null,
+    appvalue blob not null,
+    deleted integer not null,
+    changetime integer not null
+);
+create index if not exists appkv_changes_nsid_seq on appkv_changes (nsid, seq);
+create index if not exists appkv_changes_changetime on appkv_changes (changetime);
+
+create table if not exists kv_watches (
+    id text not null primary key,
+    appid text not null,
+    nsid text not null,
+    prefix blob not null,
+    lastseq integer not null,
+    createtime integer not null
+);
+create index if not exists kv_watches_appid on kv_watches (appid);
+create index if not exists kv_watches_nsid on kv_watches (nsid);
+"#,
+        ),
+    ),
+    (
+        5,
+        Migration::Sql(
+            r#"
+create table if not exists appkv_blobs (
+    nsid text not null,
+    appkey blob not null,
+    blobid text not null,
+    blobsize integer not null,
+    chunks integer not null,
+    appmetadata blob not null,
+    appexpiration integer not null,
+    primary key (nsid, appkey)
+);
+create index if not exists appkv_blobs_nsid_blobid on appkv_blobs (nsid, blobid);
+create index if not exists appkv_blobs_appexpiration on appkv_blobs (appexpiration);
+
+create table if not exists appkv_chunks (
+    nsid text not null,
+    blobid text not null,
+    chunkidx integer not null,
+    data blob not null,
+    createtime integer not null,
+    primary key (nsid, blobid, chunkidx)
+);
+create index if not exists appkv_chunks_createtime on appkv_chunks (createtime);
+"#,
+        ),
+    ),
+    (
+        6,
+        Migration::AddColumn {
+            table: "apps",
+            column: "buckets",
+            decl: "text",
+        },
+    ),
+    (
+        7,
+        Migration::Sql(
+            r#"
+create table if not exists deployments (
+    appid text not null,
+    version integer not null,
+    bundle_id text not null,
+    config text not null,
+    author text not null,
+    message text not null,
+    createtime integer not null,
+    primary key (appid, version)
+);
+create index if not exists deployments_bundle_id on deployments (bundle_id);
+"#,
+        ),
+    ),
+    (
+        8,
+        Migration::AddColumn {
+            table: "apps",
+            column: "log_retention_secs",
+            decl: "integer",
+        },
+    ),
+];
+
+/// Version of the migration that adds the KV namespace registry.
+const KV_NAMESPACES_MIGRATION: u32 = 3;
+
+const UPSERT_KV: &str = "insert into appkv (nsid, appkey, appvalue, appmetadata, appexpiration) values(?1, ?2, ?3, ?4, ?5) \
+    on conflict (nsid, appkey) do update set appvalue = excluded.appvalue, appmetadata = excluded.appmetadata, appexpiration = excluded.appexpiration";
+
+/// Returns the sizes of the KV entries at `keys` that exist, including expired entries that are not
+/// garbage-collected yet.
+fn entry_sizes(
+    conn: &Connection,
+    namespace_id: &str,
+    keys: &[&[u8]],
+) -> rusqlite::Result<BTreeMap<Vec<u8>, i64>> {
+    let mut stmt = conn.prepare_cached(
+        "select length(appkey) + length(appvalue) + length(appmetadata) from appkv where nsid = ?1 and appkey = ?2",
+    )?;
+    let mut sizes = BTreeMap::new();
+    for key in keys {
+        if let Some(size) = stmt
+            .query_row(params![namespace_id, key], |row| row.get(0))
+            .optional()?
+        {
+            sizes.insert(key.to_vec(), size);
+        }
+    }
+    Ok(sizes)
+}
+
+/// Adds to the usage counters of a namespace. Unregistered namespaces have no counters.
+///
+/// Fails with `QuotaExceeded` if the added keys or bytes go over the quota. The caller must drop
+/// its transaction then, which rolls back the write.
+fn add_usage(conn: &Connection, namespace_id: &str, keys: i64, bytes: i64) -> GenericResult<()> {
+    if keys == 0 && bytes == 0 {
+        return Ok(());
+    }
+    let changed = conn
+        .prepare_cached(
+            "update kv_namespaces set usedkeys = usedkeys + ?2, usedbytes = usedbytes + ?3 where id = ?1 \
+                and (?2 <= 0 or max_keys = 0 or usedkeys + ?2 <= max_keys) \
+                and (?3 <= 0 or max_bytes = 0 or usedbytes + ?3 <= max_bytes)",
+        )?
+        .execute(params![namespace_id, keys, bytes])?;
+    if changed == 0
+        && conn
+            .prepare_cached("select 1 from kv_namespaces where id = ?1")?
+            .exists(params![namespace_id])?
+    {
+        return Err(GenericError::QuotaExceeded);
+    }
+    Ok(())
+}
+
+/// Updates the usage counters of a namespace after writing the KV entries at `keys`, given their
+/// sizes before the write as returned by `entry_sizes`.
+fn track_entries(
+    conn: &Connection,
+    namespace_id: &str,
+    keys: &[&[u8]],
+    before: &BTreeMap<Vec<u8>, i64>,
+) -> GenericResult<()> {
+    let after = entry_sizes(conn, namespace_id, keys)?;
+    add_usage(
+        conn,
+        namespace_id,
+        after.len() as i64 - before.len() as i64,
+        after.values().sum::<i64>() - before.values().sum::<i64>(),
+    )
+}
+
+/// Registers namespaces, counting the entries and blobs already stored under their ids.
+fn insert_kv_namespaces(conn: &Connection, namespaces: &[KvNamespace]) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "insert into kv_namespaces (id, owner, name, createtime, max_keys, max_bytes, usedkeys, usedbytes) \
+            select ?1, ?2, ?3, ?4, ?5, ?6, count(*), coalesce(sum(size), 0) from ( \
+                select length(appkey) + length(appvalue) + length(appmetadata) as size from appkv where nsid = ?1 \
+                union all select length(appkey) + blobsize + length(appmetadata) from appkv_blobs where nsid = ?1 \
+            ) as stored",
+    )?;
+    for ns in namespaces {
+        stmt.execute(params![
+            ns.id,
+            ns.owner,
+            ns.name,
+            ns.createtime as i64,
+            ns.max_keys as i64,
+            ns.max_bytes as i64
+        ])?;
+    }
+    Ok(())
+}
+
+/// Registers the namespaces bound by existing apps. See `bound_kv_namespaces`.
+fn backfill_kv_namespaces(conn: &Connection) -> GenericResult<Vec<KvNamespace>> {
+    let apps = {
+        let mut stmt = conn.prepare("select id, kv_namespaces, createtime from apps")?;
+        let rows = stmt.query_map(params![], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)? as u64,
+            ))
+        })?;
+        rows.collect::<Result<Vec<_>, _>>()?
+    };
+    let apps = apps
+        .into_iter()
+        .map(|(id, bindings, createtime)| Ok((id, serde_json::from_str(&bindings)?, createtime)))
+        .collect::<GenericResult<Vec<_>>>()?;
+    let registered = {
+        let mut stmt = conn.prepare("select id from kv_namespaces")?;
+        let rows = stmt.query_map(params![], |row| row.get(0))?;
+        rows.collect::<Result<Vec<String>, _>>()?
+    };
+    let namespaces = bound_kv_namespaces(apps, &registered);
+    insert_kv_namespaces(conn, &namespaces)?;
+    if !namespaces.is_empty() {
+        info!(
+            "registered {} kv namespaces of existing apps",
+            namespaces.len()
+        );
+    }
+    Ok(namespaces)
+}
+
+/// Records a change to a KV entry in the change log. A `None` value records a delete.
+fn log_change(
+    conn: &Connection,
+    namespace_id: &str,
+    key: &[u8],
+    value: Option<&[u8]>,
+) -> rusqlite::Result<()> {
+    let empty: &[u8] = &[];
+    conn.prepare_cached(
+        "insert into appkv_changes (nsid, appkey, appvalue, deleted, changetime) values(?1, ?2, ?3, ?4, ?5)",
+    )?
+    .execute(params![
+        namespace_id,
+        key,
+        value.unwrap_or(empty),
+        value.is_none(),
+        current_millis() as i64
+    ])?;
+    Ok(())
+}
+
+/// Deletes the blob stored at `key` and its chunks, updating the usage counters.
+fn delete_blob(conn: &Connection, namespace_id: &str, key: &[u8]) -> GenericResult<()> {
+    let prev: Option<(String, i64)> = conn
+        .query_row(
+            "select blobid, length(appkey) + blobsize + length(appmetadata) from appkv_blobs where nsid = ?1 and appkey = ?2",
+            params![namespace_id, key],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    if let Some((prev, size)) = prev {
+        conn.execute(
+            "delete from appkv_blobs where nsid = ?1 and appkey = ?2",
+            params![namespace_id, key],
+        )?;
+        conn.execute(
+            "delete from appkv_chunks where nsid = ?1 and blobid = ?2",
+            params![namespace_id, prev],
+        )?;
+        add_usage(conn, namespace_id, -1, -size)?;
+    }
+    Ok(())
+}
+
+/// Adds a column to a table, unless a database created before migrations were recorded already
+/// has it.
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    decl: &str,
+) -> rusqlite::Result<()> {
+    let exists: bool = conn.query_row(
+        &format!(
+            "select count(*) > 0 from pragma_table_info('{}') where name = ?1",
+            table
+        ),
+        params![column],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute_batch(&format!(
+            "alter table {} add column {} {}",
+            table, column, decl
+        ))?;
+    }
+    Ok(())
+}
+
+impl Migration {
+    fn apply(&self, conn: &Connection) -> rusqlite::Result<()> {
+        match *self {
+            Migration::Sql(sql) => conn.execute_batch(sql),
+            Migration::AddColumn {
+                table,
+                column,
+                decl,
+            } => add_column_if_missing(conn, table, column, decl),
+        }
+    }
+}
+
+/// Applies the migrations that are not applied yet, each in its own transaction. Migrations up to
+/// `baseline` are only recorded.
+///
+/// Adding the KV namespace registry also registers the namespaces bound by existing apps, once
+/// the later migrations have created the tables they are counted from.
+fn migrate(conn: &mut Connection, baseline: u32) -> GenericResult<Vec<u32>> {
+    conn.execute_batch(SCHEMA_MIGRATIONS_TABLE)?;
+    let mut applied = vec![];
+    let mut backfill = false;
+    for (version, migration) in MIGRATIONS {
+        // Immediate, so that processes opening the database at the same time apply each migration
+        // once.
+        let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let exists: bool = txn.query_row(
+            "select count(*) > 0 from schema_migrations where version = ?1",
+            params![version],
+            |row| row.get(0),
+        )?;
+        if exists {
+            continue;
+        }
+        if *version > baseline {
+            migration.apply(&txn)?;
+            backfill |= *version == KV_NAMESPACES_MIGRATION;
+        }
+        txn.execute(
+            "insert into schema_migrations (version, applied_at) values(?1, ?2)",
+            params![version, current_millis() as i64],
+        )?;
+        txn.commit()?;
+        applied.push(*version);
+    }
+    if backfill {
+        let txn = conn.transaction()?;
+        backfill_kv_namespaces(&txn)?;
+        txn.commit()?;
+    }
+    Ok(applied)
+}
+
+pub struct SqliteBackend {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteBackend {
+    /// Opens the database at `path`, creating it if it doesn't exist, and applies pending
+    /// migrations.
+    pub async fn open(path: &str) -> GenericResult<Self> {
+        let path = path.to_string();
+        let conn = tokio::task::spawn_blocking(move || -> GenericResult<Connection> {
+            let mut conn = Connection::open(path)?;
+            let applied = migrate(&mut conn, 0)?;
+            if !applied.is_empty() {
+                info!("sqlite: applied schema migrations {:?}", applied);
+            }
+            Ok(conn)
+        })
+        .await
+        .map_err(|e| GenericError::Other(format!("sqlite task failed: {:?}", e)))??;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    async fn with_conn<T, F>(&self, f: F) -> GenericResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> GenericResult<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap()))
+            .await
+            .map_err(|e| GenericError::Other(format!("sqlite task failed: {:?}", e)))?
+    }
+}
+
+#[async_trait]
+impl DataBackend for SqliteBackend {
+    async fn worker_data_get(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+    ) -> GenericResult<Option<Vec<u8>>> {
+        let namespace_id = namespace_id.to_string();
+        let key = key.to_vec();
+        self.with_conn(move |conn| {
+            Ok(conn
+                .query_row(
+                    "select appvalue from appkv where nsid = ?1 and appkey = ?2 and (appexpiration = 0 or appexpiration > ?3)",
+                    params![namespace_id, key, current_millis() as i64],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn worker_data_get_with_metadata(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+    ) -> GenericResult<Option<(Vec<u8>, Vec<u8>)>> {
+        let namespace_id = namespace_id.to_string();
+        let key = key.to_vec();
+        self.with_conn(move |conn| {
+            Ok(conn
+                .query_row(
+                    "select appvalue, appmetadata from appkv where nsid = ?1 and appkey = ?2 and (appexpiration = 0 or appexpiration > ?3)",
+                    params![namespace_id, key, current_millis() as i64],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn worker_data_get_many(
+        &self,
+        namespace_id: &str,
+        keys: &[Vec<u8>],
+    ) -> GenericResult<Vec<Option<Vec<u8>>>> {
+        let namespace_id = namespace_id.to_string();
+        let keys = keys.to_vec();
+        self.with_conn(move |conn| {
+            let now = current_millis() as i64;
+            let mut stmt = conn.prepare_cached(
+                "select appvalue from appkv where nsid = ?1 and appkey = ?2 and (appexpiration = 0 or appexpiration > ?3)",
+            )?;
+            let mut values = Vec::with_capacity(keys.len());
+            for key in &keys {
+                values.push(
+                    stmt.query_row(params![namespace_id, key, now], |row| row.get(0))
+                        .optional()?,
+                );
+            }
+            Ok(values)
+        })
+        .await
+    }
+
+    async fn worker_data_put(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+        value: &[u8],
+        metadata: &[u8],
+        if_not_exists: bool,
+        ttl_ms: u64,
+    ) -> GenericResult<()> {
+        let namespace_id = namespace_id.to_string();
+        let key = key.to_vec();
+        let value = value.to_vec();
+        let metadata = metadata.to_vec();
+        let expiration = expiration_from_ttl(ttl_ms)? as i64;
+        self.with_conn(move |conn| {
+            let txn = conn.transaction()?;
+            let before = entry_sizes(&txn, &namespace_id, &[&key])?;
+            if if_not_exists {
+                // Only overwrite an existing row if it has expired.
+                let changed = txn.execute(
+                    &format!(
+                        "{} where not (appkv.appexpiration = 0 or appkv.appexpiration > ?6)",
+                        UPSERT_KV
+                    ),
+                    params![
+                        namespace_id,
+                        key,
+                        value,
+                        metadata,
+                        expiration,
+                        current_millis() as i64
+                    ],
+                )?;
+                if changed == 0 {
+                    return Ok(());
+                }
+            } else {
+                txn.execute(
+                    UPSERT_KV,
+                    params![namespace_id, key, value, metadata, expiration],
+                )?;
+            }
+            log_change(&txn, &namespace_id, &key, Some(&value))?;
+            track_entries(&txn, &namespace_id, &[&key], &before)?;
+            txn.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn worker_data_put_many(
+        &self,
+        namespace_id: &str,
+        entries: &[(Vec<u8>, Vec<u8>)],
+        ttl_ms: u64,
+    ) -> GenericResult<()> {
+        let namespace_id = namespace_id.to_string();
+        let entries = entries.to_vec();
+        let expiration = expiration_from_ttl(ttl_ms)? as i64;
+        self.with_conn(move |conn| {
+            let txn = conn.transaction()?;
+            let keys: Vec<&[u8]> = entries.iter().map(|(k, _)| k.as_slice()).collect();
+            let before = entry_sizes(&txn, &namespace_id, &keys)?;
+            {
+                let mut stmt = txn.prepare_cached(UPSERT_KV)?;
+                let empty_md: &[u8] = &[];
+                for (k, v) in &entries {
+                    stmt.execute(params![namespace_id, k, v, empty_md, expiration])?;
+                    log_change(&txn, &namespace_id, k, Some(v))?;
+                }
+            }
+            track_entries(&txn, &namespace_id, &keys, &before)?;
+            txn.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn worker_data_scan_keys(
+        &self,
+        namespace_id: &str,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: u32,
+    ) -> GenericResult<Vec<Vec<u8>>> {
+        let namespace_id = namespace_id.to_string();
+        let start = start.to_vec();
+        let end = end.map(|x| x.to_vec());
+        self.with_conn(move |conn| {
+            let now = current_millis() as i64;
+            let keys = if let Some(end) = end {
+                let mut stmt = conn.prepare_cached(
+                    "select appkey from appkv where nsid = ?1 and appkey between ?2 and ?3 and (appexpiration = 0 or appexpiration > ?4) order by appkey limit ?5",
+                )?;
+                let rows = stmt.query_map(params![namespace_id, start, end, now, limit], |row| {
+                    row.get(0)
+                })?;
+                rows.collect::<Result<Vec<Vec<u8>>, _>>()?
+            } else {
+                let mut stmt = conn.prepare_cached(
+                    "select appkey from appkv where nsid = ?1 and appkey >= ?2 and (appexpiration = 0 or appexpiration > ?3) order by appkey limit ?4",
+                )?;
+                let rows =
+                    stmt.query_map(params![namespace_id, start, now, limit], |row| row.get(0))?;
+                rows.collect::<Result<Vec<Vec<u8>>, _>>()?
+            };
+            Ok(keys)
+        })
+        .await
+    }
+
+    async fn worker_data_delete_many(
+        &self,
+        namespace_id: &str,
+        keys: &[Vec<u8>],
+    ) -> GenericResult<()> {
+        let namespace_id = namespace_id.to_string();
+        let keys = keys.to_vec();
+        self.with_conn(move |conn| {
+            let txn = conn.transaction()?;
+            let keys: Vec<&[u8]> = keys.iter().map(|x| x.as_slice()).collect();
+            let existing = entry_sizes(&txn, &namespace_id, &keys)?;
+            {
+                let mut stmt =
+                    txn.prepare_cached("delete from appkv where nsid = ?1 and appkey = ?2")?;
+                for key in existing.keys() {
+                    stmt.execute(params![namespace_id, key])?;
+                    log_change(&txn, &namespace_id, key, None)?;
+                }
+            }
+            add_usage(
+                &txn,
+                &namespace_id,
+                -(existing.len() as i64),
+                -existing.values().sum::<i64>(),
+            )?;
+            txn.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn worker_data_increment(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+        delta: i64,
+        ttl_ms: u64,
+    ) -> GenericResult<i64> {
+        let namespace_id = namespace_id.to_string();
+        let key = key.to_vec();
+        let expiration = expiration_from_ttl(ttl_ms)? as i64;
+        self.with_conn(move |conn| {
+            let txn = conn.transaction()?;
+            let prev: Option<(Vec<u8>, Vec<u8>, i64)> = txn
+                .query_row(
+                    "select appvalue, appmetadata, appexpiration from appkv where nsid = ?1 and appkey = ?2 and (appexpiration = 0 or appexpiration > ?3)",
+                    params![namespace_id, key, current_millis() as i64],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )
+                .optional()?;
+            let value = increment_value(prev.as_ref().map(|x| x.0.as_slice()), delta)?;
+            let (metadata, prev_expiration) = match prev {
+                Some((_, metadata, expiration)) => (metadata, expiration),
+                None => (vec![], 0),
+            };
+            let before = entry_sizes(&txn, &namespace_id, &[&key])?;
+            txn.execute(
+                UPSERT_KV,
+                params![
+                    namespace_id,
+                    key,
+                    value.to_string().into_bytes(),
+                    metadata,
+                    if ttl_ms != 0 {
+                        expiration
+                    } else {
+                        prev_expiration
+                    }
+                ],
+            )?;
+            log_change(
+                &txn,
+                &namespace_id,
+                &key,
+                Some(value.to_string().as_bytes()),
+            )?;
+            track_entries(&txn, &namespace_id, &[&key], &before)?;
+            txn.commit()?;
+            Ok(value)
+        })
+        .await
+    }
+
+    async fn worker_data_list(
+        &self,
+        namespace_id: &str,
+        prefix: &[u8],
+        after: Option<&[u8]>,
+        limit: u32,
+        include_values: bool,
+        include_metadata: bool,
+    ) -> GenericResult<(Vec<KvListEntry>, bool)> {
+        let upper_bound = prefix_upper_bound(prefix);
+
+        let mut query = format!(
+            "select appkey, {}, {}, appexpiration from appkv where nsid = ? and appkey >= ?",
+            if include_values { "appvalue" } else { "x''" },
+            if include_metadata {
+                "appmetadata"
+            } else {
+                "x''"
+            },
+        );
+        let mut prms: Vec<Value> = vec![
+            Value::Text(namespace_id.to_string()),
+            Value::Blob(prefix.to_vec()),
+        ];
+        if let Some(upper_bound) = upper_bound {
+            query += " and appkey < ?";
+            prms.push(Value::Blob(upper_bound));
+        }
+        if let Some(after) = after {
+            query += " and appkey > ?";
+            prms.push(Value::Blob(after.to_vec()));
+        }
+        query += " and (appexpiration = 0 or appexpiration > ?) order by appkey limit ?";
+        prms.push(Value::Integer(current_millis() as i64));
+
+        // Fetch one more row to tell whether there are more entries.
+        prms.push(Value::Integer(limit as i64 + 1));
+
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(&query)?;
+            let rows = stmt.query_map(rusqlite::params_from_iter(prms), |row| {
+                Ok(KvListEntry {
+                    key: row.get(0)?,
+                    value: row.get(1)?,
+                    metadata: row.get(2)?,
+                    expiration: row.get::<_, i64>(3)? as u64,
+                })
+            })?;
+            let mut entries = rows.collect::<Result<Vec<_>, _>>()?;
+            let list_complete = entries.len() <= limit as usize;
+            entries.truncate(limit as usize);
+            Ok((entries, list_complete))
+        })
+        .await
+    }
+
+    async fn worker_data_delete(&self, namespace_id: &str, key: &[u8]) -> GenericResult<()> {
+        let namespace_id = namespace_id.to_string();
+        let key = key.to_vec();
+        self.with_conn(move |conn| {
+            let txn = conn.transaction()?;
+            if let Some(size) = entry_sizes(&txn, &namespace_id, &[&key])?.get(&key) {
+                txn.execute(
+                    "delete from appkv where nsid = ?1 and appkey = ?2",
+                    params![namespace_id, key],
+                )?;
+                log_change(&txn, &namespace_id, &key, None)?;
+                add_usage(&txn, &namespace_id, -1, -size)?;
+            }
+            txn.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn worker_data_delete_expired(
+        &self,
+        batch_size: u32,
+        dry_run: bool,
+    ) -> GenericResult<u64> {
+        self.with_conn(move |conn| {
+            let now = current_millis() as i64;
+            if dry_run {
+                let count: i64 = conn.query_row(
+                    "select count(*) from (select 1 from appkv where appexpiration > 0 and appexpiration <= ?1 limit ?2)",
+                    params![now, batch_size],
+                    |row| row.get(0),
+                )?;
+                Ok(count as u64)
+            } else {
+                let txn = conn.transaction()?;
+                let expired: Vec<(i64, String, i64)> = {
+                    let mut stmt = txn.prepare(
+                        "select rowid, nsid, length(appkey) + length(appvalue) + length(appmetadata) from appkv \
+                            where appexpiration > 0 and appexpiration <= ?1 limit ?2",
+                    )?;
+                    let rows = stmt.query_map(params![now, batch_size], |row| {
+                        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                    })?;
+                    rows.collect::<Result<Vec<_>, _>>()?
+                };
+                for (rowid, namespace_id, size) in &expired {
+                    txn.execute("delete from appkv where rowid = ?1", params![rowid])?;
+                    add_usage(&txn, namespace_id, -1, -size)?;
+                }
+                txn.commit()?;
+                Ok(expired.len() as u64)
+            }
+        })
+        .await
+    }
+
+    async fn worker_data_cmpupdate(
+        &self,
+        namespace_id: &str,
+        assertions: &[(Vec<u8>, Vec<u8>)],
+        writes: &[(Vec<u8>, Vec<u8>)],
+        ttl_ms: u64,
+    ) -> GenericResult<bool> {
+        let namespace_id = namespace_id.to_string();
+        let assertions = assertions.to_vec();
+        let writes = writes.to_vec();
+        let expiration = expiration_from_ttl(ttl_ms)? as i64;
+        self.with_conn(move |conn| {
+            let now = current_millis() as i64;
+            let txn = conn.transaction()?;
+            {
+                let mut stmt = txn.prepare_cached(
+                    "select 1 from appkv where nsid = ?1 and appkey = ?2 and appvalue = ?3 and (appexpiration = 0 or appexpiration > ?4)",
+                )?;
+                for (k, v) in &assertions {
+                    if !stmt.exists(params![namespace_id, k, v, now])? {
+                        return Ok(false);
+                    }
+                }
+            }
+            let keys: Vec<&[u8]> = writes.iter().map(|(k, _)| k.as_slice()).collect();
+            let before = entry_sizes(&txn, &namespace_id, &keys)?;
+            {
+
+                let mut stmt = txn.prepare_cached(UPSERT_KV)?;
+                let empty_md: &[u8] = &[];
+                for (k, v) in &writes {
+                    stmt.execute(params![namespace_id, k, v, empty_md, expiration])?;
+                    log_change(&txn, &namespace_id, k, Some(v))?;
+                }
+            }
+            track_entries(&txn, &namespace_id, &keys, &before)?;
+            txn.commit()?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn worker_data_commit(
+        &self,
+        namespace_id: &str,
+        reads: &[(Vec<u8>, Option<Vec<u8>>)],
+        writes: &[(Vec<u8>, KvMutation)],
+    ) -> GenericResult<bool> {
+        let namespace_id = namespace_id.to_string();
+        let reads = reads.to_vec();
+        let writes = writes.to_vec();
+        self.with_conn(move |conn| {
+            let now = current_millis() as i64;
+            let txn = conn.transaction()?;
+            let keys: Vec<&[u8]> = writes.iter().map(|x| x.0.as_slice()).collect();
+            {
+                let mut stmt = txn.prepare_cached(
+                    "select appvalue from appkv where nsid = ?1 and appkey = ?2 and (appexpiration = 0 or appexpiration > ?3)",
+                )?;
+                for (k, v) in &reads {
+                    let current: Option<Vec<u8>> = stmt
+                        .query_row(params![namespace_id, k, now], |row| row.get(0))
+                        .optional()?;
+                    if current != *v {
+                        return Ok(false);
+                    }
+                }
+            }
+            let before = entry_sizes(&txn, &namespace_id, &keys)?;
+            {
+                let mut upsert = txn.prepare_cached(UPSERT_KV)?;
+                let mut delete =
+                    txn.prepare_cached("delete from appkv where nsid = ?1 and appkey = ?2")?;
+                for (k, m) in &writes {
+                    match m {
+                        KvMutation::Put {
+                            value,
+                            metadata,
+                            ttl_ms,
+                        } => {
+                            let expiration = expiration_from_ttl(*ttl_ms)? as i64;
+                            upsert.execute(params![namespace_id, k, value, metadata, expiration])?;
+                            log_change(&txn, &namespace_id, k, Some(value))?;
+                        }
+                        KvMutation::Delete => {
+                            if delete.execute(params![namespace_id, k])? != 0 {
+                                log_change(&txn, &namespace_id, k, None)?;
+                            }
+                        }
+                    }
+                }
+            }
+            track_entries(&txn, &namespace_id, &keys, &before)?;
+            txn.commit()?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn worker_blob_put_chunk(
+        &self,
+        namespace_id: &str,
+        blob_id: &str,
+        index: u32,
+        data: &[u8],
+    ) -> GenericResult<()> {
+        let namespace_id = namespace_id.to_string();
+        let blob_id = blob_id.to_string();
+        let data = data.to_vec();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "insert into appkv_chunks (nsid, blobid, chunkidx, data, createtime) values(?1, ?2, ?3, ?4, ?5)",
+                params![namespace_id, blob_id, index, data, current_millis() as i64],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn worker_blob_commit(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+        blob: &KvBlob,
+        ttl_ms: u64,
+    ) -> GenericResult<bool> {
+        let namespace_id = namespace_id.to_string();
+        let key = key.to_vec();
+        let blob = blob.clone();
+        let expiration = expiration_from_ttl(ttl_ms)? as i64;
+        self.with_conn(move |conn| {
+            let txn = conn.transaction()?;
+            let (chunks, size): (i64, i64) = txn.query_row(
+                "select count(*), coalesce(sum(length(data)), 0) from appkv_chunks where nsid = ?1 and blobid = ?2",
+                params![namespace_id, blob.blob_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+            if chunks as u32 != blob.chunks || size as u64 != blob.size {
+                return Ok(false);
+            }
+            let committed: Option<i64> = txn
+                .query_row(
+                    "select 1 from appkv_blobs where nsid = ?1 and blobid = ?2 limit 1",
+                    params![namespace_id, blob.blob_id],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if committed.is_some() {
+                return Ok(false);
+            }
+
+            delete_blob(&txn, &namespace_id, &key)?;
+            txn.execute(
+                "insert into appkv_blobs (nsid, appkey, blobid, blobsize, chunks, appmetadata, appexpiration) values(?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    namespace_id,
+                    key,
+                    blob.blob_id,
+                    blob.size as i64,
+                    blob.chunks,
+                    blob.metadata,
+                    expiration
+                ],
+            )?;
+            add_usage(
+                &txn,
+                &namespace_id,
+                1,
+                (key.len() + blob.metadata.len()) as i64 + blob.size as i64,
+            )?;
+            txn.commit()?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn worker_blob_get(
+        &self,
+        namespace_id: &str,
+        key: &[u8],
+    ) -> GenericResult<Option<KvBlob>> {
+        let namespace_id = namespace_id.to_string();
+        let key = key.to_vec();
+        self.with_conn(move |conn| {
+            Ok(conn
+                .query_row(
+                    "select blobid, blobsize, chunks, appmetadata, appexpiration from appkv_blobs where nsid = ?1 and appkey = ?2 and (appexpiration = 0 or appexpiration > ?3)",
+                    params![namespace_id, key, current_millis() as i64],
+                    |row| {
+                        Ok(KvBlob {
+                            blob_id: row.get(0)?,
+                            size: row.get::<_, i64>(1)? as u64,
+                            chunks: row.get(2)?,
+                            metadata: row.get(3)?,
+                            expiration: row.get::<_, i64>(4)? as u64,
+                        })
+                    },
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn worker_blob_get_chunk(
+        &self,
+        namespace_id: &str,
+        blob_id: &str,
+        index: u32,
+    ) -> GenericResult<Option<Vec<u8>>> {
+        let namespace_id = namespace_id.to_string();
+        let blob_id = blob_id.to_string();
+        self.with_conn(move |conn| {
+            Ok(conn
+                .query_row(
+                    "select data from appkv_chunks where nsid = ?1 and blobid = ?2 and chunkidx = ?3",
+                    params![namespace_id, blob_id, index],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn worker_blob_delete(&self, namespace_id: &str, key: &[u8]) -> GenericResult<()> {
+        let namespace_id = namespace_id.to_string();
+        let key = key.to_vec();
+        self.with_conn(move |conn| {
+            let txn = conn.transaction()?;
+            delete_blob(&txn, &namespace_id, &key)?;
+            txn.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn worker_blob_list(
+        &self,
+        namespace_id: &str,
+        prefix: &[u8],
+        from: &[u8],
+        limit: u32,
+    ) -> GenericResult<Vec<(Vec<u8>, KvBlob)>> {
+        let start = std::cmp::max(prefix, from);
+
+        let mut query = "select appkey, blobid, blobsize, chunks, appmetadata, appexpiration from appkv_blobs where nsid = ? and appkey >= ?".to_string();
+        let mut prms: Vec<Value> = vec![
+            Value::Text(namespace_id.to_string()),
+            Value::Blob(start.to_vec()),
+        ];
+        if let Some(upper_bound) = prefix_upper_bound(prefix) {
+            query += " and appkey < ?";
+            prms.push(Value::Blob(upper_bound));
+        }
+        query += " and (appexpiration = 0 or appexpiration > ?) order by appkey limit ?";
+        prms.push(Value::Integer(current_millis() as i64));
+        prms.push(Value::Integer(limit as i64));
+
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(&query)?;
+            let rows = stmt.query_map(rusqlite::params_from_iter(prms), |row| {
+                Ok((
+                    row.get(0)?,
+                    KvBlob {
+                        blob_id: row.get(1)?,
+                        size: row.get::<_, i64>(2)? as u64,
+                        chunks: row.get(3)?,
+                        metadata: row.get(4)?,
+                        expiration: row.get::<_, i64>(5)? as u64,
+                    },
+                ))
+            })?;
+            Ok(rows.collect::<Result<Vec<_>, _>>()?)
+        })
+        .await
+    }
+
+    async fn worker_blob_delete_expired(&self, batch_size: u32) -> GenericResult<u64> {
+        self.with_conn(move |conn| {
+            let now = current_millis();
+            let txn = conn.transaction()?;
+            let expired: Vec<(String, Vec<u8>)> = {
+                let mut stmt = txn.prepare(
+                    "select nsid, appkey from appkv_blobs where appexpiration > 0 and appexpiration <= ?1 limit ?2",
+                )?;
+                let rows = stmt.query_map(params![now as i64, batch_size], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?;
+                rows.collect::<Result<Vec<_>, _>>()?
+            };
+            for (namespace_id, key) in &expired {
+                delete_blob(&txn, namespace_id, key)?;
+            }
+            let orphans = txn.execute(
+                "delete from appkv_chunks where rowid in (select rowid from appkv_chunks where createtime < ?1 and not exists \
+                    (select 1 from appkv_blobs where appkv_blobs.nsid = appkv_chunks.nsid and appkv_blobs.blobid = appkv_chunks.blobid) \
+                    limit ?2)",
+                params![now.saturating_sub(BLOB_UPLOAD_TIMEOUT_MS) as i64, batch_size],
+            )?;
+            txn.commit()?;
+            Ok(expired.len() as u64 + orphans as u64)
+        })
+        .await
+    }
+
+    async fn worker_data_changes(
+        &self,
+        namespace_id: &str,
+        prefix: &[u8],
+        after: u64,
+        limit: u32,
+    ) -> GenericResult<Vec<KvChange>> {
+        let namespace_id = namespace_id.to_string();
+        let prefix = prefix.to_vec();
+        self.with_conn(move |conn| {
+            let mut query =
+                "select seq, appkey, appvalue, deleted, changetime from appkv_changes where nsid = ? and seq > ? and appkey >= ?"
+                    .to_string();
+            let mut prms: Vec<Value> = vec![
+                Value::Text(namespace_id),
+                Value::Integer(after as i64),
+                Value::Blob(prefix.clone()),
+            ];
+            if let Some(upper_bound) = prefix_upper_bound(&prefix) {
+                query += " and appkey < ?";
+                prms.push(Value::Blob(upper_bound));
+            }
+            query += " order by seq limit ?";
+            prms.push(Value::Integer(limit as i64));
+
+            let mut stmt = conn.prepare(&query)?;
+            let rows = stmt.query_map(rusqlite::params_from_iter(prms), |row| {
+                let deleted: bool = row.get(3)?;
+                Ok(KvChange {
+                    seq: row.get::<_, i64>(0)? as u64,
+                    key: row.get(1)?,
+                    value: if deleted { None } else { Some(row.get(2)?) },
+                    time: row.get::<_, i64>(4)? as u64,
+                })
+            })?;
+            Ok(rows.collect::<Result<Vec<_>, _>>()?)
+        })
+        .await
+    }
+
+    async fn worker_data_changes_latest_seq(&self) -> GenericResult<u64> {
+        self.with_conn(move |conn| {
+            let seq: Option<i64> =
+                conn.query_row("select max(seq) from appkv_changes", [], |row| row.get(0))?;
+            Ok(seq.unwrap_or(0) as u64)
+        })
+        .await
+    }
+
+    async fn worker_data_delete_changes(&self, before: u64, batch_size: u32) -> GenericResult<u64> {
+        self.with_conn(move |conn| {
+            let deleted = conn.execute(
+                "delete from appkv_changes where seq in (select seq from appkv_changes where changetime < ?1 limit ?2)",
+                params![before as i64, batch_size],
+            )?;
+            Ok(deleted as u64)
+        })
+        .await
+    }
+
+    async fn kv_watch_create(&self, watch: &KvWatch) -> GenericResult<()> {
+        let watch = watch.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "insert into kv_watches (id, appid, nsid, prefix, lastseq, createtime) values(?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    watch.id,
+                    watch.appid,
+                    watch.namespace_id,
+                    watch.prefix,
+                    watch.cursor as i64,
+                    watch.createtime as i64
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn kv_watch_list(&self, appid: Option<&str>) -> GenericResult<Vec<KvWatch>> {
+        let appid = appid.map(|x| x.to_string());
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "select id, appid, nsid, prefix, lastseq, createtime from kv_watches where ?1 is null or appid = ?1 order by id",
+            )?;
+            let rows = stmt.query_map(params![appid], |row| {
+                Ok(KvWatch {
+                    id: row.get(0)?,
+                    appid: row.get(1)?,
+                    namespace_id: row.get(2)?,
+                    prefix: row.get(3)?,
+                    cursor: row.get::<_, i64>(4)? as u64,
+                    createtime: row.get::<_, i64>(5)? as u64,
+                })
+            })?;
+            Ok(rows.collect::<Result<Vec<_>, _>>()?)
+        })
+        .await
+    }
+
+    async fn kv_watch_delete(&self, id: &str) -> GenericResult<()> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            conn.execute("delete from kv_watches where id = ?1", params![id])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn kv_watch_advance(&self, id: &str, cursor: u64) -> GenericResult<()> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "update kv_watches set lastseq = ?1 where id = ?2 and lastseq < ?1",
+                params![cursor as i64, id],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn kv_namespace_create(&self, ns: &KvNamespace) -> GenericResult<()> {
+        let ns = ns.clone();
+        self.with_conn(move |conn| {
+            insert_kv_namespaces(conn, &[ns])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn kv_namespace_get(&self, id: &str) -> GenericResult<Option<KvNamespace>> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            Ok(conn
+                .query_row(
+                    "select id, owner, name, createtime, max_keys, max_bytes, usedkeys, usedbytes from kv_namespaces where id = ?1",
+                    params![id],
+                    kv_namespace_from_row,
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn kv_namespace_list(&self, owner: Option<&str>) -> GenericResult<Vec<KvNamespace>> {
+        let owner = owner.map(|x| x.to_string());
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "select id, owner, name, createtime, max_keys, max_bytes, usedkeys, usedbytes from kv_namespaces where ?1 is null or owner = ?1 order by id",
+            )?;
+            let rows = stmt.query_map(params![owner], kv_namespace_from_row)?;
+            Ok(rows.collect::<Result<Vec<_>, _>>()?)
+        })
+        .await
+    }
+
+    async fn kv_namespace_delete(&self, id: &str) -> GenericResult<()> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            let txn = conn.transaction()?;
+            txn.execute("delete from kv_namespaces where id = ?1", params![id])?;
+            txn.execute("delete from appkv where nsid = ?1", params![id])?;
+            txn.execute("delete from appkv_blobs where nsid = ?1", params![id])?;
+            txn.execute("delete from appkv_chunks where nsid = ?1", params![id])?;
+            txn.execute("delete from appkv_changes where nsid = ?1", params![id])?;
+            txn.execute("delete from kv_watches where nsid = ?1", params![id])?;
+            txn.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn kv_namespace_backfill(&self) -> GenericResult<Vec<KvNamespace>> {
+        self.with_conn(|conn| {
+            let txn = conn.transaction()?;
+            let namespaces = backfill_kv_namespaces(&txn)?;
+            txn.commit()?;
+            Ok(namespaces)
+        })
+        .await
+    }
+
+    async fn route_mapping_delete_domain(&self, domain: &str) -> GenericResult<()> {
+        let domain = domain.to_string();
+        self.with_conn(move |conn| {
+            conn.execute("delete from routes where domain = ?1", params![domain])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn route_mapping_list_for_domain(
+        &self,
+        domain: &str,
+    ) -> GenericResult<BTreeMap<String, String>> {
+        let domain = domain.to_string();
+        self.with_conn(move |conn| {
+            let mut stmt =
+                conn.prepare_cached("select path, appid from routes where domain = ?1")?;
+            let rows = stmt.query_map(params![domain], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            Ok(rows.collect::<Result<BTreeMap<String, String>, _>>()?)
+        })
+        .await
+    }
+
+    async fn route_mapping_lookup(
+        &self,
+        domain: &str,
+        path: &str,
+    ) -> GenericResult<Option<String>> {
+        let domain = domain.to_string();
+        let path = path.to_string();
+        self.with_conn(move |conn| {
+            // Most specific match
+            Ok(conn
+                .query_row(
+                    "select appid from routes where domain = ?1 and substr(?2, 1, length(path)) = path order by length(path) desc limit 1",
+                    params![domain, path],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn route_mapping_insert(
+        &self,
+        domain: &str,
+        path: &str,
+        appid: String,
+    ) -> GenericResult<()> {
+        let domain = domain.to_string();
+        let path = path.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "replace into routes (domain, path, appid, createtime) values(?1, ?2, ?3, ?4)",
+                params![domain, path, appid, current_millis() as i64],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn route_mapping_delete(&self, domain: &str, path: &str) -> GenericResult<()> {
+        let domain = domain.to_string();
+        let path = path.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "delete from routes where domain = ?1 and path = ?2",
+                params![domain, path],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn app_metadata_get(&self, appid: &str) -> GenericResult<Option<AppConfig>> {
+        let appid = appid.to_string();
+        self.with_conn(move |conn| {
+            let row: Option<(String, String, String, Option<String>, Option<i64>)> = conn
+                .query_row(
+                    "select bundle_id, env, kv_namespaces, buckets, log_retention_secs from apps where id = ?1",
+                    params![appid],
+                    |row| {
+                        Ok((
+                            row.get(0)?,
+                            row.get(1)?,
+                            row.get(2)?,
+                            row.get(3)?,
+                            row.get(4)?,
+                        ))
+                    },
+                )
+                .optional()?;
+            let (bundle_id, env, kv_namespaces, buckets, log_retention_secs) = match row {
+                Some(x) => x,
+                None => return Ok(None),
+            };
+            Ok(Some(AppConfig {
+                id: AppId(appid),
+                bundle_id,
+                env: serde_json::from_str(&env)?,
+                kv_namespaces: serde_json::from_str(&kv_namespaces)?,
+                buckets: match buckets {
+                    Some(x) => serde_json::from_str(&x)?,
+                    None => vec![],
+                },
+                log_retention_secs: log_retention_secs.map(|x| x as u64),
+            }))
+        })
+        .await
+    }
+
+    async fn app_metadata_put(&self, config: &AppConfig) -> GenericResult<()> {
+        let config = config.clone();
+        self.with_conn(move |conn| put_app(conn, &config)).await
+    }
+
+    async fn app_metadata_delete(&self, appid: &str) -> GenericResult<()> {
+        let appid = appid.to_string();
+        self.with_conn(move |conn| {
+            conn.execute("delete from apps where id = ?1", params![appid])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn app_deploy(
+        &self,
+        config: &AppConfig,
+        author: &str,
+        message: &str,
+    ) -> GenericResult<u64> {
+        let config = config.clone();
+        let author = author.to_string();
+        let message = message.to_string();
+        self.with_conn(move |conn| {
+            let txn = conn.transaction()?;
+            let last: Option<i64> = txn.query_row(
+                "select max(version) from deployments where appid = ?1",
+                params![config.id.0],
+                |row| row.get(0),
+            )?;
+            let version = last.unwrap_or(0) as u64 + 1;
+            txn.execute(
+                "insert into deployments (appid, version, bundle_id, config, author, message, createtime) values(?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    config.id.0,
+                    version as i64,
+                    config.bundle_id,
+                    serde_json::to_string(&config)?,
+                    author,
+                    message,
+                    current_millis() as i64
+                ],
+            )?;
+            put_app(&txn, &config)?;
+            txn.commit()?;
+            Ok(version)
+        })
+        .await
+    }
+
+    async fn app_deployment_list(&self, appid: &str) -> GenericResult<Vec<Deployment>> {
+        let appid = appid.to_string();
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "select version, config, author, message, createtime from deployments where appid = ?1 order by version desc",
+            )?;
+            let rows = stmt
+                .query_map(params![appid], deployment_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            rows.into_iter().map(deployment_from_row).collect()
+        })
+        .await
+    }
+
+    async fn app_deployment_get(
+        &self,
+        appid: &str,
+        version: u64,
+    ) -> GenericResult<Option<Deployment>> {
+        let appid = appid.to_string();
+        self.with_conn(move |conn| {
+            let row = conn
+                .query_row(
+                    "select version, config, author, message, createtime from deployments where appid = ?1 and version = ?2",
+                    params![appid, version as i64],
+                    deployment_row,
+                )
+                .optional()?;
+            row.map(deployment_from_row).transpose()
+        })
+        .await
+    }
+
+    async fn app_deployment_prune(&self, appid: &str, keep: u32) -> GenericResult<u64> {
+        let appid = appid.to_string();
+        self.with_conn(move |conn| {
+            let n = conn.execute(
+                "delete from deployments where appid = ?1 and version <= \
+                 (select max(version) from deployments where appid = ?1) - ?2",
+                params![appid, keep],
+            )?;
+            Ok(n as u64)
+        })
+        .await
+    }
+
+    async fn app_bundle_get(&self, id: &str) -> GenericResult<Option<Vec<u8>>> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            Ok(conn
+                .query_row(
+                    "select bundle from bundles where id = ?1",
+                    params![id],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn app_bundle_put(&self, id: &str, value: &[u8]) -> GenericResult<()> {
+        let id = id.to_string();
+        let value = value.to_vec();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "insert into bundles (id, bundle, createtime) values(?1, ?2, ?3) \
+                 on conflict (id) do update set createtime = excluded.createtime",
+                params![id, value, current_millis() as i64],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn app_bundle_touch(&self, id: &str) -> GenericResult<bool> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            let n = conn.execute(
+                "update bundles set createtime = ?1 where id = ?2",
+                params![current_millis() as i64, id],
+            )?;
+            Ok(n != 0)
+        })
+        .await
+    }
+
+    async fn app_bundle_list_unreferenced(
+        &self,
+        created_before: u64,
+        limit: u32,
+    ) -> GenericResult<Vec<String>> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "select id from bundles where createtime < ?1 \
+                 and not exists (select 1 from apps where apps.bundle_id = bundles.id) \
+                 and not exists (select 1 from deployments where deployments.bundle_id = bundles.id) \
+                 limit ?2",
+            )?;
+            let ids = stmt
+                .query_map(params![created_before as i64, limit], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            Ok(ids)
+        })
+        .await
+    }
+
+    async fn app_bundle_delete_unreferenced(
+        &self,
+        id: &str,
+        created_before: u64,
+    ) -> GenericResult<bool> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            let txn = conn.transaction()?;
+            let n = txn.execute(
+                "delete from bundles where id = ?1 and createtime < ?2 \
+                 and not exists (select 1 from apps where bundle_id = ?1) \
+                 and not exists (select 1 from deployments where bundle_id = ?1)",
+                params![id, created_before as i64],
+            )?;
+            if n == 0 {
+                return Ok(false);
+            }
+            txn.execute(
+                "delete from bundle_code_cache where bundle_id = ?1",
+                params![id],
+            )?;
+            txn.commit()?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn app_bundle_code_cache_get(
+        &self,
+        bundle_id: &str,
+        v8_version: &str,
+    ) -> GenericResult<Option<Vec<u8>>> {
+        let bundle_id = bundle_id.to_string();
+        let v8_version = v8_version.to_string();
+        self.with_conn(move |conn| {
+            Ok(conn
+                .query_row(
+                    "select cache from bundle_code_cache where bundle_id = ?1 and v8_version = ?2",
+                    params![bundle_id, v8_version],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn app_bundle_code_cache_put(
+        &self,
+        bundle_id: &str,
+        v8_version: &str,
+        cache: &[u8],
+    ) -> GenericResult<()> {
+        let bundle_id = bundle_id.to_string();
+        let v8_version = v8_version.to_string();
+        let cache = cache.to_vec();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "replace into bundle_code_cache (bundle_id, v8_version, cache, createtime) values(?1, ?2, ?3, ?4)",
+                params![bundle_id, v8_version, cache, current_millis() as i64],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn applog_write_many(&self, entries: &[AppLogEntry]) -> GenericResult<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let entries = entries.to_vec();
+        self.with_conn(move |conn| {
+            let txn = conn.transaction()?;
+            {
+                let mut stmt = txn.prepare_cached(
+                    "insert or ignore into applog (appid, logtime, subid, logcontent) values(?1, ?2, ?3, ?4)",
+                )?;
+                for entry in &entries {
+                    stmt.execute(params![
+                        entry.appid,
+                        entry.time as i64,
+                        entry.subid,
+                        entry.text
+                    ])?;
+                }
+            }
+            txn.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn applog_list(
+        &self,
+        appid: &str,
+        start: u64,
+        end: u64,
+        after: Option<(u64, u32)>,
+        limit: u32,
+    ) -> GenericResult<Vec<AppLogEntry>> {
+        let appid = appid.to_string();
+        self.with_conn(move |conn| {
+            // Without a cursor, start before the first subid of `start`.
+            let (after_time, after_subid) = match after {
+                Some((time, subid)) => (time as i64, subid as i64),
+                None => (start as i64, -1),
+            };
+            let mut stmt = conn.prepare_cached(
+                "select logtime, subid, logcontent from applog where appid = ?1 and logtime >= ?2 and logtime < ?3 \
+                 and (logtime > ?4 or (logtime = ?4 and subid > ?5)) order by logtime asc, subid asc limit ?6",
+            )?;
+            let rows = stmt.query_map(
+                params![appid, start as i64, end as i64, after_time, after_subid, limit],
+                |row| {
+                    Ok(AppLogEntry {
+                        appid: appid.clone(),
+                        time: row.get::<_, i64>(0)? as u64,
+                        subid: row.get(1)?,
+                        text: row.get(2)?,
+                    })
+                },
+            )?;
+            Ok(rows.collect::<Result<Vec<_>, _>>()?)
+        })
+        .await
+    }
+
+    async fn applog_apps(&self) -> GenericResult<Vec<String>> {
+        self.with_conn(|conn| {
+            let mut stmt =
+                conn.prepare_cached("select distinct appid from applog order by appid")?;
+            let rows = stmt.query_map(params![], |row| row.get(0))?;
+            Ok(rows.collect::<Result<Vec<String>, _>>()?)
+        })
+        .await
+    }
+
+    async fn applog_delete_before(
+        &self,
+        appid: &str,
+        before: u64,
+        batch_size: u32,
+    ) -> GenericResult<u64> {
+        let appid = appid.to_string();
+        self.with_conn(move |conn| {
+            let deleted = conn.execute(
+                "delete from applog where rowid in (select rowid from applog where appid = ?1 and logtime < ?2 limit ?3)",
+                params![appid, before as i64, batch_size],
+            )?;
+            Ok(deleted as u64)
+        })
+        .await
+    }
+
+    async fn schema_migrations(&self) -> GenericResult<Vec<u32>> {
+        self.with_conn(|conn| {
+            let mut stmt =
+                conn.prepare("select version from schema_migrations order by version asc")?;
+            let versions = stmt
+                .query_map(params![], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<u32>>>()?;
+            Ok(versions)
+        })
+        .await
+    }
+
+    async fn schema_migrate(&self, baseline: u32) -> GenericResult<Vec<u32>> {
+        self.with_conn(move |conn| migrate(conn, baseline)).await
+    }
+}
+
+/// Inserts or replaces an app.
+fn put_app(conn: &Connection, config: &AppConfig) -> GenericResult<()> {
+    conn.execute(
+        "insert into apps (id, bundle_id, env, kv_namespaces, buckets, log_retention_secs, createtime) values(?1, ?2, ?3, ?4, ?5, ?6, ?7) \
+            on conflict (id) do update set bundle_id = excluded.bundle_id, env = excluded.env, kv_namespaces = excluded.kv_namespaces, buckets = excluded.buckets, log_retention_secs = excluded.log_retention_secs",
+        params![
+            config.id.0,
+            config.bundle_id,
+            serde_json::to_string(&config.env)?,
+            serde_json::to_string(&config.kv_namespaces)?,
+            serde_json::to_string(&config.buckets)?,
+            config.log_retention_secs.map(|x| x as i64),
+            current_millis() as i64
+        ],
+    )?;
+    Ok(())
+}
+
+/// Version, config, author, message and creation time of a deployment.
+type DeploymentRow = (i64, String, String, String, i64);
+
+fn deployment_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<DeploymentRow> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+    ))
+}
+
+fn deployment_from_row(row: DeploymentRow) -> GenericResult<Deployment> {
+    let (version, config, author, message, createtime) = row;
+    Ok(Deployment {
+        version: version as u64,
+        config: serde_json::from_str(&config)?,
+        author,
+        message,
+        createtime: createtime as u64,
+    })
+}
+
+fn kv_namespace_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<KvNamespace> {
+    Ok(KvNamespace {
+        id: row.get(0)?,
+        owner: row.get(1)?,
+        name: row.get(2)?,
+        createtime: row.get::<_, i64>(3)? as u64,
+        max_keys: row.get::<_, i64>(4)? as u64,
+        max_bytes: row.get::<_, i64>(5)? as u64,
+        used_keys: row.get::<_, i64>(6)?.max(0) as u64,
+        used_bytes: row.get::<_, i64>(7)?.max(0) as u64,
+    })
+}
@@ -0,0 +1,772 @@
+//! Tests of the storage backends. Each test runs against the memory and SQLite backends, and also
+//! against the database at `DB_URL` if it is set. A MySQL database must have the schema in `sql/`
+//! applied.
+
+use super::{decode_list_cursor, encode_list_cursor, AppLogEntry, DataClient, KvBlob, KvMutation};
+use crate::app::{bundle_id, AppConfig, AppId, KvNamespace};
+use crate::types::GenericError;
+use crate::util::{current_millis, rand_hex};
+use std::time::Duration;
+
+/// Returns a client of each backend under test, with a name for assertion messages.
+async fn clients() -> Vec<(&'static str, DataClient)> {
+    let mut clients = vec![
+        ("memory", DataClient::new("memory://").await.unwrap()),
+        (
+            "sqlite",
+            DataClient::new("sqlite://:memory:").await.unwrap(),
+        ),
+    ];
+    if let Ok(url) = std::env::var("DB_URL") {
+        clients.push(("DB_URL", DataClient::new(&url).await.unwrap()));
+    }
+    clients
+}
+
+fn put(value: &str) -> KvMutation {
+    KvMutation::Put {
+        value: value.as_bytes().to_vec(),
+        metadata: vec![],
+        ttl_ms: 0,
+    }
+}
+
+#[tokio::test]
+async fn cmpupdate_conflict() {
+    for (name, client) in clients().await {
+        let ns = rand_hex(16);
+        client
+            .worker_data_put(&ns, b"k", b"1", b"", false, 0)
+            .await
+            .unwrap();
+        let applied = client
+            .worker_data_cmpupdate(
+                &ns,
+                &[(b"k".to_vec(), b"1".to_vec())],
+                &[(b"k".to_vec(), b"2".to_vec())],
+                0,
+            )
+            .await
+            .unwrap();
+        assert!(applied, "{}", name);
+
+        // The asserted value is stale now.
+        let applied = client
+            .worker_data_cmpupdate(
+                &ns,
+                &[(b"k".to_vec(), b"1".to_vec())],
+                &[(b"k".to_vec(), b"3".to_vec())],
+                0,
+            )
+            .await
+            .unwrap();
+        assert!(!applied, "{}", name);
+        assert_eq!(
+            client.worker_data_get(&ns, b"k").await.unwrap(),
+            Some(b"2".to_vec()),
+            "{}",
+            name
+        );
+    }
+}
+
+#[tokio::test]
+async fn commit_conflict() {
+    for (name, client) in clients().await {
+        let ns = rand_hex(16);
+        let reads = vec![(b"a".to_vec(), None)];
+        let writes = vec![(b"a".to_vec(), put("1"))];
+        assert!(
+            client
+                .worker_data_commit(&ns, &reads, &writes)
+                .await
+                .unwrap(),
+            "{}",
+            name
+        );
+
+        // `a` is no longer absent.
+        assert!(
+            !client
+                .worker_data_commit(&ns, &reads, &writes)
+                .await
+                .unwrap(),
+            "{}",
+            name
+        );
+
+        let reads = vec![(b"a".to_vec(), Some(b"1".to_vec()))];
+        let writes = vec![
+            (b"a".to_vec(), KvMutation::Delete),
+            (b"b".to_vec(), put("2")),
+        ];
+        assert!(
+            client
+                .worker_data_commit(&ns, &reads, &writes)
+                .await
+                .unwrap(),
+            "{}",
+            name
+        );
+        assert_eq!(
+            client.worker_data_get(&ns, b"a").await.unwrap(),
+            None,
+            "{}",
+            name
+        );
+        assert_eq!(
+            client.worker_data_get(&ns, b"b").await.unwrap(),
+            Some(b"2".to_vec()),
+            "{}",
+            name
+        );
+
+        // Commits are subject to the namespace quota.
+        client
+            .kv_namespace_create(&KvNamespace {
+                id: ns.clone(),
+                owner: "app".into(),
+                name: "test".into(),
+                createtime: 0,
+                max_keys: 1,
+                max_bytes: 0,
+                used_keys: 0,
+                used_bytes: 0,
+            })
+            .await
+            .unwrap();
+        let result = client
+            .worker_data_commit(&ns, &[], &[(b"c".to_vec(), put("3"))])
+            .await;
+        assert!(
+            matches!(result, Err(GenericError::QuotaExceeded)),
+            "{}",
+            name
+        );
+        client.kv_namespace_delete(&ns).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn metadata() {
+    for (name, client) in clients().await {
+        let ns = rand_hex(16);
+        client
+            .worker_data_put(&ns, b"k", b"v", b"m", false, 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            client
+                .worker_data_get_with_metadata(&ns, b"k")
+                .await
+                .unwrap(),
+            Some((b"v".to_vec(), b"m".to_vec())),
+            "{}",
+            name
+        );
+
+        // Overwriting a key replaces its metadata.
+        client
+            .worker_data_put(&ns, b"k", b"w", b"", false, 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            client
+                .worker_data_get_with_metadata(&ns, b"k")
+                .await
+                .unwrap(),
+            Some((b"w".to_vec(), vec![])),
+            "{}",
+            name
+        );
+    }
+}
+
+#[tokio::test]
+async fn list_paging() {
+    for (name, client) in clients().await {
+        let ns = rand_hex(16);
+        for key in &["a1", "a2", "a3", "a4", "a5", "b1"] {
+            client
+                .worker_data_put(&ns, key.as_bytes(), key.as_bytes(), b"m", false, 0)
+                .await
+                .unwrap();
+        }
+
+        let mut pages = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let after = cursor.as_deref().map(|x| decode_list_cursor(x).unwrap());
+            let (entries, complete) = client
+                .worker_data_list(&ns, b"a", after.as_deref(), 2, true, false)
+                .await
+                .unwrap();
+            for entry in &entries {
+                assert_eq!(entry.value, entry.key, "{}", name);
+                assert!(entry.metadata.is_empty(), "{}", name);
+            }
+            let keys: Vec<String> = entries
+                .iter()
+                .map(|x| String::from_utf8(x.key.clone()).unwrap())
+                .collect();
+            pages.push(keys);
+            if complete {
+                break;
+            }
+            cursor = Some(encode_list_cursor(&entries.last().unwrap().key));
+        }
+        assert_eq!(
+            pages,
+            vec![vec!["a1", "a2"], vec!["a3", "a4"], vec!["a5"]],
+            "{}",
+            name
+        );
+    }
+}
+
+#[tokio::test]
+async fn ttl_expiry() {
+    for (name, client) in clients().await {
+        let ns = rand_hex(16);
+        client
+            .worker_data_put(&ns, b"tmp", b"x", b"", false, 1)
+            .await
+            .unwrap();
+        client
+            .worker_data_put(&ns, b"keep", b"y", b"", false, 0)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        assert_eq!(
+            client.worker_data_get(&ns, b"tmp").await.unwrap(),
+            None,
+            "{}",
+            name
+        );
+        let (entries, complete) = client
+            .worker_data_list(&ns, b"", None, 10, false, false)
+            .await
+            .unwrap();
+        let keys: Vec<&[u8]> = entries.iter().map(|x| x.key.as_slice()).collect();
+        assert_eq!(keys, vec![&b"keep"[..]], "{}", name);
+        assert!(complete, "{}", name);
+
+        // An expired entry counts as absent.
+        client
+            .worker_data_put(&ns, b"tmp", b"z", b"", true, 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            client.worker_data_get(&ns, b"tmp").await.unwrap(),
+            Some(b"z".to_vec()),
+            "{}",
+            name
+        );
+
+        // Garbage collection only removes expired entries.
+        client
+            .worker_data_put(&ns, b"old", b"x", b"", false, 1)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(
+            client.worker_data_delete_expired(1000, true).await.unwrap() >= 1,
+            "{}",
+            name
+        );
+        client
+            .worker_data_delete_expired(1000, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            client.worker_data_get(&ns, b"keep").await.unwrap(),
+            Some(b"y".to_vec()),
+            "{}",
+            name
+        );
+    }
+}
+
+#[tokio::test]
+async fn increment() {
+    for (name, client) in clients().await {
+        let ns = rand_hex(16);
+        assert_eq!(
+            client.worker_data_increment(&ns, b"n", 5, 0).await.unwrap(),
+            5,
+            "{}",
+            name
+        );
+        assert_eq!(
+            client
+                .worker_data_increment(&ns, b"n", -2, 0)
+                .await
+                .unwrap(),
+            3,
+            "{}",
+            name
+        );
+        assert_eq!(
+            client.worker_data_get(&ns, b"n").await.unwrap(),
+            Some(b"3".to_vec()),
+            "{}",
+            name
+        );
+
+        client
+            .worker_data_put(&ns, b"s", b"abc", b"", false, 0)
+            .await
+            .unwrap();
+        assert!(
+            client.worker_data_increment(&ns, b"s", 1, 0).await.is_err(),
+            "{}",
+            name
+        );
+
+        let max = i64::MAX.to_string();
+        client
+            .worker_data_put(&ns, b"max", max.as_bytes(), b"", false, 0)
+            .await
+            .unwrap();
+        assert!(
+            client
+                .worker_data_increment(&ns, b"max", 1, 0)
+                .await
+                .is_err(),
+            "{}",
+            name
+        );
+    }
+}
+
+#[tokio::test]
+async fn quota() {
+    for (name, client) in clients().await {
+        let ns = rand_hex(16);
+        client
+            .worker_data_put(&ns, b"a", b"1", b"", false, 0)
+            .await
+            .unwrap();
+        client
+            .kv_namespace_create(&KvNamespace {
+                id: ns.clone(),
+                owner: "app".into(),
+                name: "test".into(),
+                createtime: 0,
+                max_keys: 2,
+                max_bytes: 20,
+                used_keys: 0,
+                used_bytes: 0,
+            })
+            .await
+            .unwrap();
+
+        // A batch that doesn't fit is not written at all.
+        let result = client
+            .worker_data_put_many(
+                &ns,
+                &[
+                    (b"b".to_vec(), b"2".to_vec()),
+                    (b"c".to_vec(), b"3".to_vec()),
+                ],
+                0,
+            )
+            .await;
+        assert!(
+            matches!(result, Err(GenericError::QuotaExceeded)),
+            "{}",
+            name
+        );
+        assert_eq!(
+            client.worker_data_get(&ns, b"b").await.unwrap(),
+            None,
+            "{}",
+            name
+        );
+
+        client
+            .worker_data_put(&ns, b"b", b"2", b"", false, 0)
+            .await
+            .unwrap();
+        let result = client.worker_data_put(&ns, b"c", b"3", b"", false, 0).await;
+        assert!(
+            matches!(result, Err(GenericError::QuotaExceeded)),
+            "{}",
+            name
+        );
+
+        // Overwriting a key only adds the difference in size.
+        client
+            .worker_data_put(&ns, b"a", b"123456789", b"", false, 0)
+            .await
+            .unwrap();
+        let result = client.worker_data_increment(&ns, b"b", 1 << 40, 0).await;
+        assert!(
+            matches!(result, Err(GenericError::QuotaExceeded)),
+            "{}",
+            name
+        );
+
+        client.worker_data_delete(&ns, b"a").await.unwrap();
+        client
+            .worker_data_put(&ns, b"c", b"3", b"", false, 0)
+            .await
+            .unwrap();
+        let ns = client.kv_namespace_get(&ns).await.unwrap().unwrap();
+        assert_eq!((ns.used_keys, ns.used_bytes), (2, 4), "{}", name);
+        client.kv_namespace_delete(&ns.id).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn change_log_seq() {
+    for (name, client) in clients().await {
+        let ns = rand_hex(16);
+        let other = rand_hex(16);
+        let start = client.worker_data_changes_latest_seq().await.unwrap();
+        client
+            .worker_data_put(&ns, b"a", b"1", b"", false, 0)
+            .await
+            .unwrap();
+        client
+            .worker_data_put(&ns, b"b", b"2", b"", false, 0)
+            .await
+            .unwrap();
+        client.worker_data_delete(&ns, b"a").await.unwrap();
+        client
+            .worker_data_put(&other, b"a", b"3", b"", false, 0)
+            .await
+            .unwrap();
+
+        let changes = client
+            .worker_data_changes(&ns, b"", start, 10)
+            .await
+            .unwrap();
+        let summary: Vec<(&[u8], Option<&[u8]>)> = changes
+            .iter()
+            .map(|x| (x.key.as_slice(), x.value.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (&b"a"[..], Some(&b"1"[..])),
+                (&b"b"[..], Some(&b"2"[..])),
+                (&b"a"[..], None),
+            ],
+            "{}",
+            name
+        );
+        assert!(changes.windows(2).all(|x| x[0].seq < x[1].seq), "{}", name);
+        assert!(
+            client.worker_data_changes_latest_seq().await.unwrap() > changes[2].seq,
+            "{}",
+            name
+        );
+
+        let after_first = client
+            .worker_data_changes(&ns, b"", changes[0].seq, 10)
+            .await
+            .unwrap();
+        assert_eq!(after_first.len(), 2, "{}", name);
+        assert_eq!(after_first[0].seq, changes[1].seq, "{}", name);
+
+        let prefixed = client
+            .worker_data_changes(&ns, b"b", start, 10)
+            .await
+            .unwrap();
+        assert_eq!(prefixed.len(), 1, "{}", name);
+        assert_eq!(prefixed[0].seq, changes[1].seq, "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn blob_commit() {
+    for (name, client) in clients().await {
+        let ns = rand_hex(16);
+        client
+            .kv_namespace_create(&KvNamespace {
+                id: ns.clone(),
+                owner: "app".into(),
+                name: "test".into(),
+                createtime: 0,
+                max_keys: 0,
+                max_bytes: 0,
+                used_keys: 0,
+                used_bytes: 0,
+            })
+            .await
+            .unwrap();
+        client
+            .worker_blob_put_chunk(&ns, "b1", 0, b"hello ")
+            .await
+            .unwrap();
+        client
+            .worker_blob_put_chunk(&ns, "b1", 1, b"world")
+            .await
+            .unwrap();
+        let blob = KvBlob {
+            blob_id: "b1".into(),
+            size: 11,
+            chunks: 2,
+            metadata: b"m".to_vec(),
+            expiration: 0,
+        };
+
+        let wrong_size = KvBlob {
+            size: 10,
+            ..blob.clone()
+        };
+        assert!(
+            !client
+                .worker_blob_commit(&ns, b"key", &wrong_size, 0)
+                .await
+                .unwrap(),
+            "{}",
+            name
+        );
+        assert!(
+            client
+                .worker_blob_commit(&ns, b"key", &blob, 0)
+                .await
+                .unwrap(),
+            "{}",
+            name
+        );
+
+        // Committed blobs cannot be modified or committed again.
+        assert!(
+            client
+                .worker_blob_put_chunk(&ns, "b1", 0, b"x")
+                .await
+                .is_err(),
+            "{}",
+            name
+        );
+        assert!(
+            !client
+                .worker_blob_commit(&ns, b"other", &blob, 0)
+                .await
+                .unwrap(),
+            "{}",
+            name
+        );
+
+        let stored = client.worker_blob_get(&ns, b"key").await.unwrap().unwrap();
+        assert_eq!(stored.blob_id, "b1", "{}", name);
+        assert_eq!(stored.size, 11, "{}", name);
+        assert_eq!(stored.chunks, 2, "{}", name);
+        assert_eq!(stored.metadata, b"m".to_vec(), "{}", name);
+        assert_eq!(
+            client.worker_blob_get_chunk(&ns, "b1", 1).await.unwrap(),
+            Some(b"world".to_vec()),
+            "{}",
+            name
+        );
+
+        // Replacing the blob deletes the previous one.
+        client
+            .worker_blob_put_chunk(&ns, "b2", 0, b"new")
+            .await
+            .unwrap();
+        let replacement = KvBlob {
+            blob_id: "b2".into(),
+            size: 3,
+            chunks: 1,
+            metadata: vec![],
+            expiration: 0,
+        };
+        assert!(
+            client
+                .worker_blob_commit(&ns, b"key", &replacement, 0)
+                .await
+                .unwrap(),
+            "{}",
+            name
+        );
+        assert_eq!(
+            client.worker_blob_get_chunk(&ns, "b1", 0).await.unwrap(),
+            None,
+            "{}",
+            name
+        );
+
+        // Only the committed blob counts against the quota.
+        let usage = client.kv_namespace_get(&ns).await.unwrap().unwrap();
+        assert_eq!((usage.used_keys, usage.used_bytes), (1, 6), "{}", name);
+        client.worker_blob_delete(&ns, b"key").await.unwrap();
+        let usage = client.kv_namespace_get(&ns).await.unwrap().unwrap();
+        assert_eq!((usage.used_keys, usage.used_bytes), (0, 0), "{}", name);
+        client.kv_namespace_delete(&ns).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn bundle_gc() {
+    for (name, client) in clients().await {
+        let bundle = rand_hex(16).into_bytes();
+        let id = bundle_id(&bundle);
+        let later = current_millis() + 60_000;
+        assert!(!client.app_bundle_touch(&id).await.unwrap(), "{}", name);
+        client.app_bundle_put(&id, &bundle).await.unwrap();
+        assert!(client.app_bundle_touch(&id).await.unwrap(), "{}", name);
+
+        let config = AppConfig {
+            id: AppId(rand_hex(16)),
+            bundle_id: id.clone(),
+            env: Default::default(),
+            kv_namespaces: vec![],
+            buckets: vec![],
+        };
+        client.app_metadata_put(&config).await.unwrap();
+        assert!(
+            !client
+                .app_bundle_delete_unreferenced(&id, later)
+                .await
+                .unwrap(),
+            "{}",
+            name
+        );
+
+        client.app_metadata_delete(&config.id.0).await.unwrap();
+        // Too recent to be collected.
+        assert!(
+            !client.app_bundle_delete_unreferenced(&id, 0).await.unwrap(),
+            "{}",
+            name
+        );
+        assert!(
+            client
+                .app_bundle_delete_unreferenced(&id, later)
+                .await
+                .unwrap(),
+            "{}",
+            name
+        );
+        assert_eq!(client.app_bundle_get(&id).await.unwrap(), None, "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn deployments() {
+    for (name, client) in clients().await {
+        let mut config = AppConfig {
+            id: AppId(rand_hex(16)),
+            bundle_id: "a".into(),
+            env: Default::default(),
+            kv_namespaces: vec![],
+            buckets: vec![],
+        };
+        assert_eq!(
+            client.app_deploy(&config, "u", "first").await.unwrap(),
+            1,
+            "{}",
+            name
+        );
+        config.bundle_id = "b".into();
+        assert_eq!(
+            client.app_deploy(&config, "u", "second").await.unwrap(),
+            2,
+            "{}",
+            name
+        );
+        assert_eq!(
+            client.app_metadata_get(&config.id.0).await.unwrap(),
+            Some(config.clone()),
+            "{}",
+            name
+        );
+
+        let list = client.app_deployment_list(&config.id.0).await.unwrap();
+        let versions: Vec<u64> = list.iter().map(|x| x.version).collect();
+        assert_eq!(versions, vec![2, 1], "{}", name);
+        let first = client
+            .app_deployment_get(&config.id.0, 1)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.config.bundle_id, "a", "{}", name);
+        assert_eq!(first.message, "first", "{}", name);
+
+        assert_eq!(
+            client.app_deployment_prune(&config.id.0, 1).await.unwrap(),
+            1,
+            "{}",
+            name
+        );
+        assert_eq!(
+            client.app_deployment_get(&config.id.0, 1).await.unwrap(),
+            None,
+            "{}",
+            name
+        );
+        client.app_metadata_delete(&config.id.0).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn log_range_cursor() {
+    for (name, client) in clients().await {
+        let app = rand_hex(16);
+        let mut entries: Vec<AppLogEntry> = (0..5u64)
+            .map(|i| AppLogEntry {
+                appid: app.clone(),
+                time: 1000 + i / 2,
+                subid: i as u32,
+                text: format!("line {}", i),
+            })
+            .collect();
+        entries.push(AppLogEntry {
+            appid: rand_hex(16),
+            time: 1001,
+            subid: 0,
+            text: "other".into(),
+        });
+        client.applog_write_many(&entries).await.unwrap();
+        // Lines already stored are dropped.
+        client.applog_write_many(&entries).await.unwrap();
+
+        let mut pages = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let page = client
+                .log_range(&app, 1000..2000, cursor.as_deref(), 2)
+                .await
+                .unwrap();
+            pages.push(
+                page.entries
+                    .iter()
+                    .map(|x| x.text.clone())
+                    .collect::<Vec<_>>(),
+            );
+            cursor = page.cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(
+            pages,
+            vec![
+                vec!["line 0", "line 1"],
+                vec!["line 2", "line 3"],
+                vec!["line 4"],
+            ],
+            "{}",
+            name
+        );
+
+        // The end of the range is exclusive.
+        let page = client.log_range(&app, 1000..1002, None, 10).await.unwrap();
+        assert_eq!(page.entries.len(), 4, "{}", name);
+        assert!(page.cursor.is_none(), "{}", name);
+
+        assert!(
+            client
+                .log_range(&app, 1000..2000, Some("bad"), 2)
+                .await
+                .is_err(),
+            "{}",
+            name
+        );
+    }
+}
@@ -3,6 +3,7 @@ extern crate log;
 
 pub mod app;
 pub mod db;
+pub mod objstore;
 pub mod rpc;
 pub mod types;
 pub mod util;
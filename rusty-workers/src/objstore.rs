@@ -0,0 +1,194 @@
+//! Object storage for worker buckets.
+//!
+//! The store is selected by a URL:
+//!
+//! - `db`: objects are stored as blobs in the worker database, see `DataBackend`.
+//! - `file:///path/to/dir`: objects are stored as files under a local directory, for single-node
+//!   setups and development.
+
+mod db;
+mod fs;
+
+use crate::{db::DataClient, db::BLOB_CHUNK_SIZE, types::*};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ops::Deref;
+
+pub use self::db::DbObjectStore;
+pub use self::fs::FsObjectStore;
+
+/// Size of each part of an upload. Only the tail of an object, passed to
+/// `ObjectStore::complete_upload`, may be smaller.
+pub const OBJECT_PART_SIZE: usize = BLOB_CHUNK_SIZE;
+
+/// Objects of the `db` store are kept in the blob key space of the namespace with this prefix
+/// followed by the bucket id. KV namespace ids must not start with it.
+pub const BUCKET_NAMESPACE_PREFIX: &str = "bucket:";
+
+/// Maximum length of a bucket id.
+pub const MAX_BUCKET_ID_LEN: usize = 64 - BUCKET_NAMESPACE_PREFIX.len();
+
+/// A handle to the object store selected by a URL.
+///
+/// Dereferences to the store, so all `ObjectStore` operations can be called on it directly.
+pub struct ObjectStoreClient {
+    store: Box<dyn ObjectStore>,
+}
+
+/// Operations provided by an object store.
+///
+/// Objects are written in parts: an upload stores parts of `OBJECT_PART_SIZE` bytes under an
+/// upload id chosen by the caller, and `complete_upload` atomically makes them the object at a
+/// key. Readers never observe a partially written object.
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    /// Stores part `index` of an upload. `data` must be exactly `OBJECT_PART_SIZE` bytes.
+    async fn put_part(
+        &self,
+        bucket: &str,
+        upload_id: &str,
+        index: u32,
+        data: &[u8],
+    ) -> GenericResult<()>;
+
+    /// Makes the `parts` parts of an upload, followed by `tail`, the object at `key`, replacing
+    /// the previous object. Returns `None` if a part is missing or the upload is already completed.
+    async fn complete_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: u32,
+        tail: &[u8],
+        metadata: &ObjectMetadata,
+    ) -> GenericResult<Option<ObjectInfo>>;
+
+    async fn head(&self, bucket: &str, key: &str) -> GenericResult<Option<ObjectInfo>>;
+
+    /// Reads up to `max_len` bytes of `range` from an object. Returns the object, the offset of
+    /// the data read and the data.
+    async fn read(
+        &self,
+        bucket: &str,
+        key: &str,
+        range: ObjectRange,
+        max_len: u64,
+    ) -> GenericResult<Option<(ObjectInfo, u64, Vec<u8>)>>;
+
+    /// Lists objects whose keys start with `prefix`, in key order.
+    ///
+    /// With a `delimiter`, keys that contain it after the prefix are rolled up into
+    /// `ObjectList::delimited_prefixes`, each counting once against `limit`.
+    async fn list(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        delimiter: Option<&str>,
+        cursor: Option<&str>,
+        limit: u32,
+    ) -> GenericResult<ObjectList>;
+
+    async fn delete(&self, bucket: &str, key: &str) -> GenericResult<()>;
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ObjectMetadata {
+    /// Standard HTTP headers such as `content-type`, returned with the object.
+    #[serde(default)]
+    pub http_metadata: BTreeMap<String, String>,
+
+    #[serde(default)]
+    pub custom_metadata: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: u64,
+
+    /// Changes whenever the object is written.
+    pub etag: String,
+
+    /// Upload time in milliseconds since the Unix epoch.
+    pub uploaded: u64,
+
+    #[serde(flatten)]
+    pub metadata: ObjectMetadata,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ObjectList {
+    pub objects: Vec<ObjectInfo>,
+    pub delimited_prefixes: Vec<String>,
+
+    /// Continues the listing, or `None` if it is complete.
+    pub cursor: Option<String>,
+}
+
+/// A byte range of an object.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum ObjectRange {
+    /// `length` bytes from `offset`, or everything from `offset` if `length` is `None`.
+    Offset { offset: u64, length: Option<u64> },
+
+    /// The last `n` bytes.
+    Suffix(u64),
+}
+
+impl ObjectRange {
+    /// Returns the start and end offsets of this range in an object of `size` bytes, clamped to
+    /// the object.
+    pub fn resolve(&self, size: u64) -> (u64, u64) {
+        match *self {
+            ObjectRange::Offset { offset, length } => {
+                let start = offset.min(size);
+                let end = match length {
+                    Some(x) => start.saturating_add(x).min(size),
+                    None => size,
+                };
+                (start, end)
+            }
+            ObjectRange::Suffix(n) => (size.saturating_sub(n), size),
+        }
+    }
+}
+
+impl ObjectStoreClient {
+    /// Opens the store at `url`. The `db` store keeps objects in `data_client`.
+    pub async fn new(url: &str, data_client: DataClient) -> GenericResult<Self> {
+        let store: Box<dyn ObjectStore> = if url == "db" {
+            Box::new(DbObjectStore::new(data_client))
+        } else if let Some(path) = url.strip_prefix("file://") {
+            Box::new(FsObjectStore::open(path).await?)
+        } else {
+            return Err(GenericError::Other("unsupported object store url".into()));
+        };
+        Ok(Self { store })
+    }
+}
+
+impl Deref for ObjectStoreClient {
+    type Target = dyn ObjectStore;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.store
+    }
+}
+
+/// Encodes the key to continue a listing from into an opaque cursor.
+fn encode_cursor(from: &[u8]) -> String {
+    base64::encode(from)
+}
+
+fn decode_cursor(cursor: &str) -> GenericResult<Vec<u8>> {
+    base64::decode(cursor).map_err(|_| GenericError::Other("bad list cursor".into()))
+}
+
+/// Returns the delimited prefix that `key` rolls up into, if any.
+fn delimited_prefix<'a>(key: &'a str, prefix: &str, delimiter: Option<&str>) -> Option<&'a str> {
+    let delimiter = delimiter.filter(|x| !x.is_empty())?;
+    let rest = key.get(prefix.len()..)?;
+    rest.find(delimiter)
+        .map(|i| &key[..prefix.len() + i + delimiter.len()])
+}
@@ -0,0 +1,244 @@
+//! Object store backed by the blob storage of the worker database.
+
+use super::{
+    decode_cursor, delimited_prefix, encode_cursor, ObjectInfo, ObjectList, ObjectMetadata,
+    ObjectRange, ObjectStore, BUCKET_NAMESPACE_PREFIX, OBJECT_PART_SIZE,
+};
+use crate::{
+    db::{prefix_upper_bound, DataClient, KvBlob},
+    types::*,
+    util::current_millis,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of blobs fetched at once when listing.
+const MAX_LIST_PAGE_SIZE: u32 = 1000;
+
+/// Number of times a read is retried when the object is replaced while reading it.
+const READ_RETRIES: usize = 3;
+
+pub struct DbObjectStore {
+    client: DataClient,
+}
+
+/// Stored as the metadata of an object's blob.
+#[derive(Serialize, Deserialize)]
+struct StoredMetadata {
+    uploaded: u64,
+
+    #[serde(flatten)]
+    metadata: ObjectMetadata,
+}
+
+impl DbObjectStore {
+    pub fn new(client: DataClient) -> Self {
+        Self { client }
+    }
+}
+
+fn namespace_id(bucket: &str) -> String {
+    format!("{}{}", BUCKET_NAMESPACE_PREFIX, bucket)
+}
+
+fn object_info(key: String, blob: &KvBlob) -> GenericResult<ObjectInfo> {
+    let stored: StoredMetadata = serde_json::from_slice(&blob.metadata)?;
+    Ok(ObjectInfo {
+        key,
+        size: blob.size,
+        etag: blob.blob_id.clone(),
+        uploaded: stored.uploaded,
+        metadata: stored.metadata,
+    })
+}
+
+#[async_trait]
+impl ObjectStore for DbObjectStore {
+    async fn put_part(
+        &self,
+        bucket: &str,
+        upload_id: &str,
+        index: u32,
+        data: &[u8],
+    ) -> GenericResult<()> {
+        self.client
+            .worker_blob_put_chunk(&namespace_id(bucket), upload_id, index, data)
+            .await
+    }
+
+    async fn complete_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: u32,
+        tail: &[u8],
+        metadata: &ObjectMetadata,
+    ) -> GenericResult<Option<ObjectInfo>> {
+        let namespace_id = namespace_id(bucket);
+
+        // The tail is stored as the last chunk. Empty objects have a single empty chunk.
+        let mut chunks = parts;
+        if parts == 0 || !tail.is_empty() {
+            self.client
+                .worker_blob_put_chunk(&namespace_id, upload_id, parts, tail)
+                .await?;
+            chunks += 1;
+        }
+
+        let uploaded = current_millis();
+        let blob = KvBlob {
+            blob_id: upload_id.to_string(),
+            size: parts as u64 * OBJECT_PART_SIZE as u64 + tail.len() as u64,
+            chunks,
+            metadata: serde_json::to_vec(&StoredMetadata {
+                uploaded,
+                metadata: metadata.clone(),
+            })?,
+            expiration: 0,
+        };
+        if !self
+            .client
+            .worker_blob_commit(&namespace_id, key.as_bytes(), &blob, 0)
+            .await?
+        {
+            return Ok(None);
+        }
+        Ok(Some(ObjectInfo {
+            key: key.to_string(),
+            size: blob.size,
+            etag: blob.blob_id,
+            uploaded,
+            metadata: metadata.clone(),
+        }))
+    }
+
+    async fn head(&self, bucket: &str, key: &str) -> GenericResult<Option<ObjectInfo>> {
+        match self
+            .client
+            .worker_blob_get(&namespace_id(bucket), key.as_bytes())
+            .await?
+        {
+            Some(blob) => Ok(Some(object_info(key.to_string(), &blob)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn read(
+        &self,
+        bucket: &str,
+        key: &str,
+        range: ObjectRange,
+        max_len: u64,
+    ) -> GenericResult<Option<(ObjectInfo, u64, Vec<u8>)>> {
+        let namespace_id = namespace_id(bucket);
+        let part_size = OBJECT_PART_SIZE as u64;
+
+        for _ in 0..READ_RETRIES {
+            let blob = match self
+                .client
+                .worker_blob_get(&namespace_id, key.as_bytes())
+                .await?
+            {
+                Some(x) => x,
+                None => return Ok(None),
+            };
+            let (start, end) = range.resolve(blob.size);
+            let end = end.min(start.saturating_add(max_len));
+
+            let mut data = Vec::with_capacity((end - start) as usize);
+            let mut pos = start;
+            while pos < end {
+                let index = (pos / part_size) as u32;
+                let chunk = match self
+                    .client
+                    .worker_blob_get_chunk(&namespace_id, &blob.blob_id, index)
+                    .await?
+                {
+                    Some(x) => x,
+                    None => break,
+                };
+                let chunk_start = index as u64 * part_size;
+                let lo = (pos - chunk_start) as usize;
+                let hi = ((end - chunk_start) as usize).min(chunk.len());
+                if lo >= hi {
+                    return Err(GenericError::Database("object chunk too short".into()));
+                }
+                data.extend_from_slice(&chunk[lo..hi]);
+                pos += (hi - lo) as u64;
+            }
+
+            // A missing chunk means that the object was replaced while reading it.
+            if pos == end {
+                return Ok(Some((object_info(key.to_string(), &blob)?, start, data)));
+            }
+        }
+        Err(GenericError::TryAgain)
+    }
+
+    async fn list(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        delimiter: Option<&str>,
+        cursor: Option<&str>,
+        limit: u32,
+    ) -> GenericResult<ObjectList> {
+        let namespace_id = namespace_id(bucket);
+        let page_size = limit.saturating_add(1).min(MAX_LIST_PAGE_SIZE);
+        let mut from = match cursor {
+            Some(x) => decode_cursor(x)?,
+            None => prefix.as_bytes().to_vec(),
+        };
+        let mut list = ObjectList::default();
+        let mut count = 0u32;
+
+        loop {
+            let page = self
+                .client
+                .worker_blob_list(&namespace_id, prefix.as_bytes(), &from, page_size)
+                .await?;
+            let page_len = page.len();
+
+            for (key, blob) in page {
+                // Rolled up into a delimited prefix earlier in this page.
+                if key < from {
+                    continue;
+                }
+                if count == limit {
+                    list.cursor = Some(encode_cursor(&key));
+                    return Ok(list);
+                }
+
+                let mut next = key.clone();
+                next.push(0);
+                from = next;
+
+                let key = match String::from_utf8(key) {
+                    Ok(x) => x,
+                    Err(_) => continue,
+                };
+                count += 1;
+                if let Some(p) = delimited_prefix(&key, prefix, delimiter) {
+                    list.delimited_prefixes.push(p.to_string());
+                    from = match prefix_upper_bound(p.as_bytes()) {
+                        Some(x) => x,
+                        None => return Ok(list),
+                    };
+                } else {
+                    list.objects.push(object_info(key, &blob)?);
+                }
+            }
+
+            if page_len < page_size as usize {
+                return Ok(list);
+            }
+        }
+    }
+
+    async fn delete(&self, bucket: &str, key: &str) -> GenericResult<()> {
+        self.client
+            .worker_blob_delete(&namespace_id(bucket), key.as_bytes())
+            .await
+    }
+}
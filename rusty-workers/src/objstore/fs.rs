@@ -0,0 +1,293 @@
+//! Object store backed by a local directory.
+//!
+//! Each bucket is a directory named by the hex-encoded bucket id, with two subdirectories:
+//!
+//! - `objects/`: one file per object, named by the hex-encoded key. Names longer than
+//!   `MAX_NAME_LEN` are split into nested directories with a `.d` suffix. A file holds the length
+//!   of the JSON-encoded `ObjectInfo` as a little-endian `u32`, the `ObjectInfo` and the data.
+//! - `uploads/`: one directory per incomplete upload, holding one file per part.
+//!
+//! Objects are written to their upload directory and renamed into place, so readers only see
+//! complete objects. Abandoned uploads are not cleaned up.
+
+use super::{
+    decode_cursor, delimited_prefix, encode_cursor, ObjectInfo, ObjectList, ObjectMetadata,
+    ObjectRange, ObjectStore, OBJECT_PART_SIZE,
+};
+use crate::{db::prefix_upper_bound, types::*, util::current_millis};
+use async_trait::async_trait;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Maximum length of a file name component in `objects/`.
+const MAX_NAME_LEN: usize = 200;
+
+pub struct FsObjectStore {
+    root: PathBuf,
+}
+
+impl FsObjectStore {
+    /// Opens the store at `path`, creating the directory if it doesn't exist.
+    pub async fn open(path: &str) -> GenericResult<Self> {
+        let root = PathBuf::from(path);
+        tokio::fs::create_dir_all(&root).await?;
+        Ok(Self { root })
+    }
+
+    fn objects_dir(&self, bucket: &str) -> PathBuf {
+        self.root.join(hex::encode(bucket)).join("objects")
+    }
+
+    fn object_path(&self, bucket: &str, key: &str) -> PathBuf {
+        object_file(&self.objects_dir(bucket), key)
+    }
+
+    fn upload_dir(&self, bucket: &str, upload_id: &str) -> GenericResult<PathBuf> {
+        // Upload ids come from workers and must not escape the bucket directory.
+        if upload_id.is_empty() || !upload_id.bytes().all(|x| x.is_ascii_hexdigit()) {
+            return Err(GenericError::Other("invalid upload id".into()));
+        }
+        Ok(self
+            .root
+            .join(hex::encode(bucket))
+            .join("uploads")
+            .join(upload_id))
+    }
+}
+
+async fn blocking<T, F>(f: F) -> GenericResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> GenericResult<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| GenericError::Other(format!("fs task failed: {:?}", e)))?
+}
+
+/// Returns the path of the file of the object at `key`.
+fn object_file(objects_dir: &Path, key: &str) -> PathBuf {
+    let name = hex::encode(key);
+    let mut path = objects_dir.to_path_buf();
+    let mut rest = name.as_str();
+    while rest.len() > MAX_NAME_LEN {
+        path.push(format!("{}.d", &rest[..MAX_NAME_LEN]));
+        rest = &rest[MAX_NAME_LEN..];
+    }
+    path.push(rest);
+    path
+}
+
+/// Opens an object file and reads its header. Returns `None` if the object doesn't exist.
+fn open_object(path: &Path) -> GenericResult<Option<(File, ObjectInfo, u64)>> {
+    let mut file = match File::open(path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut len = [0u8; 4];
+    file.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len);
+    let mut header = vec![0u8; len as usize];
+    file.read_exact(&mut header)?;
+    let info: ObjectInfo = serde_json::from_slice(&header)?;
+    Ok(Some((file, info, 4 + len as u64)))
+}
+
+/// Adds the keys of the objects in `dir` to `keys`. `name_prefix` is the part of the file names
+/// encoded in the parent directories.
+fn collect_keys(dir: &Path, name_prefix: &str, keys: &mut Vec<String>) -> GenericResult<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        if let Some(segment) = name.strip_suffix(".d") {
+            collect_keys(&entry.path(), &format!("{}{}", name_prefix, segment), keys)?;
+        } else if let Some(key) = hex::decode(format!("{}{}", name_prefix, name))
+            .ok()
+            .and_then(|x| String::from_utf8(x).ok())
+        {
+            keys.push(key);
+        }
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl ObjectStore for FsObjectStore {
+    async fn put_part(
+        &self,
+        bucket: &str,
+        upload_id: &str,
+        index: u32,
+        data: &[u8],
+    ) -> GenericResult<()> {
+        let dir = self.upload_dir(bucket, upload_id)?;
+        let data = data.to_vec();
+        blocking(move || {
+            std::fs::create_dir_all(&dir)?;
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(dir.join(index.to_string()))?;
+            file.write_all(&data)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn complete_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: u32,
+        tail: &[u8],
+        metadata: &ObjectMetadata,
+    ) -> GenericResult<Option<ObjectInfo>> {
+        let dir = self.upload_dir(bucket, upload_id)?;
+        let path = self.object_path(bucket, key);
+        let tail = tail.to_vec();
+        let info = ObjectInfo {
+            key: key.to_string(),
+            size: parts as u64 * OBJECT_PART_SIZE as u64 + tail.len() as u64,
+            etag: upload_id.to_string(),
+            uploaded: current_millis(),
+            metadata: metadata.clone(),
+        };
+        blocking(move || {
+            for i in 0..parts {
+                match std::fs::metadata(dir.join(i.to_string())) {
+                    Ok(x) if x.len() == OBJECT_PART_SIZE as u64 => {}
+                    Ok(_) => return Ok(None),
+                    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            if parts == 0 {
+                std::fs::create_dir_all(&dir)?;
+            }
+
+            let header = serde_json::to_vec(&info)?;
+            let tmp_path = dir.join("object");
+            let mut file = match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&tmp_path)
+            {
+                Ok(x) => x,
+                // Completed concurrently.
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            file.write_all(&(header.len() as u32).to_le_bytes())?;
+            file.write_all(&header)?;
+            for i in 0..parts {
+                std::io::copy(&mut File::open(dir.join(i.to_string()))?, &mut file)?;
+            }
+            file.write_all(&tail)?;
+            file.sync_all()?;
+            drop(file);
+
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::rename(&tmp_path, &path)?;
+            std::fs::remove_dir_all(&dir)?;
+            Ok(Some(info))
+        })
+        .await
+    }
+
+    async fn head(&self, bucket: &str, key: &str) -> GenericResult<Option<ObjectInfo>> {
+        let path = self.object_path(bucket, key);
+        blocking(move || Ok(open_object(&path)?.map(|(_, info, _)| info))).await
+    }
+
+    async fn read(
+        &self,
+        bucket: &str,
+        key: &str,
+        range: ObjectRange,
+        max_len: u64,
+    ) -> GenericResult<Option<(ObjectInfo, u64, Vec<u8>)>> {
+        let path = self.object_path(bucket, key);
+        blocking(move || {
+            let (mut file, info, data_offset) = match open_object(&path)? {
+                Some(x) => x,
+                None => return Ok(None),
+            };
+            let (start, end) = range.resolve(info.size);
+            let end = end.min(start.saturating_add(max_len));
+            file.seek(SeekFrom::Start(data_offset + start))?;
+            let mut data = vec![0u8; (end - start) as usize];
+            file.read_exact(&mut data)?;
+            Ok(Some((info, start, data)))
+        })
+        .await
+    }
+
+    async fn list(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        delimiter: Option<&str>,
+        cursor: Option<&str>,
+        limit: u32,
+    ) -> GenericResult<ObjectList> {
+        let dir = self.objects_dir(bucket);
+        let prefix = prefix.to_string();
+        let delimiter = delimiter.map(|x| x.to_string());
+        let from = match cursor {
+            Some(x) => decode_cursor(x)?,
+            None => vec![],
+        };
+        blocking(move || {
+            let mut list = ObjectList::default();
+            let mut from = from;
+            let mut count = 0u32;
+            let mut keys = vec![];
+            collect_keys(&dir, "", &mut keys)?;
+            keys.sort();
+            for key in keys {
+                if !key.starts_with(&prefix) || key.as_bytes() < from.as_slice() {
+                    continue;
+                }
+                if count == limit {
+                    list.cursor = Some(encode_cursor(key.as_bytes()));
+                    break;
+                }
+                count += 1;
+                if let Some(p) = delimited_prefix(&key, &prefix, delimiter.as_deref()) {
+                    list.delimited_prefixes.push(p.to_string());
+                    from = match prefix_upper_bound(p.as_bytes()) {
+                        Some(x) => x,
+                        None => break,
+                    };
+                } else if let Some((_, info, _)) = open_object(&object_file(&dir, &key))? {
+                    list.objects.push(info);
+                }
+            }
+            Ok(list)
+        })
+        .await
+    }
+
+    async fn delete(&self, bucket: &str, key: &str) -> GenericResult<()> {
+        let path = self.object_path(bucket, key);
+        blocking(move || match std::fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        })
+        .await
+    }
+}
@@ -87,11 +87,33 @@ pub trait RuntimeService {
     /// List active workers.
     async fn list_workers() -> GenericResult<Vec<WorkerHandle>>;
 
-    /// Issue a "fetch" event.
-    async fn fetch(handle: WorkerHandle, req: RequestObject) -> ExecutionResult<ResponseObject>;
+    /// Issue a "fetch" event. Returns the response together with the resources used.
+    async fn fetch(handle: WorkerHandle, req: RequestObject) -> ExecutionResult<FetchResult>;
+
+    /// Issue a "kvchange" event for a watched KV namespace. Returns the resources used.
+    async fn kv_change(
+        handle: WorkerHandle,
+        batch: KvChangeBatch,
+    ) -> ExecutionResult<ResourceUsage>;
 
     /// The current load of this runtime instance. 0-65535.
     async fn load() -> GenericResult<u16>;
+
+    /// Returns the inspector WebSocket URL of a worker, if it was spawned with the inspector enabled.
+    async fn inspector_url(handle: WorkerHandle) -> ExecutionResult<Option<String>>;
+
+    /// Waits up to `timeout_ms` for lines logged by workers of `appid` after the sequence number
+    /// `after`, and returns those that match `filter`. Call it in a loop, passing the returned
+    /// cursor, to follow the log.
+    ///
+    /// Lines are only kept in memory while the app is being tailed, so the first call for an app
+    /// returns nothing logged before it. Pass 0 as `after` in the first call.
+    async fn tail_logs(
+        appid: String,
+        filter: LogFilter,
+        after: u64,
+        timeout_ms: u64,
+    ) -> GenericResult<LogTail>;
 }
 
 impl_connect!(RuntimeServiceClient);
@@ -106,3 +128,20 @@ pub trait FetchService {
 }
 
 impl_connect!(FetchServiceClient);
+
+/// SQL database service. Each app has its own database.
+#[tarpc::service]
+pub trait SqlService {
+    /// Runs statements against the database of `appid` in one transaction, and returns the result
+    /// of each statement.
+    ///
+    /// Errors caused by the statements are returned as the inner error, to be reported to the
+    /// worker.
+    async fn execute(
+        appid: String,
+        statements: Vec<SqlStatement>,
+        limits: SqlLimits,
+    ) -> GenericResult<Result<Vec<SqlResult>, String>>;
+}
+
+impl_connect!(SqlServiceClient);
@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use thiserror::Error;
 
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
@@ -9,6 +9,23 @@ pub struct WorkerConfiguration {
     pub fetch_service: SocketAddr,
     pub env: BTreeMap<String, String>,
     pub kv_namespaces: BTreeMap<String, String>,
+
+    /// Bucket name -> bucket id.
+    #[serde(default)]
+    pub buckets: BTreeMap<String, String>,
+
+    /// Address of the SQL service that holds the app's database. `None` disables the database.
+    #[serde(default)]
+    pub sql_service: Option<SocketAddr>,
+
+    /// ID of the bundle that this worker runs. Used as the code cache key; empty disables caching.
+    #[serde(default)]
+    pub bundle_id: String,
+
+    /// Expose the V8 inspector protocol for this worker. Only honored by runtimes started with
+    /// `--enable-inspector`.
+    #[serde(default)]
+    pub inspector: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
@@ -17,6 +34,31 @@ pub struct ExecutorConfiguration {
     pub max_time_ms: u32,
     pub max_io_concurrency: u32,
     pub max_io_per_request: u32,
+
+    /// Max number of requests that an instance handles at the same time. Requests are interleaved
+    /// while waiting for I/O, and each has its own CPU time budget.
+    #[serde(default = "default_max_concurrent_requests")]
+    pub max_concurrent_requests: u32,
+
+    /// Max number of rows returned by a SQL statement.
+    #[serde(default = "default_max_sql_rows")]
+    pub max_sql_rows: u32,
+
+    /// Max time of a batch of SQL statements, in milliseconds.
+    #[serde(default = "default_max_sql_time_ms")]
+    pub max_sql_time_ms: u32,
+}
+
+fn default_max_concurrent_requests() -> u32 {
+    1
+}
+
+fn default_max_sql_rows() -> u32 {
+    1000
+}
+
+fn default_max_sql_time_ms() -> u32 {
+    1000
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -32,6 +74,31 @@ pub struct RequestObject {
 
     #[serde(default)]
     pub body: HttpBody,
+
+    #[serde(default)]
+    pub metadata: RequestMetadata,
+}
+
+/// Information about the client connection that a request came from.
+#[derive(Default, Serialize, Deserialize, Clone, Debug)]
+pub struct RequestMetadata {
+    /// Unique identifier of this request, assigned by the proxy.
+    pub request_id: String,
+
+    /// IP address of the client.
+    pub client_ip: Option<IpAddr>,
+
+    /// Source port of the client.
+    pub client_port: Option<u16>,
+
+    /// HTTP protocol version, e.g. `HTTP/1.1`.
+    pub http_protocol: String,
+
+    /// TLS cipher suite, if the connection is encrypted.
+    pub tls_cipher: Option<String>,
+
+    /// TLS SNI hostname, if the connection is encrypted.
+    pub tls_sni: Option<String>,
 }
 
 #[derive(Default, Serialize, Deserialize, Clone, Debug)]
@@ -43,6 +110,177 @@ pub struct ResponseObject {
     pub body: HttpBody,
 }
 
+/// Result of a `fetch` event.
+#[derive(Default, Serialize, Deserialize, Clone, Debug)]
+pub struct FetchResult {
+    pub response: ResponseObject,
+    pub usage: ResourceUsage,
+}
+
+/// Resources used by a worker while handling a single request.
+#[derive(Default, Serialize, Deserialize, Clone, Debug)]
+pub struct ResourceUsage {
+    /// CPU time spent running script code, in microseconds.
+    pub cpu_time_us: u64,
+
+    /// Peak V8 heap usage, in bytes.
+    pub peak_heap_bytes: u64,
+
+    /// Peak ArrayBuffer memory usage, in bytes.
+    pub peak_array_buffer_bytes: u64,
+
+    /// Number of async I/O calls, including timers.
+    pub io_calls: u32,
+
+    /// Number of KV operations.
+    pub kv_subrequests: u32,
+
+    /// Number of fetch subrequests.
+    pub fetch_subrequests: u32,
+
+    /// Number of bucket operations.
+    pub bucket_subrequests: u32,
+
+    /// Number of SQL batches.
+    pub sql_subrequests: u32,
+}
+
+/// A change to a KV entry, read from the change log.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KvChange {
+    /// Position in the change log. Later changes have higher numbers.
+    pub seq: u64,
+
+    pub key: Vec<u8>,
+
+    /// The new value, or `None` if the key was deleted.
+    pub value: Option<Vec<u8>>,
+
+    /// Time of the change in milliseconds since the Unix epoch.
+    pub time: u64,
+}
+
+/// Changes delivered to a worker that watches a KV namespace.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KvChangeBatch {
+    /// Name of the namespace binding in the receiving worker.
+    pub namespace: String,
+
+    pub changes: Vec<KvChange>,
+}
+
+/// A statement sent to the SQL service.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SqlStatement {
+    pub sql: String,
+
+    /// Values bound to the positional parameters of the statement.
+    pub params: Vec<SqlValue>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum SqlValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+/// The result of a statement run by the SQL service.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SqlResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<SqlValue>>,
+
+    /// Number of rows modified by the last INSERT, UPDATE or DELETE statement.
+    pub changes: u64,
+
+    pub last_insert_rowid: i64,
+}
+
+/// Limits applied by the SQL service to a batch of statements.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SqlLimits {
+    /// Maximum number of rows returned by each statement.
+    pub max_rows: u32,
+
+    /// Time after which the batch is interrupted, in milliseconds.
+    pub time_budget_ms: u64,
+}
+
+/// Severity of a line logged by a worker.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Default for LogLevel {
+    fn default() -> Self {
+        Self::Debug
+    }
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = GenericError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "debug" => Ok(Self::Debug),
+            "info" => Ok(Self::Info),
+            "warn" => Ok(Self::Warn),
+            "error" => Ok(Self::Error),
+            _ => Err(GenericError::Other(format!("bad log level: {}", s))),
+        }
+    }
+}
+
+/// A line logged by a worker, delivered by `RuntimeService::tail_logs`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LiveLogEntry {
+    /// Position in the log of the app on this runtime. Later lines have higher numbers.
+    pub seq: u64,
+
+    /// Time in milliseconds since the Unix epoch.
+    pub time: u64,
+
+    pub level: LogLevel,
+    pub text: String,
+}
+
+/// Selects the lines delivered by `RuntimeService::tail_logs`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LogFilter {
+    /// Only lines of this level or higher.
+    pub min_level: LogLevel,
+
+    /// Only lines containing this text. Empty matches all lines.
+    pub contains: String,
+}
+
+impl LogFilter {
+    pub fn matches(&self, level: LogLevel, text: &str) -> bool {
+        level >= self.min_level && text.contains(&self.contains)
+    }
+}
+
+/// Lines returned by `RuntimeService::tail_logs`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LogTail {
+    pub entries: Vec<LiveLogEntry>,
+
+    /// Sequence number to pass as `after` in the next call.
+    pub cursor: u64,
+
+    /// Number of lines after the previous cursor that were evicted from the buffer before they
+    /// could be delivered.
+    pub dropped: u64,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum HttpBody {
     Binary(Vec<u8>),
@@ -138,6 +376,10 @@ pub enum GenericError {
     #[error("try again")]
     TryAgain,
 
+    /// A write would take a KV namespace over its key count or size quota. Nothing is written.
+    #[error("namespace quota exceeded")]
+    QuotaExceeded,
+
     #[error("type conversion failed")]
     Conversion,
 
@@ -165,6 +407,12 @@ impl From<mysql_async::Error> for GenericError {
     }
 }
 
+impl From<rusqlite::Error> for GenericError {
+    fn from(other: rusqlite::Error) -> Self {
+        Self::Database(format!("{:?}", other))
+    }
+}
+
 impl From<serde_json::Error> for GenericError {
     fn from(other: serde_json::Error) -> Self {
         Self::Database(format!("{:?}", other))
@@ -0,0 +1,7 @@
+CREATE TABLE `bundle_code_cache` (
+  `bundle_id` VARCHAR(64) NOT NULL ,
+  `v8_version` VARCHAR(64) NOT NULL ,
+  `cache` LONGBLOB NOT NULL ,
+  `createtime` BIGINT UNSIGNED NOT NULL ,
+  PRIMARY KEY (`bundle_id`, `v8_version`))
+  CHARSET=utf8mb4 COLLATE utf8mb4_bin;
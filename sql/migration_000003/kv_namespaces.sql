@@ -0,0 +1,13 @@
+CREATE TABLE `kv_namespaces` (
+  `id` VARCHAR(64) NOT NULL ,
+  `owner` VARCHAR(64) NOT NULL ,
+  `name` VARCHAR(200) NOT NULL ,
+  `createtime` BIGINT UNSIGNED NOT NULL ,
+  `max_keys` BIGINT UNSIGNED NOT NULL ,
+  `max_bytes` BIGINT UNSIGNED NOT NULL ,
+  `usedkeys` BIGINT NOT NULL DEFAULT 0 ,
+  `usedbytes` BIGINT NOT NULL DEFAULT 0 ,
+  PRIMARY KEY (`id`))
+  CHARSET=utf8mb4 COLLATE utf8mb4_bin;
+
+ALTER TABLE `kv_namespaces` ADD INDEX (`owner`);
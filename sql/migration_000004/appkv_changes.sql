@@ -0,0 +1,11 @@
+CREATE TABLE `appkv_changes` (
+  `seq` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT ,
+  `nsid` VARCHAR(64) NOT NULL ,
+  `appkey` VARBINARY(1024) NOT NULL ,
+  `appvalue` LONGBLOB NOT NULL ,
+  `deleted` BOOLEAN NOT NULL ,
+  `changetime` BIGINT UNSIGNED NOT NULL ,
+  PRIMARY KEY (`seq`));
+
+ALTER TABLE `appkv_changes` ADD INDEX (`nsid`, `seq`);
+ALTER TABLE `appkv_changes` ADD INDEX (`changetime`);
@@ -0,0 +1,12 @@
+CREATE TABLE `kv_watches` (
+  `id` VARCHAR(64) NOT NULL ,
+  `appid` VARCHAR(64) NOT NULL ,
+  `nsid` VARCHAR(64) NOT NULL ,
+  `prefix` VARBINARY(1024) NOT NULL ,
+  `lastseq` BIGINT UNSIGNED NOT NULL ,
+  `createtime` BIGINT UNSIGNED NOT NULL ,
+  PRIMARY KEY (`id`))
+  CHARSET=utf8mb4 COLLATE utf8mb4_bin;
+
+ALTER TABLE `kv_watches` ADD INDEX (`appid`);
+ALTER TABLE `kv_watches` ADD INDEX (`nsid`);
@@ -0,0 +1,12 @@
+CREATE TABLE `appkv_blobs` (
+  `nsid` VARCHAR(64) NOT NULL ,
+  `appkey` VARBINARY(1024) NOT NULL ,
+  `blobid` VARCHAR(64) NOT NULL ,
+  `blobsize` BIGINT UNSIGNED NOT NULL ,
+  `chunks` INT UNSIGNED NOT NULL ,
+  `appmetadata` BLOB NOT NULL ,
+  `appexpiration` BIGINT UNSIGNED NOT NULL ,
+  PRIMARY KEY (`nsid`, `appkey`));
+
+ALTER TABLE `appkv_blobs` ADD INDEX (`nsid`, `blobid`);
+ALTER TABLE `appkv_blobs` ADD INDEX (`appexpiration`);
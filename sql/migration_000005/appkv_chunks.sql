@@ -0,0 +1,9 @@
+CREATE TABLE `appkv_chunks` (
+  `nsid` VARCHAR(64) NOT NULL ,
+  `blobid` VARCHAR(64) NOT NULL ,
+  `chunkidx` INT UNSIGNED NOT NULL ,
+  `data` LONGBLOB NOT NULL ,
+  `createtime` BIGINT UNSIGNED NOT NULL ,
+  PRIMARY KEY (`nsid`, `blobid`, `chunkidx`));
+
+ALTER TABLE `appkv_chunks` ADD INDEX (`createtime`);
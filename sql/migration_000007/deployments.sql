@@ -0,0 +1,12 @@
+CREATE TABLE `deployments` (
+  `appid` VARCHAR(64) NOT NULL ,
+  `version` BIGINT UNSIGNED NOT NULL ,
+  `bundle_id` VARCHAR(64) NOT NULL ,
+  `config` TEXT NOT NULL ,
+  `author` VARCHAR(200) NOT NULL ,
+  `message` TEXT NOT NULL ,
+  `createtime` BIGINT UNSIGNED NOT NULL ,
+  PRIMARY KEY (`appid`, `version`))
+  CHARSET=utf8mb4 COLLATE utf8mb4_bin;
+
+ALTER TABLE `deployments` ADD INDEX (`bundle_id`);
@@ -0,0 +1 @@
+ALTER TABLE `apps` ADD COLUMN `log_retention_secs` BIGINT UNSIGNED NULL;